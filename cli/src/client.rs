@@ -3,9 +3,38 @@
 use anyhow::Result;
 use reqwest::Client;
 use serde_json::json;
+use std::collections::BTreeMap;
 use uuid::Uuid;
 
-use shared::{Artifact, CreateJobResponse, Job, UploadUrlResponse};
+use shared::{
+    Artifact, CreateJobResponse, Job, Matrix, RetryPolicy, RunDetails, UploadUrlResponse,
+};
+
+/// Which revision of a git repository to build, and the stored credential to fetch it with
+#[derive(Debug, Default)]
+pub struct GitRevision {
+    pub git_ref: Option<String>,
+    pub commit_sha: Option<String>,
+    pub submodules: bool,
+    pub lfs: bool,
+    pub credential_id: Option<Uuid>,
+}
+
+/// Options for a job whatever its source: the environment variables and
+/// stored secrets exported to the build, the jobs it waits for, the matrix
+/// it is run across, the labels a worker needs to run it, the image it runs
+/// in, how long it may run and when it is retried
+#[derive(Debug, Default)]
+pub struct JobOptions {
+    pub env: BTreeMap<String, String>,
+    pub secrets: Vec<String>,
+    pub needs: Vec<Uuid>,
+    pub matrix: Matrix,
+    pub runs_on: Vec<String>,
+    pub image: Option<String>,
+    pub timeout_minutes: Option<u32>,
+    pub retry: Option<RetryPolicy>,
+}
 
 #[derive(Clone)]
 pub struct AlloyClient {
@@ -41,11 +70,27 @@ impl AlloyClient {
         &self,
         command: Option<&str>,
         script: Option<&str>,
+        pipeline: Option<&str>,
         repo_url: &str,
+        revision: &GitRevision,
+        options: &JobOptions,
     ) -> Result<CreateJobResponse> {
         let mut body = json!({
             "source_type": "git",
             "source_url": repo_url,
+            "git_ref": revision.git_ref,
+            "commit_sha": revision.commit_sha,
+            "submodules": revision.submodules,
+            "lfs": revision.lfs,
+            "git_credential_id": revision.credential_id,
+            "env": options.env,
+            "secrets": options.secrets,
+            "needs": options.needs,
+            "matrix": options.matrix,
+            "runs_on": options.runs_on,
+            "image": options.image,
+            "timeout_minutes": options.timeout_minutes,
+            "retry": options.retry,
         });
 
         if let Some(cmd) = command {
@@ -54,6 +99,9 @@ impl AlloyClient {
         if let Some(scr) = script {
             body["script"] = json!(scr);
         }
+        if let Some(pipeline) = pipeline {
+            body["pipeline"] = json!(pipeline);
+        }
 
         let request = self
             .client
@@ -75,9 +123,20 @@ impl AlloyClient {
         &self,
         command: Option<&str>,
         script: Option<&str>,
+        pipeline: Option<&str>,
         commit_sha: Option<&str>,
+        options: &JobOptions,
     ) -> Result<UploadUrlResponse> {
-        let mut body = json!({});
+        let mut body = json!({
+            "env": options.env,
+            "secrets": options.secrets,
+            "needs": options.needs,
+            "matrix": options.matrix,
+            "runs_on": options.runs_on,
+            "image": options.image,
+            "timeout_minutes": options.timeout_minutes,
+            "retry": options.retry,
+        });
 
         if let Some(cmd) = command {
             body["command"] = json!(cmd);
@@ -85,6 +144,9 @@ impl AlloyClient {
         if let Some(scr) = script {
             body["script"] = json!(scr);
         }
+        if let Some(pipeline) = pipeline {
+            body["pipeline"] = json!(pipeline);
+        }
         if let Some(sha) = commit_sha {
             body["commit_sha"] = json!(sha);
         }
@@ -111,14 +173,21 @@ impl AlloyClient {
         _upload_token: &str,
         data: Vec<u8>,
     ) -> Result<()> {
-        // Upload goes through orchestrator proxy (no auth needed from CLI)
         let request = self
             .client
             .put(upload_url)
             .header("Content-Type", "application/zip")
             .body(data);
 
-        let response = self.add_auth(request).send().await?;
+        // Presigned storage URLs carry their own credentials and reject an
+        // extra Authorization header, so only authenticate against the orchestrator
+        let request = if upload_url.starts_with(&self.base_url) {
+            self.add_auth(request)
+        } else {
+            request
+        };
+
+        let response = request.send().await?;
 
         if !response.status().is_success() {
             let error = response.text().await?;
@@ -160,6 +229,22 @@ impl AlloyClient {
         Ok(response.json().await?)
     }
 
+    /// Get a matrix run with its aggregate status and jobs
+    pub async fn get_run(&self, run_id: Uuid) -> Result<RunDetails> {
+        let request = self
+            .client
+            .get(format!("{}/api/v1/runs/{}", self.base_url, run_id));
+
+        let response = self.add_auth(request).send().await?;
+
+        if !response.status().is_success() {
+            let error = response.text().await?;
+            anyhow::bail!("Failed to get run: {error}");
+        }
+
+        Ok(response.json().await?)
+    }
+
     /// Get job artifacts
     pub async fn get_artifacts(&self, job_id: Uuid) -> Result<Vec<Artifact>> {
         let request = self.client.get(format!(
@@ -203,14 +288,19 @@ impl AlloyClient {
     }
 
     /// List recent jobs
-    pub async fn list_jobs(&self, status: Option<&str>) -> Result<Vec<Job>> {
-        let mut url = format!("{}/api/v1/jobs", self.base_url);
-
+    pub async fn list_jobs(&self, status: Option<&str>, run_id: Option<Uuid>) -> Result<Vec<Job>> {
+        let mut query = Vec::new();
         if let Some(s) = status {
-            url = format!("{url}?status={s}");
+            query.push(("status", s.to_string()));
+        }
+        if let Some(run_id) = run_id {
+            query.push(("run_id", run_id.to_string()));
         }
 
-        let request = self.client.get(&url);
+        let request = self
+            .client
+            .get(format!("{}/api/v1/jobs", self.base_url))
+            .query(&query);
         let response = self.add_auth(request).send().await?;
 
         if !response.status().is_success() {
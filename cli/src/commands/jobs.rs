@@ -6,11 +6,16 @@ use crossterm::{
     style::{Color, Print, ResetColor, SetForegroundColor},
 };
 use std::io::stdout;
+use uuid::Uuid;
 
 use crate::client::AlloyClient;
 
-pub async fn execute(client: AlloyClient, status: Option<&str>) -> Result<()> {
-    let jobs = client.list_jobs(status).await?;
+pub async fn execute(
+    client: AlloyClient,
+    status: Option<&str>,
+    run_id: Option<Uuid>,
+) -> Result<()> {
+    let jobs = client.list_jobs(status, run_id).await?;
 
     if jobs.is_empty() {
         println!("No jobs found.");
@@ -32,6 +37,7 @@ pub async fn execute(client: AlloyClient, status: Option<&str>) -> Result<()> {
             shared::JobStatus::Completed => ("✓ ", Color::Green),
             shared::JobStatus::Failed => ("✗ ", Color::Red),
             shared::JobStatus::Cancelled => ("⊘ ", Color::DarkGrey),
+            shared::JobStatus::TimedOut => ("⏱ ", Color::Magenta),
         };
 
         let command = job
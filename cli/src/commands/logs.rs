@@ -77,6 +77,7 @@ pub async fn execute(client: AlloyClient, job_id: &str) -> Result<()> {
     let (status_icon, status_color) = match job.status {
         shared::JobStatus::Completed => ("✓", Color::Green),
         shared::JobStatus::Failed => ("✗", Color::Red),
+        shared::JobStatus::TimedOut => ("⏱", Color::Magenta),
         shared::JobStatus::Running => ("▶", Color::Cyan),
         _ => ("?", Color::Yellow),
     };
@@ -91,9 +92,7 @@ pub async fn execute(client: AlloyClient, job_id: &str) -> Result<()> {
         ResetColor
     )?;
 
-    if let Some(exit_code) = job.exit_code {
-        println!("   Exit code: {exit_code}");
-    }
+    super::print_exit(&job);
     if let Some(minutes) = job.build_minutes {
         println!("   Build time: {}", super::format_build_time(minutes));
     }
@@ -0,0 +1,130 @@
+//! Matrix command - summarise the jobs of a matrix run
+
+use anyhow::Result;
+use crossterm::execute;
+use crossterm::style::{Color, Print, ResetColor, SetForegroundColor};
+use std::collections::BTreeMap;
+use std::io::stdout;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::client::AlloyClient;
+use shared::{JobStatus, RunDetails};
+
+/// How often `alloy run` checks on a matrix run
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+pub async fn execute(client: AlloyClient, run_id: &str) -> Result<()> {
+    let run_id = Uuid::parse_str(run_id).map_err(|_| anyhow::anyhow!("Invalid run ID format"))?;
+    let run = client.get_run(run_id).await?;
+    print_summary(&run)
+}
+
+/// Wait for every job of a run to finish, reporting each as it does, then
+/// print the run's summary
+pub async fn wait(client: &AlloyClient, run_id: Uuid) -> Result<()> {
+    let mut reported = Vec::new();
+
+    loop {
+        let run = client.get_run(run_id).await?;
+        for job in &run.jobs {
+            if job.status.is_finished() && !reported.contains(&job.id) {
+                reported.push(job.id);
+                let (icon, color) = status_style(job.status);
+                execute!(
+                    stdout(),
+                    SetForegroundColor(color),
+                    Print(format!(
+                        "{icon} {} - {:?}\n",
+                        describe_cell(&job.matrix),
+                        job.status
+                    )),
+                    ResetColor
+                )?;
+            }
+        }
+
+        if run.status.is_finished() {
+            println!();
+            return print_summary(&run);
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Print a run's status and the outcome of each cell, then list the failed cells
+pub fn print_summary(run: &RunDetails) -> Result<()> {
+    let (icon, color) = status_style(run.status);
+    execute!(
+        stdout(),
+        SetForegroundColor(color),
+        Print(format!("{icon} Run: {} - {:?}\n", run.run.id, run.status)),
+        ResetColor
+    )?;
+    for (axis, values) in &run.run.matrix {
+        println!("   {axis}: {}", values.join(", "));
+    }
+    println!();
+
+    println!("{:<8} {:<38} CELL", "STATUS", "JOB ID");
+    println!("{}", "─".repeat(78));
+    for job in &run.jobs {
+        let (icon, color) = status_style(job.status);
+        execute!(
+            stdout(),
+            SetForegroundColor(color),
+            Print(format!("{icon:<8}")),
+            ResetColor,
+            Print(format!("{:<38} {}\n", job.id, describe_cell(&job.matrix))),
+        )?;
+    }
+
+    let failed: Vec<_> = run
+        .jobs
+        .iter()
+        .filter(|job| matches!(job.status, JobStatus::Failed | JobStatus::TimedOut))
+        .collect();
+    if !failed.is_empty() {
+        println!();
+        execute!(
+            stdout(),
+            SetForegroundColor(Color::Red),
+            Print(format!(
+                "✗ {} of {} cells failed:\n",
+                failed.len(),
+                run.jobs.len()
+            )),
+            ResetColor
+        )?;
+        for job in failed {
+            if job.status == JobStatus::TimedOut {
+                println!("   {} (timed out)", describe_cell(&job.matrix));
+            } else {
+                println!("   {}", describe_cell(&job.matrix));
+            }
+            println!("     alloy logs {}", job.id);
+        }
+    }
+
+    Ok(())
+}
+
+/// A cell's axis values, e.g. `SCHEME=App, XCODE=16.0`
+fn describe_cell(cell: &BTreeMap<String, String>) -> String {
+    cell.iter()
+        .map(|(axis, value)| format!("{axis}={value}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+const fn status_style(status: JobStatus) -> (&'static str, Color) {
+    match status {
+        JobStatus::Pending => ("⏳", Color::Yellow),
+        JobStatus::Uploading => ("📤", Color::Cyan),
+        JobStatus::Running => ("▶️ ", Color::Cyan),
+        JobStatus::Completed => ("✓ ", Color::Green),
+        JobStatus::Failed => ("✗ ", Color::Red),
+        JobStatus::Cancelled => ("⊘ ", Color::DarkGrey),
+        JobStatus::TimedOut => ("⏱ ", Color::Magenta),
+    }
+}
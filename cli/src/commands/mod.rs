@@ -1,10 +1,17 @@
 //! CLI commands
 
+use crossterm::execute;
+use crossterm::style::{Color, Print, ResetColor, SetForegroundColor};
+use shared::{FailureReason, Job, JobStatus, StepStatus};
+use std::fmt::Write as _;
+use std::io::stdout;
+
 pub mod artifacts;
 pub mod cancel;
 pub mod config;
 pub mod jobs;
 pub mod logs;
+pub mod matrix;
 pub mod retry;
 pub mod run;
 pub mod status;
@@ -26,3 +33,67 @@ pub fn format_build_time(minutes: f64) -> String {
         format!("{secs}s")
     }
 }
+
+/// Print how a finished job ended: stopped at its timeout, failed before its
+/// build could run, or else the exit code of its command (pipeline jobs have
+/// one per step instead)
+pub fn print_exit(job: &Job) {
+    if job.status == JobStatus::TimedOut {
+        match job.timeout_minutes {
+            Some(minutes) => println!("   Timed out: hit its {minutes} minute timeout"),
+            None => println!("   Timed out at the worker's time limit"),
+        }
+    } else if job.status == JobStatus::Failed && job.failure_reason == Some(FailureReason::Infra) {
+        match job.infra_retries {
+            0 => println!("   Failed: infrastructure error on the worker"),
+            retries => println!(
+                "   Failed: infrastructure error on the worker, also after {retries} retries"
+            ),
+        }
+    } else if job.status == JobStatus::Failed
+        && job.failure_reason == Some(FailureReason::SourceFetch)
+    {
+        println!("   Failed: the source could not be fetched");
+    } else if job.steps.is_empty() {
+        if let Some(exit_code) = job.exit_code {
+            println!("   Exit code: {exit_code}");
+        }
+    }
+}
+
+/// Print the outcome of each step of a pipeline job, in order
+pub fn print_steps(job: &Job) -> anyhow::Result<()> {
+    println!("🪜 Steps:");
+    for (i, step) in job.steps.iter().enumerate() {
+        let Some(result) = job.step_results.get(i) else {
+            println!("   · {} (not finished)", step.name);
+            continue;
+        };
+
+        let (icon, color) = match result.status {
+            StepStatus::Succeeded => ("✓", Color::Green),
+            StepStatus::Failed if step.continue_on_error => ("!", Color::Yellow),
+            StepStatus::Failed | StepStatus::TimedOut => ("✗", Color::Red),
+            StepStatus::Cancelled | StepStatus::Skipped => ("⊘", Color::DarkGrey),
+        };
+        let mut details = result.status.to_string();
+        if let Some(exit_code) = result.exit_code {
+            let _ = write!(details, ", exit code {exit_code}");
+        }
+        if result.started_at.is_some() {
+            let _ = write!(
+                details,
+                ", {}",
+                format_build_time(result.duration_secs / 60.0)
+            );
+        }
+
+        execute!(
+            stdout(),
+            SetForegroundColor(color),
+            Print(format!("   {icon} {} ({details})\n", step.name)),
+            ResetColor
+        )?;
+    }
+    Ok(())
+}
@@ -7,21 +7,32 @@ use futures_util::StreamExt;
 use std::io::{stdout, Write};
 use std::path::Path;
 use tokio_tungstenite::connect_async;
+use uuid::Uuid;
 
 use crate::archive;
-use crate::client::AlloyClient;
-use shared::LogEntry;
+use crate::client::{AlloyClient, GitRevision, JobOptions};
+use shared::{JobStatus, LogEntry};
+
+/// Pipeline file run when no command or script is given
+const PIPELINE_FILE: &str = "alloy.yml";
 
 #[allow(clippy::too_many_lines)]
 pub async fn execute(
     client: AlloyClient,
     command: Option<String>,
     script_path: Option<String>,
+    pipeline_path: Option<String>,
     repo: Option<String>,
+    revision: GitRevision,
+    options: JobOptions,
 ) -> Result<()> {
-    // Validate: need either command or script
-    if command.is_none() && script_path.is_none() {
-        anyhow::bail!("Either a command or --script is required");
+    // Without a command or script, run the steps of ./alloy.yml
+    let pipeline_path = pipeline_path.or_else(|| {
+        (command.is_none() && script_path.is_none() && Path::new(PIPELINE_FILE).exists())
+            .then(|| PIPELINE_FILE.to_string())
+    });
+    if command.is_none() && script_path.is_none() && pipeline_path.is_none() {
+        anyhow::bail!("Either a command, --script or an {PIPELINE_FILE} pipeline is required");
     }
 
     // Read script file if provided
@@ -33,6 +44,15 @@ pub async fn execute(
         None
     };
 
+    // The orchestrator parses and validates the pipeline
+    let pipeline = if let Some(ref path) = pipeline_path {
+        let pipeline_content = std::fs::read_to_string(Path::new(path))
+            .map_err(|e| anyhow::anyhow!("Failed to read pipeline file '{path}': {e}"))?;
+        Some(pipeline_content)
+    } else {
+        None
+    };
+
     println!("🚀 Submitting job to Alloy API...");
     if let Some(ref cmd) = command {
         println!("   Command: {cmd}");
@@ -40,15 +60,49 @@ pub async fn execute(
     if let Some(ref path) = script_path {
         println!("   Script: {path}");
     }
+    if let Some(ref path) = pipeline_path {
+        println!("   Pipeline: {path}");
+    }
+    for name in options.env.keys().chain(&options.secrets) {
+        println!("   Env: {name}");
+    }
+    for job_id in &options.needs {
+        println!("   Needs: {job_id}");
+    }
+    for (axis, values) in &options.matrix {
+        println!("   Matrix: {axis} = {}", values.join(", "));
+    }
+    if !options.runs_on.is_empty() {
+        println!("   Runs on: {}", options.runs_on.join(", "));
+    }
+    if let Some(image) = &options.image {
+        println!("   Image: {image}");
+    }
+    if let Some(minutes) = options.timeout_minutes {
+        println!("   Timeout: {minutes} minutes");
+    }
 
     let response = if let Some(ref repo_url) = repo {
         // Git-based job
         println!("   Source: Git repository");
         println!("   URL: {repo_url}");
+        if let Some(ref git_ref) = revision.git_ref {
+            println!("   Ref: {git_ref}");
+        }
+        if let Some(ref sha) = revision.commit_sha {
+            println!("   Commit: {sha}");
+        }
         println!();
 
         client
-            .create_job_git(command.as_deref(), script.as_deref(), repo_url)
+            .create_job_git(
+                command.as_deref(),
+                script.as_deref(),
+                pipeline.as_deref(),
+                repo_url,
+                &revision,
+                &options,
+            )
             .await?
     } else {
         // Local upload job
@@ -73,7 +127,13 @@ pub async fn execute(
         print!("📤 Requesting upload URL...");
         stdout().flush().ok();
         let upload_info = client
-            .request_upload_url(command.as_deref(), script.as_deref(), commit_sha.as_deref())
+            .request_upload_url(
+                command.as_deref(),
+                script.as_deref(),
+                pipeline.as_deref(),
+                commit_sha.as_deref(),
+                &options,
+            )
             .await?;
         println!(" ✓");
 
@@ -102,6 +162,25 @@ pub async fn execute(
         response
     };
 
+    // A matrix runs as many jobs at once; follow the run rather than one log
+    if let Some(run_id) = response.run_id {
+        execute!(
+            stdout(),
+            SetForegroundColor(Color::Green),
+            Print(format!(
+                "\n✓ Run created: {run_id} ({} jobs)\n",
+                response.job_ids.len()
+            )),
+            ResetColor
+        )?;
+        for job_id in &response.job_ids {
+            println!("   Job: {job_id}");
+        }
+        println!();
+        println!("⏳ Waiting for the run to finish (stream a job with 'alloy logs <job_id>')...\n");
+        return super::matrix::wait(&client, run_id).await;
+    }
+
     execute!(
         stdout(),
         SetForegroundColor(Color::Green),
@@ -109,12 +188,85 @@ pub async fn execute(
         ResetColor
     )?;
     println!("   Stream URL: {}", response.stream_url);
+
+    // Say so up front when no worker can pick the job up
+    let job = client.get_job(response.job_id).await?;
+    if let (JobStatus::Pending, Some(reason)) = (job.status, &job.status_reason) {
+        execute!(
+            stdout(),
+            SetForegroundColor(Color::Yellow),
+            Print(format!("   ⚠ {reason}\n")),
+            ResetColor
+        )?;
+    }
+
     println!();
     println!("📺 Streaming logs...\n");
     println!("{}", "─".repeat(60));
 
+    // A failed job retried by its retry policy continues as its next attempt
+    let mut job_id = response.job_id;
+    while let Some(retry_job_id) = stream_logs(&client, job_id).await? {
+        execute!(
+            stdout(),
+            SetForegroundColor(Color::Yellow),
+            Print(format!("\n↻ Retrying as job {retry_job_id}\n\n")),
+            ResetColor
+        )?;
+        job_id = retry_job_id;
+    }
+
+    println!("{}", "─".repeat(60));
+    println!();
+
+    // Get final job status
+    let job = client.get_job(job_id).await?;
+
+    let (status_icon, status_color) = match job.status {
+        shared::JobStatus::Completed => ("✓", Color::Green),
+        shared::JobStatus::Failed => ("✗", Color::Red),
+        shared::JobStatus::TimedOut => ("⏱", Color::Magenta),
+        _ => ("?", Color::Yellow),
+    };
+
+    execute!(
+        stdout(),
+        SetForegroundColor(status_color),
+        Print(format!(
+            "{} Job {} - {:?}\n",
+            status_icon, job.id, job.status
+        )),
+        ResetColor
+    )?;
+
+    super::print_exit(&job);
+    if !job.steps.is_empty() {
+        super::print_steps(&job)?;
+    }
+    if let Some(minutes) = job.build_minutes {
+        println!("   Build time: {}", super::format_build_time(minutes));
+    }
+
+    // Check for artifacts
+    let artifacts = client.get_artifacts(job_id).await?;
+    if !artifacts.is_empty() {
+        println!();
+        println!("📦 Artifacts:");
+        for artifact in &artifacts {
+            println!("   • {} ({} bytes)", artifact.name, artifact.size_bytes);
+        }
+        println!();
+        println!("   Download with: alloy artifacts {job_id}");
+    }
+
+    Ok(())
+}
+
+/// Print a job's logs until it finishes, returning the job that retries it
+/// if its retry policy retried it
+async fn stream_logs(client: &AlloyClient, job_id: Uuid) -> Result<Option<Uuid>> {
     // Connect to WebSocket for log streaming
-    let ws_url = client.get_stream_url(response.job_id);
+    let ws_url = client.get_stream_url(job_id);
     let (ws_stream, _) = connect_async(&ws_url).await?;
     let (_, mut read) = ws_stream.split();
 
@@ -165,7 +317,25 @@ pub async fn execute(
                             ResetColor
                         )?;
                         println!("   Build time: {}", super::format_build_time(build_minutes));
-                        break;
+                        return Ok(json
+                            .get("retry_job_id")
+                            .and_then(|id| id.as_str())
+                            .and_then(|id| id.parse().ok()));
+                    } else if json.get("type").and_then(|t| t.as_str()) == Some("job_reclaimed") {
+                        // The worker running the job went away
+                        let reason = json
+                            .get("reason")
+                            .and_then(|r| r.as_str())
+                            .unwrap_or("Worker lost");
+                        execute!(
+                            stdout(),
+                            SetForegroundColor(Color::Yellow),
+                            Print(format!("\n⚠ {reason}\n")),
+                            ResetColor
+                        )?;
+                        if json.get("status").and_then(|s| s.as_str()) != Some("pending") {
+                            break;
+                        }
                     } else if let Some(err) = json.get("error") {
                         execute!(
                             stdout(),
@@ -187,46 +357,5 @@ pub async fn execute(
         }
     }
 
-    println!("{}", "─".repeat(60));
-    println!();
-
-    // Get final job status
-    let job = client.get_job(response.job_id).await?;
-
-    let (status_icon, status_color) = match job.status {
-        shared::JobStatus::Completed => ("✓", Color::Green),
-        shared::JobStatus::Failed => ("✗", Color::Red),
-        _ => ("?", Color::Yellow),
-    };
-
-    execute!(
-        stdout(),
-        SetForegroundColor(status_color),
-        Print(format!(
-            "{} Job {} - {:?}\n",
-            status_icon, job.id, job.status
-        )),
-        ResetColor
-    )?;
-
-    if let Some(exit_code) = job.exit_code {
-        println!("   Exit code: {exit_code}");
-    }
-    if let Some(minutes) = job.build_minutes {
-        println!("   Build time: {}", super::format_build_time(minutes));
-    }
-
-    // Check for artifacts
-    let artifacts = client.get_artifacts(response.job_id).await?;
-    if !artifacts.is_empty() {
-        println!();
-        println!("📦 Artifacts:");
-        for artifact in &artifacts {
-            println!("   • {} ({} bytes)", artifact.name, artifact.size_bytes);
-        }
-        println!();
-        println!("   Download with: alloy artifacts {}", response.job_id);
-    }
-
-    Ok(())
+    Ok(None)
 }
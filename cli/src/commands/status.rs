@@ -3,10 +3,12 @@
 use anyhow::Result;
 use crossterm::execute;
 use crossterm::style::{Color, Print, ResetColor, SetForegroundColor};
+use std::fmt::Write as _;
 use std::io::stdout;
 use uuid::Uuid;
 
 use crate::client::AlloyClient;
+use shared::Job;
 
 pub async fn execute(client: AlloyClient, job_id: &str) -> Result<()> {
     let job_uuid = job_id.parse::<Uuid>()?;
@@ -19,6 +21,7 @@ pub async fn execute(client: AlloyClient, job_id: &str) -> Result<()> {
         shared::JobStatus::Completed => ("✓", Color::Green),
         shared::JobStatus::Failed => ("✗", Color::Red),
         shared::JobStatus::Cancelled => ("⊘", Color::DarkGrey),
+        shared::JobStatus::TimedOut => ("⏱", Color::Magenta),
     };
 
     println!();
@@ -30,18 +33,56 @@ pub async fn execute(client: AlloyClient, job_id: &str) -> Result<()> {
     )?;
 
     println!("   Status: {:?}", job.status);
+    if let Some(ref reason) = job.status_reason {
+        println!("   Reason: {reason}");
+    }
     if let Some(ref cmd) = job.command {
         println!("   Command: {cmd}");
     }
     if job.script.is_some() {
         println!("   Script: (inline script)");
     }
+    if !job.steps.is_empty() {
+        println!("   Pipeline: {} steps", job.steps.len());
+    }
     println!("   Source: {:?}", job.source_type);
 
     if let Some(ref url) = job.source_url {
         println!("   Source URL: {url}");
     }
 
+    if let Some(ref git_ref) = job.git_ref {
+        println!("   Ref: {git_ref}");
+    }
+
+    if let Some(sha) = job.resolved_sha.as_ref().or(job.commit_sha.as_ref()) {
+        println!("   Commit: {sha}");
+    }
+
+    for needed in &job.needs {
+        println!("   Needs: {needed}");
+    }
+    if !job.runs_on.is_empty() {
+        println!("   Runs on: {}", job.runs_on.join(", "));
+    }
+    if let Some(image) = &job.image {
+        println!("   Image: {image}");
+    }
+    if let Some(minutes) = job.timeout_minutes {
+        println!("   Timeout: {minutes} minutes");
+    }
+    match &job.retry {
+        Some(policy) => println!("   Attempt: {} of {}", job.attempt, policy.max_attempts),
+        None if job.attempt > 1 => println!("   Attempt: {}", job.attempt),
+        None => {},
+    }
+    if let Some(run_id) = job.run_id {
+        println!("   Run: {run_id}");
+        for (axis, value) in &job.matrix {
+            println!("   Matrix: {axis}={value}");
+        }
+    }
+
     println!("   Created: {}", job.created_at);
 
     if let Some(started) = job.started_at {
@@ -52,14 +93,22 @@ pub async fn execute(client: AlloyClient, job_id: &str) -> Result<()> {
         println!("   Completed: {completed}");
     }
 
-    if let Some(exit_code) = job.exit_code {
-        println!("   Exit code: {exit_code}");
-    }
+    super::print_exit(&job);
 
     if let Some(minutes) = job.build_minutes {
         println!("   Build time: {}", super::format_build_time(minutes));
     }
 
+    if !job.steps.is_empty() {
+        println!();
+        super::print_steps(&job)?;
+    }
+
+    if !job.attempts.is_empty() {
+        println!();
+        print_attempts(&job);
+    }
+
     // Check for artifacts
     let artifacts = client.get_artifacts(job_uuid).await?;
     if !artifacts.is_empty() {
@@ -73,3 +122,22 @@ pub async fn execute(client: AlloyClient, job_id: &str) -> Result<()> {
     println!();
     Ok(())
 }
+
+/// Print every attempt at the job, marking the one asked about
+fn print_attempts(job: &Job) {
+    println!("🔁 Attempts:");
+    for attempt in &job.attempts {
+        let mut details = format!("{:?}", attempt.status);
+        if let Some(reason) = attempt.failure_reason {
+            let _ = write!(details, ", {reason}");
+        }
+        if let Some(exit_code) = attempt.exit_code {
+            let _ = write!(details, ", exit code {exit_code}");
+        }
+        let marker = if attempt.job_id == job.id { " ←" } else { "" };
+        println!(
+            "   {}. {} ({details}){marker}",
+            attempt.attempt, attempt.job_id
+        );
+    }
+}
@@ -8,6 +8,7 @@ mod commands;
 mod config_store;
 
 use clap::{Parser, Subcommand};
+use shared::{RetryOn, RetryPolicy};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 use commands::config::ConfigAction;
@@ -31,6 +32,8 @@ struct Cli {
     command: Commands,
 }
 
+// Parsed once per invocation, so the size of `Run` doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 enum Commands {
     /// Run a build script on a remote Mac
@@ -42,9 +45,95 @@ enum Commands {
         #[arg(short, long)]
         command: Option<String>,
 
+        /// Pipeline file whose steps to run (default: ./alloy.yml if neither a
+        /// script nor a command is given)
+        #[arg(short, long, value_name = "PATH", conflicts_with_all = ["script", "command"])]
+        file: Option<String>,
+
         /// Git repository URL to clone
         #[arg(short, long)]
         repo: Option<String>,
+
+        /// Branch or tag to build (default: the repository's default branch)
+        #[arg(long = "ref", value_name = "REF", requires = "repo")]
+        git_ref: Option<String>,
+
+        /// Exact commit to build
+        #[arg(long, requires = "repo")]
+        sha: Option<String>,
+
+        /// Also check out submodules
+        #[arg(long, requires = "repo")]
+        submodules: bool,
+
+        /// Also fetch Git LFS files
+        #[arg(long, requires = "repo")]
+        lfs: bool,
+
+        /// ID of a stored git credential to clone a private repository with
+        #[arg(long, value_name = "ID", requires = "repo")]
+        credential: Option<uuid::Uuid>,
+
+        /// Environment variable for the build (repeatable)
+        #[arg(short, long = "env", value_name = "NAME=VALUE", value_parser = parse_env_var)]
+        env: Vec<(String, String)>,
+
+        /// Stored secret to export to the build under its own name (repeatable)
+        #[arg(long = "secret", value_name = "NAME")]
+        secrets: Vec<String>,
+
+        /// Job that must complete first; its artifacts are copied into
+        /// .alloy/artifacts/ in the workspace (repeatable)
+        #[arg(long = "needs", value_name = "JOB_ID")]
+        needs: Vec<uuid::Uuid>,
+
+        /// Matrix axis to run the build across, one job per combination of
+        /// values, each exported as AXIS (repeatable)
+        #[arg(long = "matrix", value_name = "AXIS=V1,V2", value_parser = parse_matrix_axis)]
+        matrix: Vec<(String, Vec<String>)>,
+
+        /// Label a worker must have to run the build, e.g. m2 or xcode-16
+        /// (repeatable)
+        #[arg(long = "runs-on", value_name = "LABEL")]
+        runs_on: Vec<String>,
+
+        /// Base image to run the build in, by the name workers give it
+        /// (default: each worker's first image)
+        #[arg(long, value_name = "NAME")]
+        image: Option<String>,
+
+        /// Minutes the build may run before it is stopped and marked timed
+        /// out (default: the worker's limit)
+        #[arg(long = "timeout", value_name = "MINUTES")]
+        timeout_minutes: Option<u32>,
+
+        /// Attempts to make at the build in all, retrying it when it fails in
+        /// one of the --retry-on ways
+        #[arg(long, value_name = "N")]
+        max_attempts: Option<u32>,
+
+        /// Failures to retry: infra, timeout or `exit_codes` (default: infra,
+        /// and `exit_codes` with --retry-exit-code)
+        #[arg(
+            long,
+            value_name = "FAILURE",
+            value_delimiter = ',',
+            requires = "max_attempts"
+        )]
+        retry_on: Vec<RetryOn>,
+
+        /// Exit code to retry under `exit_codes` (repeatable; default: any
+        /// non-zero code)
+        #[arg(
+            long = "retry-exit-code",
+            value_name = "CODE",
+            requires = "max_attempts"
+        )]
+        retry_exit_codes: Vec<i32>,
+
+        /// Seconds to wait before the first retry, doubled for each one after
+        #[arg(long, value_name = "SECONDS", requires = "max_attempts")]
+        retry_backoff: Option<u32>,
     },
 
     /// Check the status of a job
@@ -80,6 +169,16 @@ enum Commands {
         /// Filter by status (pending, running, completed, failed, cancelled)
         #[arg(short, long)]
         status: Option<String>,
+
+        /// Only list the jobs of a matrix run
+        #[arg(long = "run", value_name = "RUN_ID")]
+        run_id: Option<uuid::Uuid>,
+    },
+
+    /// Summarise a matrix run: its status and which cells failed
+    Matrix {
+        /// Run ID to summarise
+        run_id: String,
     },
 
     /// Retry a failed or cancelled job
@@ -96,6 +195,46 @@ enum Commands {
     },
 }
 
+/// Parse a `--env NAME=VALUE` argument
+fn parse_env_var(arg: &str) -> Result<(String, String), String> {
+    arg.split_once('=')
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected NAME=VALUE, got {arg:?}"))
+}
+
+/// Parse a `--matrix AXIS=V1,V2` argument
+fn parse_matrix_axis(arg: &str) -> Result<(String, Vec<String>), String> {
+    arg.split_once('=')
+        .map(|(axis, values)| {
+            (
+                axis.to_string(),
+                values.split(',').map(str::to_string).collect(),
+            )
+        })
+        .ok_or_else(|| format!("expected AXIS=V1,V2, got {arg:?}"))
+}
+
+/// The retry policy `alloy run --max-attempts` asks for
+fn retry_policy(
+    max_attempts: u32,
+    mut retry_on: Vec<RetryOn>,
+    exit_codes: Vec<i32>,
+    backoff_seconds: Option<u32>,
+) -> RetryPolicy {
+    if retry_on.is_empty() {
+        retry_on.push(RetryOn::Infra);
+        if !exit_codes.is_empty() {
+            retry_on.push(RetryOn::ExitCodes);
+        }
+    }
+    RetryPolicy {
+        max_attempts,
+        retry_on,
+        exit_codes,
+        backoff_seconds: backoff_seconds.unwrap_or(0),
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     // Load .env file if present
@@ -133,15 +272,56 @@ async fn main() -> anyhow::Result<()> {
         Commands::Run {
             script,
             command,
+            file,
             repo,
-        } => commands::run::execute(client, command, script, repo).await,
+            git_ref,
+            sha,
+            submodules,
+            lfs,
+            credential,
+            env,
+            secrets,
+            needs,
+            matrix,
+            runs_on,
+            image,
+            timeout_minutes,
+            max_attempts,
+            retry_on,
+            retry_exit_codes,
+            retry_backoff,
+        } => {
+            let revision = client::GitRevision {
+                git_ref,
+                commit_sha: sha,
+                submodules,
+                lfs,
+                credential_id: credential,
+            };
+            let options = client::JobOptions {
+                env: env.into_iter().collect(),
+                secrets,
+                needs,
+                matrix: matrix.into_iter().collect(),
+                runs_on,
+                image,
+                timeout_minutes,
+                retry: max_attempts.map(|max_attempts| {
+                    retry_policy(max_attempts, retry_on, retry_exit_codes, retry_backoff)
+                }),
+            };
+            commands::run::execute(client, command, script, file, repo, revision, options).await
+        },
         Commands::Status { job_id } => commands::status::execute(client, &job_id).await,
         Commands::Artifacts { job_id, output } => {
             commands::artifacts::execute(client, &job_id, &output).await
         },
         Commands::Cancel { job_id } => commands::cancel::execute(client, &job_id).await,
         Commands::Logs { job_id } => commands::logs::execute(client, &job_id).await,
-        Commands::Jobs { status } => commands::jobs::execute(client, status.as_deref()).await,
+        Commands::Jobs { status, run_id } => {
+            commands::jobs::execute(client, status.as_deref(), run_id).await
+        },
+        Commands::Matrix { run_id } => commands::matrix::execute(client, &run_id).await,
         Commands::Retry { job_id } => commands::retry::execute(client, &job_id).await,
         Commands::Config { action } => commands::config::execute(action).await,
     }
@@ -11,6 +11,7 @@ use axum::{
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::crypto::constant_time_compare;
 use crate::state::AppState;
 use shared::ApiError;
 
@@ -41,18 +42,6 @@ struct JwtClaims {
     exp: i64,
 }
 
-/// API key record from database
-#[derive(Debug, Deserialize)]
-#[allow(dead_code)]
-pub struct ApiKeyRecord {
-    pub id: Uuid,
-    pub user_id: Uuid,
-    pub name: String,
-    pub key_hash: String,
-    pub created_at: chrono::DateTime<chrono::Utc>,
-    pub last_used_at: Option<chrono::DateTime<chrono::Utc>>,
-}
-
 /// Extractor implementation for `AuthUser`
 #[async_trait]
 impl<S> FromRequestParts<S> for AuthUser
@@ -157,10 +146,22 @@ async fn verify_jwt(
         email: Option<String>,
     }
 
+    let (Some(supabase_url), Some(supabase_key)) =
+        (&state.config.supabase_url, &state.config.supabase_key)
+    else {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            Json(ApiError::new(
+                "JWT authentication requires Supabase, use 'ApiKey <key>' instead",
+                "invalid_token",
+            )),
+        ));
+    };
+
     let response = state
         .client
-        .get(format!("{}/auth/v1/user", state.config.supabase_url))
-        .header("apikey", &state.config.supabase_key)
+        .get(format!("{supabase_url}/auth/v1/user"))
+        .header("apikey", supabase_key)
         .header("Authorization", format!("Bearer {token}"))
         .send()
         .await
@@ -203,10 +204,10 @@ async fn verify_api_key(
     // We hash the incoming key and compare
     let key_hash = hash_api_key(key);
 
-    match state.supabase.verify_api_key(&key_hash).await {
+    match state.db.verify_api_key(&key_hash).await {
         Ok(Some(record)) => {
             // Update last_used_at
-            let _ = state.supabase.update_api_key_usage(record.id).await;
+            let _ = state.db.update_api_key_usage(record.id).await;
 
             Ok(AuthUser {
                 user_id: record.user_id,
@@ -307,16 +308,3 @@ pub async fn worker_auth_middleware(
         },
     }
 }
-
-/// Constant-time string comparison to prevent timing attacks
-fn constant_time_compare(a: &str, b: &str) -> bool {
-    if a.len() != b.len() {
-        return false;
-    }
-
-    let mut result = 0u8;
-    for (x, y) in a.bytes().zip(b.bytes()) {
-        result |= x ^ y;
-    }
-    result == 0
-}
@@ -2,13 +2,23 @@
 
 use anyhow::{Context, Result};
 
+use crate::db::DatabaseBackend;
+use crate::reaper::LeaseExpiryPolicy;
+use crate::storage::StorageBackend;
+
 #[derive(Debug, Clone)]
 pub struct Config {
-    /// Supabase project URL
-    pub supabase_url: String,
+    /// Database backend (Supabase, `SQLite` or `PostgreSQL`)
+    pub database: DatabaseBackend,
+
+    /// Object storage backend for sources, artifacts and logs
+    pub storage: StorageBackend,
+
+    /// Supabase project URL (cloud mode only)
+    pub supabase_url: Option<String>,
 
-    /// Supabase anon/service key
-    pub supabase_key: String,
+    /// Supabase anon/service key (cloud mode only)
+    pub supabase_key: Option<String>,
 
     /// Stripe secret key for billing
     #[allow(dead_code)]
@@ -35,15 +45,46 @@ pub struct Config {
     /// Self-hosted mode: when true, allows unauthenticated access
     /// When false (default), requires API key or JWT for all routes
     pub self_hosted: bool,
+
+    /// Seconds a claimed job's lease lasts unless a worker heartbeat renews it
+    pub job_lease_seconds: u64,
+
+    /// Seconds without a heartbeat before a worker is marked offline
+    pub worker_timeout_seconds: u64,
+
+    /// What the reaper does with running jobs whose lease expired
+    pub lease_expiry_policy: LeaseExpiryPolicy,
+
+    /// Longest `timeout_minutes` a job can ask for
+    pub max_job_timeout_minutes: u32,
+
+    /// Timeout of jobs that don't set one, at most `max_job_timeout_minutes`
+    pub default_job_timeout_minutes: u32,
+
+    /// Times a job that failed for infrastructure reasons is requeued before
+    /// it is marked failed
+    pub infra_retry_limit: u32,
+
+    /// Key stored git credentials are encrypted with; credentials are
+    /// disabled without one
+    pub credentials_key: Option<[u8; 32]>,
 }
 
 impl Config {
+    /// Lease granted on claim and extended by each heartbeat
+    pub fn job_lease(&self) -> chrono::Duration {
+        chrono::Duration::seconds(i64::try_from(self.job_lease_seconds).unwrap_or(i64::MAX))
+    }
+
     pub fn from_env() -> Result<Self> {
-        Ok(Self {
-            supabase_url: std::env::var("SUPABASE_URL")
-                .context("SUPABASE_URL environment variable required")?,
-            supabase_key: std::env::var("SUPABASE_KEY")
-                .context("SUPABASE_KEY environment variable required")?,
+        let base_url =
+            std::env::var("BASE_URL").unwrap_or_else(|_| "http://localhost:3000".to_string());
+
+        let config = Self {
+            database: DatabaseBackend::from_env()?,
+            storage: StorageBackend::from_env(&base_url)?,
+            supabase_url: std::env::var("SUPABASE_URL").ok(),
+            supabase_key: std::env::var("SUPABASE_KEY").ok(),
             stripe_secret_key: std::env::var("STRIPE_SECRET_KEY").ok(),
             stripe_webhook_secret: std::env::var("STRIPE_WEBHOOK_SECRET").ok(),
             stripe_publishable_key: std::env::var("STRIPE_PUBLISHABLE_KEY").ok(),
@@ -51,12 +92,47 @@ impl Config {
                 .unwrap_or_else(|_| "3000".to_string())
                 .parse()
                 .context("Invalid PORT value")?,
-            base_url: std::env::var("BASE_URL")
-                .unwrap_or_else(|_| "http://localhost:3000".to_string()),
+            base_url,
             worker_secret_key: std::env::var("WORKER_SECRET_KEY").ok(),
             self_hosted: std::env::var("SELF_HOSTED")
-                .map(|v| v.to_lowercase() == "true" || v == "1")
-                .unwrap_or(false),
-        })
+                .is_ok_and(|v| v.to_lowercase() == "true" || v == "1"),
+            job_lease_seconds: std::env::var("JOB_LEASE_SECONDS")
+                .unwrap_or_else(|_| "120".to_string())
+                .parse()
+                .context("Invalid JOB_LEASE_SECONDS value")?,
+            worker_timeout_seconds: std::env::var("WORKER_TIMEOUT_SECONDS")
+                .unwrap_or_else(|_| "60".to_string())
+                .parse()
+                .context("Invalid WORKER_TIMEOUT_SECONDS value")?,
+            lease_expiry_policy: std::env::var("LEASE_EXPIRY_POLICY")
+                .unwrap_or_else(|_| "requeue".to_string())
+                .parse()
+                .map_err(anyhow::Error::msg)
+                .context("Invalid LEASE_EXPIRY_POLICY value")?,
+            max_job_timeout_minutes: std::env::var("MAX_JOB_TIMEOUT_MINUTES")
+                .unwrap_or_else(|_| "360".to_string())
+                .parse()
+                .context("Invalid MAX_JOB_TIMEOUT_MINUTES value")?,
+            default_job_timeout_minutes: std::env::var("DEFAULT_JOB_TIMEOUT_MINUTES")
+                .unwrap_or_else(|_| "60".to_string())
+                .parse()
+                .context("Invalid DEFAULT_JOB_TIMEOUT_MINUTES value")?,
+            infra_retry_limit: std::env::var("INFRA_RETRY_LIMIT")
+                .unwrap_or_else(|_| "2".to_string())
+                .parse()
+                .context("Invalid INFRA_RETRY_LIMIT value")?,
+            credentials_key: std::env::var("CREDENTIALS_KEY")
+                .ok()
+                .filter(|secret| !secret.is_empty())
+                .map(|secret| crate::crypto::derive_key(&secret)),
+        };
+
+        anyhow::ensure!(
+            (1..=config.max_job_timeout_minutes).contains(&config.default_job_timeout_minutes),
+            "DEFAULT_JOB_TIMEOUT_MINUTES must be between 1 and MAX_JOB_TIMEOUT_MINUTES ({})",
+            config.max_job_timeout_minutes
+        );
+
+        Ok(config)
     }
 }
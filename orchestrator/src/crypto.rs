@@ -1,9 +1,16 @@
-//! Cryptographic utilities for password and API key hashing
+//! Cryptographic utilities for password and API key hashing and for
+//! encrypting secrets at rest
 
 use argon2::{
     password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
     Argon2,
 };
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, Payload},
+    ChaCha20Poly1305, Nonce,
+};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
 
 /// Hash a password using Argon2id (recommended for password storage)
 /// Returns the PHC string format hash which includes algorithm, salt, and hash
@@ -25,6 +32,113 @@ pub fn verify_password(password: &str, hash: &str) -> bool {
         .is_ok()
 }
 
+/// Compute a raw HMAC-SHA256 of `message`
+pub fn hmac_sha256(secret: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Compute a hex-encoded HMAC-SHA256 of `message` (used for signed download URLs)
+pub fn hmac_sha256_hex(secret: &[u8], message: &str) -> String {
+    hex_encode(&hmac_sha256(secret, message.as_bytes()))
+}
+
+/// Compute a hex-encoded SHA-256 digest
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex_encode(&Sha256::digest(data))
+}
+
+/// Lowercase hex encoding
+pub fn hex_encode(bytes: &[u8]) -> String {
+    use std::fmt::Write;
+
+    bytes
+        .iter()
+        .fold(String::with_capacity(bytes.len() * 2), |mut out, b| {
+            let _ = write!(out, "{b:02x}");
+            out
+        })
+}
+
+/// Decode lowercase or uppercase hex, or `None` if `hex` isn't valid hex
+pub fn hex_decode(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Derive a 256-bit encryption key from a configured secret
+pub fn derive_key(secret: &str) -> [u8; 32] {
+    Sha256::digest(secret.as_bytes()).into()
+}
+
+/// Encrypt `plaintext` with ChaCha20-Poly1305 under a random nonce.
+/// `context` (e.g. the record's ID) must be passed again to decrypt, so a
+/// ciphertext copied onto another record won't decrypt. Returns hex of
+/// nonce || ciphertext.
+pub fn encrypt(key: &[u8; 32], plaintext: &[u8], context: &[u8]) -> String {
+    let cipher = ChaCha20Poly1305::new(key.into());
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: plaintext,
+                aad: context,
+            },
+        )
+        .expect("encryption only fails for oversized messages");
+
+    let mut sealed = nonce.to_vec();
+    sealed.extend_from_slice(&ciphertext);
+    hex_encode(&sealed)
+}
+
+/// Decrypt the output of [`encrypt`], or `None` if the key or context is
+/// wrong or the data was tampered with
+pub fn decrypt(key: &[u8; 32], sealed: &str, context: &[u8]) -> Option<Vec<u8>> {
+    const NONCE_LEN: usize = 12;
+
+    let sealed = hex_decode(sealed)?;
+    if sealed.len() < NONCE_LEN {
+        return None;
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    ChaCha20Poly1305::new(key.into())
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: context,
+            },
+        )
+        .ok()
+}
+
+/// Verify a hex-encoded HMAC-SHA256 signature in constant time
+pub fn verify_hmac_sha256_hex(secret: &[u8], message: &str, signature: &str) -> bool {
+    constant_time_compare(&hmac_sha256_hex(secret, message), signature)
+}
+
+/// Constant-time string comparison to prevent timing attacks
+pub fn constant_time_compare(a: &str, b: &str) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    let mut result = 0u8;
+    for (x, y) in a.bytes().zip(b.bytes()) {
+        result |= x ^ y;
+    }
+    result == 0
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -57,4 +171,59 @@ mod tests {
         assert!(verify_password(password, &hash1));
         assert!(verify_password(password, &hash2));
     }
+
+    #[test]
+    fn test_encrypt_round_trip() {
+        let key = derive_key("credentials-secret");
+        let sealed = encrypt(&key, b"ghp_token", b"credential-1");
+
+        assert!(!sealed.contains(&hex_encode(b"ghp_token")));
+        assert_eq!(
+            decrypt(&key, &sealed, b"credential-1").as_deref(),
+            Some(&b"ghp_token"[..])
+        );
+
+        // Same plaintext encrypts differently each time
+        assert_ne!(sealed, encrypt(&key, b"ghp_token", b"credential-1"));
+
+        // Wrong key, wrong context or tampered data fail
+        assert!(decrypt(&derive_key("other"), &sealed, b"credential-1").is_none());
+        assert!(decrypt(&key, &sealed, b"credential-2").is_none());
+        let mut tampered = sealed.into_bytes();
+        let last = tampered.len() - 1;
+        tampered[last] = if tampered[last] == b'0' { b'1' } else { b'0' };
+        let tampered = String::from_utf8(tampered).unwrap();
+        assert!(decrypt(&key, &tampered, b"credential-1").is_none());
+        assert!(decrypt(&key, "zz", b"credential-1").is_none());
+    }
+
+    #[test]
+    fn test_hmac_signature() {
+        let secret = b"storage-secret";
+        let signature = hmac_sha256_hex(secret, "logs/abc.log:1700000000");
+
+        assert_eq!(signature.len(), 64);
+        assert!(verify_hmac_sha256_hex(
+            secret,
+            "logs/abc.log:1700000000",
+            &signature
+        ));
+
+        // Tampered message, signature or key should fail
+        assert!(!verify_hmac_sha256_hex(
+            secret,
+            "logs/abc.log:1700000001",
+            &signature
+        ));
+        assert!(!verify_hmac_sha256_hex(
+            secret,
+            "logs/abc.log:1700000000",
+            "00"
+        ));
+        assert!(!verify_hmac_sha256_hex(
+            b"other-secret",
+            "logs/abc.log:1700000000",
+            &signature
+        ));
+    }
 }
@@ -8,33 +8,113 @@
 //! Note: Billing/subscription operations are handled directly by Supabase
 //! via the Stripe Wrapper and Edge Functions (cloud mode only).
 
+pub mod postgres;
 pub mod sqlite;
 
+use std::sync::Arc;
+
 use anyhow::Result;
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use uuid::Uuid;
 
-use shared::{Artifact, Job, JobStatus, WorkerInfo};
+use shared::{
+    Artifact, FailureReason, GitCredentialInfo, GitCredentialKind, Job, JobStatus, Run, SecretInfo,
+    StepResult, WorkerInfo,
+};
 
+use crate::services::SupabaseClient;
+pub use postgres::PostgresDb;
 pub use sqlite::SqliteDb;
 
 /// Abstract database operations
+///
+/// Handlers hold the backend as `Arc<dyn Database>` (see `AppState::db`),
+/// so the trait must stay object safe.
 #[async_trait]
-pub trait Database: Send + Sync + Clone {
+pub trait Database: Send + Sync {
     // Job operations
     async fn create_job(&self, job: &Job) -> Result<()>;
     async fn get_job(&self, job_id: Uuid) -> Result<Option<Job>>;
-    async fn claim_pending_job(&self, worker_id: Uuid) -> Result<Option<Job>>;
+    async fn list_jobs(
+        &self,
+        status: Option<&str>,
+        run_id: Option<Uuid>,
+        limit: usize,
+    ) -> Result<Vec<Job>>;
+    async fn update_job_status(&self, job_id: Uuid, status: JobStatus) -> Result<()>;
+    /// Cancel the pending (or uploading) jobs that need `job_id`, returning their IDs
+    async fn cancel_dependents(&self, job_id: Uuid, reason: &str) -> Result<Vec<Uuid>>;
+    /// Make the pending (or uploading) jobs that need `from` need `to`, its
+    /// retry, instead
+    async fn repoint_needs(&self, from: Uuid, to: Uuid) -> Result<()>;
+    /// The attempt that retried `job_id`, if any
+    async fn get_retry(&self, job_id: Uuid) -> Result<Option<Job>>;
+    /// Claim the oldest pending job whose `needs` have all completed, whose
+    /// `runs_on` labels are all among the worker's `labels` and whose image (if
+    /// any) is among its `images`, skipping jobs the worker is to avoid (see
+    /// `requeue_job`) and retries still waiting out their backoff
+    async fn claim_pending_job(
+        &self,
+        worker_id: Uuid,
+        labels: &[String],
+        images: &[String],
+        lease_expires_at: DateTime<Utc>,
+    ) -> Result<Option<Job>>;
+    /// Record a job's result; returns `false` if the job is no longer running on `worker_id`.
+    /// A `Cancelled` result is instead accepted for a job cancelled while on `worker_id`.
     async fn complete_job(
         &self,
         job_id: Uuid,
+        worker_id: Uuid,
         status: JobStatus,
+        failure_reason: Option<FailureReason>,
         exit_code: i32,
         build_minutes: f64,
+    ) -> Result<bool>;
+    /// Put a job that failed on `worker_id` for infrastructure reasons back in
+    /// the queue, unless it already was `max_retries` times; `worker_id` won't
+    /// claim it again before `avoid_until`. Returns `false` if the job was not
+    /// requeued (including when it is no longer running on `worker_id`).
+    async fn requeue_job(
+        &self,
+        job_id: Uuid,
+        worker_id: Uuid,
+        max_retries: u32,
+        avoid_until: DateTime<Utc>,
+        reason: &str,
+    ) -> Result<bool>;
+    /// Put a job `worker_id` claimed but never started back in the queue,
+    /// without counting a retry. Returns `false` if the job is no longer
+    /// running on `worker_id`.
+    async fn release_job(&self, job_id: Uuid, worker_id: Uuid, reason: &str) -> Result<bool>;
+    /// Record the commit a worker checked out for a git job
+    async fn set_resolved_sha(&self, job_id: Uuid, sha: &str) -> Result<()>;
+    /// Record the outcome of each step of a pipeline job
+    async fn set_step_results(&self, job_id: Uuid, results: &[StepResult]) -> Result<()>;
+
+    // Run operations
+    /// Create a run and the jobs of its matrix, all or none of them
+    async fn create_run(&self, run: &Run, jobs: &[Job]) -> Result<()>;
+    async fn get_run(&self, run_id: Uuid) -> Result<Option<Run>>;
+
+    // Lease operations
+    async fn renew_job_leases(
+        &self,
+        worker_id: Uuid,
+        job_ids: &[Uuid],
+        lease_expires_at: DateTime<Utc>,
     ) -> Result<()>;
+    /// Running jobs whose lease ran out before `now`
+    async fn list_expired_jobs(&self, now: DateTime<Utc>) -> Result<Vec<Job>>;
+    /// Move a job with an expired lease to `Pending` (requeue, counted in its
+    /// `infra_retries`) or a terminal status; returns `false` if the lease was
+    /// renewed or the job finished in the meantime
+    async fn reclaim_job(&self, job_id: Uuid, status: JobStatus, reason: &str) -> Result<bool>;
 
     // Worker operations
     async fn register_worker(&self, worker: &WorkerInfo) -> Result<()>;
+    async fn get_worker(&self, worker_id: Uuid) -> Result<Option<WorkerInfo>>;
     async fn update_worker_heartbeat(&self, worker_id: Uuid) -> Result<()>;
     async fn update_worker_status(
         &self,
@@ -53,13 +133,35 @@ pub trait Database: Send + Sync + Clone {
     async fn list_api_keys(&self, user_id: Uuid) -> Result<Vec<ApiKeyInfo>>;
     async fn delete_api_key(&self, user_id: Uuid, key_id: Uuid) -> Result<bool>;
 
+    // Git credential operations
+    async fn create_git_credential(&self, credential: &GitCredentialRecord) -> Result<()>;
+    async fn get_git_credential(&self, credential_id: Uuid) -> Result<Option<GitCredentialRecord>>;
+    async fn list_git_credentials(&self, user_id: Uuid) -> Result<Vec<GitCredentialRecord>>;
+    async fn delete_git_credential(&self, user_id: Uuid, credential_id: Uuid) -> Result<bool>;
+
+    // Secret operations
+    /// Store a secret, replacing the value of an existing one with the same name
+    async fn set_secret(&self, secret: &SecretRecord) -> Result<()>;
+    async fn list_secrets(&self, user_id: Uuid) -> Result<Vec<SecretRecord>>;
+    async fn delete_secret(&self, user_id: Uuid, name: &str) -> Result<bool>;
+
     // User operations
     async fn verify_user(&self, email: &str, password: &str) -> Result<Option<Uuid>>;
     async fn create_user(&self, email: &str, password_hash: &str) -> Result<Uuid>;
 }
 
+/// Status a job must be in for a worker's result with `status` to be recorded
+#[must_use]
+pub const fn completable_status(status: JobStatus) -> JobStatus {
+    match status {
+        JobStatus::Cancelled => JobStatus::Cancelled,
+        _ => JobStatus::Running,
+    }
+}
+
 /// API key record from database
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Deserialize)]
+#[allow(dead_code)]
 pub struct ApiKeyRecord {
     pub id: Uuid,
     pub user_id: Uuid,
@@ -78,6 +180,54 @@ pub struct ApiKeyInfo {
     pub last_used_at: Option<chrono::DateTime<chrono::Utc>>,
 }
 
+/// Git credential record from database; the secret stays encrypted
+/// (see `crypto::encrypt`, with the credential's ID as context)
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct GitCredentialRecord {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub name: String,
+    pub kind: GitCredentialKind,
+    pub username: Option<String>,
+    pub encrypted_secret: String,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<GitCredentialRecord> for GitCredentialInfo {
+    fn from(record: GitCredentialRecord) -> Self {
+        Self {
+            id: record.id,
+            name: record.name,
+            kind: record.kind,
+            username: record.username,
+            expires_at: record.expires_at,
+            created_at: record.created_at,
+        }
+    }
+}
+
+/// Secret record from database; the value stays encrypted (see
+/// `crypto::encrypt`, with the owner and name as context)
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SecretRecord {
+    pub user_id: Uuid,
+    pub name: String,
+    pub encrypted_value: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl From<SecretRecord> for SecretInfo {
+    fn from(record: SecretRecord) -> Self {
+        Self {
+            name: record.name,
+            created_at: record.created_at,
+            updated_at: record.updated_at,
+        }
+    }
+}
+
 /// Database backend type
 #[derive(Debug, Clone)]
 pub enum DatabaseBackend {
@@ -110,4 +260,72 @@ impl DatabaseBackend {
 
         Ok(Self::Sqlite { path })
     }
+
+    /// Connect to the configured backend
+    pub async fn connect(&self) -> Result<Arc<dyn Database>> {
+        match self {
+            Self::Supabase { url, key } => Ok(Arc::new(SupabaseClient::new(url, key))),
+            Self::Sqlite { path } => Ok(Arc::new(SqliteDb::new(path).await?)),
+            Self::Postgres { url } => Ok(Arc::new(PostgresDb::new(url).await?)),
+        }
+    }
+
+    /// Short name of the backend for logging
+    #[must_use]
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Supabase { .. } => "supabase",
+            Self::Sqlite { .. } => "sqlite",
+            Self::Postgres { .. } => "postgres",
+        }
+    }
+}
+
+/// Hammer `claim_pending_job` from many simulated workers at once and check
+/// that every job is handed out exactly once
+#[cfg(test)]
+pub async fn assert_exclusive_claims(db: Arc<dyn Database>) {
+    use shared::SourceType;
+    use std::collections::HashSet;
+
+    const JOBS: usize = 25;
+    const WORKERS: usize = 16;
+
+    let mut job_ids = HashSet::new();
+    for _ in 0..JOBS {
+        let job = Job::with_command(Uuid::nil(), "make".to_string(), SourceType::Git, None);
+        db.create_job(&job).await.unwrap();
+        job_ids.insert(job.id);
+    }
+
+    let handles: Vec<_> = (0..WORKERS)
+        .map(|_| {
+            let db = db.clone();
+            let worker_id = Uuid::new_v4();
+            tokio::spawn(async move {
+                let mut claimed = Vec::new();
+                let lease = Utc::now() + chrono::Duration::minutes(5);
+                while let Some(job) = db
+                    .claim_pending_job(worker_id, &[], &[], lease)
+                    .await
+                    .unwrap()
+                {
+                    assert_eq!(job.worker_id, Some(worker_id));
+                    claimed.push(job.id);
+                }
+                claimed
+            })
+        })
+        .collect();
+
+    let mut claimed = Vec::new();
+    for handle in handles {
+        claimed.extend(handle.await.unwrap());
+    }
+
+    // Other tests may share the database, so only look at our own jobs
+    claimed.retain(|id| job_ids.contains(id));
+    let unique: HashSet<_> = claimed.iter().copied().collect();
+    assert_eq!(claimed.len(), unique.len(), "a job was claimed twice");
+    assert_eq!(unique, job_ids, "every job should be claimed");
 }
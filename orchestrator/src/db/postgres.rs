@@ -0,0 +1,1653 @@
+//! `PostgreSQL` database backend for production self-hosted deployments
+//!
+//! The schema mirrors `supabase/schemas/schema.sql` (minus the Supabase-only
+//! storage buckets and RLS policies), so a database can be moved between
+//! Supabase and a plain `PostgreSQL` server.
+//!
+//! Note: Billing/subscription features are only available in cloud mode (Supabase).
+
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::{postgres::PgPoolOptions, PgExecutor, PgPool};
+use uuid::Uuid;
+
+use super::{
+    completable_status, ApiKeyInfo, ApiKeyRecord, Database, GitCredentialRecord, SecretRecord,
+};
+use shared::{
+    Artifact, FailureReason, GitCredentialKind, Job, JobStatus, Run, SourceType, StepResult,
+    WorkerInfo, WorkerStatus,
+};
+
+/// `PostgreSQL` database implementation
+#[derive(Clone)]
+pub struct PostgresDb {
+    pool: PgPool,
+}
+
+impl PostgresDb {
+    /// Create a new `PostgreSQL` connection pool
+    pub async fn new(database_url: &str) -> Result<Self> {
+        let pool = PgPoolOptions::new()
+            .max_connections(10)
+            .connect(database_url)
+            .await?;
+
+        let db = Self { pool };
+        db.run_migrations().await?;
+
+        Ok(db)
+    }
+
+    /// Run database migrations
+    async fn run_migrations(&self) -> Result<()> {
+        sqlx::query(
+            r"
+            CREATE TABLE IF NOT EXISTS jobs (
+                id UUID PRIMARY KEY,
+                customer_id UUID NOT NULL,
+                source_type TEXT NOT NULL DEFAULT 'git' CHECK (source_type IN ('git', 'upload')),
+                source_url TEXT,
+                command TEXT,
+                script TEXT,
+                status TEXT NOT NULL DEFAULT 'pending'
+                    CHECK (status IN ('pending', 'uploading', 'running', 'completed', 'failed', 'cancelled', 'timed_out')),
+                worker_id UUID,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                started_at TIMESTAMPTZ,
+                completed_at TIMESTAMPTZ,
+                exit_code INTEGER,
+                build_minutes DOUBLE PRECISION
+            )
+            ",
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r"
+            CREATE TABLE IF NOT EXISTS workers (
+                id UUID PRIMARY KEY,
+                hostname TEXT NOT NULL,
+                capacity INTEGER NOT NULL DEFAULT 2,
+                current_jobs INTEGER NOT NULL DEFAULT 0,
+                last_heartbeat TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                status TEXT NOT NULL DEFAULT 'online'
+                    CHECK (status IN ('online', 'busy', 'offline', 'draining'))
+            )
+            ",
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r"
+            CREATE TABLE IF NOT EXISTS artifacts (
+                id UUID PRIMARY KEY,
+                job_id UUID NOT NULL REFERENCES jobs(id) ON DELETE CASCADE,
+                name TEXT NOT NULL,
+                path TEXT NOT NULL,
+                size_bytes BIGINT NOT NULL DEFAULT 0,
+                download_url TEXT,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+            )
+            ",
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r"
+            CREATE TABLE IF NOT EXISTS api_keys (
+                id UUID PRIMARY KEY,
+                user_id UUID NOT NULL,
+                name TEXT NOT NULL,
+                key_hash TEXT NOT NULL UNIQUE,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                last_used_at TIMESTAMPTZ
+            )
+            ",
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Supabase keeps users in auth.users, self-hosted Postgres needs its own table
+        sqlx::query(
+            r"
+            CREATE TABLE IF NOT EXISTS users (
+                id UUID PRIMARY KEY,
+                email TEXT UNIQUE,
+                password_hash TEXT,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+            )
+            ",
+        )
+        .execute(&self.pool)
+        .await?;
+
+        for statement in SCHEMA_CHANGES {
+            sqlx::query(statement).execute(&self.pool).await?;
+        }
+
+        // Create indexes
+        for statement in [
+            "CREATE INDEX IF NOT EXISTS idx_jobs_status ON jobs(status)",
+            "CREATE INDEX IF NOT EXISTS idx_jobs_customer_id ON jobs(customer_id)",
+            "CREATE INDEX IF NOT EXISTS idx_jobs_created_at ON jobs(created_at)",
+            "CREATE INDEX IF NOT EXISTS idx_artifacts_job_id ON artifacts(job_id)",
+            "CREATE INDEX IF NOT EXISTS idx_workers_status ON workers(status)",
+            "CREATE INDEX IF NOT EXISTS idx_api_keys_hash ON api_keys(key_hash)",
+            "CREATE INDEX IF NOT EXISTS idx_api_keys_user ON api_keys(user_id)",
+        ] {
+            sqlx::query(statement).execute(&self.pool).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Changes made after the initial schema, each safe to re-run and a no-op
+/// once applied
+const SCHEMA_CHANGES: &[&str] = &[
+    "ALTER TABLE jobs ADD COLUMN IF NOT EXISTS lease_expires_at TIMESTAMPTZ",
+    "ALTER TABLE jobs ADD COLUMN IF NOT EXISTS status_reason TEXT",
+    "ALTER TABLE jobs ADD COLUMN IF NOT EXISTS git_ref TEXT",
+    "ALTER TABLE jobs ADD COLUMN IF NOT EXISTS commit_sha TEXT",
+    "ALTER TABLE jobs ADD COLUMN IF NOT EXISTS submodules BOOLEAN NOT NULL DEFAULT FALSE",
+    "ALTER TABLE jobs ADD COLUMN IF NOT EXISTS lfs BOOLEAN NOT NULL DEFAULT FALSE",
+    "ALTER TABLE jobs ADD COLUMN IF NOT EXISTS resolved_sha TEXT",
+    "ALTER TABLE jobs ADD COLUMN IF NOT EXISTS git_credential_id UUID",
+    r"
+    CREATE TABLE IF NOT EXISTS git_credentials (
+        id UUID PRIMARY KEY,
+        user_id UUID NOT NULL,
+        name TEXT NOT NULL,
+        kind TEXT NOT NULL CHECK (kind IN ('ssh_key', 'https_token', 'installation_token')),
+        username TEXT,
+        encrypted_secret TEXT NOT NULL,
+        expires_at TIMESTAMPTZ,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+    )
+    ",
+    "CREATE INDEX IF NOT EXISTS idx_git_credentials_user ON git_credentials(user_id)",
+    "ALTER TABLE jobs ADD COLUMN IF NOT EXISTS env JSONB NOT NULL DEFAULT '{}'",
+    "ALTER TABLE jobs ADD COLUMN IF NOT EXISTS secrets JSONB NOT NULL DEFAULT '[]'",
+    "ALTER TABLE jobs ADD COLUMN IF NOT EXISTS steps JSONB NOT NULL DEFAULT '[]'",
+    "ALTER TABLE jobs ADD COLUMN IF NOT EXISTS step_results JSONB NOT NULL DEFAULT '[]'",
+    "ALTER TABLE jobs ADD COLUMN IF NOT EXISTS needs JSONB NOT NULL DEFAULT '[]'",
+    r"
+    CREATE TABLE IF NOT EXISTS secrets (
+        user_id UUID NOT NULL,
+        name TEXT NOT NULL,
+        encrypted_value TEXT NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        PRIMARY KEY (user_id, name)
+    )
+    ",
+    r"
+    CREATE TABLE IF NOT EXISTS runs (
+        id UUID PRIMARY KEY,
+        customer_id UUID NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        matrix JSONB NOT NULL DEFAULT '{}'
+    )
+    ",
+    "ALTER TABLE jobs ADD COLUMN IF NOT EXISTS run_id UUID",
+    "ALTER TABLE jobs ADD COLUMN IF NOT EXISTS matrix JSONB NOT NULL DEFAULT '{}'",
+    "CREATE INDEX IF NOT EXISTS idx_jobs_run_id ON jobs(run_id)",
+    "ALTER TABLE jobs ADD COLUMN IF NOT EXISTS runs_on JSONB NOT NULL DEFAULT '[]'",
+    "ALTER TABLE workers ADD COLUMN IF NOT EXISTS labels JSONB NOT NULL DEFAULT '[]'",
+    "ALTER TABLE workers ADD COLUMN IF NOT EXISTS capabilities JSONB NOT NULL DEFAULT '{}'",
+    "ALTER TABLE jobs ADD COLUMN IF NOT EXISTS image TEXT",
+    "ALTER TABLE workers ADD COLUMN IF NOT EXISTS images JSONB NOT NULL DEFAULT '[]'",
+    "ALTER TABLE jobs ADD COLUMN IF NOT EXISTS timeout_minutes INTEGER",
+    // Databases created before `timed_out` existed have the old status check
+    r"
+    DO $$
+    BEGIN
+        IF NOT EXISTS (
+            SELECT 1 FROM pg_constraint
+            WHERE conrelid = 'jobs'::regclass AND conname = 'jobs_status_check'
+              AND pg_get_constraintdef(oid) LIKE '%timed_out%'
+        ) THEN
+            ALTER TABLE jobs DROP CONSTRAINT IF EXISTS jobs_status_check;
+            ALTER TABLE jobs ADD CONSTRAINT jobs_status_check CHECK (status IN
+                ('pending', 'uploading', 'running', 'completed', 'failed', 'cancelled', 'timed_out'));
+        END IF;
+    END $$
+    ",
+    "ALTER TABLE jobs ADD COLUMN IF NOT EXISTS failure_reason TEXT \
+     CHECK (failure_reason IN ('infra', 'source_fetch', 'timeout', 'user_exit', 'cancelled'))",
+    "ALTER TABLE jobs ADD COLUMN IF NOT EXISTS infra_retries INTEGER NOT NULL DEFAULT 0",
+    "ALTER TABLE jobs ADD COLUMN IF NOT EXISTS avoid_worker_id UUID",
+    "ALTER TABLE jobs ADD COLUMN IF NOT EXISTS avoid_worker_until TIMESTAMPTZ",
+    "ALTER TABLE jobs ADD COLUMN IF NOT EXISTS attempt INTEGER NOT NULL DEFAULT 1",
+    "ALTER TABLE jobs ADD COLUMN IF NOT EXISTS parent_job_id UUID",
+    "ALTER TABLE jobs ADD COLUMN IF NOT EXISTS retry JSONB",
+    "ALTER TABLE jobs ADD COLUMN IF NOT EXISTS not_before TIMESTAMPTZ",
+    "CREATE INDEX IF NOT EXISTS idx_jobs_parent_job_id ON jobs(parent_job_id)",
+    // Earlier versions stored these JSON columns as TEXT, and added
+    // `failure_reason` without its check
+    r"
+    DO $$
+    DECLARE
+        col RECORD;
+    BEGIN
+        FOR col IN
+            SELECT wanted.table_name, wanted.column_name, wanted.default_value
+            FROM (VALUES
+                ('jobs', 'env', '{}'), ('jobs', 'secrets', '[]'), ('jobs', 'steps', '[]'),
+                ('jobs', 'step_results', '[]'), ('jobs', 'needs', '[]'), ('jobs', 'matrix', '{}'),
+                ('jobs', 'runs_on', '[]'), ('jobs', 'retry', NULL), ('runs', 'matrix', '{}'),
+                ('workers', 'labels', '[]'), ('workers', 'capabilities', '{}'),
+                ('workers', 'images', '[]')
+            ) AS wanted (table_name, column_name, default_value)
+            JOIN information_schema.columns AS existing
+              ON existing.table_schema = current_schema()
+             AND existing.table_name = wanted.table_name
+             AND existing.column_name = wanted.column_name
+            WHERE existing.data_type = 'text'
+        LOOP
+            EXECUTE format('ALTER TABLE %I ALTER COLUMN %I DROP DEFAULT',
+                col.table_name, col.column_name);
+            EXECUTE format('ALTER TABLE %I ALTER COLUMN %I TYPE JSONB USING %I::jsonb',
+                col.table_name, col.column_name, col.column_name);
+            IF col.default_value IS NOT NULL THEN
+                EXECUTE format('ALTER TABLE %I ALTER COLUMN %I SET DEFAULT %L::jsonb',
+                    col.table_name, col.column_name, col.default_value);
+            END IF;
+        END LOOP;
+
+        IF NOT EXISTS (
+            SELECT 1 FROM pg_constraint
+            WHERE conrelid = 'jobs'::regclass AND conname = 'jobs_failure_reason_check'
+        ) THEN
+            ALTER TABLE jobs ADD CONSTRAINT jobs_failure_reason_check CHECK (failure_reason IN
+                ('infra', 'source_fetch', 'timeout', 'user_exit', 'cancelled'));
+        END IF;
+    END $$
+    ",
+];
+
+/// Insert `job` through `executor`, on its own or as part of a run
+async fn insert_job<'e>(executor: impl PgExecutor<'e>, job: &Job) -> Result<()> {
+    sqlx::query(
+        r"
+        INSERT INTO jobs (id, customer_id, source_type, source_url, command, script, status, created_at,
+                          git_ref, commit_sha, submodules, lfs, git_credential_id, env, secrets,
+                          steps, needs, run_id, matrix, runs_on, image, timeout_minutes,
+                          attempt, parent_job_id, retry, not_before)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17,
+                $18, $19, $20, $21, $22, $23, $24, $25, $26)
+        ",
+    )
+    .bind(job.id)
+    .bind(job.customer_id)
+    .bind(job.source_type.to_string())
+    .bind(&job.source_url)
+    .bind(&job.command)
+    .bind(&job.script)
+    .bind(job.status.to_string())
+    .bind(job.created_at)
+    .bind(&job.git_ref)
+    .bind(&job.commit_sha)
+    .bind(job.submodules)
+    .bind(job.lfs)
+    .bind(job.git_credential_id)
+    .bind(serde_json::to_value(&job.env)?)
+    .bind(serde_json::to_value(&job.secrets)?)
+    .bind(serde_json::to_value(&job.steps)?)
+    .bind(serde_json::to_value(&job.needs)?)
+    .bind(job.run_id)
+    .bind(serde_json::to_value(&job.matrix)?)
+    .bind(serde_json::to_value(&job.runs_on)?)
+    .bind(&job.image)
+    .bind(job.timeout_minutes.and_then(|minutes| i32::try_from(minutes).ok()))
+    .bind(i32::try_from(job.attempt).unwrap_or(i32::MAX))
+    .bind(job.parent_job_id)
+    .bind(job.retry.as_ref().map(serde_json::to_value).transpose()?)
+    .bind(job.not_before)
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+#[async_trait]
+impl Database for PostgresDb {
+    async fn create_job(&self, job: &Job) -> Result<()> {
+        insert_job(&self.pool, job).await
+    }
+
+    async fn get_job(&self, job_id: Uuid) -> Result<Option<Job>> {
+        let row = sqlx::query_as::<_, JobRow>("SELECT * FROM jobs WHERE id = $1")
+            .bind(job_id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.map(std::convert::Into::into))
+    }
+
+    async fn list_jobs(
+        &self,
+        status: Option<&str>,
+        run_id: Option<Uuid>,
+        limit: usize,
+    ) -> Result<Vec<Job>> {
+        #[allow(clippy::cast_possible_wrap)]
+        let limit = limit as i64;
+
+        let rows = sqlx::query_as::<_, JobRow>(
+            r"
+            SELECT * FROM jobs
+            WHERE ($1::TEXT IS NULL OR status = $1)
+              AND ($2::UUID IS NULL OR run_id = $2)
+            ORDER BY created_at DESC
+            LIMIT $3
+            ",
+        )
+        .bind(status)
+        .bind(run_id)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(std::convert::Into::into).collect())
+    }
+
+    async fn update_job_status(&self, job_id: Uuid, status: JobStatus) -> Result<()> {
+        sqlx::query("UPDATE jobs SET status = $1, failure_reason = $2 WHERE id = $3")
+            .bind(status.to_string())
+            .bind(status.failure_reason().map(|reason| reason.to_string()))
+            .bind(job_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn cancel_dependents(&self, job_id: Uuid, reason: &str) -> Result<Vec<Uuid>> {
+        let ids = sqlx::query_scalar(
+            r"
+            UPDATE jobs
+            SET status = 'cancelled', status_reason = $1, completed_at = NOW(),
+                failure_reason = 'cancelled'
+            WHERE status IN ('pending', 'uploading')
+              AND needs @> jsonb_build_array($2::text)
+            RETURNING id
+            ",
+        )
+        .bind(reason)
+        .bind(job_id.to_string())
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(ids)
+    }
+
+    async fn repoint_needs(&self, from: Uuid, to: Uuid) -> Result<()> {
+        sqlx::query(
+            r"
+            UPDATE jobs
+            SET needs = replace(needs::text, $1, $2)::jsonb
+            WHERE status IN ('pending', 'uploading')
+              AND needs @> jsonb_build_array($1::text)
+            ",
+        )
+        .bind(from.to_string())
+        .bind(to.to_string())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_retry(&self, job_id: Uuid) -> Result<Option<Job>> {
+        let row = sqlx::query_as::<_, JobRow>(
+            "SELECT * FROM jobs WHERE parent_job_id = $1 ORDER BY created_at ASC LIMIT 1",
+        )
+        .bind(job_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(std::convert::Into::into))
+    }
+
+    async fn claim_pending_job(
+        &self,
+        worker_id: Uuid,
+        labels: &[String],
+        images: &[String],
+        lease_expires_at: DateTime<Utc>,
+    ) -> Result<Option<Job>> {
+        // SKIP LOCKED lets concurrent claimers move past rows another transaction
+        // is already claiming instead of blocking on (or double-claiming) them
+        let row = sqlx::query_as::<_, JobRow>(
+            r"
+            UPDATE jobs
+            SET status = 'running', worker_id = $1, started_at = NOW(), lease_expires_at = $2,
+                status_reason = NULL
+            WHERE id = (
+                SELECT id FROM jobs
+                WHERE status = 'pending'
+                  AND NOT EXISTS (
+                      SELECT 1 FROM jsonb_array_elements_text(jobs.needs) AS need
+                      LEFT JOIN jobs AS upstream ON upstream.id = need::uuid
+                      WHERE upstream.status IS DISTINCT FROM 'completed'
+                  )
+                  AND jobs.runs_on <@ $3
+                  AND (jobs.image IS NULL OR jobs.image = ANY($4))
+                  AND (jobs.avoid_worker_id IS DISTINCT FROM $1 OR jobs.avoid_worker_until < NOW())
+                  AND (jobs.not_before IS NULL OR jobs.not_before < NOW())
+                ORDER BY created_at ASC
+                LIMIT 1
+                FOR UPDATE SKIP LOCKED
+            ) AND status = 'pending'
+            RETURNING *
+            ",
+        )
+        .bind(worker_id)
+        .bind(lease_expires_at)
+        .bind(serde_json::to_value(labels)?)
+        .bind(images)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(std::convert::Into::into))
+    }
+
+    async fn complete_job(
+        &self,
+        job_id: Uuid,
+        worker_id: Uuid,
+        status: JobStatus,
+        failure_reason: Option<FailureReason>,
+        exit_code: i32,
+        build_minutes: f64,
+    ) -> Result<bool> {
+        let result = sqlx::query(
+            r"
+            UPDATE jobs
+            SET status = $1, failure_reason = $2, exit_code = $3, build_minutes = $4,
+                completed_at = NOW(), lease_expires_at = NULL
+            WHERE id = $5 AND worker_id = $6 AND status = $7
+            ",
+        )
+        .bind(status.to_string())
+        .bind(failure_reason.map(|reason| reason.to_string()))
+        .bind(exit_code)
+        .bind(build_minutes)
+        .bind(job_id)
+        .bind(worker_id)
+        .bind(completable_status(status).to_string())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn requeue_job(
+        &self,
+        job_id: Uuid,
+        worker_id: Uuid,
+        max_retries: u32,
+        avoid_until: DateTime<Utc>,
+        reason: &str,
+    ) -> Result<bool> {
+        let result = sqlx::query(
+            r"
+            UPDATE jobs
+            SET status = 'pending', worker_id = NULL, started_at = NULL, lease_expires_at = NULL,
+                status_reason = $1, infra_retries = infra_retries + 1,
+                avoid_worker_id = $2, avoid_worker_until = $3
+            WHERE id = $4 AND worker_id = $2 AND status = 'running' AND infra_retries < $5
+            ",
+        )
+        .bind(reason)
+        .bind(worker_id)
+        .bind(avoid_until)
+        .bind(job_id)
+        .bind(i32::try_from(max_retries).unwrap_or(i32::MAX))
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn release_job(&self, job_id: Uuid, worker_id: Uuid, reason: &str) -> Result<bool> {
+        let result = sqlx::query(
+            r"
+            UPDATE jobs
+            SET status = 'pending', worker_id = NULL, started_at = NULL, lease_expires_at = NULL,
+                status_reason = $1
+            WHERE id = $2 AND worker_id = $3 AND status = 'running'
+            ",
+        )
+        .bind(reason)
+        .bind(job_id)
+        .bind(worker_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn set_resolved_sha(&self, job_id: Uuid, sha: &str) -> Result<()> {
+        sqlx::query("UPDATE jobs SET resolved_sha = $1 WHERE id = $2")
+            .bind(sha)
+            .bind(job_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn set_step_results(&self, job_id: Uuid, results: &[StepResult]) -> Result<()> {
+        sqlx::query("UPDATE jobs SET step_results = $1 WHERE id = $2")
+            .bind(serde_json::to_value(results)?)
+            .bind(job_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn create_run(&self, run: &Run, jobs: &[Job]) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "INSERT INTO runs (id, customer_id, created_at, matrix) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(run.id)
+        .bind(run.customer_id)
+        .bind(run.created_at)
+        .bind(serde_json::to_value(&run.matrix)?)
+        .execute(&mut *tx)
+        .await?;
+
+        for job in jobs {
+            insert_job(&mut *tx, job).await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn get_run(&self, run_id: Uuid) -> Result<Option<Run>> {
+        let row = sqlx::query_as::<_, RunRow>("SELECT * FROM runs WHERE id = $1")
+            .bind(run_id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.map(std::convert::Into::into))
+    }
+
+    async fn renew_job_leases(
+        &self,
+        worker_id: Uuid,
+        job_ids: &[Uuid],
+        lease_expires_at: DateTime<Utc>,
+    ) -> Result<()> {
+        sqlx::query(
+            r"
+            UPDATE jobs SET lease_expires_at = $1
+            WHERE id = ANY($2) AND worker_id = $3 AND status = 'running'
+            ",
+        )
+        .bind(lease_expires_at)
+        .bind(job_ids)
+        .bind(worker_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn list_expired_jobs(&self, now: DateTime<Utc>) -> Result<Vec<Job>> {
+        // Jobs claimed before leases existed have no lease and count as expired
+        let rows = sqlx::query_as::<_, JobRow>(
+            r"
+            SELECT * FROM jobs
+            WHERE status = 'running' AND (lease_expires_at IS NULL OR lease_expires_at < $1)
+            ",
+        )
+        .bind(now)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(std::convert::Into::into).collect())
+    }
+
+    async fn reclaim_job(&self, job_id: Uuid, status: JobStatus, reason: &str) -> Result<bool> {
+        let result = sqlx::query(
+            r"
+            UPDATE jobs
+            SET status = $1,
+                worker_id = CASE WHEN $1 = 'pending' THEN NULL ELSE worker_id END,
+                started_at = CASE WHEN $1 = 'pending' THEN NULL ELSE started_at END,
+                completed_at = CASE WHEN $1 = 'pending' THEN NULL ELSE NOW() END,
+                lease_expires_at = NULL,
+                status_reason = $2,
+                failure_reason = CASE WHEN $1 = 'pending' THEN NULL ELSE 'infra' END,
+                infra_retries = CASE WHEN $1 = 'pending' THEN infra_retries + 1 ELSE infra_retries END
+            WHERE id = $3 AND status = 'running'
+                AND (lease_expires_at IS NULL OR lease_expires_at < NOW())
+            ",
+        )
+        .bind(status.to_string())
+        .bind(reason)
+        .bind(job_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn register_worker(&self, worker: &WorkerInfo) -> Result<()> {
+        sqlx::query(
+            r"
+            INSERT INTO workers (id, hostname, capacity, current_jobs, last_heartbeat, status,
+                                 labels, capabilities, images)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            ON CONFLICT (id) DO UPDATE SET
+                hostname = EXCLUDED.hostname,
+                capacity = EXCLUDED.capacity,
+                current_jobs = EXCLUDED.current_jobs,
+                last_heartbeat = EXCLUDED.last_heartbeat,
+                status = EXCLUDED.status,
+                labels = EXCLUDED.labels,
+                capabilities = EXCLUDED.capabilities,
+                images = EXCLUDED.images
+            ",
+        )
+        .bind(worker.id)
+        .bind(&worker.hostname)
+        .bind(i32::try_from(worker.capacity).unwrap_or(i32::MAX))
+        .bind(i32::try_from(worker.current_jobs).unwrap_or(i32::MAX))
+        .bind(worker.last_heartbeat)
+        .bind(format!("{:?}", worker.status).to_lowercase())
+        .bind(serde_json::to_value(&worker.labels)?)
+        .bind(serde_json::to_value(&worker.capabilities)?)
+        .bind(serde_json::to_value(&worker.images)?)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_worker(&self, worker_id: Uuid) -> Result<Option<WorkerInfo>> {
+        let row = sqlx::query_as::<_, WorkerRow>("SELECT * FROM workers WHERE id = $1")
+            .bind(worker_id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.map(std::convert::Into::into))
+    }
+
+    async fn update_worker_heartbeat(&self, worker_id: Uuid) -> Result<()> {
+        sqlx::query("UPDATE workers SET last_heartbeat = NOW() WHERE id = $1")
+            .bind(worker_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn update_worker_status(&self, worker_id: Uuid, status: WorkerStatus) -> Result<()> {
+        sqlx::query("UPDATE workers SET status = $1 WHERE id = $2")
+            .bind(format!("{status:?}").to_lowercase())
+            .bind(worker_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_job_artifacts(&self, job_id: Uuid) -> Result<Vec<Artifact>> {
+        let rows = sqlx::query_as::<_, ArtifactRow>(
+            "SELECT name, path, size_bytes, download_url FROM artifacts WHERE job_id = $1 ORDER BY created_at",
+        )
+        .bind(job_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(std::convert::Into::into).collect())
+    }
+
+    async fn store_artifact(&self, job_id: Uuid, artifact: &Artifact) -> Result<()> {
+        sqlx::query(
+            r"
+            INSERT INTO artifacts (id, job_id, name, path, size_bytes, download_url)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ",
+        )
+        .bind(Uuid::new_v4())
+        .bind(job_id)
+        .bind(&artifact.name)
+        .bind(&artifact.path)
+        .bind(i64::try_from(artifact.size_bytes).unwrap_or(i64::MAX))
+        .bind(&artifact.download_url)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn verify_api_key(&self, key_hash: &str) -> Result<Option<ApiKeyRecord>> {
+        let row = sqlx::query_as::<_, ApiKeyRow>("SELECT * FROM api_keys WHERE key_hash = $1")
+            .bind(key_hash)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.map(std::convert::Into::into))
+    }
+
+    async fn update_api_key_usage(&self, key_id: Uuid) -> Result<()> {
+        sqlx::query("UPDATE api_keys SET last_used_at = NOW() WHERE id = $1")
+            .bind(key_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn create_api_key(&self, user_id: Uuid, name: &str, key_hash: &str) -> Result<Uuid> {
+        let key_id = Uuid::new_v4();
+
+        sqlx::query("INSERT INTO api_keys (id, user_id, name, key_hash) VALUES ($1, $2, $3, $4)")
+            .bind(key_id)
+            .bind(user_id)
+            .bind(name)
+            .bind(key_hash)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(key_id)
+    }
+
+    async fn list_api_keys(&self, user_id: Uuid) -> Result<Vec<ApiKeyInfo>> {
+        let rows = sqlx::query_as::<_, ApiKeyInfoRow>(
+            "SELECT id, name, created_at, last_used_at FROM api_keys WHERE user_id = $1",
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(std::convert::Into::into).collect())
+    }
+
+    async fn delete_api_key(&self, user_id: Uuid, key_id: Uuid) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM api_keys WHERE id = $1 AND user_id = $2")
+            .bind(key_id)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn create_git_credential(&self, credential: &GitCredentialRecord) -> Result<()> {
+        sqlx::query(
+            r"
+            INSERT INTO git_credentials (id, user_id, name, kind, username, encrypted_secret, expires_at, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            ",
+        )
+        .bind(credential.id)
+        .bind(credential.user_id)
+        .bind(&credential.name)
+        .bind(credential.kind.to_string())
+        .bind(&credential.username)
+        .bind(&credential.encrypted_secret)
+        .bind(credential.expires_at)
+        .bind(credential.created_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_git_credential(&self, credential_id: Uuid) -> Result<Option<GitCredentialRecord>> {
+        let row =
+            sqlx::query_as::<_, GitCredentialRow>("SELECT * FROM git_credentials WHERE id = $1")
+                .bind(credential_id)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        Ok(row.map(std::convert::Into::into))
+    }
+
+    async fn list_git_credentials(&self, user_id: Uuid) -> Result<Vec<GitCredentialRecord>> {
+        let rows = sqlx::query_as::<_, GitCredentialRow>(
+            "SELECT * FROM git_credentials WHERE user_id = $1 ORDER BY created_at",
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(std::convert::Into::into).collect())
+    }
+
+    async fn delete_git_credential(&self, user_id: Uuid, credential_id: Uuid) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM git_credentials WHERE id = $1 AND user_id = $2")
+            .bind(credential_id)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn set_secret(&self, secret: &SecretRecord) -> Result<()> {
+        sqlx::query(
+            r"
+            INSERT INTO secrets (user_id, name, encrypted_value, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (user_id, name)
+            DO UPDATE SET encrypted_value = EXCLUDED.encrypted_value, updated_at = EXCLUDED.updated_at
+            ",
+        )
+        .bind(secret.user_id)
+        .bind(&secret.name)
+        .bind(&secret.encrypted_value)
+        .bind(secret.created_at)
+        .bind(secret.updated_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn list_secrets(&self, user_id: Uuid) -> Result<Vec<SecretRecord>> {
+        let rows = sqlx::query_as::<_, SecretRow>(
+            "SELECT * FROM secrets WHERE user_id = $1 ORDER BY name",
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(std::convert::Into::into).collect())
+    }
+
+    async fn delete_secret(&self, user_id: Uuid, name: &str) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM secrets WHERE user_id = $1 AND name = $2")
+            .bind(user_id)
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn verify_user(&self, email: &str, password: &str) -> Result<Option<Uuid>> {
+        let result: Option<(Uuid, Option<String>)> =
+            sqlx::query_as("SELECT id, password_hash FROM users WHERE email = $1")
+                .bind(email)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        match result {
+            Some((id, Some(stored_hash))) => {
+                // Verify password using Argon2
+                if crate::crypto::verify_password(password, &stored_hash) {
+                    Ok(Some(id))
+                } else {
+                    Ok(None)
+                }
+            },
+            _ => Ok(None),
+        }
+    }
+
+    async fn create_user(&self, email: &str, password_hash: &str) -> Result<Uuid> {
+        let user_id = Uuid::new_v4();
+
+        sqlx::query("INSERT INTO users (id, email, password_hash) VALUES ($1, $2, $3)")
+            .bind(user_id)
+            .bind(email)
+            .bind(password_hash)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| match e {
+                // Registration maps "UNIQUE" errors to 409, match SQLite's wording
+                sqlx::Error::Database(ref db_err) if db_err.is_unique_violation() => {
+                    anyhow::anyhow!("UNIQUE constraint failed: users.email")
+                },
+                other => other.into(),
+            })?;
+
+        Ok(user_id)
+    }
+}
+
+// Row types for SQLx
+#[derive(sqlx::FromRow)]
+struct JobRow {
+    id: Uuid,
+    customer_id: Uuid,
+    source_type: String,
+    source_url: Option<String>,
+    command: Option<String>,
+    script: Option<String>,
+    status: String,
+    worker_id: Option<Uuid>,
+    created_at: DateTime<Utc>,
+    started_at: Option<DateTime<Utc>>,
+    completed_at: Option<DateTime<Utc>>,
+    exit_code: Option<i32>,
+    build_minutes: Option<f64>,
+    lease_expires_at: Option<DateTime<Utc>>,
+    status_reason: Option<String>,
+    git_ref: Option<String>,
+    commit_sha: Option<String>,
+    submodules: bool,
+    lfs: bool,
+    resolved_sha: Option<String>,
+    git_credential_id: Option<Uuid>,
+    env: serde_json::Value,
+    secrets: serde_json::Value,
+    steps: serde_json::Value,
+    step_results: serde_json::Value,
+    needs: serde_json::Value,
+    run_id: Option<Uuid>,
+    matrix: serde_json::Value,
+    runs_on: serde_json::Value,
+    image: Option<String>,
+    timeout_minutes: Option<i32>,
+    failure_reason: Option<String>,
+    infra_retries: i32,
+    attempt: i32,
+    parent_job_id: Option<Uuid>,
+    retry: Option<serde_json::Value>,
+    not_before: Option<DateTime<Utc>>,
+}
+
+impl From<JobRow> for Job {
+    fn from(row: JobRow) -> Self {
+        Self {
+            id: row.id,
+            customer_id: row.customer_id,
+            source_type: match row.source_type.as_str() {
+                "upload" => SourceType::Upload,
+                _ => SourceType::Git,
+            },
+            source_url: row.source_url,
+            command: row.command,
+            script: row.script,
+            status: row.status.parse().unwrap_or(JobStatus::Pending),
+            worker_id: row.worker_id,
+            created_at: row.created_at,
+            started_at: row.started_at,
+            completed_at: row.completed_at,
+            exit_code: row.exit_code,
+            build_minutes: row.build_minutes,
+            lease_expires_at: row.lease_expires_at,
+            status_reason: row.status_reason,
+            git_ref: row.git_ref,
+            commit_sha: row.commit_sha,
+            submodules: row.submodules,
+            lfs: row.lfs,
+            resolved_sha: row.resolved_sha,
+            git_credential_id: row.git_credential_id,
+            env: serde_json::from_value(row.env).unwrap_or_default(),
+            secrets: serde_json::from_value(row.secrets).unwrap_or_default(),
+            steps: serde_json::from_value(row.steps).unwrap_or_default(),
+            step_results: serde_json::from_value(row.step_results).unwrap_or_default(),
+            needs: serde_json::from_value(row.needs).unwrap_or_default(),
+            run_id: row.run_id,
+            matrix: serde_json::from_value(row.matrix).unwrap_or_default(),
+            runs_on: serde_json::from_value(row.runs_on).unwrap_or_default(),
+            image: row.image,
+            timeout_minutes: row
+                .timeout_minutes
+                .and_then(|minutes| u32::try_from(minutes).ok()),
+            failure_reason: row.failure_reason.and_then(|reason| reason.parse().ok()),
+            infra_retries: u32::try_from(row.infra_retries).unwrap_or_default(),
+            attempt: u32::try_from(row.attempt).unwrap_or(1),
+            parent_job_id: row.parent_job_id,
+            retry: row
+                .retry
+                .and_then(|retry| serde_json::from_value(retry).ok()),
+            not_before: row.not_before,
+            attempts: Vec::new(),
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct RunRow {
+    id: Uuid,
+    customer_id: Uuid,
+    created_at: DateTime<Utc>,
+    matrix: serde_json::Value,
+}
+
+impl From<RunRow> for Run {
+    fn from(row: RunRow) -> Self {
+        Self {
+            id: row.id,
+            customer_id: row.customer_id,
+            created_at: row.created_at,
+            matrix: serde_json::from_value(row.matrix).unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct WorkerRow {
+    id: Uuid,
+    hostname: String,
+    capacity: i32,
+    current_jobs: i32,
+    last_heartbeat: DateTime<Utc>,
+    status: String,
+    labels: serde_json::Value,
+    capabilities: serde_json::Value,
+    images: serde_json::Value,
+}
+
+impl From<WorkerRow> for WorkerInfo {
+    fn from(row: WorkerRow) -> Self {
+        Self {
+            id: row.id,
+            hostname: row.hostname,
+            capacity: u32::try_from(row.capacity).unwrap_or_default(),
+            current_jobs: u32::try_from(row.current_jobs).unwrap_or_default(),
+            last_heartbeat: row.last_heartbeat,
+            status: row.status.parse().unwrap_or(WorkerStatus::Online),
+            labels: serde_json::from_value(row.labels).unwrap_or_default(),
+            capabilities: serde_json::from_value(row.capabilities).unwrap_or_default(),
+            images: serde_json::from_value(row.images).unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct ArtifactRow {
+    name: String,
+    path: String,
+    size_bytes: i64,
+    download_url: Option<String>,
+}
+
+impl From<ArtifactRow> for Artifact {
+    fn from(row: ArtifactRow) -> Self {
+        Self {
+            name: row.name,
+            path: row.path,
+            size_bytes: u64::try_from(row.size_bytes).unwrap_or_default(),
+            download_url: row.download_url,
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct GitCredentialRow {
+    id: Uuid,
+    user_id: Uuid,
+    name: String,
+    kind: String,
+    username: Option<String>,
+    encrypted_secret: String,
+    expires_at: Option<DateTime<Utc>>,
+    created_at: DateTime<Utc>,
+}
+
+impl From<GitCredentialRow> for GitCredentialRecord {
+    fn from(row: GitCredentialRow) -> Self {
+        Self {
+            id: row.id,
+            user_id: row.user_id,
+            name: row.name,
+            kind: row.kind.parse().unwrap_or(GitCredentialKind::HttpsToken),
+            username: row.username,
+            encrypted_secret: row.encrypted_secret,
+            expires_at: row.expires_at,
+            created_at: row.created_at,
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct SecretRow {
+    user_id: Uuid,
+    name: String,
+    encrypted_value: String,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+impl From<SecretRow> for SecretRecord {
+    fn from(row: SecretRow) -> Self {
+        Self {
+            user_id: row.user_id,
+            name: row.name,
+            encrypted_value: row.encrypted_value,
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct ApiKeyRow {
+    id: Uuid,
+    user_id: Uuid,
+    name: String,
+    key_hash: String,
+    created_at: DateTime<Utc>,
+    last_used_at: Option<DateTime<Utc>>,
+}
+
+impl From<ApiKeyRow> for ApiKeyRecord {
+    fn from(row: ApiKeyRow) -> Self {
+        Self {
+            id: row.id,
+            user_id: row.user_id,
+            name: row.name,
+            key_hash: row.key_hash,
+            created_at: row.created_at,
+            last_used_at: row.last_used_at,
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct ApiKeyInfoRow {
+    id: Uuid,
+    name: String,
+    created_at: DateTime<Utc>,
+    last_used_at: Option<DateTime<Utc>>,
+}
+
+impl From<ApiKeyInfoRow> for ApiKeyInfo {
+    fn from(row: ApiKeyInfoRow) -> Self {
+        Self {
+            id: row.id,
+            name: row.name,
+            created_at: row.created_at,
+            last_used_at: row.last_used_at,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests run against `TEST_DATABASE_URL` and are skipped when it is unset
+    async fn test_db() -> Option<PostgresDb> {
+        let url = std::env::var("TEST_DATABASE_URL").ok()?;
+        Some(PostgresDb::new(&url).await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_schema_matches_supabase() {
+        let Some(db) = test_db().await else {
+            return;
+        };
+
+        let text_columns: Vec<String> = sqlx::query_scalar(
+            r"
+            SELECT table_name || '.' || column_name FROM information_schema.columns
+            WHERE table_schema = current_schema() AND data_type <> 'jsonb'
+              AND (table_name, column_name) IN (
+                  ('jobs', 'env'), ('jobs', 'secrets'), ('jobs', 'steps'),
+                  ('jobs', 'step_results'), ('jobs', 'needs'), ('jobs', 'matrix'),
+                  ('jobs', 'runs_on'), ('jobs', 'retry'), ('runs', 'matrix'),
+                  ('workers', 'labels'), ('workers', 'capabilities'), ('workers', 'images')
+              )
+            ",
+        )
+        .fetch_all(&db.pool)
+        .await
+        .unwrap();
+        assert!(text_columns.is_empty(), "{text_columns:?}");
+
+        // Migrating again leaves the constraints as they are
+        let constraints = || async {
+            sqlx::query_scalar::<_, String>(
+                r"
+                SELECT string_agg(oid::text, ',' ORDER BY conname) FROM pg_constraint
+                WHERE conrelid = 'jobs'::regclass
+                  AND conname IN ('jobs_status_check', 'jobs_failure_reason_check')
+                ",
+            )
+            .fetch_one(&db.pool)
+            .await
+            .unwrap()
+        };
+        let before = constraints().await;
+        assert_eq!(before.split(',').count(), 2);
+        db.run_migrations().await.unwrap();
+        assert_eq!(constraints().await, before);
+
+        let job = Job::with_command(Uuid::new_v4(), "make".to_string(), SourceType::Git, None);
+        db.create_job(&job).await.unwrap();
+        assert!(
+            sqlx::query("UPDATE jobs SET failure_reason = 'bogus' WHERE id = $1")
+                .bind(job.id)
+                .execute(&db.pool)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_job_lifecycle() {
+        let Some(db) = test_db().await else {
+            return;
+        };
+
+        let job = Job::with_command(Uuid::new_v4(), "make".to_string(), SourceType::Git, None);
+        db.create_job(&job).await.unwrap();
+
+        let stored = db.get_job(job.id).await.unwrap().unwrap();
+        assert_eq!(stored.status, JobStatus::Pending);
+        assert_eq!(stored.command.as_deref(), Some("make"));
+
+        // Other tests share the queue, so hand the job to a worker directly
+        // with a lease that has already run out
+        let worker_id = Uuid::new_v4();
+        sqlx::query(
+            "UPDATE jobs SET status = 'running', worker_id = $1, lease_expires_at = NOW() - INTERVAL '1 minute' WHERE id = $2",
+        )
+        .bind(worker_id)
+        .bind(job.id)
+        .execute(&db.pool)
+        .await
+        .unwrap();
+
+        let expired = db.list_expired_jobs(Utc::now()).await.unwrap();
+        assert!(expired.iter().any(|j| j.id == job.id));
+        assert!(db
+            .reclaim_job(job.id, JobStatus::Pending, "lease expired")
+            .await
+            .unwrap());
+        let requeued = db.get_job(job.id).await.unwrap().unwrap();
+        assert_eq!(requeued.status, JobStatus::Pending);
+        assert_eq!(requeued.worker_id, None);
+        assert_eq!(requeued.status_reason.as_deref(), Some("lease expired"));
+        assert_eq!(requeued.infra_retries, 1);
+
+        // A worker that lost its lease can no longer complete the job
+        assert!(!db
+            .complete_job(
+                job.id,
+                worker_id,
+                JobStatus::Failed,
+                Some(FailureReason::UserExit),
+                65,
+                1.5
+            )
+            .await
+            .unwrap());
+
+        sqlx::query("UPDATE jobs SET status = 'running', worker_id = $1 WHERE id = $2")
+            .bind(worker_id)
+            .bind(job.id)
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        db.renew_job_leases(
+            worker_id,
+            &[job.id],
+            Utc::now() + chrono::Duration::minutes(1),
+        )
+        .await
+        .unwrap();
+        assert!(!db
+            .reclaim_job(job.id, JobStatus::Pending, "lease expired")
+            .await
+            .unwrap());
+
+        assert!(db
+            .complete_job(
+                job.id,
+                worker_id,
+                JobStatus::Failed,
+                Some(FailureReason::UserExit),
+                65,
+                1.5
+            )
+            .await
+            .unwrap());
+        let completed = db.get_job(job.id).await.unwrap().unwrap();
+        assert_eq!(completed.status, JobStatus::Failed);
+        assert_eq!(completed.exit_code, Some(65));
+        assert_eq!(completed.failure_reason, Some(FailureReason::UserExit));
+        assert!(completed.completed_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_infra_requeue() {
+        let Some(db) = test_db().await else {
+            return;
+        };
+
+        let job = Job::with_command(Uuid::new_v4(), "make".to_string(), SourceType::Git, None);
+        db.create_job(&job).await.unwrap();
+
+        // Other tests share the queue, so hand the job to a worker directly
+        let worker_id = Uuid::new_v4();
+        sqlx::query("UPDATE jobs SET status = 'running', worker_id = $1 WHERE id = $2")
+            .bind(worker_id)
+            .bind(job.id)
+            .execute(&db.pool)
+            .await
+            .unwrap();
+
+        let avoid_until = Utc::now() + chrono::Duration::minutes(1);
+        assert!(db
+            .requeue_job(job.id, worker_id, 1, avoid_until, "VM failed")
+            .await
+            .unwrap());
+        let requeued = db.get_job(job.id).await.unwrap().unwrap();
+        assert_eq!(requeued.status, JobStatus::Pending);
+        assert_eq!(requeued.worker_id, None);
+        assert_eq!(requeued.infra_retries, 1);
+        assert_eq!(requeued.status_reason.as_deref(), Some("VM failed"));
+
+        sqlx::query("UPDATE jobs SET status = 'running', worker_id = $1 WHERE id = $2")
+            .bind(worker_id)
+            .bind(job.id)
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        assert!(!db
+            .requeue_job(job.id, worker_id, 1, avoid_until, "VM failed")
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_release_job() {
+        let Some(db) = test_db().await else {
+            return;
+        };
+
+        let job = Job::with_command(Uuid::new_v4(), "make".to_string(), SourceType::Git, None);
+        db.create_job(&job).await.unwrap();
+
+        let worker_id = Uuid::new_v4();
+        sqlx::query("UPDATE jobs SET status = 'running', worker_id = $1 WHERE id = $2")
+            .bind(worker_id)
+            .bind(job.id)
+            .execute(&db.pool)
+            .await
+            .unwrap();
+
+        assert!(!db
+            .release_job(job.id, Uuid::new_v4(), "Handed back")
+            .await
+            .unwrap());
+        assert!(db
+            .release_job(job.id, worker_id, "Handed back")
+            .await
+            .unwrap());
+        let released = db.get_job(job.id).await.unwrap().unwrap();
+        assert_eq!(released.status, JobStatus::Pending);
+        assert_eq!(released.worker_id, None);
+        assert_eq!(released.infra_retries, 0);
+        assert_eq!(released.status_reason.as_deref(), Some("Handed back"));
+        assert!(!db
+            .release_job(job.id, worker_id, "Handed back")
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_job_needs() {
+        let Some(db) = test_db().await else {
+            return;
+        };
+        let new_job = |needs: &[Uuid]| {
+            let mut job =
+                Job::with_command(Uuid::new_v4(), "make".to_string(), SourceType::Git, None);
+            job.needs = needs.to_vec();
+            job
+        };
+
+        let build = new_job(&[]);
+        let test = new_job(&[build.id]);
+        let package = new_job(&[test.id]);
+        db.create_job(&build).await.unwrap();
+        db.create_job(&test).await.unwrap();
+        db.create_job(&package).await.unwrap();
+        assert_eq!(
+            db.get_job(test.id).await.unwrap().unwrap().needs,
+            [build.id]
+        );
+
+        // Other tests share the queue: drain it, and jobs still waiting on
+        // `build` must never be handed out
+        let lease = Utc::now() + chrono::Duration::minutes(1);
+        while let Some(job) = db
+            .claim_pending_job(Uuid::new_v4(), &[], &[], lease)
+            .await
+            .unwrap()
+        {
+            assert!(job.id != test.id && job.id != package.id);
+        }
+        assert_eq!(
+            db.get_job(test.id).await.unwrap().unwrap().status,
+            JobStatus::Pending
+        );
+
+        let cancelled = db.cancel_dependents(test.id, "test failed").await.unwrap();
+        assert_eq!(cancelled, [package.id]);
+        let stored = db.get_job(package.id).await.unwrap().unwrap();
+        assert_eq!(stored.status, JobStatus::Cancelled);
+        assert_eq!(stored.status_reason.as_deref(), Some("test failed"));
+    }
+
+    #[tokio::test]
+    async fn test_retry_attempts() {
+        let Some(db) = test_db().await else {
+            return;
+        };
+
+        let job = Job::with_command(Uuid::new_v4(), "make".to_string(), SourceType::Git, None);
+        db.create_job(&job).await.unwrap();
+        let mut dependent =
+            Job::with_command(job.customer_id, "deploy".to_string(), SourceType::Git, None);
+        dependent.needs = vec![job.id];
+        db.create_job(&dependent).await.unwrap();
+        assert!(db.get_retry(job.id).await.unwrap().is_none());
+
+        let mut retry =
+            Job::with_command(job.customer_id, "make".to_string(), SourceType::Git, None);
+        retry.attempt = 2;
+        retry.parent_job_id = Some(job.id);
+        retry.not_before = Some(Utc::now() + chrono::Duration::minutes(5));
+        db.create_job(&retry).await.unwrap();
+
+        let found = db.get_retry(job.id).await.unwrap().unwrap();
+        assert_eq!(found.id, retry.id);
+        assert_eq!(found.attempt, 2);
+        assert!(found.not_before.is_some());
+
+        db.repoint_needs(job.id, retry.id).await.unwrap();
+        let dependent = db.get_job(dependent.id).await.unwrap().unwrap();
+        assert_eq!(dependent.needs, vec![retry.id]);
+    }
+
+    #[tokio::test]
+    async fn test_claim_by_labels_and_image() {
+        let Some(db) = test_db().await else {
+            return;
+        };
+        let label = format!("test-{}", Uuid::new_v4());
+        let mut job = Job::with_command(Uuid::new_v4(), "make".to_string(), SourceType::Git, None);
+        job.runs_on = vec![label.clone(), "xcode-16".to_string()];
+        job.image = Some(label.clone());
+        db.create_job(&job).await.unwrap();
+        let stored = db.get_job(job.id).await.unwrap().unwrap();
+        assert_eq!(stored.runs_on, job.runs_on);
+        assert_eq!(stored.image, job.image);
+
+        // Other tests share the queue: workers lacking a label or the image
+        // drain it without ever being handed the job
+        let lease = Utc::now() + chrono::Duration::minutes(1);
+        let labels = [label.clone(), "xcode-16".to_string(), "m2".to_string()];
+        let images = [label];
+        for (labels, images) in [(&labels[..1], &images[..]), (&labels[..], &[][..])] {
+            while let Some(claimed) = db
+                .claim_pending_job(Uuid::new_v4(), labels, images, lease)
+                .await
+                .unwrap()
+            {
+                assert_ne!(claimed.id, job.id);
+            }
+        }
+
+        let claimed = db
+            .claim_pending_job(Uuid::new_v4(), &labels, &images, lease)
+            .await
+            .unwrap();
+        assert_eq!(claimed.unwrap().id, job.id);
+    }
+
+    #[tokio::test]
+    async fn test_runs() {
+        let Some(db) = test_db().await else {
+            return;
+        };
+        let template = Job::with_command(Uuid::new_v4(), "make".to_string(), SourceType::Git, None);
+        let matrix = [(
+            "XCODE".to_string(),
+            vec!["15.4".to_string(), "16.0".to_string()],
+        )]
+        .into();
+        let (run, jobs) = crate::matrix::expand(&template, &matrix);
+
+        db.create_run(&run, &jobs).await.unwrap();
+
+        let stored = db.get_run(run.id).await.unwrap().unwrap();
+        assert_eq!(stored.customer_id, template.customer_id);
+        assert_eq!(stored.matrix, matrix);
+
+        let listed = db.list_jobs(None, Some(run.id), 10).await.unwrap();
+        assert_eq!(listed.len(), 2);
+        assert!(listed.iter().any(|job| job.matrix["XCODE"] == "16.0"));
+        assert!(db
+            .list_jobs(Some("completed"), Some(run.id), 10)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // A run is created with all of its jobs or not at all
+        let (failed_run, mut failed_jobs) = crate::matrix::expand(
+            &Job::with_command(Uuid::new_v4(), "make".to_string(), SourceType::Git, None),
+            &matrix,
+        );
+        failed_jobs[1].id = jobs[0].id;
+        db.create_run(&failed_run, &failed_jobs).await.unwrap_err();
+        assert!(db.get_run(failed_run.id).await.unwrap().is_none());
+        assert!(db.get_job(failed_jobs[0].id).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_claims() {
+        let Some(db) = test_db().await else {
+            return;
+        };
+
+        crate::db::assert_exclusive_claims(std::sync::Arc::new(db)).await;
+    }
+
+    #[tokio::test]
+    async fn test_api_keys_and_users() {
+        let Some(db) = test_db().await else {
+            return;
+        };
+
+        let email = format!("{}@example.com", Uuid::new_v4());
+        let hash = crate::crypto::hash_password("hunter2").unwrap();
+        let user_id = db.create_user(&email, &hash).await.unwrap();
+        assert_eq!(
+            db.verify_user(&email, "hunter2").await.unwrap(),
+            Some(user_id)
+        );
+        assert_eq!(db.verify_user(&email, "wrong").await.unwrap(), None);
+        let duplicate = db.create_user(&email, &hash).await.unwrap_err();
+        assert!(duplicate.to_string().contains("UNIQUE"));
+
+        let key_hash = Uuid::new_v4().to_string();
+        let key_id = db.create_api_key(user_id, "ci", &key_hash).await.unwrap();
+        let record = db.verify_api_key(&key_hash).await.unwrap().unwrap();
+        assert_eq!(record.id, key_id);
+        assert_eq!(db.list_api_keys(user_id).await.unwrap().len(), 1);
+        assert!(db.delete_api_key(user_id, key_id).await.unwrap());
+        assert!(db.verify_api_key(&key_hash).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_git_credentials() {
+        let Some(db) = test_db().await else {
+            return;
+        };
+
+        let user_id = Uuid::new_v4();
+        let record = GitCredentialRecord {
+            id: Uuid::new_v4(),
+            user_id,
+            name: "deploy".to_string(),
+            kind: GitCredentialKind::InstallationToken,
+            username: None,
+            encrypted_secret: "00ff".to_string(),
+            expires_at: Some(Utc::now()),
+            created_at: Utc::now(),
+        };
+        db.create_git_credential(&record).await.unwrap();
+
+        let stored = db.get_git_credential(record.id).await.unwrap().unwrap();
+        assert_eq!(stored.kind, GitCredentialKind::InstallationToken);
+        assert_eq!(stored.encrypted_secret, "00ff");
+        assert_eq!(db.list_git_credentials(user_id).await.unwrap().len(), 1);
+
+        assert!(!db
+            .delete_git_credential(Uuid::new_v4(), record.id)
+            .await
+            .unwrap());
+        assert!(db.delete_git_credential(user_id, record.id).await.unwrap());
+        assert!(db.get_git_credential(record.id).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_secrets() {
+        let Some(db) = test_db().await else {
+            return;
+        };
+
+        let user_id = Uuid::new_v4();
+        let created_at = Utc::now() - chrono::Duration::hours(1);
+        let mut secret = SecretRecord {
+            user_id,
+            name: "MATCH_PASSWORD".to_string(),
+            encrypted_value: "00ff".to_string(),
+            created_at,
+            updated_at: created_at,
+        };
+        db.set_secret(&secret).await.unwrap();
+
+        // Setting it again replaces the value but keeps the creation time
+        secret.encrypted_value = "11ee".to_string();
+        secret.created_at = Utc::now();
+        secret.updated_at = secret.created_at;
+        db.set_secret(&secret).await.unwrap();
+
+        let stored = db.list_secrets(user_id).await.unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].encrypted_value, "11ee");
+        assert!(stored[0].created_at < stored[0].updated_at);
+
+        assert!(!db
+            .delete_secret(Uuid::new_v4(), "MATCH_PASSWORD")
+            .await
+            .unwrap());
+        assert!(db.delete_secret(user_id, "MATCH_PASSWORD").await.unwrap());
+        assert!(db.list_secrets(user_id).await.unwrap().is_empty());
+    }
+}
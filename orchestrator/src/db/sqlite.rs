@@ -5,12 +5,17 @@
 
 use anyhow::Result;
 use async_trait::async_trait;
-use chrono::Utc;
-use sqlx::{sqlite::SqlitePoolOptions, Pool, Sqlite};
+use chrono::{DateTime, SecondsFormat, Utc};
+use sqlx::{sqlite::SqlitePoolOptions, Pool, Sqlite, SqliteExecutor};
 use uuid::Uuid;
 
-use super::{ApiKeyInfo, ApiKeyRecord, Database};
-use shared::{Artifact, Job, JobStatus, SourceType, WorkerInfo};
+use super::{
+    completable_status, ApiKeyInfo, ApiKeyRecord, Database, GitCredentialRecord, SecretRecord,
+};
+use shared::{
+    Artifact, FailureReason, GitCredentialKind, Job, JobStatus, Run, SourceType, StepResult,
+    WorkerInfo, WorkerStatus,
+};
 
 /// `SQLite` database implementation
 #[derive(Clone)]
@@ -39,86 +44,13 @@ impl SqliteDb {
 
     /// Run database migrations
     async fn run_migrations(&self) -> Result<()> {
-        sqlx::query(
-            r"
-            CREATE TABLE IF NOT EXISTS jobs (
-                id TEXT PRIMARY KEY,
-                customer_id TEXT NOT NULL,
-                source_type TEXT NOT NULL DEFAULT 'git',
-                source_url TEXT,
-                command TEXT,
-                script TEXT,
-                status TEXT NOT NULL DEFAULT 'pending',
-                worker_id TEXT,
-                created_at TEXT NOT NULL,
-                started_at TEXT,
-                completed_at TEXT,
-                exit_code INTEGER,
-                build_minutes REAL
-            )
-            ",
-        )
-        .execute(&self.pool)
-        .await?;
-
-        sqlx::query(
-            r"
-            CREATE TABLE IF NOT EXISTS workers (
-                id TEXT PRIMARY KEY,
-                hostname TEXT NOT NULL,
-                capacity INTEGER NOT NULL,
-                current_jobs INTEGER NOT NULL DEFAULT 0,
-                last_heartbeat TEXT NOT NULL,
-                status TEXT NOT NULL DEFAULT 'online'
-            )
-            ",
-        )
-        .execute(&self.pool)
-        .await?;
-
-        sqlx::query(
-            r"
-            CREATE TABLE IF NOT EXISTS artifacts (
-                id TEXT PRIMARY KEY,
-                job_id TEXT NOT NULL,
-                name TEXT NOT NULL,
-                path TEXT NOT NULL,
-                size_bytes INTEGER NOT NULL DEFAULT 0,
-                download_url TEXT,
-                FOREIGN KEY (job_id) REFERENCES jobs(id)
-            )
-            ",
-        )
-        .execute(&self.pool)
-        .await?;
-
-        sqlx::query(
-            r"
-            CREATE TABLE IF NOT EXISTS api_keys (
-                id TEXT PRIMARY KEY,
-                user_id TEXT NOT NULL,
-                name TEXT NOT NULL,
-                key_hash TEXT NOT NULL UNIQUE,
-                created_at TEXT NOT NULL,
-                last_used_at TEXT
-            )
-            ",
-        )
-        .execute(&self.pool)
-        .await?;
+        for statement in TABLES {
+            sqlx::query(statement).execute(&self.pool).await?;
+        }
 
-        sqlx::query(
-            r"
-            CREATE TABLE IF NOT EXISTS users (
-                id TEXT PRIMARY KEY,
-                email TEXT UNIQUE,
-                password_hash TEXT,
-                created_at TEXT NOT NULL
-            )
-            ",
-        )
-        .execute(&self.pool)
-        .await?;
+        for (table, column, definition) in ADDED_COLUMNS {
+            self.add_column(table, column, definition).await?;
+        }
 
         // Create indexes
         sqlx::query("CREATE INDEX IF NOT EXISTS idx_jobs_status ON jobs(status)")
@@ -127,60 +59,357 @@ impl SqliteDb {
         sqlx::query("CREATE INDEX IF NOT EXISTS idx_api_keys_hash ON api_keys(key_hash)")
             .execute(&self.pool)
             .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_jobs_run_id ON jobs(run_id)")
+            .execute(&self.pool)
+            .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_jobs_parent_job_id ON jobs(parent_job_id)")
+            .execute(&self.pool)
+            .await?;
 
         // Note: No subscription/billing tables for SQLite (self-hosted mode)
         // Billing is only available in Supabase (cloud) mode
 
         Ok(())
     }
+
+    /// Add a column to an existing table unless an earlier run already did
+    async fn add_column(&self, table: &str, column: &str, definition: &str) -> Result<()> {
+        let exists: bool =
+            sqlx::query_scalar("SELECT COUNT(*) > 0 FROM pragma_table_info(?) WHERE name = ?")
+                .bind(table)
+                .bind(column)
+                .fetch_one(&self.pool)
+                .await?;
+
+        if !exists {
+            sqlx::query(&format!(
+                "ALTER TABLE {table} ADD COLUMN {column} {definition}"
+            ))
+            .execute(&self.pool)
+            .await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Tables of the current schema, created when missing
+const TABLES: &[&str] = &[
+    r"
+    CREATE TABLE IF NOT EXISTS jobs (
+        id TEXT PRIMARY KEY,
+        customer_id TEXT NOT NULL,
+        source_type TEXT NOT NULL DEFAULT 'git',
+        source_url TEXT,
+        command TEXT,
+        script TEXT,
+        status TEXT NOT NULL DEFAULT 'pending',
+        worker_id TEXT,
+        created_at TEXT NOT NULL,
+        started_at TEXT,
+        completed_at TEXT,
+        exit_code INTEGER,
+        build_minutes REAL
+    )
+    ",
+    r"
+    CREATE TABLE IF NOT EXISTS workers (
+        id TEXT PRIMARY KEY,
+        hostname TEXT NOT NULL,
+        capacity INTEGER NOT NULL,
+        current_jobs INTEGER NOT NULL DEFAULT 0,
+        last_heartbeat TEXT NOT NULL,
+        status TEXT NOT NULL DEFAULT 'online'
+    )
+    ",
+    r"
+    CREATE TABLE IF NOT EXISTS artifacts (
+        id TEXT PRIMARY KEY,
+        job_id TEXT NOT NULL,
+        name TEXT NOT NULL,
+        path TEXT NOT NULL,
+        size_bytes INTEGER NOT NULL DEFAULT 0,
+        download_url TEXT,
+        FOREIGN KEY (job_id) REFERENCES jobs(id)
+    )
+    ",
+    r"
+    CREATE TABLE IF NOT EXISTS api_keys (
+        id TEXT PRIMARY KEY,
+        user_id TEXT NOT NULL,
+        name TEXT NOT NULL,
+        key_hash TEXT NOT NULL UNIQUE,
+        created_at TEXT NOT NULL,
+        last_used_at TEXT
+    )
+    ",
+    r"
+    CREATE TABLE IF NOT EXISTS users (
+        id TEXT PRIMARY KEY,
+        email TEXT UNIQUE,
+        password_hash TEXT,
+        created_at TEXT NOT NULL
+    )
+    ",
+    r"
+    CREATE TABLE IF NOT EXISTS git_credentials (
+        id TEXT PRIMARY KEY,
+        user_id TEXT NOT NULL,
+        name TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        username TEXT,
+        encrypted_secret TEXT NOT NULL,
+        expires_at TEXT,
+        created_at TEXT NOT NULL
+    )
+    ",
+    r"
+    CREATE TABLE IF NOT EXISTS secrets (
+        user_id TEXT NOT NULL,
+        name TEXT NOT NULL,
+        encrypted_value TEXT NOT NULL,
+        created_at TEXT NOT NULL,
+        updated_at TEXT NOT NULL,
+        PRIMARY KEY (user_id, name)
+    )
+    ",
+    r"
+    CREATE TABLE IF NOT EXISTS runs (
+        id TEXT PRIMARY KEY,
+        customer_id TEXT NOT NULL,
+        created_at TEXT NOT NULL,
+        matrix TEXT NOT NULL DEFAULT '{}'
+    )
+    ",
+];
+
+/// Columns added after the initial schema, as (table, column, definition)
+const ADDED_COLUMNS: &[(&str, &str, &str)] = &[
+    ("jobs", "lease_expires_at", "TEXT"),
+    ("jobs", "status_reason", "TEXT"),
+    ("jobs", "git_ref", "TEXT"),
+    ("jobs", "commit_sha", "TEXT"),
+    ("jobs", "submodules", "INTEGER NOT NULL DEFAULT 0"),
+    ("jobs", "lfs", "INTEGER NOT NULL DEFAULT 0"),
+    ("jobs", "resolved_sha", "TEXT"),
+    ("jobs", "git_credential_id", "TEXT"),
+    ("jobs", "env", "TEXT NOT NULL DEFAULT '{}'"),
+    ("jobs", "secrets", "TEXT NOT NULL DEFAULT '[]'"),
+    ("jobs", "steps", "TEXT NOT NULL DEFAULT '[]'"),
+    ("jobs", "step_results", "TEXT NOT NULL DEFAULT '[]'"),
+    ("jobs", "needs", "TEXT NOT NULL DEFAULT '[]'"),
+    ("jobs", "run_id", "TEXT"),
+    ("jobs", "matrix", "TEXT NOT NULL DEFAULT '{}'"),
+    ("jobs", "runs_on", "TEXT NOT NULL DEFAULT '[]'"),
+    ("workers", "labels", "TEXT NOT NULL DEFAULT '[]'"),
+    ("workers", "capabilities", "TEXT NOT NULL DEFAULT '{}'"),
+    ("jobs", "image", "TEXT"),
+    ("workers", "images", "TEXT NOT NULL DEFAULT '[]'"),
+    ("jobs", "timeout_minutes", "INTEGER"),
+    ("jobs", "failure_reason", "TEXT"),
+    ("jobs", "infra_retries", "INTEGER NOT NULL DEFAULT 0"),
+    ("jobs", "avoid_worker_id", "TEXT"),
+    ("jobs", "avoid_worker_until", "TEXT"),
+    ("jobs", "attempt", "INTEGER NOT NULL DEFAULT 1"),
+    ("jobs", "parent_job_id", "TEXT"),
+    ("jobs", "retry", "TEXT"),
+    ("jobs", "not_before", "TEXT"),
+];
+
+/// Fixed-width RFC 3339 timestamp, so stored values compare correctly as text
+fn sortable_timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+/// Insert `job` through `executor`, on its own or as part of a run
+async fn insert_job<'e>(executor: impl SqliteExecutor<'e>, job: &Job) -> Result<()> {
+    sqlx::query(
+        r"
+        INSERT INTO jobs (id, customer_id, source_type, source_url, command, script, status, created_at,
+                          git_ref, commit_sha, submodules, lfs, git_credential_id, env, secrets,
+                          steps, needs, run_id, matrix, runs_on, image, timeout_minutes,
+                          attempt, parent_job_id, retry, not_before)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        ",
+    )
+    .bind(job.id.to_string())
+    .bind(job.customer_id.to_string())
+    .bind(job.source_type.to_string())
+    .bind(&job.source_url)
+    .bind(&job.command)
+    .bind(&job.script)
+    .bind(job.status.to_string())
+    .bind(job.created_at.to_rfc3339())
+    .bind(&job.git_ref)
+    .bind(&job.commit_sha)
+    .bind(job.submodules)
+    .bind(job.lfs)
+    .bind(job.git_credential_id.map(|id| id.to_string()))
+    .bind(serde_json::to_string(&job.env)?)
+    .bind(serde_json::to_string(&job.secrets)?)
+    .bind(serde_json::to_string(&job.steps)?)
+    .bind(serde_json::to_string(&job.needs)?)
+    .bind(job.run_id.map(|id| id.to_string()))
+    .bind(serde_json::to_string(&job.matrix)?)
+    .bind(serde_json::to_string(&job.runs_on)?)
+    .bind(&job.image)
+    .bind(job.timeout_minutes)
+    .bind(job.attempt)
+    .bind(job.parent_job_id.map(|id| id.to_string()))
+    .bind(job.retry.as_ref().map(serde_json::to_string).transpose()?)
+    .bind(job.not_before.map(sortable_timestamp))
+    .execute(executor)
+    .await?;
+
+    Ok(())
 }
 
 #[async_trait]
 impl Database for SqliteDb {
     async fn create_job(&self, job: &Job) -> Result<()> {
+        insert_job(&self.pool, job).await
+    }
+
+    async fn get_job(&self, job_id: Uuid) -> Result<Option<Job>> {
+        let row = sqlx::query_as::<_, JobRow>("SELECT * FROM jobs WHERE id = ?")
+            .bind(job_id.to_string())
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.map(std::convert::Into::into))
+    }
+
+    async fn list_jobs(
+        &self,
+        status: Option<&str>,
+        run_id: Option<Uuid>,
+        limit: usize,
+    ) -> Result<Vec<Job>> {
+        #[allow(clippy::cast_possible_wrap)]
+        let limit = limit as i64;
+        let run_id = run_id.map(|id| id.to_string());
+
+        let rows = sqlx::query_as::<_, JobRow>(
+            r"
+            SELECT * FROM jobs
+            WHERE (?1 IS NULL OR status = ?1)
+              AND (?2 IS NULL OR run_id = ?2)
+            ORDER BY created_at DESC
+            LIMIT ?3
+            ",
+        )
+        .bind(status)
+        .bind(run_id)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(std::convert::Into::into).collect())
+    }
+
+    async fn update_job_status(&self, job_id: Uuid, status: JobStatus) -> Result<()> {
+        sqlx::query("UPDATE jobs SET status = ?, failure_reason = ? WHERE id = ?")
+            .bind(status.to_string())
+            .bind(status.failure_reason().map(|reason| reason.to_string()))
+            .bind(job_id.to_string())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn cancel_dependents(&self, job_id: Uuid, reason: &str) -> Result<Vec<Uuid>> {
+        let ids: Vec<String> = sqlx::query_scalar(
+            r"
+            UPDATE jobs
+            SET status = 'cancelled', status_reason = ?, completed_at = ?,
+                failure_reason = 'cancelled'
+            WHERE status IN ('pending', 'uploading')
+              AND EXISTS (SELECT 1 FROM json_each(jobs.needs) WHERE value = ?)
+            RETURNING id
+            ",
+        )
+        .bind(reason)
+        .bind(Utc::now().to_rfc3339())
+        .bind(job_id.to_string())
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(ids
+            .iter()
+            .filter_map(|id| Uuid::parse_str(id).ok())
+            .collect())
+    }
+
+    async fn repoint_needs(&self, from: Uuid, to: Uuid) -> Result<()> {
         sqlx::query(
             r"
-            INSERT INTO jobs (id, customer_id, source_type, source_url, command, script, status, created_at)
-            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+            UPDATE jobs
+            SET needs = replace(needs, ?1, ?2)
+            WHERE status IN ('pending', 'uploading')
+              AND EXISTS (SELECT 1 FROM json_each(jobs.needs) WHERE value = ?1)
             ",
         )
-        .bind(job.id.to_string())
-        .bind(job.customer_id.to_string())
-        .bind(job.source_type.to_string())
-        .bind(&job.source_url)
-        .bind(&job.command)
-        .bind(&job.script)
-        .bind(job.status.to_string())
-        .bind(job.created_at.to_rfc3339())
+        .bind(from.to_string())
+        .bind(to.to_string())
         .execute(&self.pool)
         .await?;
 
         Ok(())
     }
 
-    async fn get_job(&self, job_id: Uuid) -> Result<Option<Job>> {
-        let row = sqlx::query_as::<_, JobRow>("SELECT * FROM jobs WHERE id = ?")
-            .bind(job_id.to_string())
-            .fetch_optional(&self.pool)
-            .await?;
+    async fn get_retry(&self, job_id: Uuid) -> Result<Option<Job>> {
+        let row = sqlx::query_as::<_, JobRow>(
+            "SELECT * FROM jobs WHERE parent_job_id = ? ORDER BY created_at ASC LIMIT 1",
+        )
+        .bind(job_id.to_string())
+        .fetch_optional(&self.pool)
+        .await?;
 
         Ok(row.map(std::convert::Into::into))
     }
 
-    async fn claim_pending_job(&self, worker_id: Uuid) -> Result<Option<Job>> {
+    async fn claim_pending_job(
+        &self,
+        worker_id: Uuid,
+        labels: &[String],
+        images: &[String],
+        lease_expires_at: DateTime<Utc>,
+    ) -> Result<Option<Job>> {
         // Find and claim a pending job atomically
         let row = sqlx::query_as::<_, JobRow>(
             r"
             UPDATE jobs 
-            SET status = 'running', worker_id = ?, started_at = ?
+            SET status = 'running', worker_id = ?, started_at = ?, lease_expires_at = ?,
+                status_reason = NULL
             WHERE id = (
-                SELECT id FROM jobs WHERE status = 'pending' ORDER BY created_at ASC LIMIT 1
-            )
+                SELECT id FROM jobs
+                WHERE status = 'pending'
+                  AND NOT EXISTS (
+                      SELECT 1 FROM json_each(jobs.needs) AS need
+                      LEFT JOIN jobs AS upstream ON upstream.id = need.value
+                      WHERE upstream.status IS NOT 'completed'
+                  )
+                  AND NOT EXISTS (
+                      SELECT 1 FROM json_each(jobs.runs_on)
+                      WHERE value NOT IN (SELECT value FROM json_each(?))
+                  )
+                  AND (jobs.image IS NULL OR jobs.image IN (SELECT value FROM json_each(?)))
+                  AND (jobs.avoid_worker_id IS NOT ? OR jobs.avoid_worker_until < ?)
+                  AND (jobs.not_before IS NULL OR jobs.not_before < ?)
+                ORDER BY created_at ASC LIMIT 1
+            ) AND status = 'pending'
             RETURNING *
             ",
         )
         .bind(worker_id.to_string())
         .bind(Utc::now().to_rfc3339())
+        .bind(sortable_timestamp(lease_expires_at))
+        .bind(serde_json::to_string(labels)?)
+        .bind(serde_json::to_string(images)?)
+        .bind(worker_id.to_string())
+        .bind(sortable_timestamp(Utc::now()))
+        .bind(sortable_timestamp(Utc::now()))
         .fetch_optional(&self.pool)
         .await?;
 
@@ -190,33 +419,208 @@ impl Database for SqliteDb {
     async fn complete_job(
         &self,
         job_id: Uuid,
+        worker_id: Uuid,
         status: JobStatus,
+        failure_reason: Option<FailureReason>,
         exit_code: i32,
         build_minutes: f64,
-    ) -> Result<()> {
-        sqlx::query(
+    ) -> Result<bool> {
+        let result = sqlx::query(
             r"
             UPDATE jobs 
-            SET status = ?, exit_code = ?, build_minutes = ?, completed_at = ?
-            WHERE id = ?
+            SET status = ?, failure_reason = ?, exit_code = ?, build_minutes = ?, completed_at = ?,
+                lease_expires_at = NULL
+            WHERE id = ? AND worker_id = ? AND status = ?
             ",
         )
         .bind(status.to_string())
+        .bind(failure_reason.map(|reason| reason.to_string()))
         .bind(exit_code)
         .bind(build_minutes)
         .bind(Utc::now().to_rfc3339())
         .bind(job_id.to_string())
+        .bind(worker_id.to_string())
+        .bind(completable_status(status).to_string())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn requeue_job(
+        &self,
+        job_id: Uuid,
+        worker_id: Uuid,
+        max_retries: u32,
+        avoid_until: DateTime<Utc>,
+        reason: &str,
+    ) -> Result<bool> {
+        let result = sqlx::query(
+            r"
+            UPDATE jobs
+            SET status = 'pending', worker_id = NULL, started_at = NULL, lease_expires_at = NULL,
+                status_reason = ?, infra_retries = infra_retries + 1,
+                avoid_worker_id = ?, avoid_worker_until = ?
+            WHERE id = ? AND worker_id = ? AND status = 'running' AND infra_retries < ?
+            ",
+        )
+        .bind(reason)
+        .bind(worker_id.to_string())
+        .bind(sortable_timestamp(avoid_until))
+        .bind(job_id.to_string())
+        .bind(worker_id.to_string())
+        .bind(max_retries)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn release_job(&self, job_id: Uuid, worker_id: Uuid, reason: &str) -> Result<bool> {
+        let result = sqlx::query(
+            r"
+            UPDATE jobs
+            SET status = 'pending', worker_id = NULL, started_at = NULL, lease_expires_at = NULL,
+                status_reason = ?
+            WHERE id = ? AND worker_id = ? AND status = 'running'
+            ",
+        )
+        .bind(reason)
+        .bind(job_id.to_string())
+        .bind(worker_id.to_string())
         .execute(&self.pool)
         .await?;
 
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn set_resolved_sha(&self, job_id: Uuid, sha: &str) -> Result<()> {
+        sqlx::query("UPDATE jobs SET resolved_sha = ? WHERE id = ?")
+            .bind(sha)
+            .bind(job_id.to_string())
+            .execute(&self.pool)
+            .await?;
+
         Ok(())
     }
 
+    async fn set_step_results(&self, job_id: Uuid, results: &[StepResult]) -> Result<()> {
+        sqlx::query("UPDATE jobs SET step_results = ? WHERE id = ?")
+            .bind(serde_json::to_string(results)?)
+            .bind(job_id.to_string())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn create_run(&self, run: &Run, jobs: &[Job]) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("INSERT INTO runs (id, customer_id, created_at, matrix) VALUES (?, ?, ?, ?)")
+            .bind(run.id.to_string())
+            .bind(run.customer_id.to_string())
+            .bind(run.created_at.to_rfc3339())
+            .bind(serde_json::to_string(&run.matrix)?)
+            .execute(&mut *tx)
+            .await?;
+
+        for job in jobs {
+            insert_job(&mut *tx, job).await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn get_run(&self, run_id: Uuid) -> Result<Option<Run>> {
+        let row = sqlx::query_as::<_, RunRow>("SELECT * FROM runs WHERE id = ?")
+            .bind(run_id.to_string())
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.map(std::convert::Into::into))
+    }
+
+    async fn renew_job_leases(
+        &self,
+        worker_id: Uuid,
+        job_ids: &[Uuid],
+        lease_expires_at: DateTime<Utc>,
+    ) -> Result<()> {
+        for job_id in job_ids {
+            sqlx::query(
+                r"
+                UPDATE jobs SET lease_expires_at = ?
+                WHERE id = ? AND worker_id = ? AND status = 'running'
+                ",
+            )
+            .bind(sortable_timestamp(lease_expires_at))
+            .bind(job_id.to_string())
+            .bind(worker_id.to_string())
+            .execute(&self.pool)
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn list_expired_jobs(&self, now: DateTime<Utc>) -> Result<Vec<Job>> {
+        // Jobs claimed before leases existed have no lease and count as expired
+        let rows = sqlx::query_as::<_, JobRow>(
+            r"
+            SELECT * FROM jobs
+            WHERE status = 'running' AND (lease_expires_at IS NULL OR lease_expires_at < ?)
+            ",
+        )
+        .bind(sortable_timestamp(now))
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(std::convert::Into::into).collect())
+    }
+
+    async fn reclaim_job(&self, job_id: Uuid, status: JobStatus, reason: &str) -> Result<bool> {
+        let now = Utc::now();
+        let query = if status == JobStatus::Pending {
+            sqlx::query(
+                r"
+                UPDATE jobs
+                SET status = 'pending', worker_id = NULL, started_at = NULL,
+                    lease_expires_at = NULL, status_reason = ?, infra_retries = infra_retries + 1
+                WHERE id = ? AND status = 'running'
+                    AND (lease_expires_at IS NULL OR lease_expires_at < ?)
+                ",
+            )
+        } else {
+            sqlx::query(
+                r"
+                UPDATE jobs
+                SET status = ?, completed_at = ?, lease_expires_at = NULL, status_reason = ?,
+                    failure_reason = 'infra'
+                WHERE id = ? AND status = 'running'
+                    AND (lease_expires_at IS NULL OR lease_expires_at < ?)
+                ",
+            )
+            .bind(status.to_string())
+            .bind(now.to_rfc3339())
+        };
+
+        let result = query
+            .bind(reason)
+            .bind(job_id.to_string())
+            .bind(sortable_timestamp(now))
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
     async fn register_worker(&self, worker: &WorkerInfo) -> Result<()> {
         sqlx::query(
             r"
-            INSERT OR REPLACE INTO workers (id, hostname, capacity, current_jobs, last_heartbeat, status)
-            VALUES (?, ?, ?, ?, ?, ?)
+            INSERT OR REPLACE INTO workers (id, hostname, capacity, current_jobs, last_heartbeat, status,
+                                            labels, capabilities, images)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
             ",
         )
         .bind(worker.id.to_string())
@@ -225,12 +629,24 @@ impl Database for SqliteDb {
         .bind(i64::from(worker.current_jobs))
         .bind(worker.last_heartbeat.to_rfc3339())
         .bind(format!("{:?}", worker.status).to_lowercase())
+        .bind(serde_json::to_string(&worker.labels)?)
+        .bind(serde_json::to_string(&worker.capabilities)?)
+        .bind(serde_json::to_string(&worker.images)?)
         .execute(&self.pool)
         .await?;
 
         Ok(())
     }
 
+    async fn get_worker(&self, worker_id: Uuid) -> Result<Option<WorkerInfo>> {
+        let row = sqlx::query_as::<_, WorkerRow>("SELECT * FROM workers WHERE id = ?")
+            .bind(worker_id.to_string())
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.map(std::convert::Into::into))
+    }
+
     async fn update_worker_heartbeat(&self, worker_id: Uuid) -> Result<()> {
         sqlx::query("UPDATE workers SET last_heartbeat = ? WHERE id = ?")
             .bind(Utc::now().to_rfc3339())
@@ -346,6 +762,98 @@ impl Database for SqliteDb {
         Ok(result.rows_affected() > 0)
     }
 
+    async fn create_git_credential(&self, credential: &GitCredentialRecord) -> Result<()> {
+        sqlx::query(
+            r"
+            INSERT INTO git_credentials (id, user_id, name, kind, username, encrypted_secret, expires_at, created_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+            ",
+        )
+        .bind(credential.id.to_string())
+        .bind(credential.user_id.to_string())
+        .bind(&credential.name)
+        .bind(credential.kind.to_string())
+        .bind(&credential.username)
+        .bind(&credential.encrypted_secret)
+        .bind(credential.expires_at.map(|t| t.to_rfc3339()))
+        .bind(credential.created_at.to_rfc3339())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_git_credential(&self, credential_id: Uuid) -> Result<Option<GitCredentialRecord>> {
+        let row =
+            sqlx::query_as::<_, GitCredentialRow>("SELECT * FROM git_credentials WHERE id = ?")
+                .bind(credential_id.to_string())
+                .fetch_optional(&self.pool)
+                .await?;
+
+        Ok(row.map(std::convert::Into::into))
+    }
+
+    async fn list_git_credentials(&self, user_id: Uuid) -> Result<Vec<GitCredentialRecord>> {
+        let rows = sqlx::query_as::<_, GitCredentialRow>(
+            "SELECT * FROM git_credentials WHERE user_id = ? ORDER BY created_at",
+        )
+        .bind(user_id.to_string())
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(std::convert::Into::into).collect())
+    }
+
+    async fn delete_git_credential(&self, user_id: Uuid, credential_id: Uuid) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM git_credentials WHERE id = ? AND user_id = ?")
+            .bind(credential_id.to_string())
+            .bind(user_id.to_string())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn set_secret(&self, secret: &SecretRecord) -> Result<()> {
+        sqlx::query(
+            r"
+            INSERT INTO secrets (user_id, name, encrypted_value, created_at, updated_at)
+            VALUES (?, ?, ?, ?, ?)
+            ON CONFLICT (user_id, name)
+            DO UPDATE SET encrypted_value = excluded.encrypted_value, updated_at = excluded.updated_at
+            ",
+        )
+        .bind(secret.user_id.to_string())
+        .bind(&secret.name)
+        .bind(&secret.encrypted_value)
+        .bind(secret.created_at.to_rfc3339())
+        .bind(secret.updated_at.to_rfc3339())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn list_secrets(&self, user_id: Uuid) -> Result<Vec<SecretRecord>> {
+        let rows =
+            sqlx::query_as::<_, SecretRow>("SELECT * FROM secrets WHERE user_id = ? ORDER BY name")
+                .bind(user_id.to_string())
+                .fetch_all(&self.pool)
+                .await?;
+
+        Ok(rows.into_iter().map(std::convert::Into::into).collect())
+    }
+
+    async fn delete_secret(&self, user_id: Uuid, name: &str) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM secrets WHERE user_id = ? AND name = ?")
+            .bind(user_id.to_string())
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
     async fn verify_user(&self, email: &str, password: &str) -> Result<Option<Uuid>> {
         // Fetch the stored hash for the user
         let result: Option<(String, String)> =
@@ -399,6 +907,30 @@ struct JobRow {
     completed_at: Option<String>,
     exit_code: Option<i32>,
     build_minutes: Option<f64>,
+    lease_expires_at: Option<String>,
+    status_reason: Option<String>,
+    git_ref: Option<String>,
+    commit_sha: Option<String>,
+    submodules: bool,
+    lfs: bool,
+    resolved_sha: Option<String>,
+    git_credential_id: Option<String>,
+    env: String,
+    secrets: String,
+    steps: String,
+    step_results: String,
+    needs: String,
+    run_id: Option<String>,
+    matrix: String,
+    runs_on: String,
+    image: Option<String>,
+    timeout_minutes: Option<u32>,
+    failure_reason: Option<String>,
+    infra_retries: u32,
+    attempt: u32,
+    parent_job_id: Option<String>,
+    retry: Option<String>,
+    not_before: Option<String>,
 }
 
 impl From<JobRow> for Job {
@@ -413,13 +945,7 @@ impl From<JobRow> for Job {
             source_url: row.source_url,
             command: row.command,
             script: row.script,
-            status: match row.status.as_str() {
-                "running" => JobStatus::Running,
-                "completed" => JobStatus::Completed,
-                "failed" => JobStatus::Failed,
-                "cancelled" => JobStatus::Cancelled,
-                _ => JobStatus::Pending,
-            },
+            status: row.status.parse().unwrap_or(JobStatus::Pending),
             worker_id: row.worker_id.and_then(|s| Uuid::parse_str(&s).ok()),
             created_at: chrono::DateTime::parse_from_rfc3339(&row.created_at)
                 .map_or_else(|_| Utc::now(), |dt| dt.with_timezone(&Utc)),
@@ -435,6 +961,91 @@ impl From<JobRow> for Job {
             }),
             exit_code: row.exit_code,
             build_minutes: row.build_minutes,
+            lease_expires_at: row.lease_expires_at.and_then(|s| {
+                chrono::DateTime::parse_from_rfc3339(&s)
+                    .map(|dt| dt.with_timezone(&Utc))
+                    .ok()
+            }),
+            status_reason: row.status_reason,
+            git_ref: row.git_ref,
+            commit_sha: row.commit_sha,
+            submodules: row.submodules,
+            lfs: row.lfs,
+            resolved_sha: row.resolved_sha,
+            git_credential_id: row.git_credential_id.and_then(|s| Uuid::parse_str(&s).ok()),
+            env: serde_json::from_str(&row.env).unwrap_or_default(),
+            secrets: serde_json::from_str(&row.secrets).unwrap_or_default(),
+            steps: serde_json::from_str(&row.steps).unwrap_or_default(),
+            step_results: serde_json::from_str(&row.step_results).unwrap_or_default(),
+            needs: serde_json::from_str(&row.needs).unwrap_or_default(),
+            run_id: row.run_id.and_then(|s| Uuid::parse_str(&s).ok()),
+            matrix: serde_json::from_str(&row.matrix).unwrap_or_default(),
+            runs_on: serde_json::from_str(&row.runs_on).unwrap_or_default(),
+            image: row.image,
+            timeout_minutes: row.timeout_minutes,
+            failure_reason: row.failure_reason.and_then(|reason| reason.parse().ok()),
+            infra_retries: row.infra_retries,
+            attempt: row.attempt,
+            parent_job_id: row.parent_job_id.and_then(|id| Uuid::parse_str(&id).ok()),
+            retry: row
+                .retry
+                .and_then(|retry| serde_json::from_str(&retry).ok()),
+            not_before: row.not_before.and_then(|s| {
+                chrono::DateTime::parse_from_rfc3339(&s)
+                    .map(|dt| dt.with_timezone(&Utc))
+                    .ok()
+            }),
+            attempts: Vec::new(),
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct RunRow {
+    id: String,
+    customer_id: String,
+    created_at: String,
+    matrix: String,
+}
+
+impl From<RunRow> for Run {
+    fn from(row: RunRow) -> Self {
+        Self {
+            id: Uuid::parse_str(&row.id).unwrap_or_default(),
+            customer_id: Uuid::parse_str(&row.customer_id).unwrap_or_default(),
+            created_at: chrono::DateTime::parse_from_rfc3339(&row.created_at)
+                .map_or_else(|_| Utc::now(), |dt| dt.with_timezone(&Utc)),
+            matrix: serde_json::from_str(&row.matrix).unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct WorkerRow {
+    id: String,
+    hostname: String,
+    capacity: i64,
+    current_jobs: i64,
+    last_heartbeat: String,
+    status: String,
+    labels: String,
+    capabilities: String,
+    images: String,
+}
+
+impl From<WorkerRow> for WorkerInfo {
+    fn from(row: WorkerRow) -> Self {
+        Self {
+            id: Uuid::parse_str(&row.id).unwrap_or_default(),
+            hostname: row.hostname,
+            capacity: u32::try_from(row.capacity).unwrap_or_default(),
+            current_jobs: u32::try_from(row.current_jobs).unwrap_or_default(),
+            last_heartbeat: chrono::DateTime::parse_from_rfc3339(&row.last_heartbeat)
+                .map_or_else(|_| Utc::now(), |dt| dt.with_timezone(&Utc)),
+            status: row.status.parse().unwrap_or(WorkerStatus::Online),
+            labels: serde_json::from_str(&row.labels).unwrap_or_default(),
+            capabilities: serde_json::from_str(&row.capabilities).unwrap_or_default(),
+            images: serde_json::from_str(&row.images).unwrap_or_default(),
         }
     }
 }
@@ -491,6 +1102,61 @@ impl From<ApiKeyRow> for ApiKeyRecord {
     }
 }
 
+#[derive(sqlx::FromRow)]
+struct GitCredentialRow {
+    id: String,
+    user_id: String,
+    name: String,
+    kind: String,
+    username: Option<String>,
+    encrypted_secret: String,
+    expires_at: Option<String>,
+    created_at: String,
+}
+
+impl From<GitCredentialRow> for GitCredentialRecord {
+    fn from(row: GitCredentialRow) -> Self {
+        Self {
+            id: Uuid::parse_str(&row.id).unwrap_or_default(),
+            user_id: Uuid::parse_str(&row.user_id).unwrap_or_default(),
+            name: row.name,
+            kind: row.kind.parse().unwrap_or(GitCredentialKind::HttpsToken),
+            username: row.username,
+            encrypted_secret: row.encrypted_secret,
+            expires_at: row.expires_at.and_then(|s| {
+                chrono::DateTime::parse_from_rfc3339(&s)
+                    .map(|dt| dt.with_timezone(&Utc))
+                    .ok()
+            }),
+            created_at: chrono::DateTime::parse_from_rfc3339(&row.created_at)
+                .map_or_else(|_| Utc::now(), |dt| dt.with_timezone(&Utc)),
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct SecretRow {
+    user_id: String,
+    name: String,
+    encrypted_value: String,
+    created_at: String,
+    updated_at: String,
+}
+
+impl From<SecretRow> for SecretRecord {
+    fn from(row: SecretRow) -> Self {
+        Self {
+            user_id: Uuid::parse_str(&row.user_id).unwrap_or_default(),
+            name: row.name,
+            encrypted_value: row.encrypted_value,
+            created_at: chrono::DateTime::parse_from_rfc3339(&row.created_at)
+                .map_or_else(|_| Utc::now(), |dt| dt.with_timezone(&Utc)),
+            updated_at: chrono::DateTime::parse_from_rfc3339(&row.updated_at)
+                .map_or_else(|_| Utc::now(), |dt| dt.with_timezone(&Utc)),
+        }
+    }
+}
+
 #[derive(sqlx::FromRow)]
 struct ApiKeyInfoRow {
     id: String,
@@ -514,3 +1180,556 @@ impl From<ApiKeyInfoRow> for ApiKeyInfo {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::{Step, StepStatus};
+
+    async fn test_db() -> SqliteDb {
+        let path = std::env::temp_dir().join(format!("alloy-test-{}.db", Uuid::new_v4()));
+        SqliteDb::new(path.to_str().unwrap()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_job_status_round_trip() {
+        let db = test_db().await;
+        let mut job = Job::with_command(Uuid::nil(), "make".to_string(), SourceType::Upload, None);
+        job.status = JobStatus::Uploading;
+        db.create_job(&job).await.unwrap();
+
+        let stored = db.get_job(job.id).await.unwrap().unwrap();
+        assert_eq!(stored.status, JobStatus::Uploading);
+
+        db.update_job_status(job.id, JobStatus::Pending)
+            .await
+            .unwrap();
+        let pending = db.list_jobs(Some("pending"), None, 10).await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].id, job.id);
+        assert!(db
+            .list_jobs(Some("running"), None, 10)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_git_revision_round_trip() {
+        let db = test_db().await;
+        let mut job = Job::with_command(Uuid::nil(), "make".to_string(), SourceType::Git, None);
+        job.git_ref = Some("release/1.0".to_string());
+        job.commit_sha = Some("0123abc".to_string());
+        job.submodules = true;
+        db.create_job(&job).await.unwrap();
+
+        let sha = "0123abcdef0123abcdef0123abcdef0123abcdef";
+        db.set_resolved_sha(job.id, sha).await.unwrap();
+
+        let stored = db.get_job(job.id).await.unwrap().unwrap();
+        assert_eq!(stored.git_ref.as_deref(), Some("release/1.0"));
+        assert_eq!(stored.commit_sha.as_deref(), Some("0123abc"));
+        assert!(stored.submodules);
+        assert!(!stored.lfs);
+        assert_eq!(stored.resolved_sha.as_deref(), Some(sha));
+    }
+
+    #[tokio::test]
+    async fn test_job_needs() {
+        let db = test_db().await;
+        let worker_id = Uuid::new_v4();
+        let lease = Utc::now() + chrono::Duration::minutes(1);
+        let new_job = |needs: &[Uuid]| {
+            let mut job = Job::with_command(Uuid::nil(), "make".to_string(), SourceType::Git, None);
+            job.needs = needs.to_vec();
+            job
+        };
+
+        let build = new_job(&[]);
+        let test = new_job(&[build.id]);
+        db.create_job(&build).await.unwrap();
+        db.create_job(&test).await.unwrap();
+        assert_eq!(
+            db.get_job(test.id).await.unwrap().unwrap().needs,
+            [build.id]
+        );
+
+        // Waits until what it needs has completed
+        let claimed = db
+            .claim_pending_job(worker_id, &[], &[], lease)
+            .await
+            .unwrap();
+        assert_eq!(claimed.unwrap().id, build.id);
+        assert!(db
+            .claim_pending_job(worker_id, &[], &[], lease)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(db
+            .complete_job(build.id, worker_id, JobStatus::Completed, None, 0, 1.0)
+            .await
+            .unwrap());
+        let claimed = db
+            .claim_pending_job(worker_id, &[], &[], lease)
+            .await
+            .unwrap();
+        assert_eq!(claimed.unwrap().id, test.id);
+
+        // Cancels one level of dependents at a time
+        let package = new_job(&[test.id]);
+        let deploy = new_job(&[package.id]);
+        db.create_job(&package).await.unwrap();
+        db.create_job(&deploy).await.unwrap();
+        let cancelled = db.cancel_dependents(test.id, "test failed").await.unwrap();
+        assert_eq!(cancelled, [package.id]);
+        let stored = db.get_job(package.id).await.unwrap().unwrap();
+        assert_eq!(stored.status, JobStatus::Cancelled);
+        assert_eq!(stored.status_reason.as_deref(), Some("test failed"));
+        assert_eq!(
+            db.get_job(deploy.id).await.unwrap().unwrap().status,
+            JobStatus::Pending
+        );
+        assert!(db
+            .claim_pending_job(worker_id, &[], &[], lease)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_runs() {
+        let db = test_db().await;
+        let template = Job::with_command(Uuid::nil(), "make".to_string(), SourceType::Git, None);
+        let matrix = [(
+            "XCODE".to_string(),
+            vec!["15.4".to_string(), "16.0".to_string()],
+        )]
+        .into();
+        let (run, jobs) = crate::matrix::expand(&template, &matrix);
+
+        db.create_run(&run, &jobs).await.unwrap();
+        db.create_job(&template).await.unwrap_err(); // Same ID as the first cell
+        let other = Job::with_command(Uuid::nil(), "make".to_string(), SourceType::Git, None);
+        db.create_job(&other).await.unwrap();
+
+        // A run is created with all of its jobs or not at all
+        let (failed_run, mut failed_jobs) = crate::matrix::expand(
+            &Job::with_command(Uuid::nil(), "make".to_string(), SourceType::Git, None),
+            &matrix,
+        );
+        failed_jobs[1].id = other.id;
+        db.create_run(&failed_run, &failed_jobs).await.unwrap_err();
+        assert!(db.get_run(failed_run.id).await.unwrap().is_none());
+        assert!(db.get_job(failed_jobs[0].id).await.unwrap().is_none());
+
+        let stored = db.get_run(run.id).await.unwrap().unwrap();
+        assert_eq!(stored.matrix, matrix);
+        assert!(db.get_run(Uuid::new_v4()).await.unwrap().is_none());
+
+        // Filtered by run, alone or with a status
+        let listed = db.list_jobs(None, Some(run.id), 10).await.unwrap();
+        assert_eq!(listed.len(), 2);
+        assert!(listed.iter().all(|job| job.run_id == Some(run.id)));
+        assert_eq!(db.list_jobs(None, None, 10).await.unwrap().len(), 3);
+        assert_eq!(
+            db.list_jobs(Some("pending"), Some(run.id), 10)
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+        assert!(db
+            .list_jobs(Some("running"), Some(run.id), 10)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let cell = db.get_job(jobs[1].id).await.unwrap().unwrap();
+        assert_eq!(cell.matrix["XCODE"], "16.0");
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_steps_round_trip() {
+        let db = test_db().await;
+        let step = Step {
+            name: "Build".to_string(),
+            run: "make".to_string(),
+            timeout_minutes: Some(30),
+            env: [("CONFIGURATION".to_string(), "Release".to_string())].into(),
+            continue_on_error: true,
+            artifacts: vec!["build/*.ipa".to_string()],
+        };
+        let job = Job::with_steps(Uuid::nil(), vec![step.clone()], SourceType::Git, None);
+        db.create_job(&job).await.unwrap();
+
+        let results = [StepResult {
+            name: "Build".to_string(),
+            status: StepStatus::TimedOut,
+            exit_code: None,
+            started_at: Some(Utc::now()),
+            duration_secs: 1800.0,
+        }];
+        db.set_step_results(job.id, &results).await.unwrap();
+
+        let stored = db.get_job(job.id).await.unwrap().unwrap();
+        assert_eq!(stored.steps, [step]);
+        assert_eq!(stored.step_results.len(), 1);
+        assert_eq!(stored.step_results[0].status, StepStatus::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn test_job_leases() {
+        let db = test_db().await;
+        let job = Job::with_command(Uuid::nil(), "make".to_string(), SourceType::Git, None);
+        db.create_job(&job).await.unwrap();
+
+        // Claim with a lease that is already over, as if the worker died
+        let dead_worker = Uuid::new_v4();
+        let past = Utc::now() - chrono::Duration::seconds(1);
+        let claimed = db
+            .claim_pending_job(dead_worker, &[], &[], past)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(claimed.id, job.id);
+        assert!(claimed.lease_expires_at.is_some());
+
+        let expired = db.list_expired_jobs(Utc::now()).await.unwrap();
+        assert_eq!(expired.len(), 1);
+        assert!(db
+            .reclaim_job(job.id, JobStatus::Pending, "worker went offline")
+            .await
+            .unwrap());
+
+        let requeued = db.get_job(job.id).await.unwrap().unwrap();
+        assert_eq!(requeued.status, JobStatus::Pending);
+        assert_eq!(requeued.worker_id, None);
+        assert_eq!(
+            requeued.status_reason.as_deref(),
+            Some("worker went offline")
+        );
+        assert_eq!(requeued.infra_retries, 1);
+
+        // The dead worker can no longer report a result for the job
+        assert!(!db
+            .complete_job(job.id, dead_worker, JobStatus::Completed, None, 0, 1.0)
+            .await
+            .unwrap());
+
+        // A live worker renews its lease, so the job is left alone
+        let worker = Uuid::new_v4();
+        db.claim_pending_job(worker, &[], &[], past)
+            .await
+            .unwrap()
+            .unwrap();
+        db.renew_job_leases(worker, &[job.id], Utc::now() + chrono::Duration::minutes(1))
+            .await
+            .unwrap();
+        assert!(db.list_expired_jobs(Utc::now()).await.unwrap().is_empty());
+        assert!(!db
+            .reclaim_job(job.id, JobStatus::Failed, "lease expired")
+            .await
+            .unwrap());
+
+        assert!(db
+            .complete_job(job.id, worker, JobStatus::Completed, None, 0, 1.0)
+            .await
+            .unwrap());
+        let completed = db.get_job(job.id).await.unwrap().unwrap();
+        assert_eq!(completed.status, JobStatus::Completed);
+        assert_eq!(completed.lease_expires_at, None);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_claims() {
+        crate::db::assert_exclusive_claims(std::sync::Arc::new(test_db().await)).await;
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_job_result() {
+        let db = test_db().await;
+        let job = Job::with_command(Uuid::nil(), "make".to_string(), SourceType::Git, None);
+        db.create_job(&job).await.unwrap();
+
+        let worker = Uuid::new_v4();
+        let lease = Utc::now() + chrono::Duration::minutes(1);
+        db.claim_pending_job(worker, &[], &[], lease)
+            .await
+            .unwrap()
+            .unwrap();
+        db.update_job_status(job.id, JobStatus::Cancelled)
+            .await
+            .unwrap();
+
+        // A build that finished before the worker heard about the cancellation
+        assert!(!db
+            .complete_job(job.id, worker, JobStatus::Completed, None, 0, 1.0)
+            .await
+            .unwrap());
+
+        // The worker's cancelled result is recorded and the status preserved
+        assert!(db
+            .complete_job(
+                job.id,
+                worker,
+                JobStatus::Cancelled,
+                Some(FailureReason::Cancelled),
+                -1,
+                0.5
+            )
+            .await
+            .unwrap());
+        let cancelled = db.get_job(job.id).await.unwrap().unwrap();
+        assert_eq!(cancelled.status, JobStatus::Cancelled);
+        assert_eq!(cancelled.build_minutes, Some(0.5));
+        assert_eq!(cancelled.failure_reason, Some(FailureReason::Cancelled));
+        assert!(cancelled.completed_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_infra_requeue() {
+        let db = test_db().await;
+        let job = Job::with_command(Uuid::nil(), "make".to_string(), SourceType::Git, None);
+        db.create_job(&job).await.unwrap();
+
+        let (broken, healthy) = (Uuid::new_v4(), Uuid::new_v4());
+        let lease = Utc::now() + chrono::Duration::minutes(1);
+        let avoid_until = Utc::now() + chrono::Duration::minutes(1);
+        db.claim_pending_job(broken, &[], &[], lease)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(db
+            .requeue_job(job.id, broken, 1, avoid_until, "VM failed")
+            .await
+            .unwrap());
+
+        let requeued = db.get_job(job.id).await.unwrap().unwrap();
+        assert_eq!(requeued.status, JobStatus::Pending);
+        assert_eq!(requeued.worker_id, None);
+        assert_eq!(requeued.infra_retries, 1);
+        assert_eq!(requeued.status_reason.as_deref(), Some("VM failed"));
+
+        // The worker it failed on leaves it to others for a while
+        assert!(db
+            .claim_pending_job(broken, &[], &[], lease)
+            .await
+            .unwrap()
+            .is_none());
+        let claimed = db
+            .claim_pending_job(healthy, &[], &[], lease)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(claimed.id, job.id);
+
+        // Out of retries, the job fails
+        assert!(!db
+            .requeue_job(job.id, healthy, 1, avoid_until, "VM failed")
+            .await
+            .unwrap());
+        assert!(db
+            .complete_job(
+                job.id,
+                healthy,
+                JobStatus::Failed,
+                Some(FailureReason::Infra),
+                -1,
+                0.1
+            )
+            .await
+            .unwrap());
+        let failed = db.get_job(job.id).await.unwrap().unwrap();
+        assert_eq!(failed.status, JobStatus::Failed);
+        assert_eq!(failed.failure_reason, Some(FailureReason::Infra));
+
+        // Once the hold is over, the worker may take the job back
+        let job = Job::with_command(Uuid::nil(), "make".to_string(), SourceType::Git, None);
+        db.create_job(&job).await.unwrap();
+        db.claim_pending_job(broken, &[], &[], lease)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(db
+            .requeue_job(job.id, broken, 1, Utc::now(), "VM failed")
+            .await
+            .unwrap());
+        let claimed = db
+            .claim_pending_job(broken, &[], &[], lease)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(claimed.id, job.id);
+    }
+
+    #[tokio::test]
+    async fn test_release_job() {
+        let db = test_db().await;
+        let job = Job::with_command(Uuid::nil(), "make".to_string(), SourceType::Git, None);
+        db.create_job(&job).await.unwrap();
+
+        let (leaving, other) = (Uuid::new_v4(), Uuid::new_v4());
+        let lease = Utc::now() + chrono::Duration::minutes(1);
+        db.claim_pending_job(leaving, &[], &[], lease)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!db.release_job(job.id, other, "Handed back").await.unwrap());
+        assert!(db
+            .release_job(job.id, leaving, "Handed back")
+            .await
+            .unwrap());
+
+        let released = db.get_job(job.id).await.unwrap().unwrap();
+        assert_eq!(released.status, JobStatus::Pending);
+        assert_eq!(released.worker_id, None);
+        assert_eq!(released.infra_retries, 0);
+        assert_eq!(released.status_reason.as_deref(), Some("Handed back"));
+        assert!(!db
+            .release_job(job.id, leaving, "Handed back")
+            .await
+            .unwrap());
+
+        // Nothing holds the worker back from the job
+        let claimed = db
+            .claim_pending_job(leaving, &[], &[], lease)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(claimed.id, job.id);
+    }
+
+    #[tokio::test]
+    async fn test_retry_attempts() {
+        let db = test_db().await;
+        let lease = Utc::now() + chrono::Duration::minutes(1);
+        let worker = Uuid::new_v4();
+        let job = Job::with_command(Uuid::nil(), "make".to_string(), SourceType::Git, None);
+        db.create_job(&job).await.unwrap();
+        let mut dependent =
+            Job::with_command(Uuid::nil(), "deploy".to_string(), SourceType::Git, None);
+        dependent.needs = vec![job.id];
+        db.create_job(&dependent).await.unwrap();
+        assert!(db.get_retry(job.id).await.unwrap().is_none());
+
+        // A retry held back for its backoff is not claimed yet
+        let mut retry = Job::with_command(Uuid::nil(), "make".to_string(), SourceType::Git, None);
+        retry.attempt = 2;
+        retry.parent_job_id = Some(job.id);
+        retry.not_before = Some(Utc::now() + chrono::Duration::minutes(5));
+        db.create_job(&retry).await.unwrap();
+        db.claim_pending_job(worker, &[], &[], lease)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(db
+            .claim_pending_job(worker, &[], &[], lease)
+            .await
+            .unwrap()
+            .is_none());
+
+        let found = db.get_retry(job.id).await.unwrap().unwrap();
+        assert_eq!(found.id, retry.id);
+        assert_eq!(found.attempt, 2);
+        assert_eq!(found.parent_job_id, Some(job.id));
+
+        // Jobs that needed the failed attempt need its retry instead
+        db.repoint_needs(job.id, retry.id).await.unwrap();
+        let dependent = db.get_job(dependent.id).await.unwrap().unwrap();
+        assert_eq!(dependent.needs, vec![retry.id]);
+    }
+
+    #[tokio::test]
+    async fn test_get_worker() {
+        let db = test_db().await;
+        let worker = WorkerInfo {
+            id: Uuid::new_v4(),
+            hostname: "mac-mini-1".to_string(),
+            capacity: 2,
+            current_jobs: 0,
+            last_heartbeat: Utc::now(),
+            status: WorkerStatus::Online,
+            labels: vec!["signing".to_string()],
+            capabilities: shared::WorkerCapabilities {
+                xcode_versions: vec!["16.0".to_string()],
+                ..Default::default()
+            },
+            images: vec!["sonoma".to_string()],
+        };
+        db.register_worker(&worker).await.unwrap();
+        db.update_worker_status(worker.id, WorkerStatus::Draining)
+            .await
+            .unwrap();
+
+        let stored = db.get_worker(worker.id).await.unwrap().unwrap();
+        assert_eq!(stored.hostname, "mac-mini-1");
+        assert_eq!(stored.capacity, 2);
+        assert_eq!(stored.status, WorkerStatus::Draining);
+        assert_eq!(stored.labels, worker.labels);
+        assert_eq!(stored.capabilities, worker.capabilities);
+        assert_eq!(stored.images, worker.images);
+        assert!(db.get_worker(Uuid::new_v4()).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_claim_by_labels_and_image() {
+        let db = test_db().await;
+        let lease = Utc::now() + chrono::Duration::minutes(5);
+        let labels =
+            |labels: &[&str]| -> Vec<String> { labels.iter().map(ToString::to_string).collect() };
+
+        let mut m2 = Job::with_command(Uuid::new_v4(), "make".to_string(), SourceType::Git, None);
+        m2.runs_on = labels(&["m2", "xcode-16"]);
+        let mut any = m2.clone();
+        any.id = Uuid::new_v4();
+        any.runs_on = Vec::new();
+        any.created_at += chrono::Duration::seconds(1);
+        db.create_job(&m2).await.unwrap();
+        db.create_job(&any).await.unwrap();
+        assert_eq!(
+            db.get_job(m2.id).await.unwrap().unwrap().runs_on,
+            m2.runs_on
+        );
+
+        // A worker lacking a label skips the job for a later one it can run
+        let claimed = db
+            .claim_pending_job(Uuid::new_v4(), &labels(&["m2", "xcode-15"]), &[], lease)
+            .await
+            .unwrap();
+        assert_eq!(claimed.unwrap().id, any.id);
+        assert!(db
+            .claim_pending_job(Uuid::new_v4(), &[], &[], lease)
+            .await
+            .unwrap()
+            .is_none());
+
+        let claimed = db
+            .claim_pending_job(
+                Uuid::new_v4(),
+                &labels(&["arm64", "m2", "xcode-16"]),
+                &[],
+                lease,
+            )
+            .await
+            .unwrap();
+        assert_eq!(claimed.unwrap().id, m2.id);
+
+        // Jobs naming an image only go to workers that have it
+        let mut sequoia =
+            Job::with_command(Uuid::new_v4(), "make".to_string(), SourceType::Git, None);
+        sequoia.image = Some("sequoia".to_string());
+        db.create_job(&sequoia).await.unwrap();
+        assert!(db
+            .claim_pending_job(Uuid::new_v4(), &[], &labels(&["sonoma"]), lease)
+            .await
+            .unwrap()
+            .is_none());
+        let claimed = db
+            .claim_pending_job(Uuid::new_v4(), &[], &labels(&["sonoma", "sequoia"]), lease)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(claimed.id, sequoia.id);
+        assert_eq!(claimed.image.as_deref(), Some("sequoia"));
+    }
+}
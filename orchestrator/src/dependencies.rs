@@ -0,0 +1,69 @@
+//! Jobs that need other jobs
+//!
+//! A job with `needs` stays pending until every job it needs has completed
+//! (see `Database::claim_pending_job`). When one of them fails or is
+//! cancelled instead, the job can never run, so it is cancelled too, and so
+//! on down the graph.
+
+use chrono::Utc;
+use uuid::Uuid;
+
+use crate::state::AppState;
+use shared::{JobStatus, LogEntry, LogStream};
+
+/// Cancel every job waiting on `job_id`, then the jobs waiting on those
+pub async fn cancel_dependents(state: &AppState, job_id: Uuid) {
+    let mut upstream = vec![job_id];
+
+    while let Some(job_id) = upstream.pop() {
+        let reason = format!("Needed job {job_id} did not complete");
+        let cancelled = match state.db.cancel_dependents(job_id, &reason).await {
+            Ok(cancelled) => cancelled,
+            Err(e) => {
+                tracing::error!(job_id = %job_id, "Failed to cancel dependent jobs: {}", e);
+                continue;
+            },
+        };
+
+        for dependent in cancelled {
+            tracing::info!(job_id = %dependent, needed_job_id = %job_id, "Dependent job cancelled");
+
+            // Nobody else will close the stream of a job that never started
+            if let Some(tx) = state.get_log_stream(dependent).await {
+                let entry = LogEntry {
+                    job_id: dependent,
+                    timestamp: Utc::now(),
+                    stream: LogStream::Stderr,
+                    content: reason.clone(),
+                };
+                let _ = tx.send(serde_json::to_string(&entry).unwrap());
+                let message = serde_json::json!({
+                    "type": "job_complete",
+                    "job_id": dependent.to_string(),
+                    "status": format!("{:?}", JobStatus::Cancelled),
+                    "exit_code": -1,
+                    "build_minutes": 0.0,
+                    "artifacts_count": 0,
+                });
+                let _ = tx.send(message.to_string());
+            }
+            state.remove_log_stream(dependent).await;
+
+            upstream.push(dependent);
+        }
+    }
+}
+
+/// Cancel a just-created job if a job it needs stopped short while it was
+/// being created
+pub async fn recheck_needs(state: &AppState, needs: &[Uuid]) {
+    for &needed in needs {
+        match state.db.get_job(needed).await {
+            Ok(Some(job)) if job.status.stopped_short() => {
+                cancel_dependents(state, needed).await;
+            },
+            Ok(_) => {},
+            Err(e) => tracing::warn!(job_id = %needed, "Failed to recheck needed job: {}", e),
+        }
+    }
+}
@@ -7,9 +7,16 @@ mod auth;
 mod config;
 mod crypto;
 pub mod db;
+mod dependencies;
+mod matrix;
+mod pipeline;
+mod reaper;
+mod retries;
 mod routes;
+mod routing;
 mod services;
 mod state;
+mod storage;
 
 use axum::extract::DefaultBodyLimit;
 use axum::http::{HeaderValue, Method};
@@ -81,10 +88,17 @@ async fn main() -> anyhow::Result<()> {
     let config = Config::from_env()?;
 
     tracing::info!("Starting Alloy Orchestrator");
-    tracing::info!("Supabase URL: {}", config.supabase_url);
+    tracing::info!("Database backend: {}", config.database.name());
+    tracing::info!("Storage backend: {}", config.storage.name());
+    if let Some(ref url) = config.supabase_url {
+        tracing::info!("Supabase URL: {}", url);
+    }
 
     // Create application state
-    let state = AppState::new(config.clone());
+    let state = AppState::new(config.clone()).await?;
+
+    // Requeue jobs from workers that stop heartbeating
+    tokio::spawn(reaper::run(state.clone()));
 
     // Log worker auth status
     if config.worker_secret_key.is_some() {
@@ -111,7 +125,7 @@ async fn main() -> anyhow::Result<()> {
     tracing::info!("Rate limiting enabled: 100 requests/minute per client");
 
     // Start server
-    let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
+    let addr = SocketAddr::from(([0, 0, 0, 0], config.port));
     tracing::info!("Listening on {}", addr);
 
     let listener = tokio::net::TcpListener::bind(addr).await?;
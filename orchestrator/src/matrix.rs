@@ -0,0 +1,195 @@
+//! Build matrices
+//!
+//! A job submitted with a matrix (e.g. Xcode version × destination × scheme)
+//! is expanded into one job per combination of axis values, all grouped under
+//! a `Run`. Each job gets its combination (its cell) exported as environment
+//! variables named after the axes.
+
+use std::collections::BTreeMap;
+
+use chrono::Utc;
+use uuid::Uuid;
+
+use shared::{ApiError, Job, Matrix, Run};
+
+/// Most axes a matrix can have
+const MAX_AXES: usize = 8;
+
+/// Most jobs a matrix can expand into
+pub const MAX_CELLS: usize = 64;
+
+/// Check the axes and values of a matrix
+pub fn validate(matrix: &Matrix) -> Result<(), ApiError> {
+    let invalid = |message: String| ApiError::new(message, "validation_error");
+
+    if matrix.len() > MAX_AXES {
+        return Err(invalid(format!(
+            "A matrix can have at most {MAX_AXES} axes"
+        )));
+    }
+
+    let mut cells: usize = 1;
+    for (axis, values) in matrix {
+        if !shared::is_valid_env_name(axis) {
+            return Err(invalid(format!("Invalid matrix axis name: {axis:?}")));
+        }
+        if values.is_empty() {
+            return Err(invalid(format!("Matrix axis {axis} has no values")));
+        }
+        for (i, value) in values.iter().enumerate() {
+            if value.trim().is_empty() || value.contains('\0') {
+                return Err(invalid(format!(
+                    "Matrix axis {axis} has an empty or invalid value"
+                )));
+            }
+            if values[..i].contains(value) {
+                return Err(invalid(format!(
+                    "Matrix axis {axis} lists {value:?} more than once"
+                )));
+            }
+        }
+        cells = cells.saturating_mul(values.len());
+    }
+
+    if cells > MAX_CELLS {
+        return Err(invalid(format!(
+            "A matrix can expand into at most {MAX_CELLS} jobs, not {cells}"
+        )));
+    }
+
+    Ok(())
+}
+
+/// Every combination of axis values, varying the last axis fastest
+pub fn cells(matrix: &Matrix) -> Vec<BTreeMap<String, String>> {
+    let mut cells = vec![BTreeMap::new()];
+    for (axis, values) in matrix {
+        cells = cells
+            .into_iter()
+            .flat_map(|cell| {
+                values.iter().map(move |value| {
+                    let mut cell = cell.clone();
+                    cell.insert(axis.clone(), value.clone());
+                    cell
+                })
+            })
+            .collect();
+    }
+    cells
+}
+
+/// Expand `template` into a run with one job per cell of `matrix`, each
+/// requiring the template's labels with the cell's values filled in
+///
+/// The first job keeps the template's ID, so an upload created for it covers
+/// the whole run.
+pub fn expand(template: &Job, matrix: &Matrix) -> (Run, Vec<Job>) {
+    let run = Run {
+        id: Uuid::new_v4(),
+        customer_id: template.customer_id,
+        created_at: Utc::now(),
+        matrix: matrix.clone(),
+    };
+
+    let jobs = cells(matrix)
+        .into_iter()
+        .enumerate()
+        .map(|(i, cell)| Job {
+            id: if i == 0 { template.id } else { Uuid::new_v4() },
+            run_id: Some(run.id),
+            runs_on: crate::routing::expand_labels(&template.runs_on, &cell),
+            image: template
+                .image
+                .as_ref()
+                .map(|image| crate::routing::expand_label(image, &cell)),
+            matrix: cell,
+            ..template.clone()
+        })
+        .collect();
+
+    (run, jobs)
+}
+
+/// The latest job of each cell of a run, in cell order
+///
+/// Retrying a cell's job adds a job to the run; only the retry counts.
+pub fn latest_per_cell(matrix: &Matrix, mut jobs: Vec<Job>) -> Vec<Job> {
+    let cells = cells(matrix);
+    jobs.sort_by_key(|job| std::cmp::Reverse(job.created_at));
+
+    cells
+        .iter()
+        .filter_map(|cell| {
+            let i = jobs.iter().position(|job| &job.matrix == cell)?;
+            Some(jobs.remove(i))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matrix(axes: &[(&str, &[&str])]) -> Matrix {
+        axes.iter()
+            .map(|(axis, values)| {
+                (
+                    (*axis).to_string(),
+                    values.iter().map(ToString::to_string).collect(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_expand() {
+        let matrix = matrix(&[
+            ("XCODE", &["15.4", "16.0"]),
+            ("SCHEME", &["App", "Widget", "Watch"]),
+        ]);
+        validate(&matrix).unwrap();
+
+        let mut template = Job::with_command(
+            Uuid::new_v4(),
+            "make".to_string(),
+            shared::SourceType::Git,
+            None,
+        );
+        template.runs_on = vec!["xcode-${XCODE}".to_string()];
+        template.image = Some("sequoia-xcode-${XCODE}".to_string());
+        let (run, jobs) = expand(&template, &matrix);
+        assert_eq!(jobs.len(), 6);
+        assert_eq!(jobs[0].id, template.id);
+        assert!(jobs.iter().all(|job| job.run_id == Some(run.id)));
+        assert_eq!(jobs[0].matrix["SCHEME"], "App");
+        assert_eq!(jobs[0].matrix["XCODE"], "15.4");
+        assert_eq!(jobs[1].matrix["XCODE"], "16.0");
+        assert_eq!(jobs[1].runs_on, ["xcode-16.0"]);
+        assert_eq!(jobs[1].image.as_deref(), Some("sequoia-xcode-16.0"));
+        assert_eq!(jobs[2].matrix["SCHEME"], "Widget");
+        assert_eq!(jobs[5].matrix["SCHEME"], "Watch");
+
+        // A retried cell replaces its earlier job
+        let mut retry = jobs[1].clone();
+        retry.id = Uuid::new_v4();
+        retry.created_at += chrono::Duration::seconds(1);
+        let mut all = jobs.clone();
+        all.push(retry.clone());
+        let latest = latest_per_cell(&matrix, all);
+        assert_eq!(latest.len(), 6);
+        assert_eq!(latest[1].id, retry.id);
+        assert_eq!(latest[2].id, jobs[2].id);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(validate(&matrix(&[("xcode-version", &["16.0"])])).is_err());
+        assert!(validate(&matrix(&[("XCODE", &[])])).is_err());
+        assert!(validate(&matrix(&[("XCODE", &["16.0", "16.0"])])).is_err());
+        assert!(validate(&matrix(&[("XCODE", &[" "])])).is_err());
+
+        let values = ["1", "2", "3", "4", "5", "6", "7", "8", "9"];
+        assert!(validate(&matrix(&[("A", &values[..8]), ("B", &values[..8])])).is_ok());
+        assert!(validate(&matrix(&[("A", &values), ("B", &values[..8])])).is_err());
+    }
+}
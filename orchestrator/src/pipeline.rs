@@ -0,0 +1,141 @@
+//! Parsing and validation of `alloy.yml` pipeline files
+
+use std::collections::HashSet;
+
+use shared::{ApiError, Pipeline, Step};
+
+use crate::matrix;
+
+/// Most steps a pipeline can have
+const MAX_STEPS: usize = 50;
+
+/// Longest a step may run for (one day)
+const MAX_STEP_TIMEOUT_MINUTES: u32 = 24 * 60;
+
+/// Longest a step name can be
+const MAX_STEP_NAME_LEN: usize = 100;
+
+/// Parse an `alloy.yml` into the steps a job runs and the matrix it runs across
+pub fn parse(yaml: &str) -> Result<Pipeline, ApiError> {
+    let pipeline: Pipeline = serde_yaml::from_str(yaml)
+        .map_err(|e| ApiError::new(format!("Invalid pipeline: {e}"), "validation_error"))?;
+    validate(&pipeline.steps)?;
+    matrix::validate(&pipeline.matrix)?;
+    Ok(pipeline)
+}
+
+/// Check the steps of a pipeline
+fn validate(steps: &[Step]) -> Result<(), ApiError> {
+    let invalid = |message: String| ApiError::new(message, "validation_error");
+
+    if steps.is_empty() {
+        return Err(invalid("A pipeline needs at least one step".to_string()));
+    }
+    if steps.len() > MAX_STEPS {
+        return Err(invalid(format!(
+            "A pipeline can have at most {MAX_STEPS} steps"
+        )));
+    }
+
+    let mut names = HashSet::new();
+    for step in steps {
+        let name = step.name.as_str();
+        if name.trim().is_empty()
+            || name.len() > MAX_STEP_NAME_LEN
+            || name.chars().any(char::is_control)
+        {
+            return Err(invalid(format!(
+                "Step names must be 1 to {MAX_STEP_NAME_LEN} printable characters: {name:?}"
+            )));
+        }
+        if !names.insert(name) {
+            return Err(invalid(format!("Duplicate step name: {name:?}")));
+        }
+        if step.run.trim().is_empty() || step.run.contains('\0') {
+            return Err(invalid(format!("Step {name:?} has nothing to run")));
+        }
+        if step
+            .timeout_minutes
+            .is_some_and(|minutes| minutes == 0 || minutes > MAX_STEP_TIMEOUT_MINUTES)
+        {
+            return Err(invalid(format!(
+                "Step {name:?}: 'timeout_minutes' must be between 1 and {MAX_STEP_TIMEOUT_MINUTES}"
+            )));
+        }
+        if let Some(glob) = step
+            .artifacts
+            .iter()
+            .find(|glob| !is_valid_artifact_glob(glob))
+        {
+            return Err(invalid(format!(
+                "Step {name:?}: artifact globs must be relative to the workspace: {glob:?}"
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether an artifact glob stays inside the workspace
+fn is_valid_artifact_glob(glob: &str) -> bool {
+    !glob.trim().is_empty()
+        && !glob.starts_with(['/', '~', '-'])
+        && !glob.chars().any(char::is_control)
+        && !glob.split('/').any(|component| component == "..")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let pipeline = parse(
+            "matrix:
+  XCODE: ['15.4', '16.0']
+  DESTINATION:
+    - platform=iOS Simulator,name=iPhone 16
+steps:
+  - name: Build
+    run: xcodebuild build
+    timeout_minutes: 30
+    env:
+      CONFIGURATION: Release
+    artifacts:
+      - build/*.ipa
+  - name: Lint
+    run: |
+      swiftlint
+      echo done
+    continue_on_error: true
+",
+        )
+        .unwrap();
+        assert_eq!(pipeline.matrix["XCODE"], ["15.4", "16.0"]);
+        assert_eq!(pipeline.matrix["DESTINATION"].len(), 1);
+        let steps = pipeline.steps;
+        assert_eq!(steps.len(), 2);
+        assert_eq!(steps[0].timeout_minutes, Some(30));
+        assert_eq!(steps[0].env["CONFIGURATION"], "Release");
+        assert_eq!(steps[0].artifacts, ["build/*.ipa"]);
+        assert!(!steps[0].continue_on_error);
+        assert_eq!(steps[1].run, "swiftlint\necho done\n");
+        assert!(steps[1].continue_on_error);
+
+        // Invalid
+        assert!(parse("steps: []").is_err());
+        assert!(parse("steps:\n  - run: make").is_err()); // No name
+        assert!(parse("steps:\n  - name: a\n    run: make\n    timeout: 5").is_err()); // Typo
+        assert!(parse("steps:\n  - name: a\n    run: make\n  - name: a\n    run: make").is_err());
+        assert!(parse("steps:\n  - name: a\n    run: ' '").is_err());
+        assert!(parse("steps:\n  - name: a\n    run: make\n    timeout_minutes: 0").is_err());
+        assert!(parse("steps:\n  - name: a\n    run: make\n    timeout_minutes: 1441").is_err());
+        assert!(parse("steps:\n  - name: a\n    run: make\n    artifacts: [/etc/*]").is_err());
+        assert!(parse("steps:\n  - name: a\n    run: make\n    artifacts: [../*.ipa]").is_err());
+        assert!(parse("steps:\n  - name: a\n    run: make\n    artifacts: [out/../../x]").is_err());
+        assert!(
+            parse("matrix:\n  xcode-version: ['16.0']\nsteps:\n  - name: a\n    run: make")
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,245 @@
+//! Background reaper for dead workers and expired job leases
+//!
+//! Workers renew the lease on each job they run with every heartbeat. When a
+//! worker crashes or loses its network, its heartbeats stop: the reaper marks
+//! it offline and, once the leases on its jobs run out, requeues or fails them
+//! according to `LEASE_EXPIRY_POLICY`.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use chrono::Utc;
+
+use crate::state::AppState;
+use shared::{JobStatus, WorkerStatus};
+
+/// How often the reaper scans workers and leases
+const REAP_INTERVAL: Duration = Duration::from_secs(15);
+
+/// What to do with a running job whose lease expired
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeaseExpiryPolicy {
+    /// Put the job back in the queue for another worker
+    Requeue,
+    /// Mark the job as failed
+    Fail,
+}
+
+impl std::str::FromStr for LeaseExpiryPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "requeue" => Ok(Self::Requeue),
+            "fail" => Ok(Self::Fail),
+            _ => Err(format!("Unknown lease expiry policy: {s}")),
+        }
+    }
+}
+
+/// Run the reaper until the process exits
+pub async fn run(state: AppState) {
+    let mut interval = tokio::time::interval(REAP_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+
+        mark_silent_workers_offline(&state).await;
+        if let Err(e) = reclaim_expired_jobs(&state).await {
+            tracing::error!("Failed to reclaim expired jobs: {}", e);
+        }
+    }
+}
+
+/// Mark workers that stopped sending heartbeats as offline
+async fn mark_silent_workers_offline(state: &AppState) {
+    let cutoff = Utc::now()
+        - chrono::Duration::seconds(
+            i64::try_from(state.config.worker_timeout_seconds).unwrap_or(i64::MAX),
+        );
+
+    let silent: Vec<_> = {
+        let mut workers = state.workers.write().await;
+        workers
+            .values_mut()
+            .filter(|w| w.status != WorkerStatus::Offline && w.last_heartbeat < cutoff)
+            .map(|w| {
+                w.status = WorkerStatus::Offline;
+                (w.id, w.last_heartbeat)
+            })
+            .collect()
+    };
+
+    for (worker_id, last_heartbeat) in silent {
+        tracing::warn!(
+            worker_id = %worker_id,
+            last_heartbeat = %last_heartbeat,
+            "Worker missed heartbeats, marking offline"
+        );
+        if let Err(e) = state
+            .db
+            .update_worker_status(worker_id, WorkerStatus::Offline)
+            .await
+        {
+            tracing::warn!("Failed to persist offline worker status: {}", e);
+        }
+    }
+}
+
+/// Requeue or fail running jobs whose lease ran out; requeues count against
+/// the job's infrastructure retries, and a job that used them all up is failed
+async fn reclaim_expired_jobs(state: &AppState) -> Result<()> {
+    let limit = state.config.infra_retry_limit;
+    for job in state.db.list_expired_jobs(Utc::now()).await? {
+        let (status, action) = match state.config.lease_expiry_policy {
+            LeaseExpiryPolicy::Requeue if job.infra_retries < limit => (
+                JobStatus::Pending,
+                format!("requeued (retry {} of {limit})", job.infra_retries + 1),
+            ),
+            LeaseExpiryPolicy::Requeue => (
+                JobStatus::Failed,
+                format!("marked failed after {limit} retries"),
+            ),
+            LeaseExpiryPolicy::Fail => (JobStatus::Failed, "marked failed".to_string()),
+        };
+        let worker = job
+            .worker_id
+            .map_or_else(|| "unknown".to_string(), |id| id.to_string());
+        let reason = format!("Lease expired on worker {worker} without a heartbeat; {action}");
+
+        if state.db.reclaim_job(job.id, status, &reason).await? {
+            tracing::warn!(job_id = %job.id, worker_id = %worker, "Job lease expired, {}", action);
+
+            // An infrastructure failure the job's retry policy covers becomes
+            // its next attempt, as when a worker reports one
+            let retry = if status == JobStatus::Pending {
+                None
+            } else {
+                crate::retries::retry_finished(state, job.id).await
+            };
+
+            // Let anyone following the logs know the job is not coming back from that worker
+            if let Some(tx) = state.get_log_stream(job.id).await {
+                let message = serde_json::json!({
+                    "type": "job_reclaimed",
+                    "job_id": job.id.to_string(),
+                    "status": status.to_string(),
+                    "reason": reason,
+                    "retry_job_id": retry,
+                });
+                let _ = tx.send(message.to_string());
+            }
+            if status != JobStatus::Pending {
+                state.remove_log_stream(job.id).await;
+            }
+            // Jobs that need a retried job wait for its retry instead
+            if status == JobStatus::Pending || retry.is_some() {
+                state.wake_workers();
+            } else {
+                crate::dependencies::cancel_dependents(state, job.id).await;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::db::DatabaseBackend;
+    use crate::storage::StorageBackend;
+    use shared::{FailureReason, Job, RetryOn, RetryPolicy, SourceType};
+    use uuid::Uuid;
+
+    async fn test_state(lease_expiry_policy: LeaseExpiryPolicy) -> AppState {
+        let dir = std::env::temp_dir().join(format!("alloy-reaper-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let base_url = "http://localhost:3000".to_string();
+        AppState::new(Config {
+            database: DatabaseBackend::Sqlite {
+                path: dir.join("alloy.db").to_string_lossy().into_owned(),
+            },
+            storage: StorageBackend::Local {
+                path: dir.join("storage").to_string_lossy().into_owned(),
+                base_url: base_url.clone(),
+                signing_key: vec![0; 32],
+            },
+            supabase_url: None,
+            supabase_key: None,
+            stripe_secret_key: None,
+            stripe_webhook_secret: None,
+            stripe_publishable_key: None,
+            port: 3000,
+            base_url,
+            worker_secret_key: None,
+            self_hosted: true,
+            job_lease_seconds: 120,
+            worker_timeout_seconds: 60,
+            lease_expiry_policy,
+            max_job_timeout_minutes: 360,
+            default_job_timeout_minutes: 60,
+            infra_retry_limit: 2,
+            credentials_key: None,
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_expired_jobs_are_retried_by_their_policy() {
+        let state = test_state(LeaseExpiryPolicy::Fail).await;
+        let job = |command: &str| {
+            Job::with_command(Uuid::nil(), command.to_string(), SourceType::Git, None)
+        };
+        let mut build = job("make");
+        build.retry = Some(RetryPolicy {
+            max_attempts: 2,
+            retry_on: vec![RetryOn::Infra],
+            exit_codes: Vec::new(),
+            backoff_seconds: 0,
+        });
+        let mut deploy = job("make deploy");
+        deploy.needs = vec![build.id];
+        state.db.create_job(&build).await.unwrap();
+        state.db.create_job(&deploy).await.unwrap();
+
+        // Its worker dies with the build running
+        let past = Utc::now() - chrono::Duration::seconds(1);
+        let claimed = state
+            .db
+            .claim_pending_job(Uuid::new_v4(), &[], &[], past)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(claimed.id, build.id);
+        reclaim_expired_jobs(&state).await.unwrap();
+
+        let failed = state.db.get_job(build.id).await.unwrap().unwrap();
+        assert_eq!(failed.status, JobStatus::Failed);
+        assert_eq!(failed.failure_reason, Some(FailureReason::Infra));
+        let retry = state.db.get_retry(build.id).await.unwrap().unwrap();
+        assert_eq!(retry.status, JobStatus::Pending);
+        assert_eq!(retry.attempt, 2);
+
+        // The deploy waits for the retry instead of being cancelled
+        let deploy = state.db.get_job(deploy.id).await.unwrap().unwrap();
+        assert_eq!(deploy.status, JobStatus::Pending);
+        assert_eq!(deploy.needs, vec![retry.id]);
+
+        // With its last attempt used up, the job and its dependents are done
+        let past = Utc::now() - chrono::Duration::seconds(1);
+        state
+            .db
+            .claim_pending_job(Uuid::new_v4(), &[], &[], past)
+            .await
+            .unwrap()
+            .unwrap();
+        reclaim_expired_jobs(&state).await.unwrap();
+        assert!(state.db.get_retry(retry.id).await.unwrap().is_none());
+        let deploy = state.db.get_job(deploy.id).await.unwrap().unwrap();
+        assert_eq!(deploy.status, JobStatus::Cancelled);
+    }
+}
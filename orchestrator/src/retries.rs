@@ -0,0 +1,283 @@
+//! Retries
+//!
+//! A retry is a new attempt at a job: a copy of it that points back at the
+//! attempt before through `parent_job_id`. Retries are made by hand
+//! (`POST /api/v1/jobs/:job_id/retry`) or, when a job's `RetryPolicy` covers
+//! the way it failed, as soon as it finishes, held back for the policy's
+//! backoff. Jobs that need the failed attempt are moved over to the retry and
+//! wait for it instead of being cancelled.
+
+use chrono::{Duration, Utc};
+use uuid::Uuid;
+
+use crate::state::AppState;
+use shared::{ApiError, FailureReason, Job, JobAttempt, JobStatus, RetryOn, RetryPolicy};
+
+/// Most attempts a retry policy can allow
+const MAX_ATTEMPTS: u32 = 10;
+
+/// Longest a retry is held back, however many attempts came before it
+const MAX_BACKOFF_SECONDS: u32 = 3600;
+
+/// Check a job's retry policy
+pub fn validate(policy: Option<&RetryPolicy>) -> Result<(), ApiError> {
+    let invalid = |message: String| ApiError::new(message, "validation_error");
+    let Some(policy) = policy else {
+        return Ok(());
+    };
+
+    if !(1..=MAX_ATTEMPTS).contains(&policy.max_attempts) {
+        return Err(invalid(format!(
+            "'retry.max_attempts' must be between 1 and {MAX_ATTEMPTS}"
+        )));
+    }
+    if policy.backoff_seconds > MAX_BACKOFF_SECONDS {
+        return Err(invalid(format!(
+            "'retry.backoff_seconds' can be at most {MAX_BACKOFF_SECONDS}"
+        )));
+    }
+    if !policy.exit_codes.is_empty() && !policy.retry_on.contains(&RetryOn::ExitCodes) {
+        return Err(invalid(
+            "'retry.exit_codes' only applies with 'exit_codes' in 'retry.retry_on'".to_string(),
+        ));
+    }
+    if policy.exit_codes.contains(&0) {
+        return Err(invalid(
+            "'retry.exit_codes' cannot include 0, which is success".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// The attempt after `job`, pending and with nothing of `job`'s run carried over
+pub fn next_attempt(job: &Job) -> Job {
+    Job {
+        id: Uuid::new_v4(),
+        status: JobStatus::Pending,
+        worker_id: None,
+        created_at: Utc::now(),
+        started_at: None,
+        completed_at: None,
+        exit_code: None,
+        build_minutes: None,
+        lease_expires_at: None,
+        status_reason: None,
+        resolved_sha: None,
+        step_results: Vec::new(),
+        failure_reason: None,
+        infra_retries: 0,
+        attempt: job.attempt + 1,
+        parent_job_id: Some(job.id),
+        not_before: None,
+        attempts: Vec::new(),
+        ..job.clone()
+    }
+}
+
+/// Whether `job`'s retry policy covers the way it finished
+fn should_retry(job: &Job) -> bool {
+    let Some(policy) = &job.retry else {
+        return false;
+    };
+    if job.attempt >= policy.max_attempts {
+        return false;
+    }
+
+    match job.failure_reason {
+        Some(FailureReason::Infra) => policy.retry_on.contains(&RetryOn::Infra),
+        Some(FailureReason::Timeout) => policy.retry_on.contains(&RetryOn::Timeout),
+        Some(FailureReason::UserExit) => {
+            policy.retry_on.contains(&RetryOn::ExitCodes)
+                && job.exit_code.is_some_and(|code| {
+                    code != 0 && (policy.exit_codes.is_empty() || policy.exit_codes.contains(&code))
+                })
+        },
+        Some(FailureReason::SourceFetch | FailureReason::Cancelled) | None => false,
+    }
+}
+
+/// How long the retry after attempt `attempt` is held back
+fn backoff(policy: &RetryPolicy, attempt: u32) -> Duration {
+    let seconds = u64::from(policy.backoff_seconds) << attempt.saturating_sub(1).min(32);
+    let seconds = seconds.min(u64::from(MAX_BACKOFF_SECONDS));
+    Duration::seconds(i64::try_from(seconds).unwrap_or(i64::MAX))
+}
+
+/// Retry a just-finished job if its policy covers the way it failed,
+/// returning the new attempt's ID
+pub async fn retry_finished(state: &AppState, job_id: Uuid) -> Option<Uuid> {
+    match try_retry_finished(state, job_id).await {
+        Ok(retry) => retry,
+        Err(e) => {
+            tracing::error!(job_id = %job_id, "Failed to retry job: {}", e);
+            None
+        },
+    }
+}
+
+async fn try_retry_finished(state: &AppState, job_id: Uuid) -> anyhow::Result<Option<Uuid>> {
+    let Some(job) = state.db.get_job(job_id).await? else {
+        return Ok(None);
+    };
+    if !should_retry(&job) {
+        return Ok(None);
+    }
+
+    let mut retry = next_attempt(&job);
+    if let Some(policy) = &job.retry {
+        let wait = backoff(policy, job.attempt);
+        if wait > Duration::zero() {
+            retry.not_before = Some(Utc::now() + wait);
+        }
+    }
+    state.db.create_job(&retry).await?;
+    state.db.repoint_needs(job.id, retry.id).await?;
+    state.create_log_stream(retry.id).await;
+
+    tracing::info!(
+        job_id = %retry.id,
+        parent_job_id = %job.id,
+        attempt = retry.attempt,
+        "Job retried by its retry policy"
+    );
+    Ok(Some(retry.id))
+}
+
+/// Every attempt at the job `job` is one of, oldest first
+pub async fn attempts(state: &AppState, job: &Job) -> anyhow::Result<Vec<JobAttempt>> {
+    let mut earlier = Vec::new();
+    let mut parent = job.parent_job_id;
+    while let Some(id) = parent {
+        let Some(attempt) = state.db.get_job(id).await? else {
+            break;
+        };
+        parent = attempt.parent_job_id;
+        earlier.push(JobAttempt::from(&attempt));
+    }
+    earlier.reverse();
+
+    let mut attempts = earlier;
+    attempts.push(JobAttempt::from(job));
+    let mut latest = job.id;
+    while let Some(retry) = state.db.get_retry(latest).await? {
+        latest = retry.id;
+        attempts.push(JobAttempt::from(&retry));
+    }
+
+    Ok(attempts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(retry_on: &[RetryOn], exit_codes: &[i32]) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            retry_on: retry_on.to_vec(),
+            exit_codes: exit_codes.to_vec(),
+            backoff_seconds: 10,
+        }
+    }
+
+    fn failed(policy: RetryPolicy, reason: FailureReason, exit_code: i32) -> Job {
+        let mut job = Job::with_command(
+            Uuid::new_v4(),
+            "make".to_string(),
+            shared::SourceType::Git,
+            None,
+        );
+        job.status = JobStatus::Failed;
+        job.failure_reason = Some(reason);
+        job.exit_code = Some(exit_code);
+        job.retry = Some(policy);
+        job
+    }
+
+    #[test]
+    fn test_should_retry() {
+        let infra = policy(&[RetryOn::Infra], &[]);
+        assert!(should_retry(&failed(
+            infra.clone(),
+            FailureReason::Infra,
+            -1
+        )));
+        assert!(!should_retry(&failed(
+            infra.clone(),
+            FailureReason::Timeout,
+            -1
+        )));
+        assert!(!should_retry(&failed(infra, FailureReason::UserExit, 1)));
+
+        let codes = policy(&[RetryOn::ExitCodes], &[65]);
+        assert!(should_retry(&failed(
+            codes.clone(),
+            FailureReason::UserExit,
+            65
+        )));
+        assert!(!should_retry(&failed(codes, FailureReason::UserExit, 1)));
+
+        let any_code = policy(&[RetryOn::ExitCodes], &[]);
+        assert!(should_retry(&failed(
+            any_code.clone(),
+            FailureReason::UserExit,
+            1
+        )));
+        assert!(!should_retry(&failed(
+            any_code.clone(),
+            FailureReason::SourceFetch,
+            1
+        )));
+        assert!(!should_retry(&failed(
+            any_code.clone(),
+            FailureReason::Cancelled,
+            1
+        )));
+
+        // The last attempt the policy allows is not retried
+        let mut last = failed(any_code, FailureReason::UserExit, 1);
+        last.attempt = 3;
+        assert!(!should_retry(&last));
+        last.retry = None;
+        last.attempt = 1;
+        assert!(!should_retry(&last));
+    }
+
+    #[test]
+    fn test_next_attempt() {
+        let job = failed(policy(&[RetryOn::Infra], &[]), FailureReason::Infra, -1);
+        let retry = next_attempt(&job);
+        assert_ne!(retry.id, job.id);
+        assert_eq!(retry.status, JobStatus::Pending);
+        assert_eq!(retry.attempt, 2);
+        assert_eq!(retry.parent_job_id, Some(job.id));
+        assert_eq!(retry.command, job.command);
+        assert_eq!(retry.retry, job.retry);
+        assert!(retry.exit_code.is_none() && retry.failure_reason.is_none());
+    }
+
+    #[test]
+    fn test_backoff() {
+        let mut policy = policy(&[RetryOn::Infra], &[]);
+        assert_eq!(backoff(&policy, 1), Duration::seconds(10));
+        assert_eq!(backoff(&policy, 3), Duration::seconds(40));
+        assert_eq!(backoff(&policy, 40), Duration::seconds(3600));
+        policy.backoff_seconds = 0;
+        assert_eq!(backoff(&policy, 2), Duration::zero());
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(validate(None).is_ok());
+        assert!(validate(Some(&policy(&[RetryOn::ExitCodes], &[65]))).is_ok());
+
+        let mut bad = policy(&[RetryOn::Infra], &[]);
+        bad.max_attempts = 0;
+        assert!(validate(Some(&bad)).is_err());
+        bad.max_attempts = 11;
+        assert!(validate(Some(&bad)).is_err());
+        assert!(validate(Some(&policy(&[RetryOn::Infra], &[65]))).is_err());
+        assert!(validate(Some(&policy(&[RetryOn::ExitCodes], &[0]))).is_err());
+    }
+}
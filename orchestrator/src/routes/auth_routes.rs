@@ -9,7 +9,7 @@ use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use crate::auth::{self, AuthUser, CreateApiKeyRequest, CreateApiKeyResponse};
-use crate::services::supabase::ApiKeyInfo;
+use crate::db::ApiKeyInfo;
 use crate::state::AppState;
 use shared::ApiError;
 
@@ -24,7 +24,7 @@ pub async fn create_api_key(
 
     // Store in database
     match state
-        .supabase
+        .db
         .create_api_key(user.user_id, &request.name, &key_hash)
         .await
     {
@@ -55,7 +55,7 @@ pub async fn list_api_keys(
     State(state): State<AppState>,
     user: AuthUser,
 ) -> Result<Json<Vec<ApiKeyInfo>>, (StatusCode, Json<ApiError>)> {
-    match state.supabase.list_api_keys(user.user_id).await {
+    match state.db.list_api_keys(user.user_id).await {
         Ok(keys) => Ok(Json(keys)),
         Err(e) => {
             tracing::error!("Failed to list API keys: {}", e);
@@ -73,7 +73,7 @@ pub async fn delete_api_key(
     user: AuthUser,
     Path(key_id): Path<Uuid>,
 ) -> Result<StatusCode, (StatusCode, Json<ApiError>)> {
-    match state.supabase.delete_api_key(user.user_id, key_id).await {
+    match state.db.delete_api_key(user.user_id, key_id).await {
         Ok(true) => {
             tracing::info!(user_id = %user.user_id, key_id = %key_id, "Deleted API key");
             Ok(StatusCode::NO_CONTENT)
@@ -128,18 +128,18 @@ pub async fn login(
     Json(request): Json<LoginRequest>,
 ) -> Result<Json<LoginResponse>, (StatusCode, Json<ApiError>)> {
     match state
-        .supabase
+        .db
         .verify_user(&request.email, &request.password)
         .await
     {
         Ok(Some(user_id)) => {
             // Generate a simple token (API key format)
-            let (token, _) = auth::generate_api_key();
+            let (token, token_hash) = auth::generate_api_key();
 
             // Store as session (reuse API key table for simplicity)
             let _ = state
-                .supabase
-                .create_api_key(user_id, "session", &token)
+                .db
+                .create_api_key(user_id, "session", &token_hash)
                 .await;
 
             Ok(Json(LoginResponse { token, user_id }))
@@ -181,17 +181,13 @@ pub async fn register(
         )
     })?;
 
-    match state
-        .supabase
-        .create_user(&request.email, &password_hash)
-        .await
-    {
+    match state.db.create_user(&request.email, &password_hash).await {
         Ok(user_id) => {
             // Auto-login after registration
-            let (token, _) = auth::generate_api_key();
+            let (token, token_hash) = auth::generate_api_key();
             let _ = state
-                .supabase
-                .create_api_key(user_id, "session", &token)
+                .db
+                .create_api_key(user_id, "session", &token_hash)
                 .await;
 
             tracing::info!(user_id = %user_id, email = %request.email, "User registered");
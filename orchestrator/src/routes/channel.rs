@@ -0,0 +1,178 @@
+//! Worker push channel
+//!
+//! Instead of polling, a worker keeps a WebSocket open to the orchestrator. It
+//! sends `Claim` when it has a free slot (and `Unclaim` if it stops waiting for
+//! one), its heartbeats and its jobs' log batches up the channel, and is pushed
+//! a job offer as soon as a job it can run is queued, along with cancellations
+//! of its jobs, drain commands and log acknowledgements. A worker that can't
+//! keep one open polls `/api/v1/workers/claim` and posts heartbeats and logs
+//! instead.
+
+use std::time::Duration;
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path, State,
+    },
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures_util::{stream::SplitSink, SinkExt, StreamExt};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::state::AppState;
+use shared::{ApiError, WorkerCommand, WorkerHeartbeat, WorkerMessage};
+
+/// How often a worker waiting for a job checks the queue without being woken,
+/// for jobs that became claimable on their own (a retry's backoff ran out) or
+/// were queued by another orchestrator
+const RECHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// GET `/api/v1/workers/:worker_id/channel` - Open a worker's push channel
+pub async fn worker_channel(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    Path(worker_id): Path<Uuid>,
+) -> Response {
+    // A worker this orchestrator doesn't know registers again over HTTP first
+    if !state.workers.read().await.contains_key(&worker_id) {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(ApiError::new(
+                format!("Worker {worker_id} not found"),
+                "worker_not_found",
+            )),
+        )
+            .into_response();
+    }
+
+    ws.on_upgrade(move |socket| handle_channel(socket, state, worker_id))
+}
+
+async fn handle_channel(socket: WebSocket, state: AppState, worker_id: Uuid) {
+    let (mut sender, mut receiver) = socket.split();
+    let (tx, mut commands) = mpsc::unbounded_channel();
+    state
+        .worker_channels
+        .write()
+        .await
+        .insert(worker_id, tx.clone());
+    tracing::info!(worker_id = %worker_id, "Worker channel opened");
+
+    let mut job_queue = state.job_queue.subscribe();
+    let mut recheck = tokio::time::interval(RECHECK_INTERVAL);
+    let mut wants_job = false;
+
+    loop {
+        let check_queue = tokio::select! {
+            message = receiver.next() => match message {
+                Some(Ok(Message::Text(text))) => match serde_json::from_str(&text) {
+                    Ok(WorkerMessage::Claim) => {
+                        wants_job = true;
+                        true
+                    },
+                    Ok(WorkerMessage::Unclaim) => {
+                        wants_job = false;
+                        false
+                    },
+                    Ok(WorkerMessage::Heartbeat(heartbeat)) => {
+                        answer_heartbeat(&state, worker_id, &heartbeat, &tx).await;
+                        false
+                    },
+                    Ok(WorkerMessage::Logs(batch)) => {
+                        let ack = super::logs::accept_batch(&state, worker_id, batch).await;
+                        let _ = tx.send(WorkerCommand::LogAck(ack));
+                        false
+                    },
+                    Err(e) => {
+                        tracing::warn!(worker_id = %worker_id, "Ignoring malformed channel message: {}", e);
+                        false
+                    },
+                },
+                Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                Some(Ok(_)) => false,
+            },
+            Some(command) = commands.recv() => {
+                if send(&mut sender, &command).await.is_err() {
+                    break;
+                }
+                false
+            },
+            Ok(()) = job_queue.changed(), if wants_job => true,
+            _ = recheck.tick(), if wants_job => true,
+        };
+        if !check_queue {
+            continue;
+        }
+
+        // A job offered to a worker that is gone by now is requeued when its
+        // lease runs out, as when a claim response is lost
+        match super::workers::claim_for_worker(&state, worker_id).await {
+            Ok(Some(claimed)) => {
+                wants_job = false;
+                let offer = WorkerCommand::Offer {
+                    job: Box::new(claimed),
+                };
+                if send(&mut sender, &offer).await.is_err() {
+                    break;
+                }
+            },
+            Ok(None) => {},
+            Err((_, Json(e))) => {
+                tracing::warn!(worker_id = %worker_id, "Failed to claim job for channel: {}", e.error);
+            },
+        }
+    }
+
+    // The worker may have opened a new channel in the meantime
+    let mut channels = state.worker_channels.write().await;
+    if channels
+        .get(&worker_id)
+        .is_some_and(|channel| channel.same_channel(&tx))
+    {
+        channels.remove(&worker_id);
+    }
+    drop(channels);
+    tracing::info!(worker_id = %worker_id, "Worker channel closed");
+}
+
+/// Record a heartbeat sent on the channel, answering with the worker's
+/// cancelled jobs and whether it should drain
+async fn answer_heartbeat(
+    state: &AppState,
+    worker_id: Uuid,
+    heartbeat: &WorkerHeartbeat,
+    tx: &mpsc::UnboundedSender<WorkerCommand>,
+) {
+    if heartbeat.worker_id != worker_id {
+        tracing::warn!(worker_id = %worker_id, "Ignoring heartbeat for another worker");
+        return;
+    }
+
+    match super::workers::record_heartbeat(state, heartbeat).await {
+        Ok(response) => {
+            if !response.cancelled_job_ids.is_empty() {
+                let _ = tx.send(WorkerCommand::Cancel {
+                    job_ids: response.cancelled_job_ids,
+                });
+            }
+            if response.drain {
+                let _ = tx.send(WorkerCommand::Drain);
+            }
+        },
+        Err((_, Json(e))) => {
+            tracing::warn!(worker_id = %worker_id, "Failed to record heartbeat: {}", e.error);
+        },
+    }
+}
+
+async fn send(
+    sender: &mut SplitSink<WebSocket, Message>,
+    command: &WorkerCommand,
+) -> Result<(), axum::Error> {
+    let text = serde_json::to_string(command).expect("worker commands serialize");
+    sender.send(Message::Text(text)).await
+}
@@ -5,15 +5,27 @@ use axum::{
     http::StatusCode,
     Json,
 };
+use futures_util::StreamExt;
 use serde::Deserialize;
+use std::collections::BTreeMap;
 use uuid::Uuid;
 
 use crate::auth::AuthUser;
+use crate::config::Config;
 use crate::state::AppState;
+use crate::storage::{self, SIGNED_URL_TTL};
 use shared::{
-    ApiError, CreateJobRequest, CreateJobResponse, Job, JobStatus, SourceType, UploadUrlResponse,
+    ApiError, CreateJobRequest, CreateJobResponse, Job, JobStatus, Matrix, RetryPolicy, SourceType,
+    UploadUrlResponse, WorkerCommand,
 };
 
+/// Adapt a request body into a storage byte stream
+fn body_stream(body: axum::body::Body) -> storage::ByteStream {
+    body.into_data_stream()
+        .map(|chunk| chunk.map_err(std::io::Error::other))
+        .boxed()
+}
+
 /// Helper to validate artifact filenames
 fn validate_artifact_filename(filename: &str) -> Result<(), ApiError> {
     if filename.trim().is_empty() {
@@ -72,70 +84,357 @@ fn validate_storage_path(path: &str) -> Result<(), ApiError> {
     Ok(())
 }
 
+/// Helper to validate the revision options of a job request
+fn validate_git_revision(request: &CreateJobRequest) -> Result<(), ApiError> {
+    if request.source_type != SourceType::Git
+        && (request.git_ref.is_some()
+            || request.commit_sha.is_some()
+            || request.submodules
+            || request.lfs
+            || request.git_credential_id.is_some())
+    {
+        return Err(ApiError::new(
+            "'git_ref', 'commit_sha', 'submodules', 'lfs' and 'git_credential_id' only apply to git sources",
+            "validation_error",
+        ));
+    }
+
+    if let Some(git_ref) = &request.git_ref {
+        if !shared::is_valid_git_ref(git_ref) {
+            return Err(ApiError::new(
+                format!("Invalid git ref: {git_ref:?}"),
+                "validation_error",
+            ));
+        }
+    }
+
+    if let Some(sha) = &request.commit_sha {
+        if !shared::is_valid_commit_sha(sha) {
+            return Err(ApiError::new(
+                "'commit_sha' must be 7 to 64 hexadecimal characters",
+                "validation_error",
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Helper to validate the environment variables and secrets exported to a job
+fn validate_env(env: &BTreeMap<String, String>, secrets: &[String]) -> Result<(), ApiError> {
+    for name in env.keys().chain(secrets) {
+        if !shared::is_valid_env_name(name) {
+            return Err(ApiError::new(
+                format!("Invalid environment variable name: {name:?}"),
+                "validation_error",
+            ));
+        }
+    }
+
+    if env.values().any(|value| value.contains('\0')) {
+        return Err(ApiError::new(
+            "Environment variables cannot contain NUL characters",
+            "validation_error",
+        ));
+    }
+
+    for (i, name) in secrets.iter().enumerate() {
+        if env.contains_key(name) || secrets[..i].contains(name) {
+            return Err(ApiError::new(
+                format!("{name} is exported more than once"),
+                "validation_error",
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Helper to build a new job running exactly one of a command, a script or
+/// the steps of a pipeline, along with the matrix it is run across (from the
+/// request or the pipeline, not both)
+fn new_job(
+    customer_id: Uuid,
+    command: Option<String>,
+    script: Option<String>,
+    pipeline: Option<&str>,
+    matrix: Matrix,
+    source_type: SourceType,
+    source_url: Option<String>,
+) -> Result<(Job, Matrix), ApiError> {
+    let job = match (command, script, pipeline) {
+        (None, None, Some(pipeline)) => {
+            let pipeline = crate::pipeline::parse(pipeline)?;
+            if !pipeline.matrix.is_empty() && !matrix.is_empty() {
+                return Err(ApiError::new(
+                    "'matrix' cannot be given when the pipeline has one",
+                    "validation_error",
+                ));
+            }
+            let mut job = Job::with_steps(customer_id, pipeline.steps, source_type, source_url);
+            job.runs_on = pipeline.runs_on;
+            job.image = pipeline.image;
+            if !pipeline.matrix.is_empty() {
+                return Ok((job, pipeline.matrix));
+            }
+            job
+        },
+        (_, _, Some(_)) => {
+            return Err(ApiError::new(
+                "'pipeline' cannot be combined with 'command' or 'script'",
+                "validation_error",
+            ))
+        },
+        (_, Some(script), None) => Job::with_script(customer_id, script, source_type, source_url),
+        (Some(command), None, None) => {
+            Job::with_command(customer_id, command, source_type, source_url)
+        },
+        (None, None, None) => {
+            return Err(ApiError::new(
+                "Either 'command', 'script' or 'pipeline' is required",
+                "validation_error",
+            ))
+        },
+    };
+
+    crate::matrix::validate(&matrix)?;
+    Ok((job, matrix))
+}
+
+/// Helper to validate the environment of a job and of each of its steps,
+/// which must not set the matrix axes
+fn validate_job_env(job: &Job, matrix: &Matrix) -> Result<(), ApiError> {
+    validate_env(&job.env, &job.secrets)?;
+    for step in &job.steps {
+        validate_env(&step.env, &job.secrets)?;
+    }
+
+    for axis in matrix.keys() {
+        if job.env.contains_key(axis)
+            || job.secrets.contains(axis)
+            || job.steps.iter().any(|step| step.env.contains_key(axis))
+        {
+            return Err(ApiError::new(
+                format!("{axis} is exported more than once"),
+                "validation_error",
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Helper to pick a job's timeout: its own, or else the server's default
+fn job_timeout(timeout_minutes: Option<u32>, config: &Config) -> u32 {
+    timeout_minutes.unwrap_or(config.default_job_timeout_minutes)
+}
+
+/// Helper to check a job's timeout against the server's maximum
+fn validate_timeout(timeout_minutes: Option<u32>, max: u32) -> Result<(), ApiError> {
+    match timeout_minutes {
+        Some(minutes) if minutes == 0 || minutes > max => Err(ApiError::new(
+            format!("'timeout_minutes' must be between 1 and {max}"),
+            "validation_error",
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Helper to set the labels a worker needs to run a job and the image it runs
+/// in, each from the request or the job's pipeline (not both)
+fn set_routing(
+    job: &mut Job,
+    runs_on: Vec<String>,
+    image: Option<String>,
+    matrix: &Matrix,
+) -> Result<(), ApiError> {
+    if !runs_on.is_empty() {
+        if !job.runs_on.is_empty() {
+            return Err(ApiError::new(
+                "'runs_on' cannot be given when the pipeline has one",
+                "validation_error",
+            ));
+        }
+        job.runs_on = runs_on;
+    }
+    if image.is_some() {
+        if job.image.is_some() {
+            return Err(ApiError::new(
+                "'image' cannot be given when the pipeline has one",
+                "validation_error",
+            ));
+        }
+        job.image = image;
+    }
+
+    crate::routing::validate(&job.runs_on, job.image.as_deref(), matrix)?;
+    // Matrix jobs get their labels and image when expanded into cells
+    if matrix.is_empty() {
+        let cell = BTreeMap::new();
+        job.runs_on = crate::routing::expand_labels(&job.runs_on, &cell);
+        job.image = job
+            .image
+            .as_ref()
+            .map(|image| crate::routing::expand_label(image, &cell));
+    }
+    Ok(())
+}
+
+/// Helper to store a new job, or one job per cell of its matrix under a new run
+async fn store_jobs(
+    state: &AppState,
+    job: Job,
+    matrix: &Matrix,
+) -> Result<Vec<Job>, (StatusCode, Json<ApiError>)> {
+    let database_error = |e: anyhow::Error| {
+        tracing::error!("Failed to create job: {}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiError::new(e.to_string(), "database_error")),
+        )
+    };
+
+    if matrix.is_empty() {
+        state.db.create_job(&job).await.map_err(database_error)?;
+        return Ok(vec![job]);
+    }
+
+    let (run, jobs) = crate::matrix::expand(&job, matrix);
+    state
+        .db
+        .create_run(&run, &jobs)
+        .await
+        .map_err(database_error)?;
+    tracing::info!(run_id = %run.id, jobs = jobs.len(), "Created matrix run");
+
+    Ok(jobs)
+}
+
+/// Helper to describe newly created jobs (one, or every cell of a run)
+fn created_response(state: &AppState, jobs: &[Job]) -> CreateJobResponse {
+    let job = &jobs[0];
+    CreateJobResponse {
+        job_id: job.id,
+        status: job.status,
+        stream_url: format!("{}/api/v1/jobs/{}/logs", state.config.base_url, job.id),
+        run_id: job.run_id,
+        job_ids: if job.run_id.is_some() {
+            jobs.iter().map(|job| job.id).collect()
+        } else {
+            Vec::new()
+        },
+    }
+}
+
+/// Most jobs a single job can need
+const MAX_NEEDS: usize = 20;
+
+/// Helper to check that a new job can wait on the jobs in `needs`
+async fn check_needs(
+    state: &AppState,
+    customer_id: Uuid,
+    needs: &[Uuid],
+) -> Result<(), (StatusCode, Json<ApiError>)> {
+    let invalid = |message: String| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(ApiError::new(message, "validation_error")),
+        )
+    };
+
+    if needs.len() > MAX_NEEDS {
+        return Err(invalid(format!("A job can need at most {MAX_NEEDS} jobs")));
+    }
+
+    for (i, &needed) in needs.iter().enumerate() {
+        if needs[..i].contains(&needed) {
+            return Err(invalid(format!("Job {needed} is needed more than once")));
+        }
+
+        let job = state.db.get_job(needed).await.map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiError::new(e.to_string(), "database_error")),
+            )
+        })?;
+        match job {
+            Some(job) if job.customer_id == customer_id => {
+                if job.status.stopped_short() {
+                    return Err(invalid(format!("Needed job {needed} has {}", job.status)));
+                }
+            },
+            _ => return Err(invalid(format!("Needed job {needed} not found"))),
+        }
+    }
+
+    Ok(())
+}
+
 /// POST /api/v1/jobs - Create a new build job
 pub async fn create_job(
     State(state): State<AppState>,
     auth_user: AuthUser,
     Json(request): Json<CreateJobRequest>,
 ) -> Result<(StatusCode, Json<CreateJobResponse>), (StatusCode, Json<ApiError>)> {
-    // Validate request - need either command or script
-    if request.command.is_none() && request.script.is_none() {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            Json(ApiError::new(
-                "Either 'command' or 'script' is required",
-                "validation_error",
-            )),
-        ));
+    if let Err(e) = validate_git_revision(&request) {
+        return Err((StatusCode::BAD_REQUEST, Json(e)));
     }
 
     // Extract customer_id from auth token
     let customer_id = auth_user.user_id;
 
-    // Create the job based on command or script
-    let job = if let Some(ref script) = request.script {
-        Job::with_script(
-            customer_id,
-            script.clone(),
-            request.source_type,
-            request.source_url.clone(),
-        )
-    } else {
-        Job::with_command(
-            customer_id,
-            request.command.clone().unwrap(),
-            request.source_type,
-            request.source_url.clone(),
-        )
+    let (mut job, matrix) = match new_job(
+        customer_id,
+        request.command,
+        request.script,
+        request.pipeline.as_deref(),
+        request.matrix,
+        request.source_type,
+        request.source_url,
+    ) {
+        Ok(job) => job,
+        Err(e) => return Err((StatusCode::BAD_REQUEST, Json(e))),
     };
+    job.env = request.env;
+    job.secrets = request.secrets;
+    job.timeout_minutes = Some(job_timeout(request.timeout_minutes, &state.config));
+    job.retry = request.retry;
+    if let Err(e) = validate_job_env(&job, &matrix)
+        .and_then(|()| set_routing(&mut job, request.runs_on, request.image, &matrix))
+        .and_then(|()| {
+            validate_timeout(
+                request.timeout_minutes,
+                state.config.max_job_timeout_minutes,
+            )
+        })
+        .and_then(|()| crate::retries::validate(job.retry.as_ref()))
+    {
+        return Err((StatusCode::BAD_REQUEST, Json(e)));
+    }
 
-    // Store in Supabase
-    match state.supabase.create_job(&job).await {
-        Ok(()) => {
-            // Create a log stream for this job
-            state.create_log_stream(job.id).await;
-
-            let stream_url = format!("{}/api/v1/jobs/{}/logs", state.config.base_url, job.id);
+    if let Some(credential_id) = request.git_credential_id {
+        super::credentials::check_usable(&state, customer_id, credential_id).await?;
+    }
+    super::secrets::check_available(&state, customer_id, &job.secrets).await?;
+    check_needs(&state, customer_id, &request.needs).await?;
 
-            tracing::info!(job_id = %job.id, "Created new job");
+    job.needs = request.needs;
+    job.git_ref = request.git_ref;
+    job.commit_sha = request.commit_sha;
+    job.submodules = request.submodules;
+    job.lfs = request.lfs;
+    job.git_credential_id = request.git_credential_id;
 
-            Ok((
-                StatusCode::CREATED,
-                Json(CreateJobResponse {
-                    job_id: job.id,
-                    status: job.status,
-                    stream_url,
-                }),
-            ))
-        },
-        Err(e) => {
-            tracing::error!("Failed to create job: {}", e);
-            Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ApiError::new(e.to_string(), "database_error")),
-            ))
-        },
+    let jobs = store_jobs(&state, job, &matrix).await?;
+    for job in &jobs {
+        // Create a log stream for this job
+        state.create_log_stream(job.id).await;
+        tracing::info!(job_id = %job.id, "Created new job");
     }
+    crate::dependencies::recheck_needs(&state, &jobs[0].needs).await;
+    state.wake_workers();
+
+    Ok((StatusCode::CREATED, Json(created_response(&state, &jobs))))
 }
 
 /// Request body for upload URL
@@ -147,6 +446,30 @@ pub struct UploadRequest {
     pub script: Option<String>,
     /// Git commit SHA (for archive deduplication)
     pub commit_sha: Option<String>,
+    /// Environment variables exported to the build
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Stored secrets exported to the build
+    #[serde(default)]
+    pub secrets: Vec<String>,
+    /// Contents of an `alloy.yml` to run instead of a command or script
+    pub pipeline: Option<String>,
+    /// Jobs that must complete before this one runs
+    #[serde(default)]
+    pub needs: Vec<Uuid>,
+    /// Axes to run the job across, one job per combination of values
+    #[serde(default)]
+    pub matrix: Matrix,
+    /// Labels a worker must have to run the job
+    #[serde(default)]
+    pub runs_on: Vec<String>,
+    /// Base image to run the job in, by the name workers give it
+    pub image: Option<String>,
+    /// Minutes the job may run before it is stopped
+    pub timeout_minutes: Option<u32>,
+    /// When a failed job is retried as a new attempt
+    #[serde(default)]
+    pub retry: Option<RetryPolicy>,
 }
 
 /// POST /api/v1/jobs/upload - Request an upload URL for local files
@@ -155,17 +478,6 @@ pub async fn request_upload(
     auth_user: AuthUser,
     Json(request): Json<UploadRequest>,
 ) -> Result<(StatusCode, Json<UploadUrlResponse>), (StatusCode, Json<ApiError>)> {
-    // Validate request
-    if request.command.is_none() && request.script.is_none() {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            Json(ApiError::new(
-                "Either 'command' or 'script' is required",
-                "validation_error",
-            )),
-        ));
-    }
-
     // Extract customer_id from auth token
     let customer_id = auth_user.user_id;
     let job_id = Uuid::new_v4();
@@ -173,159 +485,191 @@ pub async fn request_upload(
     // Use commit_sha for storage path if provided (enables deduplication)
     // Same commit = same archive file in storage
     let storage_key = request.commit_sha.as_ref().map_or_else(
-        || format!("sources/{job_id}.zip"),
-        |sha| format!("sources/{sha}.zip"),
+        || storage::source_key(&job_id.to_string()),
+        |sha| storage::source_key(sha),
     );
-
-    // Check if archive already exists (enables skip_upload for deduplication)
-    let archive_exists = if request.commit_sha.is_some() {
-        let check_url = format!(
-            "{}/storage/v1/object/public/{}",
-            state.config.supabase_url, storage_key
-        );
-        state
-            .client
-            .head(&check_url)
-            .send()
-            .await
-            .is_ok_and(|resp| resp.status().is_success())
-    } else {
-        false
-    };
-
-    if archive_exists {
-        tracing::info!(storage_key = %storage_key, "Archive already exists, skip_upload enabled");
-    }
-
-    // CLI uploads to orchestrator, which proxies to Supabase (keeps service key secure)
-    let upload_url = format!("{}/api/v1/jobs/{}/upload", state.config.base_url, job_id);
     // Store the path relative to storage root (bucket/path) in the DB
     // This allows us to sign it later
     let download_url = storage_key.clone();
 
-    // Create job based on command or script
-    let mut job = if let Some(ref script) = request.script {
-        Job::with_script(
-            customer_id,
-            script.clone(),
-            SourceType::Upload,
-            Some(download_url.clone()),
-        )
-    } else {
-        Job::with_command(
-            customer_id,
-            request.command.clone().unwrap(),
-            SourceType::Upload,
-            Some(download_url.clone()),
-        )
+    let (mut job, matrix) = match new_job(
+        customer_id,
+        request.command,
+        request.script,
+        request.pipeline.as_deref(),
+        request.matrix,
+        SourceType::Upload,
+        Some(download_url.clone()),
+    ) {
+        Ok(job) => job,
+        Err(e) => return Err((StatusCode::BAD_REQUEST, Json(e))),
     };
     job.status = JobStatus::Uploading;
     job.id = job_id;
+    job.env = request.env;
+    job.secrets = request.secrets;
+    job.timeout_minutes = Some(job_timeout(request.timeout_minutes, &state.config));
+    job.retry = request.retry;
+    if let Err(e) = validate_job_env(&job, &matrix)
+        .and_then(|()| set_routing(&mut job, request.runs_on, request.image, &matrix))
+        .and_then(|()| {
+            validate_timeout(
+                request.timeout_minutes,
+                state.config.max_job_timeout_minutes,
+            )
+        })
+        .and_then(|()| crate::retries::validate(job.retry.as_ref()))
+    {
+        return Err((StatusCode::BAD_REQUEST, Json(e)));
+    }
+    super::secrets::check_available(&state, customer_id, &job.secrets).await?;
+    check_needs(&state, customer_id, &request.needs).await?;
+    job.needs = request.needs;
 
-    match state.supabase.create_job(&job).await {
-        Ok(()) => {
-            tracing::info!(job_id = %job.id, "Created upload job, awaiting file upload");
-
-            Ok((
-                StatusCode::CREATED,
-                Json(UploadUrlResponse {
-                    upload_url,
-                    download_url,
-                    job_id,
-                    upload_token: state.config.supabase_key.clone(),
-                    skip_upload: archive_exists,
-                }),
-            ))
-        },
+    // Check if archive already exists (enables skip_upload for deduplication)
+    let archive_exists = request.commit_sha.is_some()
+        && state
+            .storage
+            .exists(&storage_key)
+            .await
+            .unwrap_or_else(|e| {
+                tracing::warn!("Failed to check for existing archive: {}", e);
+                false
+            });
+
+    if archive_exists {
+        tracing::info!(storage_key = %storage_key, "Archive already exists, skip_upload enabled");
+    }
+
+    // CLI uploads straight to storage when the backend can presign uploads, otherwise
+    // to the orchestrator, which proxies to storage (keeps storage credentials server-side)
+    let upload_url = match state
+        .storage
+        .signed_upload_url(&storage_key, SIGNED_URL_TTL)
+        .await
+    {
+        Ok(Some(url)) => url,
+        Ok(None) => format!("{}/api/v1/jobs/{}/upload", state.config.base_url, job_id),
         Err(e) => {
-            tracing::error!("Failed to create upload job: {}", e);
-            Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ApiError::new(e.to_string(), "database_error")),
-            ))
+            tracing::warn!("Failed to presign upload, falling back to proxy: {}", e);
+            format!("{}/api/v1/jobs/{}/upload", state.config.base_url, job_id)
         },
-    }
+    };
+    // Every job of a matrix run builds the same upload, started together
+    let jobs = store_jobs(&state, job, &matrix).await?;
+    tracing::info!(job_id = %job_id, "Created upload job, awaiting file upload");
+    crate::dependencies::recheck_needs(&state, &jobs[0].needs).await;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(UploadUrlResponse {
+            upload_url,
+            download_url,
+            job_id,
+            upload_token: state.config.supabase_key.clone().unwrap_or_default(),
+            skip_upload: archive_exists,
+        }),
+    ))
 }
 
 /// POST /`api/v1/jobs/:job_id/start` - Start a job after upload is complete
+///
+/// Starting the first job of a matrix run starts every job of the run.
 pub async fn start_job(
     State(state): State<AppState>,
     _auth_user: AuthUser,
     Path(job_id): Path<Uuid>,
 ) -> Result<Json<CreateJobResponse>, (StatusCode, Json<ApiError>)> {
+    let database_error = |e: anyhow::Error| {
+        tracing::error!("Failed to start job: {}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiError::new(e.to_string(), "database_error")),
+        )
+    };
+
     // Verify job exists and is in pending status
-    match state.supabase.get_job(job_id).await {
-        Ok(Some(mut job)) => {
-            // If job is already running (worker claimed it), that's ok - just return success
-            if job.status == JobStatus::Running {
-                let stream_url = format!("{}/api/v1/jobs/{}/logs", state.config.base_url, job.id);
-                tracing::info!(job_id = %job.id, "Job already running (worker claimed it)");
-                return Ok(Json(CreateJobResponse {
-                    job_id: job.id,
-                    status: job.status,
-                    stream_url,
-                }));
-            }
+    let job = match state.db.get_job(job_id).await {
+        Ok(Some(job)) => job,
+        Ok(None) => {
+            return Err((
+                StatusCode::NOT_FOUND,
+                Json(ApiError::new(
+                    format!("Job {job_id} not found"),
+                    "job_not_found",
+                )),
+            ))
+        },
+        Err(e) => return Err(database_error(e)),
+    };
 
-            if job.status != JobStatus::Pending && job.status != JobStatus::Uploading {
-                return Err((
-                    StatusCode::BAD_REQUEST,
-                    Json(ApiError::new(
-                        format!(
-                            "Job is in {:?} status, expected pending, uploading, or running",
-                            job.status
-                        ),
-                        "invalid_state",
-                    )),
-                ));
+    // A job already running (a worker claimed it) is fine too
+    if job.status != JobStatus::Running
+        && job.status != JobStatus::Pending
+        && job.status != JobStatus::Uploading
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(ApiError::new(
+                format!(
+                    "Job is in {:?} status, expected pending, uploading, or running",
+                    job.status
+                ),
+                "invalid_state",
+            )),
+        ));
+    }
+
+    let mut jobs = match job.run_id {
+        Some(run_id) => {
+            let run = state.db.get_run(run_id).await.map_err(database_error)?;
+            let jobs = state
+                .db
+                .list_jobs(None, Some(run_id), crate::matrix::MAX_CELLS)
+                .await
+                .map_err(database_error)?;
+            match run {
+                Some(run) => crate::matrix::latest_per_cell(&run.matrix, jobs),
+                None => jobs,
             }
+        },
+        None => vec![job],
+    };
 
+    for job in &mut jobs {
+        match job.status {
+            JobStatus::Running => {
+                tracing::info!(job_id = %job.id, "Job already running (worker claimed it)");
+                continue;
+            },
             // Update status to pending if it was uploading
             // This enables it to be picked up by workers
-            if job.status == JobStatus::Uploading {
-                if let Err(e) = state
-                    .supabase
-                    .update_job_status(job_id, JobStatus::Pending)
+            JobStatus::Uploading => {
+                state
+                    .db
+                    .update_job_status(job.id, JobStatus::Pending)
                     .await
-                {
-                    tracing::error!("Failed to update job status to pending: {}", e);
-                    return Err((
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        Json(ApiError::new(e.to_string(), "database_error")),
-                    ));
-                }
+                    .map_err(database_error)?;
                 // Update local job status so response reflects the change
                 job.status = JobStatus::Pending;
-            }
-
-            // Create log stream
-            state.create_log_stream(job.id).await;
+            },
+            JobStatus::Pending => {},
+            // A sibling that stopped short (e.g. cancelled by a failed need)
+            _ => continue,
+        }
 
-            let stream_url = format!("{}/api/v1/jobs/{}/logs", state.config.base_url, job.id);
+        // Create log stream
+        state.create_log_stream(job.id).await;
+        tracing::info!(job_id = %job.id, "Job started, ready for worker pickup");
+    }
 
-            tracing::info!(job_id = %job.id, "Job started, ready for worker pickup");
+    state.wake_workers();
 
-            Ok(Json(CreateJobResponse {
-                job_id: job.id,
-                status: job.status,
-                stream_url,
-            }))
-        },
-        Ok(None) => Err((
-            StatusCode::NOT_FOUND,
-            Json(ApiError::new(
-                format!("Job {job_id} not found"),
-                "job_not_found",
-            )),
-        )),
-        Err(e) => {
-            tracing::error!("Failed to get job: {}", e);
-            Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ApiError::new(e.to_string(), "database_error")),
-            ))
-        },
+    // The requested job first
+    if let Some(i) = jobs.iter().position(|job| job.id == job_id) {
+        jobs.swap(0, i);
     }
+    Ok(Json(created_response(&state, &jobs)))
 }
 
 /// Query params for listing jobs
@@ -333,6 +677,8 @@ pub async fn start_job(
 pub struct ListJobsQuery {
     /// Optional status filter
     pub status: Option<String>,
+    /// Optional filter on the matrix run jobs belong to
+    pub run_id: Option<Uuid>,
     /// Max number of jobs to return (default: 20)
     pub limit: Option<usize>,
 }
@@ -346,11 +692,14 @@ pub async fn list_jobs(
     let limit = query.limit.unwrap_or(20).min(100); // Cap at 100
 
     match state
-        .supabase
-        .list_jobs(query.status.as_deref(), limit)
+        .db
+        .list_jobs(query.status.as_deref(), query.run_id, limit)
         .await
     {
-        Ok(jobs) => Ok(Json(jobs)),
+        Ok(mut jobs) => {
+            crate::routing::explain_unmatched(&state, &mut jobs).await;
+            Ok(Json(jobs))
+        },
         Err(e) => {
             tracing::error!("Failed to list jobs: {}", e);
             Err((
@@ -367,8 +716,18 @@ pub async fn get_job(
     _auth_user: AuthUser,
     Path(job_id): Path<Uuid>,
 ) -> Result<Json<Job>, (StatusCode, Json<ApiError>)> {
-    match state.supabase.get_job(job_id).await {
-        Ok(Some(job)) => Ok(Json(job)),
+    match state.db.get_job(job_id).await {
+        Ok(Some(job)) => {
+            let mut jobs = [job];
+            crate::routing::explain_unmatched(&state, &mut jobs).await;
+            let [mut job] = jobs;
+            match crate::retries::attempts(&state, &job).await {
+                Ok(attempts) if attempts.len() > 1 => job.attempts = attempts,
+                Ok(_) => {},
+                Err(e) => tracing::warn!("Failed to list attempts: {}", e),
+            }
+            Ok(Json(job))
+        },
         Ok(None) => Err((
             StatusCode::NOT_FOUND,
             Json(ApiError::new(
@@ -392,8 +751,18 @@ pub async fn get_artifacts(
     _auth_user: AuthUser,
     Path(job_id): Path<Uuid>,
 ) -> Result<Json<Vec<shared::Artifact>>, (StatusCode, Json<ApiError>)> {
-    match state.supabase.get_job_artifacts(job_id).await {
-        Ok(artifacts) => Ok(Json(artifacts)),
+    match state.db.get_job_artifacts(job_id).await {
+        Ok(mut artifacts) => {
+            // Re-sign download URLs on every read so they never go stale
+            for artifact in &mut artifacts {
+                let key = storage::artifact_key(job_id, &artifact.name);
+                match state.storage.signed_url(&key, SIGNED_URL_TTL).await {
+                    Ok(url) => artifact.download_url = Some(url),
+                    Err(e) => tracing::warn!("Failed to sign artifact URL: {}", e),
+                }
+            }
+            Ok(Json(artifacts))
+        },
         Err(e) => {
             tracing::error!("Failed to get artifacts: {}", e);
             Err((
@@ -404,15 +773,15 @@ pub async fn get_artifacts(
     }
 }
 
-/// PUT /`api/v1/jobs/:job_id/upload` - Upload source archive (proxied to Supabase Storage)
+/// PUT /`api/v1/jobs/:job_id/upload` - Upload source archive (proxied to object storage)
 pub async fn upload_archive(
     State(state): State<AppState>,
     Path(job_id): Path<Uuid>,
-    body: axum::body::Bytes,
+    body: axum::body::Body,
 ) -> Result<StatusCode, (StatusCode, Json<ApiError>)> {
     // Get the job to find the correct storage path from source_url
     let job = state
-        .supabase
+        .db
         .get_job(job_id)
         .await
         .map_err(|e| {
@@ -454,38 +823,16 @@ pub async fn upload_archive(
         return Err((StatusCode::BAD_REQUEST, Json(e)));
     }
 
-    // Proxy upload to Supabase Storage
-    let storage_url = format!(
-        "{}/storage/v1/object/{}",
-        state.config.supabase_url, upload_path
-    );
-
-    let response = state
-        .client
-        .put(&storage_url)
-        .header("apikey", &state.config.supabase_key)
-        .header(
-            "Authorization",
-            format!("Bearer {}", state.config.supabase_key),
-        )
-        .header("Content-Type", "application/zip")
-        .body(body)
-        .send()
+    // Stream the upload straight through to storage
+    if let Err(e) = state
+        .storage
+        .put(&upload_path, body_stream(body), "application/zip")
         .await
-        .map_err(|e| {
-            tracing::error!("Failed to upload to storage: {}", e);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ApiError::new(e.to_string(), "storage_error")),
-            )
-        })?;
-
-    if !response.status().is_success() {
-        let error_text = response.text().await.unwrap_or_default();
-        tracing::error!("Storage upload failed: {}", error_text);
+    {
+        tracing::error!("Storage upload failed: {}", e);
         return Err((
             StatusCode::BAD_GATEWAY,
-            Json(ApiError::new(error_text, "storage_upload_failed")),
+            Json(ApiError::new(e.to_string(), "storage_upload_failed")),
         ));
     }
 
@@ -506,7 +853,7 @@ pub async fn upload_artifact(
     }
 
     // 2. Validate job exists (optional but good practice)
-    if let Err(e) = state.supabase.get_job(job_id).await {
+    if let Err(e) = state.db.get_job(job_id).await {
         tracing::error!("Failed to check job existence: {}", e);
         return Err((
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -514,19 +861,26 @@ pub async fn upload_artifact(
         ));
     }
 
-    // 2. Upload to Supabase Storage (streaming)
-    match state
-        .supabase
-        .upload_artifact_file(
-            job_id,
-            &filename,
-            reqwest::Body::wrap_stream(body.into_data_stream()),
-        )
-        .await
-    {
-        Ok(public_url) => {
+    // 3. Upload to storage (streaming)
+    let key = storage::artifact_key(job_id, &filename);
+    let uploaded = state
+        .storage
+        .put(&key, body_stream(body), "application/octet-stream")
+        .await;
+
+    match uploaded {
+        Ok(()) => {
             tracing::info!(job_id = %job_id, filename = %filename, "Artifact uploaded");
-            Ok(public_url)
+            state
+                .storage
+                .signed_url(&key, SIGNED_URL_TTL)
+                .await
+                .map_err(|e| {
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        Json(ApiError::new(e.to_string(), "upload_failed")),
+                    )
+                })
         },
         Err(e) => {
             tracing::error!("Failed to upload artifact: {}", e);
@@ -560,6 +914,85 @@ mod tests {
         assert!(validate_artifact_filename("app;.sh").is_err()); // Shell injection chars
     }
 
+    #[test]
+    fn test_validate_git_revision() {
+        let request =
+            |source_type, git_ref: Option<&str>, commit_sha: Option<&str>| CreateJobRequest {
+                source_type,
+                source_url: Some("https://github.com/example/app.git".to_string()),
+                command: Some("make".to_string()),
+                script: None,
+                git_ref: git_ref.map(str::to_string),
+                commit_sha: commit_sha.map(str::to_string),
+                submodules: false,
+                lfs: false,
+                git_credential_id: None,
+                env: BTreeMap::new(),
+                secrets: Vec::new(),
+                pipeline: None,
+                needs: Vec::new(),
+                matrix: Matrix::new(),
+                runs_on: Vec::new(),
+                image: None,
+                timeout_minutes: None,
+                retry: None,
+            };
+        let git = |git_ref, commit_sha| {
+            validate_git_revision(&request(SourceType::Git, git_ref, commit_sha))
+        };
+
+        // Valid
+        assert!(git(None, None).is_ok());
+        assert!(git(Some("main"), None).is_ok());
+        assert!(git(Some("release/1.2"), Some("0123abcDEF")).is_ok());
+        assert!(git(Some("refs/pull/7/head"), None).is_ok());
+        assert!(git(None, Some("0123456789abcdef0123456789abcdef01234567")).is_ok());
+
+        // Invalid
+        assert!(git(Some(""), None).is_err());
+        assert!(git(Some("--upload-pack=touch pwned"), None).is_err()); // Option injection
+        assert!(git(Some("main..dev"), None).is_err());
+        assert!(git(Some("feature branch"), None).is_err());
+        assert!(git(Some("main^"), None).is_err());
+        assert!(git(Some("topic.lock"), None).is_err());
+        assert!(git(None, Some("abc")).is_err()); // Too short
+        assert!(git(None, Some("-0123456")).is_err());
+        assert!(git(None, Some("main1234")).is_err()); // Not hex
+
+        // Only for git sources
+        assert!(validate_git_revision(&request(SourceType::Upload, Some("main"), None)).is_err());
+    }
+
+    #[test]
+    fn test_validate_env() {
+        let env = |pairs: &[(&str, &str)]| -> BTreeMap<String, String> {
+            pairs
+                .iter()
+                .map(|(k, v)| ((*k).to_string(), (*v).to_string()))
+                .collect()
+        };
+        let secrets = |names: &[&str]| -> Vec<String> {
+            names.iter().map(|name| (*name).to_string()).collect()
+        };
+
+        // Valid
+        assert!(validate_env(&env(&[]), &[]).is_ok());
+        assert!(validate_env(
+            &env(&[("CONFIGURATION", "Release")]),
+            &secrets(&["MATCH_PASSWORD"])
+        )
+        .is_ok());
+        assert!(validate_env(&env(&[("_X1", "a b $HOME 'quoted'")]), &[]).is_ok());
+
+        // Invalid
+        assert!(validate_env(&env(&[("1X", "a")]), &[]).is_err());
+        assert!(validate_env(&env(&[("A=B", "a")]), &[]).is_err());
+        assert!(validate_env(&env(&[("X", "a\0b")]), &[]).is_err());
+        assert!(validate_env(&env(&[]), &secrets(&["bad name"])).is_err());
+        assert!(validate_env(&env(&[("KEY", "a")]), &secrets(&["KEY"])).is_err()); // Exported twice
+        assert!(validate_env(&env(&[]), &secrets(&["KEY", "KEY"])).is_err());
+    }
+
     #[test]
     fn test_validate_storage_path() {
         // Valid paths
@@ -583,7 +1016,7 @@ pub async fn cancel_job(
     Path(job_id): Path<Uuid>,
 ) -> Result<StatusCode, (StatusCode, Json<ApiError>)> {
     // Get the job to check its status
-    match state.supabase.get_job(job_id).await {
+    match state.db.get_job(job_id).await {
         Ok(Some(job)) => {
             // Can only cancel pending or running jobs
             if job.status != JobStatus::Pending && job.status != JobStatus::Running {
@@ -598,12 +1031,38 @@ pub async fn cancel_job(
 
             // Update job status to cancelled
             match state
-                .supabase
+                .db
                 .update_job_status(job_id, JobStatus::Cancelled)
                 .await
             {
                 Ok(()) => {
                     tracing::info!(job_id = %job_id, "Job cancelled");
+                    crate::dependencies::cancel_dependents(&state, job_id).await;
+
+                    // A running job's worker is told right away on its channel, or else
+                    // on its next heartbeat, and closes the stream when it reports back;
+                    // nobody else will close a pending job's
+                    if let (JobStatus::Running, Some(worker_id)) = (job.status, job.worker_id) {
+                        let command = WorkerCommand::Cancel {
+                            job_ids: vec![job_id],
+                        };
+                        state.send_to_worker(worker_id, command).await;
+                    }
+                    if job.status == JobStatus::Pending {
+                        if let Some(tx) = state.get_log_stream(job_id).await {
+                            let message = serde_json::json!({
+                                "type": "job_complete",
+                                "job_id": job_id.to_string(),
+                                "status": format!("{:?}", JobStatus::Cancelled),
+                                "exit_code": -1,
+                                "build_minutes": 0.0,
+                                "artifacts_count": 0,
+                            });
+                            let _ = tx.send(message.to_string());
+                        }
+                        state.remove_log_stream(job_id).await;
+                    }
+
                     Ok(StatusCode::OK)
                 },
                 Err(e) => {
@@ -645,15 +1104,15 @@ pub async fn retry_job(
     Path(job_id): Path<Uuid>,
 ) -> Result<(StatusCode, Json<RetryJobResponse>), (StatusCode, Json<ApiError>)> {
     // Get the original job
-    match state.supabase.get_job(job_id).await {
+    match state.db.get_job(job_id).await {
         Ok(Some(original)) => {
-            // Can only retry failed or cancelled jobs
-            if original.status != JobStatus::Failed && original.status != JobStatus::Cancelled {
+            // Can only retry failed, cancelled or timed out jobs
+            if !original.status.stopped_short() {
                 return Err((
                     StatusCode::BAD_REQUEST,
                     Json(ApiError::new(
                         format!(
-                            "Cannot retry job in {} status (only failed/cancelled)",
+                            "Cannot retry job in {} status (only failed/cancelled/timed_out)",
                             original.status
                         ),
                         "invalid_state",
@@ -661,37 +1120,36 @@ pub async fn retry_job(
                 ));
             }
 
-            // Create new job with same parameters
-            let mut new_job = if let Some(ref script) = original.script {
-                Job::with_script(
-                    original.customer_id,
-                    script.clone(),
-                    original.source_type,
-                    original.source_url.clone(),
-                )
-            } else if let Some(ref command) = original.command {
-                Job::with_command(
-                    original.customer_id,
-                    command.clone(),
-                    original.source_type,
-                    original.source_url.clone(),
-                )
-            } else {
-                return Err((
-                    StatusCode::BAD_REQUEST,
-                    Json(ApiError::new(
-                        "Original job has no command or script",
-                        "invalid_job",
-                    )),
-                ));
-            };
+            // A job is retried once; later retries start from its latest attempt
+            match state.db.get_retry(job_id).await {
+                Ok(None) => {},
+                Ok(Some(retry)) => {
+                    return Err((
+                        StatusCode::CONFLICT,
+                        Json(ApiError::new(
+                            format!("Job was already retried as {}", retry.id),
+                            "already_retried",
+                        )),
+                    ));
+                },
+                Err(e) => {
+                    tracing::error!("Failed to look up retry: {}", e);
+                    return Err((
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        Json(ApiError::new(e.to_string(), "database_error")),
+                    ));
+                },
+            }
 
-            // Set status to pending so it gets picked up
-            new_job.status = JobStatus::Pending;
+            // The new attempt runs the same job, in the original's place in its run
+            let new_job = crate::retries::next_attempt(&original);
 
-            match state.supabase.create_job(&new_job).await {
+            match state.db.create_job(&new_job).await {
                 Ok(()) => {
                     tracing::info!(new_job_id = %new_job.id, original_job_id = %job_id, "Job retried");
+                    state.create_log_stream(new_job.id).await;
+                    crate::dependencies::recheck_needs(&state, &new_job.needs).await;
+                    state.wake_workers();
                     Ok((
                         StatusCode::CREATED,
                         Json(RetryJobResponse {
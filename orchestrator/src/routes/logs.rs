@@ -10,10 +10,12 @@ use axum::{
     Json,
 };
 use futures_util::{SinkExt, StreamExt};
+use std::sync::Arc;
 use uuid::Uuid;
 
-use crate::state::AppState;
-use shared::{ApiError, LogEntry};
+use crate::state::{AppState, LogCursor};
+use crate::storage;
+use shared::{ApiError, LogAck, LogBatch, LogEntry, LogMask};
 
 /// GET /`api/v1/jobs/:job_id/logs` - Stream logs via WebSocket
 pub async fn stream_logs(
@@ -66,62 +68,168 @@ async fn handle_log_stream(socket: WebSocket, state: AppState, job_id: Uuid) {
 /// POST /`api/v1/workers/:worker_id/log` - Push log entry from worker
 pub async fn push_log(
     State(state): State<AppState>,
-    Json(entry): Json<LogEntry>,
+    Json(mut entry): Json<LogEntry>,
 ) -> Result<StatusCode, (StatusCode, Json<ApiError>)> {
     if let Some(tx) = state.get_log_stream(entry.job_id).await {
+        let mask = job_mask(&state, entry.job_id)
+            .await
+            .map_err(|e| mask_error(&e))?;
+        entry.content = mask.apply(&entry.content);
         let log_json = serde_json::to_string(&entry).unwrap();
         let _ = tx.send(log_json);
     }
     Ok(StatusCode::OK)
 }
 
+/// POST /`api/v1/workers/:worker_id/logs` - Push a batch of log entries from worker
+pub async fn push_logs(
+    State(state): State<AppState>,
+    Path(worker_id): Path<Uuid>,
+    Json(batch): Json<LogBatch>,
+) -> Json<LogAck> {
+    Json(accept_batch(&state, worker_id, batch).await)
+}
+
+/// Stream the entries of a batch that weren't received before, in order, and
+/// say how far the job's logs have come.
+///
+/// A worker resends a batch until it is acknowledged, so entries can arrive
+/// more than once; those behind the job's cursor are dropped. A batch that
+/// starts past the cursor is dropped whole, and the cursor acknowledged, so the
+/// worker goes back for the entries in between. Batches from any worker but
+/// the one running the job, or for a job that has no stream, are dropped too,
+/// but acknowledged in full so the worker stops resending them.
+///
+/// The job's secret values are masked in what is streamed, whether or not the
+/// worker masked them already.
+pub(super) async fn accept_batch(state: &AppState, worker_id: Uuid, batch: LogBatch) -> LogAck {
+    let job_id = batch.job_id;
+    let end_seq = batch.end_seq();
+    let log_tx = state.get_log_stream(job_id).await;
+
+    // Claimed through another orchestrator: the job's secrets are loaded here
+    let claimed_elsewhere =
+        log_tx.is_some() && !state.log_cursors.read().await.contains_key(&job_id);
+    let mask = if claimed_elsewhere {
+        match job_mask(state, job_id).await {
+            Ok(mask) => Some(mask),
+            Err(e) => {
+                // Entries that can't be masked are left unstreamed
+                tracing::error!(job_id = %job_id, "Failed to load secrets to mask logs: {}", e);
+                return LogAck {
+                    job_id,
+                    next_seq: batch.first_seq,
+                };
+            },
+        }
+    } else {
+        None
+    };
+
+    let mut cursors = state.log_cursors.write().await;
+    let cursor = match (cursors.get_mut(&job_id), &log_tx, mask) {
+        (Some(cursor), _, _) if cursor.worker_id == worker_id => cursor,
+        // Take up where the worker is
+        (None, Some(_), Some(mask)) => cursors.entry(job_id).or_insert(LogCursor {
+            worker_id,
+            next_seq: batch.first_seq,
+            mask,
+        }),
+        _ => {
+            return LogAck {
+                job_id,
+                next_seq: end_seq,
+            }
+        },
+    };
+
+    if batch.first_seq > cursor.next_seq {
+        let next_seq = cursor.next_seq;
+        drop(cursors);
+        return LogAck { job_id, next_seq };
+    }
+
+    let seen = usize::try_from(cursor.next_seq - batch.first_seq).unwrap_or(usize::MAX);
+    if let Some(tx) = &log_tx {
+        for mut entry in batch.entries.into_iter().skip(seen) {
+            entry.content = cursor.mask.apply(&entry.content);
+            let _ = tx.send(serde_json::to_string(&entry).unwrap());
+        }
+    }
+    cursor.next_seq = cursor.next_seq.max(end_seq);
+    let next_seq = cursor.next_seq;
+    // Held while streaming, so batches sent twice at once stay in order
+    drop(cursors);
+
+    LogAck { job_id, next_seq }
+}
+
 /// GET /`api/v1/jobs/:job_id/logs/stored` - Get stored logs for a job
 pub async fn get_stored_logs(
     State(state): State<AppState>,
     Path(job_id): Path<Uuid>,
 ) -> Result<Json<Vec<shared::JobLog>>, (StatusCode, Json<ApiError>)> {
     // Try to get the log file from storage
-    // Path: logs/{job_id}.log
-    state.supabase.download_log_file(job_id).await.map_or_else(
-        |_| Ok(Json(vec![])),
-        |content| {
-            // Parse content line by line into JobLog entries
-            // Format in file: "[STDOUT] line content" or just content
-            let logs: Vec<shared::JobLog> = content
-                .lines()
-                .map(|line| {
-                    // Try to parse stream type if present, otherwise default to stdout
-                    let (_stream, content) = line.strip_prefix("[STDOUT] ").map_or_else(
-                        || {
-                            line.strip_prefix("[STDERR] ")
-                                .map_or((shared::LogStream::Stdout, line), |stripped| {
-                                    (shared::LogStream::Stderr, stripped)
-                                })
-                        },
-                        |stripped| (shared::LogStream::Stdout, stripped),
-                    );
-
-                    shared::JobLog {
-                        id: Uuid::new_v4(), // Ephemeral ID
-                        job_id,
-                        content: content.to_string(),
-                        created_at: chrono::Utc::now(), // We don't have timestamps in simple log file yet
-                    }
-                })
-                .collect();
-
-            Ok(Json(logs))
+    let content = match state.storage.get_bytes(&storage::log_key(job_id)).await {
+        Ok(Some(data)) => String::from_utf8_lossy(&data).into_owned(),
+        Ok(None) => return Ok(Json(vec![])),
+        Err(e) => {
+            tracing::warn!("Failed to read stored logs: {}", e);
+            return Ok(Json(vec![]));
         },
-    )
+    };
+
+    // Parse content line by line into JobLog entries
+    // Format in file: "[STDOUT] line content" or just content
+    let logs: Vec<shared::JobLog> = content
+        .lines()
+        .map(|line| {
+            // Try to parse stream type if present, otherwise default to stdout
+            let (_stream, content) = line.strip_prefix("[STDOUT] ").map_or_else(
+                || {
+                    line.strip_prefix("[STDERR] ")
+                        .map_or((shared::LogStream::Stdout, line), |stripped| {
+                            (shared::LogStream::Stderr, stripped)
+                        })
+                },
+                |stripped| (shared::LogStream::Stdout, stripped),
+            );
+
+            shared::JobLog {
+                id: Uuid::new_v4(), // Ephemeral ID
+                job_id,
+                content: content.to_string(),
+                created_at: chrono::Utc::now(), // We don't have timestamps in simple log file yet
+            }
+        })
+        .collect();
+
+    Ok(Json(logs))
 }
 
-/// POST /`api/v1/jobs/:job_id/logs/upload` - Upload complete log file
+/// PUT /`api/v1/jobs/:job_id/logs/upload` - Upload complete log file, with
+/// the job's secret values masked
 pub async fn upload_logs(
     State(state): State<AppState>,
     Path(job_id): Path<Uuid>,
     body: axum::body::Bytes,
 ) -> Result<StatusCode, (StatusCode, Json<ApiError>)> {
-    match state.supabase.upload_log_file(job_id, body.to_vec()).await {
+    let mask = job_mask(&state, job_id).await.map_err(|e| mask_error(&e))?;
+    let body = if mask.is_empty() {
+        body
+    } else {
+        mask.apply(&String::from_utf8_lossy(&body)).into()
+    };
+
+    match state
+        .storage
+        .put(
+            &storage::log_key(job_id),
+            storage::bytes_stream(body),
+            "text/plain",
+        )
+        .await
+    {
         Ok(()) => Ok(StatusCode::OK),
         Err(e) => {
             tracing::error!("Failed to upload log file: {}", e);
@@ -132,3 +240,25 @@ pub async fn upload_logs(
         },
     }
 }
+
+/// The mask for a job's secret values: the one loaded when it was claimed
+/// here, or else loaded now
+async fn job_mask(state: &AppState, job_id: Uuid) -> anyhow::Result<Arc<LogMask>> {
+    if let Some(cursor) = state.log_cursors.read().await.get(&job_id) {
+        return Ok(Arc::clone(&cursor.mask));
+    }
+
+    let Some(job) = state.db.get_job(job_id).await? else {
+        return Ok(Arc::default());
+    };
+    let values = super::secrets::decrypt_for_job(state, &job).await?;
+    Ok(Arc::new(LogMask::new(values.values().map(String::as_str))))
+}
+
+fn mask_error(e: &anyhow::Error) -> (StatusCode, Json<ApiError>) {
+    tracing::error!("Failed to load secrets to mask logs: {}", e);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(ApiError::new(e.to_string(), "secrets_error")),
+    )
+}
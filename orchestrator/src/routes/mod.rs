@@ -1,9 +1,14 @@
 //! API route definitions
 
 mod auth_routes;
+mod channel;
+mod credentials;
 mod health;
 mod jobs;
 mod logs;
+mod runs;
+mod secrets;
+mod storage;
 mod workers;
 
 use crate::state::AppState;
@@ -18,15 +23,28 @@ pub fn worker_routes() -> Router<AppState> {
         .route("/api/v1/workers/register", post(workers::register_worker))
         .route("/api/v1/workers/heartbeat", post(workers::heartbeat))
         .route("/api/v1/workers/claim", post(workers::claim_job))
+        .route(
+            "/api/v1/workers/:worker_id/channel",
+            get(channel::worker_channel),
+        )
         .route(
             "/api/v1/workers/:worker_id/complete",
             post(workers::complete_job),
         )
+        .route(
+            "/api/v1/workers/:worker_id/release",
+            post(workers::release_job),
+        )
         .route(
             "/api/v1/workers/:worker_id/deregister",
             post(workers::deregister_worker),
         )
+        .route(
+            "/api/v1/workers/:worker_id/drain",
+            post(workers::drain_worker),
+        )
         .route("/api/v1/workers/:worker_id/log", post(logs::push_log))
+        .route("/api/v1/workers/:worker_id/logs", post(logs::push_logs))
         // Worker artifact upload
         .route(
             "/api/v1/jobs/:job_id/artifacts/:filename",
@@ -56,6 +74,10 @@ pub fn api_routes() -> Router<AppState> {
         )
         .route("/api/v1/jobs/:job_id/logs/upload", put(logs::upload_logs))
         .route("/api/v1/jobs/:job_id/artifacts", get(jobs::get_artifacts))
+        // Matrix runs (requires auth)
+        .route("/api/v1/runs/:run_id", get(runs::get_run))
+        // Signed object downloads (local storage backend)
+        .route("/api/v1/storage/*key", get(storage::download_object))
         // Auth/API key management (requires auth)
         .route("/api/v1/auth/me", get(auth_routes::get_current_user))
         .route("/api/v1/api-keys", post(auth_routes::create_api_key))
@@ -64,6 +86,21 @@ pub fn api_routes() -> Router<AppState> {
             "/api/v1/api-keys/:key_id",
             delete(auth_routes::delete_api_key),
         )
+        // Git credentials for private repositories (requires auth)
+        .route(
+            "/api/v1/git-credentials",
+            get(credentials::list_git_credentials).post(credentials::create_git_credential),
+        )
+        .route(
+            "/api/v1/git-credentials/:credential_id",
+            delete(credentials::delete_git_credential),
+        )
+        // Secrets exported to jobs (requires auth)
+        .route("/api/v1/secrets", get(secrets::list_secrets))
+        .route(
+            "/api/v1/secrets/:name",
+            put(secrets::set_secret).delete(secrets::delete_secret),
+        )
         // Public auth endpoints
         .route("/api/v1/auth/login", post(auth_routes::login))
         .route("/api/v1/auth/register", post(auth_routes::register))
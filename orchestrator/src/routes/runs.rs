@@ -0,0 +1,59 @@
+//! Matrix run routes
+//!
+//! A run groups the jobs a matrix expanded into, one per cell. Its status is
+//! derived from the latest job of each cell (see `Run::status`).
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use uuid::Uuid;
+
+use crate::auth::AuthUser;
+use crate::matrix;
+use crate::state::AppState;
+use shared::{ApiError, Run, RunDetails};
+
+/// GET /`api/v1/runs/:run_id` - Get a run with its aggregate status and jobs
+pub async fn get_run(
+    State(state): State<AppState>,
+    auth_user: AuthUser,
+    Path(run_id): Path<Uuid>,
+) -> Result<Json<RunDetails>, (StatusCode, Json<ApiError>)> {
+    let database_error = |e: anyhow::Error| {
+        tracing::error!("Failed to get run: {}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiError::new(e.to_string(), "database_error")),
+        )
+    };
+
+    let run = match state.db.get_run(run_id).await.map_err(database_error)? {
+        Some(run) if run.customer_id == auth_user.user_id => run,
+        _ => {
+            return Err((
+                StatusCode::NOT_FOUND,
+                Json(ApiError::new(
+                    format!("Run {run_id} not found"),
+                    "run_not_found",
+                )),
+            ))
+        },
+    };
+
+    // Room for every cell plus a retry of each
+    let jobs = state
+        .db
+        .list_jobs(None, Some(run_id), 2 * matrix::MAX_CELLS)
+        .await
+        .map_err(database_error)?;
+    let mut jobs = matrix::latest_per_cell(&run.matrix, jobs);
+    crate::routing::explain_unmatched(&state, &mut jobs).await;
+
+    Ok(Json(RunDetails {
+        status: Run::status(&jobs),
+        run,
+        jobs,
+    }))
+}
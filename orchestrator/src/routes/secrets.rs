@@ -0,0 +1,241 @@
+//! Secret management routes
+//!
+//! Secrets belong to a user and are exported to that user's jobs by name.
+//! Values are encrypted with `CREDENTIALS_KEY` before they are stored and are
+//! never returned by the API; they are decrypted only for the worker that
+//! claims a job referencing them.
+
+use std::collections::BTreeMap;
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::Utc;
+use uuid::Uuid;
+
+use super::credentials::credentials_key;
+use crate::auth::AuthUser;
+use crate::crypto;
+use crate::db::SecretRecord;
+use crate::state::AppState;
+use shared::{is_valid_env_name, ApiError, Job, SecretInfo, SetSecretRequest};
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+/// Largest secret value accepted (a base64 App Store Connect key fits easily)
+const MAX_SECRET_BYTES: usize = 64 * 1024;
+
+fn database_error(e: &anyhow::Error) -> (StatusCode, Json<ApiError>) {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(ApiError::new(e.to_string(), "database_error")),
+    )
+}
+
+/// What a secret's value is encrypted against, so it can't be moved to
+/// another user or name
+fn secret_context(user_id: Uuid, name: &str) -> Vec<u8> {
+    format!("{user_id}/{name}").into_bytes()
+}
+
+/// Helper to validate a secret before it is stored
+fn validate_secret(name: &str, value: &str) -> Result<(), ApiError> {
+    if !is_valid_env_name(name) {
+        return Err(ApiError::new(
+            "Secret names may only contain letters, digits and underscores, and can't start with a digit",
+            "validation_error",
+        ));
+    }
+
+    if value.is_empty() {
+        return Err(ApiError::new("Value cannot be empty", "validation_error"));
+    }
+
+    if value.len() > MAX_SECRET_BYTES {
+        return Err(ApiError::new(
+            format!("Value cannot be larger than {MAX_SECRET_BYTES} bytes"),
+            "validation_error",
+        ));
+    }
+
+    // Environment variables can't hold NUL bytes
+    if value.contains('\0') {
+        return Err(ApiError::new(
+            "Value cannot contain NUL characters",
+            "validation_error",
+        ));
+    }
+
+    Ok(())
+}
+
+/// GET /api/v1/secrets - List the user's secrets (without values)
+pub async fn list_secrets(
+    State(state): State<AppState>,
+    user: AuthUser,
+) -> ApiResult<Json<Vec<SecretInfo>>> {
+    match state.db.list_secrets(user.user_id).await {
+        Ok(records) => Ok(Json(records.into_iter().map(Into::into).collect())),
+        Err(e) => {
+            tracing::error!("Failed to list secrets: {}", e);
+            Err(database_error(&e))
+        },
+    }
+}
+
+/// PUT /`api/v1/secrets/:name` - Create or replace a secret
+pub async fn set_secret(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path(name): Path<String>,
+    Json(request): Json<SetSecretRequest>,
+) -> ApiResult<Json<SecretInfo>> {
+    let key = credentials_key(&state)?;
+    if let Err(e) = validate_secret(&name, &request.value) {
+        return Err((StatusCode::BAD_REQUEST, Json(e)));
+    }
+
+    let now = Utc::now();
+    let encrypted_value = crypto::encrypt(
+        &key,
+        request.value.as_bytes(),
+        &secret_context(user.user_id, &name),
+    );
+    let record = SecretRecord {
+        user_id: user.user_id,
+        name,
+        encrypted_value,
+        created_at: now,
+        updated_at: now,
+    };
+
+    state.db.set_secret(&record).await.map_err(|e| {
+        tracing::error!("Failed to set secret: {}", e);
+        database_error(&e)
+    })?;
+
+    tracing::info!(user_id = %user.user_id, name = %record.name, "Set secret");
+
+    // Return what was stored, with the original creation time of a replaced secret
+    let stored = state
+        .db
+        .list_secrets(user.user_id)
+        .await
+        .map_err(|e| database_error(&e))?
+        .into_iter()
+        .find(|secret| secret.name == record.name)
+        .unwrap_or(record);
+
+    Ok(Json(stored.into()))
+}
+
+/// DELETE /`api/v1/secrets/:name` - Delete a secret
+pub async fn delete_secret(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path(name): Path<String>,
+) -> ApiResult<StatusCode> {
+    match state.db.delete_secret(user.user_id, &name).await {
+        Ok(true) => {
+            tracing::info!(user_id = %user.user_id, name = %name, "Deleted secret");
+            Ok(StatusCode::NO_CONTENT)
+        },
+        Ok(false) => Err((
+            StatusCode::NOT_FOUND,
+            Json(ApiError::new("Secret not found", "not_found")),
+        )),
+        Err(e) => {
+            tracing::error!("Failed to delete secret: {}", e);
+            Err(database_error(&e))
+        },
+    }
+}
+
+/// Check that `user_id` has stored every secret in `names`
+pub async fn check_available(state: &AppState, user_id: Uuid, names: &[String]) -> ApiResult<()> {
+    if names.is_empty() {
+        return Ok(());
+    }
+    credentials_key(state)?;
+
+    let stored = state
+        .db
+        .list_secrets(user_id)
+        .await
+        .map_err(|e| database_error(&e))?;
+    let missing: Vec<&str> = names
+        .iter()
+        .filter(|name| !stored.iter().any(|secret| &secret.name == *name))
+        .map(String::as_str)
+        .collect();
+
+    if !missing.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(ApiError::new(
+                format!("Unknown secrets: {}", missing.join(", ")),
+                "validation_error",
+            )),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Decrypt the secrets a claimed job exports, by name
+pub async fn decrypt_for_job(
+    state: &AppState,
+    job: &Job,
+) -> anyhow::Result<BTreeMap<String, String>> {
+    if job.secrets.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+
+    let key = state
+        .config
+        .credentials_key
+        .ok_or_else(|| anyhow::anyhow!("CREDENTIALS_KEY is not set"))?;
+    let stored = state.db.list_secrets(job.customer_id).await?;
+
+    job.secrets
+        .iter()
+        .map(|name| {
+            let record = stored
+                .iter()
+                .find(|secret| &secret.name == name)
+                .ok_or_else(|| anyhow::anyhow!("Secret {name} no longer exists"))?;
+            let value = crypto::decrypt(
+                &key,
+                &record.encrypted_value,
+                &secret_context(job.customer_id, name),
+            )
+            .and_then(|value| String::from_utf8(value).ok())
+            .ok_or_else(|| {
+                anyhow::anyhow!("Secret {name} could not be decrypted (CREDENTIALS_KEY changed?)")
+            })?;
+            Ok((name.clone(), value))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_secret() {
+        // Valid
+        assert!(validate_secret("MATCH_PASSWORD", "hunter2").is_ok());
+        assert!(validate_secret("_KEY_1", "line 1\nline 2").is_ok());
+
+        // Invalid
+        assert!(validate_secret("1PASSWORD", "hunter2").is_err());
+        assert!(validate_secret("MATCH-PASSWORD", "hunter2").is_err());
+        assert!(validate_secret("", "hunter2").is_err());
+        assert!(validate_secret("KEY", "").is_err());
+        assert!(validate_secret("KEY", "a\0b").is_err());
+        assert!(validate_secret("KEY", &"x".repeat(MAX_SECRET_BYTES + 1)).is_err());
+    }
+}
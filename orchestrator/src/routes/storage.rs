@@ -0,0 +1,64 @@
+//! Signed object download endpoint for the local storage backend
+
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+
+use crate::state::AppState;
+use crate::storage::{local, StorageBackend};
+use shared::ApiError;
+
+#[derive(Debug, Deserialize)]
+pub struct SignedQuery {
+    pub expires: i64,
+    pub signature: String,
+}
+
+/// GET /`api/v1/storage/*key` - Download an object through a signed URL
+pub async fn download_object(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    Query(query): Query<SignedQuery>,
+) -> Result<Response, (StatusCode, Json<ApiError>)> {
+    // Only the local backend hands out orchestrator-served URLs
+    let StorageBackend::Local { signing_key, .. } = &state.config.storage else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(ApiError::new("Object not found", "not_found")),
+        ));
+    };
+
+    if !local::verify(signing_key, &key, query.expires, &query.signature) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            Json(ApiError::new(
+                "Invalid or expired signature",
+                "invalid_signature",
+            )),
+        ));
+    }
+
+    match state.storage.get(&key).await {
+        Ok(Some(stream)) => Ok((
+            [(header::CONTENT_TYPE, "application/octet-stream")],
+            Body::from_stream(stream),
+        )
+            .into_response()),
+        Ok(None) => Err((
+            StatusCode::NOT_FOUND,
+            Json(ApiError::new("Object not found", "not_found")),
+        )),
+        Err(e) => {
+            tracing::error!("Failed to read object: {}", e);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiError::new(e.to_string(), "storage_error")),
+            ))
+        },
+    }
+}
@@ -9,11 +9,18 @@ use chrono::Utc;
 use uuid::Uuid;
 
 use crate::state::AppState;
+use crate::storage::{self, SIGNED_URL_TTL};
 use shared::{
-    ApiError, ClaimJobRequest, Job, JobResult, JobStatus, RegisterWorkerRequest,
-    RegisterWorkerResponse, WorkerHeartbeat, WorkerInfo, WorkerStatus,
+    ApiError, Artifact, ClaimJobRequest, ClaimedJob, FailureReason, Job, JobResult, JobStatus,
+    LogEntry, LogMask, LogStream, RegisterWorkerRequest, RegisterWorkerResponse, ReleaseJobRequest,
+    SourceType, StepResult, WorkerCommand, WorkerHeartbeat, WorkerHeartbeatResponse, WorkerInfo,
+    WorkerStatus,
 };
 
+/// How long a job requeued after an infrastructure failure is kept from the
+/// worker it failed on, so another worker can pick it up first
+const INFRA_RETRY_AVOID_SECS: i64 = 60;
+
 /// POST /api/v1/workers/register - Register a new worker
 pub async fn register_worker(
     State(state): State<AppState>,
@@ -22,7 +29,7 @@ pub async fn register_worker(
     let worker_id = if let Some(id) = request.worker_id {
         if state.workers.read().await.contains_key(&id) {
             tracing::info!("Worker {} re-registering", id);
-        } else if let Ok(Some(_)) = state.supabase.get_worker(id).await {
+        } else if let Ok(Some(_)) = state.db.get_worker(id).await {
             tracing::info!("Worker {} found in DB, re-registering", id);
         } else {
             tracing::info!("Worker {} claiming new ID (not found in DB/cache)", id);
@@ -32,6 +39,11 @@ pub async fn register_worker(
         Uuid::new_v4()
     };
 
+    let labels = crate::routing::normalize_worker_names(&request.labels, "label")
+        .map_err(|e| (StatusCode::BAD_REQUEST, Json(e)))?;
+    let images = crate::routing::normalize_worker_names(&request.images, "image")
+        .map_err(|e| (StatusCode::BAD_REQUEST, Json(e)))?;
+
     let token = Uuid::new_v4().to_string(); // Simple token for now
 
     let worker = WorkerInfo {
@@ -41,6 +53,9 @@ pub async fn register_worker(
         current_jobs: 0,
         last_heartbeat: Utc::now(),
         status: WorkerStatus::Online,
+        labels,
+        capabilities: request.capabilities,
+        images,
     };
 
     // Store in memory cache
@@ -50,23 +65,18 @@ pub async fn register_worker(
         .await
         .insert(worker_id, worker.clone());
 
-    // Also persist to Supabase
-    // We use upsert behavior here (register_worker usually does insert, make sure it supports update or we use a separate method)
-    // The previous code used `register_worker`. I need to check `state.supabase.register_worker` implementation.
-    // Assuming `register_worker` in supabase client does an upsert or INSERT.
-    // If it's strict INSERT, this might fail on re-registration.
-    // Let's check `state.supabase` methods first. actually I should have checked this in planning.
-    // I'll assume `register_worker` might need adjustment or we use `update_worker_status`.
-    // But `register_worker` likely updates all fields (hostname, capacity).
-    // To be safe, I'll assume I need to check `SupabaseClient` implementation.
-    // For now I will proceed assuming I can fix `SupabaseClient` if needed.
-
-    if let Err(e) = state.supabase.register_worker(&worker).await {
+    // Also persist it; registering again updates the stored worker
+    if let Err(e) = state.db.register_worker(&worker).await {
         tracing::warn!("Failed to persist worker to DB: {}", e);
-        // If it failed because of conflict, we might want to try update.
     }
 
-    tracing::info!(worker_id = %worker_id, hostname = %request.hostname, "Worker registered");
+    tracing::info!(
+        worker_id = %worker_id,
+        hostname = %request.hostname,
+        labels = %worker.all_labels().join(","),
+        images = %worker.images.join(","),
+        "Worker registered"
+    );
 
     Ok((
         StatusCode::CREATED,
@@ -78,41 +88,189 @@ pub async fn register_worker(
 pub async fn heartbeat(
     State(state): State<AppState>,
     Json(request): Json<WorkerHeartbeat>,
-) -> Result<StatusCode, (StatusCode, Json<ApiError>)> {
-    let mut workers = state.workers.write().await;
+) -> Result<Json<WorkerHeartbeatResponse>, (StatusCode, Json<ApiError>)> {
+    record_heartbeat(&state, &request).await.map(Json)
+}
 
-    if let Some(worker) = workers.get_mut(&request.worker_id) {
+/// Record a worker's heartbeat, posted or sent on its channel, renewing the
+/// leases on its jobs
+pub(super) async fn record_heartbeat(
+    state: &AppState,
+    request: &WorkerHeartbeat,
+) -> Result<WorkerHeartbeatResponse, (StatusCode, Json<ApiError>)> {
+    let (was_offline, drain) = {
+        let mut workers = state.workers.write().await;
+        let Some(worker) = workers.get_mut(&request.worker_id) else {
+            return Err((
+                StatusCode::NOT_FOUND,
+                Json(ApiError::new(
+                    format!("Worker {} not found", request.worker_id),
+                    "worker_not_found",
+                )),
+            ));
+        };
+
+        let was_offline = worker.status == WorkerStatus::Offline;
+        let drain = worker.status == WorkerStatus::Draining;
         worker.last_heartbeat = Utc::now();
         worker.current_jobs = request.current_jobs;
-        worker.status = if request.current_jobs >= request.capacity {
+        worker.status = if drain {
+            WorkerStatus::Draining
+        } else if request.current_jobs >= request.capacity {
             WorkerStatus::Busy
         } else {
             WorkerStatus::Online
         };
-        Ok(StatusCode::OK)
-    } else {
-        Err((
-            StatusCode::NOT_FOUND,
-            Json(ApiError::new(
-                format!("Worker {} not found", request.worker_id),
-                "worker_not_found",
-            )),
-        ))
+        drop(workers);
+        (was_offline, drain)
+    };
+
+    if was_offline {
+        tracing::info!(worker_id = %request.worker_id, "Worker is back online");
+        if let Err(e) = state
+            .db
+            .update_worker_status(request.worker_id, WorkerStatus::Online)
+            .await
+        {
+            tracing::warn!("Failed to persist worker status: {}", e);
+        }
+    }
+
+    if let Err(e) = state.db.update_worker_heartbeat(request.worker_id).await {
+        tracing::warn!("Failed to persist heartbeat: {}", e);
+    }
+
+    // Extend the leases on everything the worker is still running
+    let lease_expires_at = Utc::now() + state.config.job_lease();
+    if let Err(e) = state
+        .db
+        .renew_job_leases(request.worker_id, &request.job_ids, lease_expires_at)
+        .await
+    {
+        tracing::error!("Failed to renew job leases: {}", e);
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiError::new(e.to_string(), "database_error")),
+        ));
     }
+
+    // Tell the worker which of its jobs were cancelled so it can stop them
+    let mut cancelled_job_ids = Vec::new();
+    for &job_id in &request.job_ids {
+        match state.db.get_job(job_id).await {
+            Ok(Some(job))
+                if job.status == JobStatus::Cancelled
+                    && job.worker_id == Some(request.worker_id) =>
+            {
+                cancelled_job_ids.push(job_id);
+            },
+            Ok(_) => {},
+            Err(e) => tracing::warn!(job_id = %job_id, "Failed to check job status: {}", e),
+        }
+    }
+
+    Ok(WorkerHeartbeatResponse {
+        cancelled_job_ids,
+        drain,
+    })
 }
 
 /// POST /api/v1/workers/claim - Worker claims a pending job
 pub async fn claim_job(
     State(state): State<AppState>,
     Json(request): Json<ClaimJobRequest>,
-) -> Result<Json<Option<Job>>, (StatusCode, Json<ApiError>)> {
-    // Find a pending job and assign it to this worker
-    match state.supabase.claim_pending_job(request.worker_id).await {
-        Ok(job) => {
-            if let Some(ref j) = job {
-                tracing::info!(job_id = %j.id, worker_id = %request.worker_id, "Job claimed");
+) -> Result<Json<Option<ClaimedJob>>, (StatusCode, Json<ApiError>)> {
+    claim_for_worker(&state, request.worker_id).await.map(Json)
+}
+
+/// Claim a pending job for a worker, polling or waiting on its channel, with
+/// what it needs to run it
+pub(super) async fn claim_for_worker(
+    state: &AppState,
+    worker_id: Uuid,
+) -> Result<Option<ClaimedJob>, (StatusCode, Json<ApiError>)> {
+    // Find a pending job this worker has the labels and image for and assign
+    // it (an unknown worker only gets jobs that need neither); a draining
+    // worker gets none
+    let worker = crate::routing::find_worker(state, worker_id).await;
+    if worker
+        .as_ref()
+        .is_some_and(|worker| worker.status == WorkerStatus::Draining)
+    {
+        return Ok(None);
+    }
+    let (labels, images) = worker
+        .map(|worker| (worker.all_labels(), worker.images))
+        .unwrap_or_default();
+    let lease_expires_at = Utc::now() + state.config.job_lease();
+    match state
+        .db
+        .claim_pending_job(worker_id, &labels, &images, lease_expires_at)
+        .await
+    {
+        Ok(mut job) => {
+            if let Some(ref mut j) = job {
+                tracing::info!(job_id = %j.id, worker_id = %worker_id, "Job claimed");
+
+                // Uploaded sources are stored by key; hand the worker a signed URL
+                if j.source_type == SourceType::Upload {
+                    if let Some(key) = &j.source_url {
+                        match state.storage.signed_url(key, SIGNED_URL_TTL).await {
+                            Ok(url) => j.source_url = Some(url),
+                            Err(e) => tracing::error!("Failed to sign source URL: {}", e),
+                        }
+                    }
+                }
             }
-            Ok(Json(job))
+
+            let Some(job) = job else {
+                return Ok(None);
+            };
+
+            // Without its secrets the build can't run as asked
+            let secret_values = match super::secrets::decrypt_for_job(state, &job).await {
+                Ok(values) => values,
+                Err(e) => {
+                    tracing::error!(job_id = %job.id, "Failed to load secrets, failing job: {}", e);
+                    fail_claimed_job(state, &job, worker_id, &e.to_string()).await;
+                    return Ok(None);
+                },
+            };
+            // The worker masks them in the build's output, and so do we
+            let mask = LogMask::new(secret_values.values().map(String::as_str));
+            state.reset_log_cursor(job.id, worker_id, mask).await;
+
+            // Copy in what the jobs it needs produced
+            let upstream_artifacts = match upstream_artifacts(state, &job).await {
+                Ok(artifacts) => artifacts,
+                Err(e) => {
+                    tracing::error!(job_id = %job.id, "Failed to load needed artifacts, failing job: {}", e);
+                    fail_claimed_job(state, &job, worker_id, &e.to_string()).await;
+                    return Ok(None);
+                },
+            };
+
+            // Private repositories: decrypt the credential for this worker only
+            let git_credential = match job.git_credential_id {
+                Some(credential_id) => {
+                    match super::credentials::decrypt_for_job(state, credential_id).await {
+                        Ok(credential) => Some(credential),
+                        Err(e) => {
+                            tracing::error!(job_id = %job.id, "Failed to load git credential, failing job: {}", e);
+                            fail_claimed_job(state, &job, worker_id, &e.to_string()).await;
+                            return Ok(None);
+                        },
+                    }
+                },
+                None => None,
+            };
+
+            Ok(Some(ClaimedJob {
+                job,
+                git_credential,
+                secret_values,
+                upstream_artifacts,
+            }))
         },
         Err(e) => {
             tracing::error!("Failed to claim job: {}", e);
@@ -124,39 +282,118 @@ pub async fn claim_job(
     }
 }
 
+/// Artifacts of the jobs `job` needs, with download URLs signed for the worker
+async fn upstream_artifacts(state: &AppState, job: &Job) -> anyhow::Result<Vec<Artifact>> {
+    let mut artifacts = Vec::new();
+    for &needed in &job.needs {
+        for mut artifact in state.db.get_job_artifacts(needed).await? {
+            let key = storage::artifact_key(needed, &artifact.name);
+            artifact.download_url = Some(state.storage.signed_url(&key, SIGNED_URL_TTL).await?);
+            artifacts.push(artifact);
+        }
+    }
+    Ok(artifacts)
+}
+
+/// Fail a job the orchestrator can't hand to the worker that claimed it,
+/// telling anyone following its logs why
+async fn fail_claimed_job(state: &AppState, job: &Job, worker_id: Uuid, reason: &str) {
+    if let Some(tx) = state.get_log_stream(job.id).await {
+        let entry = LogEntry {
+            job_id: job.id,
+            timestamp: Utc::now(),
+            stream: LogStream::Stderr,
+            content: format!("Job could not be started: {reason}"),
+        };
+        let _ = tx.send(serde_json::to_string(&entry).unwrap());
+    }
+    state.remove_log_stream(job.id).await;
+
+    if let Err(e) = state
+        .db
+        .complete_job(
+            job.id,
+            worker_id,
+            JobStatus::Failed,
+            Some(FailureReason::Infra),
+            -1,
+            0.0,
+        )
+        .await
+    {
+        tracing::error!(job_id = %job.id, "Failed to fail job: {}", e);
+    }
+    crate::dependencies::cancel_dependents(state, job.id).await;
+}
+
 /// POST /`api/v1/workers/:worker_id/complete` - Mark a job as complete
 pub async fn complete_job(
     State(state): State<AppState>,
     Path(worker_id): Path<Uuid>,
     Json(result): Json<JobResult>,
 ) -> Result<StatusCode, (StatusCode, Json<ApiError>)> {
-    // Update job status
-    let status = if result.exit_code == 0 {
-        JobStatus::Completed
-    } else {
-        JobStatus::Failed
-    };
+    let (status, failure_reason) = outcome(&result);
+
+    if status == JobStatus::Failed && failure_reason == Some(FailureReason::Infra) {
+        match requeue_infra_failure(&state, result.job_id, worker_id).await {
+            Ok(true) => return Ok(StatusCode::OK),
+            Ok(false) => {},
+            Err(e) => {
+                tracing::error!("Failed to requeue job: {}", e);
+                return Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(ApiError::new(e.to_string(), "database_error")),
+                ));
+            },
+        }
+    }
 
     match state
-        .supabase
+        .db
         .complete_job(
             result.job_id,
+            worker_id,
             status,
+            failure_reason,
             result.exit_code,
             result.build_minutes,
         )
         .await
     {
-        Ok(()) => {
+        Ok(false) => {
+            // The lease expired and the job was reclaimed, or it was cancelled
+            tracing::warn!(
+                job_id = %result.job_id,
+                worker_id = %worker_id,
+                "Ignoring result for job no longer assigned to this worker"
+            );
+            Err((
+                StatusCode::CONFLICT,
+                Json(ApiError::new(
+                    "Job is no longer assigned to this worker",
+                    "job_not_assigned",
+                )),
+            ))
+        },
+        Ok(true) => {
+            // A failure the job's retry policy covers becomes its next attempt
+            let retry = if status.stopped_short() {
+                crate::retries::retry_finished(&state, result.job_id).await
+            } else {
+                None
+            };
+
             // Send completion message via log stream before closing
             if let Some(tx) = state.get_log_stream(result.job_id).await {
                 let completion_msg = serde_json::json!({
                     "type": "job_complete",
                     "job_id": result.job_id.to_string(),
                     "status": format!("{:?}", status),
+                    "failure_reason": failure_reason,
                     "exit_code": result.exit_code,
                     "build_minutes": result.build_minutes,
                     "artifacts_count": result.artifacts.len(),
+                    "retry_job_id": retry,
                 });
                 let _ = tx.send(completion_msg.to_string());
             }
@@ -164,17 +401,22 @@ pub async fn complete_job(
             // Clean up log stream
             state.remove_log_stream(result.job_id).await;
 
-            // Upload artifacts if any
-            for artifact in result.artifacts {
-                if let Err(e) = state
-                    .supabase
-                    .store_artifact(result.job_id, &artifact)
-                    .await
-                {
-                    tracing::warn!("Failed to store artifact: {}", e);
-                }
+            // Jobs that need a retried job wait for its retry instead
+            if status.stopped_short() && retry.is_none() {
+                crate::dependencies::cancel_dependents(&state, result.job_id).await;
             }
 
+            record_result(
+                &state,
+                result.job_id,
+                result.resolved_sha.as_deref(),
+                &result.steps,
+                result.artifacts,
+            )
+            .await;
+            // Jobs that needed this one, or its retry, may be claimable now
+            state.wake_workers();
+
             tracing::info!(
                 job_id = %result.job_id,
                 worker_id = %worker_id,
@@ -194,6 +436,154 @@ pub async fn complete_job(
     }
 }
 
+/// Record what a finished job's worker reported beyond its status
+async fn record_result(
+    state: &AppState,
+    job_id: Uuid,
+    resolved_sha: Option<&str>,
+    steps: &[StepResult],
+    artifacts: Vec<Artifact>,
+) {
+    if let Some(sha) = resolved_sha {
+        if let Err(e) = state.db.set_resolved_sha(job_id, sha).await {
+            tracing::warn!("Failed to record resolved SHA: {}", e);
+        }
+    }
+
+    if !steps.is_empty() {
+        if let Err(e) = state.db.set_step_results(job_id, steps).await {
+            tracing::warn!("Failed to record step results: {}", e);
+        }
+    }
+
+    for artifact in artifacts {
+        if let Err(e) = state.db.store_artifact(job_id, &artifact).await {
+            tracing::warn!("Failed to store artifact: {}", e);
+        }
+    }
+}
+
+/// The status a job's result leaves it in, and why it didn't succeed
+fn outcome(result: &JobResult) -> (JobStatus, Option<FailureReason>) {
+    let status = if result.cancelled {
+        JobStatus::Cancelled
+    } else if result.timed_out {
+        JobStatus::TimedOut
+    } else if result.exit_code == 0 {
+        JobStatus::Completed
+    } else {
+        JobStatus::Failed
+    };
+
+    // Older workers don't say why a job failed
+    let failure_reason = if status == JobStatus::Completed {
+        None
+    } else {
+        result.failure_reason.or_else(|| status.failure_reason())
+    };
+    (status, failure_reason)
+}
+
+/// Put a job that failed on `worker_id` for infrastructure reasons back in the
+/// queue, unless it has used up its retries; returns whether it was requeued
+async fn requeue_infra_failure(
+    state: &AppState,
+    job_id: Uuid,
+    worker_id: Uuid,
+) -> anyhow::Result<bool> {
+    let limit = state.config.infra_retry_limit;
+    let Some(job) = state.db.get_job(job_id).await? else {
+        return Ok(false);
+    };
+    if job.infra_retries >= limit {
+        return Ok(false);
+    }
+
+    let worker = crate::routing::find_worker(state, worker_id)
+        .await
+        .map_or_else(|| worker_id.to_string(), |worker| worker.hostname);
+    let reason = format!(
+        "Infrastructure failure on worker {worker}; requeued (retry {} of {limit})",
+        job.infra_retries + 1
+    );
+    let avoid_until = Utc::now() + chrono::Duration::seconds(INFRA_RETRY_AVOID_SECS);
+    if !state
+        .db
+        .requeue_job(job_id, worker_id, limit, avoid_until, &reason)
+        .await?
+    {
+        return Ok(false);
+    }
+
+    tracing::warn!(job_id = %job_id, worker_id = %worker_id, "Job hit an infrastructure failure, requeued");
+    state.wake_workers();
+
+    // Anyone following the logs keeps following the next attempt
+    if let Some(tx) = state.get_log_stream(job_id).await {
+        let message = serde_json::json!({
+            "type": "job_reclaimed",
+            "job_id": job_id.to_string(),
+            "status": JobStatus::Pending.to_string(),
+            "reason": reason,
+        });
+        let _ = tx.send(message.to_string());
+    }
+
+    Ok(true)
+}
+
+/// POST `/api/v1/workers/:worker_id/release` - Hand back a job the worker
+/// claimed but never started, without counting it against its retries
+pub async fn release_job(
+    State(state): State<AppState>,
+    Path(worker_id): Path<Uuid>,
+    Json(request): Json<ReleaseJobRequest>,
+) -> Result<StatusCode, (StatusCode, Json<ApiError>)> {
+    let worker = crate::routing::find_worker(&state, worker_id)
+        .await
+        .map_or_else(|| worker_id.to_string(), |worker| worker.hostname);
+    let reason = format!("Handed back by worker {worker}; requeued");
+
+    match state
+        .db
+        .release_job(request.job_id, worker_id, &reason)
+        .await
+    {
+        Ok(true) => {},
+        Ok(false) => {
+            return Err((
+                StatusCode::CONFLICT,
+                Json(ApiError::new(
+                    "Job is no longer assigned to this worker",
+                    "job_not_assigned",
+                )),
+            ));
+        },
+        Err(e) => {
+            tracing::error!("Failed to release job: {}", e);
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiError::new(e.to_string(), "database_error")),
+            ));
+        },
+    }
+
+    tracing::info!(job_id = %request.job_id, worker_id = %worker_id, "Job handed back, requeued");
+    state.wake_workers();
+
+    if let Some(tx) = state.get_log_stream(request.job_id).await {
+        let message = serde_json::json!({
+            "type": "job_reclaimed",
+            "job_id": request.job_id.to_string(),
+            "status": JobStatus::Pending.to_string(),
+            "reason": reason,
+        });
+        let _ = tx.send(message.to_string());
+    }
+
+    Ok(StatusCode::OK)
+}
+
 /// POST `/api/v1/workers/:worker_id/deregister` - Deregister a worker (mark as offline)
 pub async fn deregister_worker(
     State(state): State<AppState>,
@@ -209,7 +599,7 @@ pub async fn deregister_worker(
 
     // Update in database
     if let Err(e) = state
-        .supabase
+        .db
         .update_worker_status(worker_id, WorkerStatus::Offline)
         .await
     {
@@ -219,3 +609,40 @@ pub async fn deregister_worker(
     tracing::info!(worker_id = %worker_id, "Worker deregistered (offline)");
     Ok(StatusCode::OK)
 }
+
+/// POST `/api/v1/workers/:worker_id/drain` - Stop giving a worker jobs and have
+/// it shut down once the ones it runs finish
+pub async fn drain_worker(
+    State(state): State<AppState>,
+    Path(worker_id): Path<Uuid>,
+) -> Result<StatusCode, (StatusCode, Json<ApiError>)> {
+    let known = state
+        .workers
+        .write()
+        .await
+        .get_mut(&worker_id)
+        .map(|worker| worker.status = WorkerStatus::Draining)
+        .is_some();
+    if !known {
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(ApiError::new(
+                format!("Worker {worker_id} not found"),
+                "worker_not_found",
+            )),
+        ));
+    }
+
+    if let Err(e) = state
+        .db
+        .update_worker_status(worker_id, WorkerStatus::Draining)
+        .await
+    {
+        tracing::warn!("Failed to update worker status in DB: {}", e);
+    }
+
+    // A worker without a channel open hears it on its next heartbeat
+    let pushed = state.send_to_worker(worker_id, WorkerCommand::Drain).await;
+    tracing::info!(worker_id = %worker_id, pushed, "Worker draining");
+    Ok(StatusCode::ACCEPTED)
+}
@@ -0,0 +1,259 @@
+//! Routing jobs to workers by label and image
+//!
+//! A job lists the labels a worker must have to run it (`runs_on`), and may
+//! name the base image it runs in. Workers register labels of their own plus
+//! the capabilities they discovered on their host, from which more labels are
+//! derived (see `WorkerInfo::all_labels`), and the images they keep VMs of.
+//! They are only handed jobs whose labels they all have and whose image they
+//! offer.
+
+use std::collections::BTreeMap;
+
+use uuid::Uuid;
+
+use crate::state::AppState;
+use shared::{ApiError, Job, JobStatus, Matrix, WorkerInfo, WorkerStatus};
+
+/// A job's label or image for one matrix cell: `${AXIS}` is replaced with the
+/// cell's value for the axis (e.g. `xcode-${XCODE}`), and the result lowercased
+pub fn expand_label(label: &str, cell: &BTreeMap<String, String>) -> String {
+    let mut label = label.to_string();
+    for (axis, value) in cell {
+        label = label.replace(&format!("${{{axis}}}"), value);
+    }
+    label.to_lowercase()
+}
+
+/// A job's labels for one matrix cell (see `expand_label`)
+pub fn expand_labels(runs_on: &[String], cell: &BTreeMap<String, String>) -> Vec<String> {
+    let mut labels: Vec<String> = Vec::new();
+    for label in runs_on {
+        let label = expand_label(label, cell);
+        if !labels.contains(&label) {
+            labels.push(label);
+        }
+    }
+    labels
+}
+
+/// Check a job's labels and image as expanded for every cell of its matrix
+pub fn validate(runs_on: &[String], image: Option<&str>, matrix: &Matrix) -> Result<(), ApiError> {
+    let invalid = |message: String| ApiError::new(message, "validation_error");
+
+    if runs_on.len() > shared::MAX_LABELS {
+        return Err(invalid(format!(
+            "A job can require at most {} labels",
+            shared::MAX_LABELS
+        )));
+    }
+
+    for cell in crate::matrix::cells(matrix) {
+        if let Some(label) = expand_labels(runs_on, &cell)
+            .into_iter()
+            .find(|label| !shared::is_valid_label(label))
+        {
+            return Err(invalid(format!("Invalid runs_on label: {label:?}")));
+        }
+        if let Some(image) = image.map(|image| expand_label(image, &cell)) {
+            if !shared::is_valid_label(&image) {
+                return Err(invalid(format!("Invalid image name: {image:?}")));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Check the labels or image names (`what`) a worker registers with,
+/// lowercasing them
+pub fn normalize_worker_names(names: &[String], what: &str) -> Result<Vec<String>, ApiError> {
+    let names = expand_labels(names, &BTreeMap::new());
+    if names.len() > shared::MAX_LABELS {
+        return Err(ApiError::new(
+            format!("A worker can have at most {} {what}s", shared::MAX_LABELS),
+            "validation_error",
+        ));
+    }
+    if let Some(name) = names.iter().find(|name| !shared::is_valid_label(name)) {
+        return Err(ApiError::new(
+            format!("Invalid worker {what}: {name:?}"),
+            "validation_error",
+        ));
+    }
+    Ok(names)
+}
+
+/// A worker, from the cache or else the database
+pub async fn find_worker(state: &AppState, worker_id: Uuid) -> Option<WorkerInfo> {
+    if let Some(worker) = state.workers.read().await.get(&worker_id) {
+        return Some(worker.clone());
+    }
+
+    match state.db.get_worker(worker_id).await {
+        Ok(worker) => worker,
+        Err(e) => {
+            tracing::warn!(worker_id = %worker_id, "Failed to load worker: {}", e);
+            None
+        },
+    }
+}
+
+/// What a job needs that a worker lacks: the job's image, then its labels
+fn missing(worker: &WorkerInfo, job: &Job) -> Vec<String> {
+    let image = job
+        .image
+        .as_ref()
+        .filter(|image| !worker.images.contains(image))
+        .map(|image| format!("image {image}"));
+    image
+        .into_iter()
+        .chain(worker.missing_labels(&job.runs_on))
+        .collect()
+}
+
+/// Why no worker can run a job, if none online has its image and all of its
+/// labels
+///
+/// Names the worker missing the fewest, to show how close the farm is.
+pub fn unmatched_reason(workers: &[WorkerInfo], job: &Job) -> Option<String> {
+    let online = workers
+        .iter()
+        .filter(|worker| matches!(worker.status, WorkerStatus::Online | WorkerStatus::Busy));
+
+    let mut closest: Option<(&WorkerInfo, Vec<String>)> = None;
+    for worker in online {
+        let missing = missing(worker, job);
+        if missing.is_empty() {
+            return None;
+        }
+        if closest
+            .as_ref()
+            .is_none_or(|(_, fewest)| missing.len() < fewest.len())
+        {
+            closest = Some((worker, missing));
+        }
+    }
+
+    let labels = job.runs_on.join(", ");
+    let reason = match &job.image {
+        None => format!("No online worker has all of the labels: {labels}"),
+        Some(image) if job.runs_on.is_empty() => {
+            format!("No online worker has the image {image}")
+        },
+        Some(image) => {
+            format!("No online worker has the image {image} and all of the labels: {labels}")
+        },
+    };
+    Some(match closest {
+        Some((worker, missing)) => format!(
+            "{reason} (closest: {} lacks {})",
+            worker.hostname,
+            missing.join(", ")
+        ),
+        None => reason,
+    })
+}
+
+/// Explain pending jobs that no online worker can run in their `status_reason`
+pub async fn explain_unmatched(state: &AppState, jobs: &mut [Job]) {
+    let routed = |job: &Job| {
+        job.status == JobStatus::Pending && (!job.runs_on.is_empty() || job.image.is_some())
+    };
+    if !jobs.iter().any(routed) {
+        return;
+    }
+
+    let workers: Vec<WorkerInfo> = state.workers.read().await.values().cloned().collect();
+    for job in jobs.iter_mut().filter(|job| routed(job)) {
+        if let Some(reason) = unmatched_reason(&workers, job) {
+            job.status_reason = Some(reason);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn labels(labels: &[&str]) -> Vec<String> {
+        labels.iter().map(ToString::to_string).collect()
+    }
+
+    fn job(runs_on: &[&str], image: Option<&str>) -> Job {
+        let mut job = Job::with_command(
+            Uuid::new_v4(),
+            "make".to_string(),
+            shared::SourceType::Git,
+            None,
+        );
+        job.runs_on = labels(runs_on);
+        job.image = image.map(str::to_string);
+        job
+    }
+
+    fn worker(hostname: &str, labels: &[&str], status: WorkerStatus) -> WorkerInfo {
+        WorkerInfo {
+            id: Uuid::new_v4(),
+            hostname: hostname.to_string(),
+            capacity: 2,
+            current_jobs: 0,
+            last_heartbeat: Utc::now(),
+            status,
+            labels: self::labels(labels),
+            capabilities: shared::WorkerCapabilities {
+                xcode_versions: vec!["16.0".to_string()],
+                ..Default::default()
+            },
+            images: vec!["default".to_string(), "sonoma".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let matrix: Matrix = [("XCODE".to_string(), labels(&["15.4", "16.0"]))].into();
+        assert!(validate(&labels(&["xcode-${XCODE}", "M2"]), None, &matrix).is_ok());
+        assert!(validate(&labels(&["xcode-${SCHEME}"]), None, &matrix).is_err());
+        assert!(validate(&labels(&["apple silicon"]), None, &Matrix::new()).is_err());
+        assert!(validate(&labels(&[""]), None, &Matrix::new()).is_err());
+        assert!(validate(&[], Some("Sequoia-Xcode-${XCODE}"), &matrix).is_ok());
+        assert!(validate(&[], Some("sequoia:latest"), &Matrix::new()).is_err());
+
+        let cell = [("XCODE".to_string(), "16.0".to_string())].into();
+        assert_eq!(
+            expand_labels(&labels(&["xcode-${XCODE}", "M2", "m2"]), &cell),
+            ["xcode-16.0", "m2"]
+        );
+    }
+
+    #[test]
+    fn test_unmatched_reason() {
+        let workers = [
+            worker("mini-1", &["m1"], WorkerStatus::Online),
+            worker("mini-2", &["m2"], WorkerStatus::Offline),
+            worker("studio", &["m2", "signing"], WorkerStatus::Busy),
+        ];
+
+        // Derived labels count as the worker's own
+        assert!(unmatched_reason(&workers, &job(&["m1", "xcode-16"], Some("sonoma"))).is_none());
+
+        // Offline workers can't run anything
+        assert_eq!(
+            unmatched_reason(&workers, &job(&["m2", "xcode-15"], None)).unwrap(),
+            "No online worker has all of the labels: m2, xcode-15 (closest: studio lacks xcode-15)"
+        );
+        assert_eq!(
+            unmatched_reason(&[], &job(&["m2"], None)).unwrap(),
+            "No online worker has all of the labels: m2"
+        );
+        assert_eq!(
+            unmatched_reason(&workers, &job(&[], Some("sequoia"))).unwrap(),
+            "No online worker has the image sequoia (closest: mini-1 lacks image sequoia)"
+        );
+        assert_eq!(
+            unmatched_reason(&workers, &job(&["signing"], Some("sequoia"))).unwrap(),
+            "No online worker has the image sequoia and all of the labels: signing \
+             (closest: studio lacks image sequoia)"
+        );
+    }
+}
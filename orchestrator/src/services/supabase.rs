@@ -1,12 +1,39 @@
 //! Supabase client for database and storage operations
 
+use std::time::Duration;
+
 use anyhow::Result;
-use chrono::Utc;
+use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures_util::StreamExt;
 use reqwest::Client;
 use serde_json::json;
 use uuid::Uuid;
 
-use shared::{Artifact, Job, JobStatus, SourceType, WorkerInfo};
+use crate::db::{
+    completable_status, ApiKeyInfo, ApiKeyRecord, Database, GitCredentialRecord, SecretRecord,
+};
+use crate::storage::{ByteStream, Storage};
+use shared::{Artifact, FailureReason, Job, JobStatus, Run, StepResult, WorkerInfo};
+
+/// Number of pending jobs fetched per claim attempt, so losing a race for one
+/// job doesn't leave the worker idle until its next poll
+const CLAIM_CANDIDATES: usize = 5;
+
+/// Timestamp formatted for a `PostgREST` filter (no `+` that would need escaping)
+fn filter_timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+/// Values for a `PostgREST` `in.(...)` filter, each double-quoted so commas,
+/// parentheses, dots and quotes in them are taken literally
+fn filter_list(values: &[String]) -> String {
+    let quoted: Vec<String> = values
+        .iter()
+        .map(|value| format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"")))
+        .collect();
+    format!("({})", quoted.join(","))
+}
 
 /// Client for interacting with Supabase
 #[derive(Clone)]
@@ -33,28 +60,225 @@ impl SupabaseClient {
         format!("{}/storage/v1", self.base_url)
     }
 
-    /// Create a new job in the database
-    pub async fn create_job(&self, job: &Job) -> Result<()> {
+    /// Create a job log entry
+    #[allow(dead_code)]
+    pub async fn create_job_log(&self, job_id: Uuid, content: String) -> Result<()> {
         let response = self
             .client
-            .post(format!("{}/jobs", self.rest_url()))
+            .post(format!("{}/job_logs", self.rest_url()))
             .header("apikey", &self.api_key)
             .header("Authorization", format!("Bearer {}", self.api_key))
             .header("Content-Type", "application/json")
             .header("Prefer", "return=minimal")
             .json(&json!({
-                "id": job.id,
-                "customer_id": job.customer_id,
-                "source_type": job.source_type.to_string(),
-                "source_url": job.source_url,
-                "command": job.command,
-                "script": job.script,
-                "status": job.status.to_string(),
-                "created_at": job.created_at,
+                "job_id": job_id,
+                "content": content,
+            }))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await?;
+            anyhow::bail!("Failed to create job log: {error_text}");
+        }
+
+        Ok(())
+    }
+
+    /// Get logs for a job
+    #[allow(dead_code)]
+    pub async fn get_job_logs(&self, job_id: Uuid) -> Result<Vec<shared::JobLog>> {
+        let response = self
+            .client
+            .get(format!(
+                "{}/job_logs?job_id=eq.{}&order=created_at.asc",
+                self.rest_url(),
+                job_id
+            ))
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await?;
+            anyhow::bail!("Failed to get job logs: {error_text}");
+        }
+
+        let logs: Vec<shared::JobLog> = response.json().await?;
+        Ok(logs)
+    }
+
+    /// Create a signed URL for a file
+    async fn create_signed_url(
+        &self,
+        bucket: &str,
+        path: &str,
+        expiry_seconds: i32,
+    ) -> Result<String> {
+        #[derive(serde::Deserialize)]
+        struct SignedUrlResponse {
+            #[serde(rename = "signedURL")]
+            signed_url: String,
+        }
+
+        let response = self
+            .client
+            .post(format!(
+                "{}/object/sign/{}/{}",
+                self.storage_url(),
+                bucket,
+                path
+            ))
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .header("Content-Type", "application/json")
+            .json(&json!({
+                "expiresIn": expiry_seconds,
             }))
             .send()
             .await?;
 
+        if !response.status().is_success() {
+            let error_text = response.text().await?;
+            anyhow::bail!("Failed to create signed URL: {error_text}");
+        }
+
+        let data: SignedUrlResponse = response.json().await?;
+
+        // Prepend base_url if returned URL is relative
+        let signed_url = if data.signed_url.starts_with('/') {
+            format!("{}{}", self.base_url, data.signed_url)
+        } else {
+            data.signed_url
+        };
+
+        Ok(signed_url)
+    }
+}
+
+#[async_trait]
+impl Storage for SupabaseClient {
+    async fn put(&self, key: &str, body: ByteStream, content_type: &str) -> Result<()> {
+        let response = self
+            .client
+            .post(format!("{}/object/{}", self.storage_url(), key))
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .header("Content-Type", content_type)
+            .header("x-upsert", "true")
+            .body(reqwest::Body::wrap_stream(body))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await?;
+            anyhow::bail!("Failed to upload {key}: {error_text}");
+        }
+
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<ByteStream>> {
+        let response = self
+            .client
+            .get(format!(
+                "{}/object/authenticated/{}",
+                self.storage_url(),
+                key
+            ))
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            // Supabase reports missing objects as 400 or 404 depending on version
+            return Ok(None);
+        }
+
+        Ok(Some(
+            response
+                .bytes_stream()
+                .map(|chunk| chunk.map_err(std::io::Error::other))
+                .boxed(),
+        ))
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        let response = self
+            .client
+            .head(format!(
+                "{}/object/authenticated/{}",
+                self.storage_url(),
+                key
+            ))
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .send()
+            .await?;
+
+        Ok(response.status().is_success())
+    }
+
+    async fn signed_url(&self, key: &str, expires_in: Duration) -> Result<String> {
+        // Keys are "bucket/path"
+        let Some((bucket, path)) = key.split_once('/') else {
+            anyhow::bail!("Invalid storage key: {key}");
+        };
+        let expiry_seconds = i32::try_from(expires_in.as_secs()).unwrap_or(i32::MAX);
+
+        self.create_signed_url(bucket, path, expiry_seconds).await
+    }
+}
+
+/// A job as a row of the `jobs` table
+fn job_row(job: &Job) -> serde_json::Value {
+    json!({
+        "id": job.id,
+        "customer_id": job.customer_id,
+        "source_type": job.source_type.to_string(),
+        "source_url": job.source_url,
+        "command": job.command,
+        "script": job.script,
+        "status": job.status.to_string(),
+        "created_at": job.created_at,
+        "git_ref": job.git_ref,
+        "commit_sha": job.commit_sha,
+        "submodules": job.submodules,
+        "lfs": job.lfs,
+        "git_credential_id": job.git_credential_id,
+        "env": job.env,
+        "secrets": job.secrets,
+        "steps": job.steps,
+        "needs": job.needs,
+        "run_id": job.run_id,
+        "matrix": job.matrix,
+        "runs_on": job.runs_on,
+        "image": job.image,
+        "timeout_minutes": job.timeout_minutes,
+        "attempt": job.attempt,
+        "parent_job_id": job.parent_job_id,
+        "retry": job.retry,
+        "not_before": job.not_before,
+    })
+}
+
+#[async_trait]
+impl Database for SupabaseClient {
+    /// Create a new job in the database
+    async fn create_job(&self, job: &Job) -> Result<()> {
+        let response = self
+            .client
+            .post(format!("{}/jobs", self.rest_url()))
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .header("Content-Type", "application/json")
+            .header("Prefer", "return=minimal")
+            .json(&job_row(job))
+            .send()
+            .await?;
+
         if !response.status().is_success() {
             let error_text = response.text().await?;
             anyhow::bail!("Failed to create job: {error_text}");
@@ -64,7 +288,7 @@ impl SupabaseClient {
     }
 
     /// Get a job by ID
-    pub async fn get_job(&self, job_id: Uuid) -> Result<Option<Job>> {
+    async fn get_job(&self, job_id: Uuid) -> Result<Option<Job>> {
         let response = self
             .client
             .get(format!("{}/jobs?id=eq.{}", self.rest_url(), job_id))
@@ -82,8 +306,13 @@ impl SupabaseClient {
         Ok(jobs.into_iter().next())
     }
 
-    /// List recent jobs with optional status filter
-    pub async fn list_jobs(&self, status: Option<&str>, limit: usize) -> Result<Vec<Job>> {
+    /// List recent jobs with optional status and run filters
+    async fn list_jobs(
+        &self,
+        status: Option<&str>,
+        run_id: Option<Uuid>,
+        limit: usize,
+    ) -> Result<Vec<Job>> {
         let mut url = format!(
             "{}/jobs?order=created_at.desc&limit={}",
             self.rest_url(),
@@ -93,6 +322,9 @@ impl SupabaseClient {
         if let Some(status_filter) = status {
             url = format!("{url}&status=eq.{status_filter}");
         }
+        if let Some(run_id) = run_id {
+            url = format!("{url}&run_id=eq.{run_id}");
+        }
 
         let response = self
             .client
@@ -110,15 +342,167 @@ impl SupabaseClient {
         Ok(response.json().await?)
     }
 
+    /// Update job status (e.g., for cancellation)
+    async fn update_job_status(&self, job_id: Uuid, status: JobStatus) -> Result<()> {
+        let response = self
+            .client
+            .patch(format!("{}/jobs?id=eq.{}", self.rest_url(), job_id))
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .header("Content-Type", "application/json")
+            .json(&json!({
+                "status": status.to_string(),
+                "failure_reason": status.failure_reason(),
+            }))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await?;
+            anyhow::bail!("Failed to update job status: {error_text}");
+        }
+
+        Ok(())
+    }
+
+    /// Cancel the jobs waiting on a job that won't complete
+    async fn cancel_dependents(&self, job_id: Uuid, reason: &str) -> Result<Vec<Uuid>> {
+        // `cs` = the `needs` array contains this job's ID
+        let response = self
+            .client
+            .patch(format!(
+                "{}/jobs?status=in.(pending,uploading)&needs=cs.%5B%22{}%22%5D&select=id",
+                self.rest_url(),
+                job_id
+            ))
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .header("Content-Type", "application/json")
+            .header("Prefer", "return=representation")
+            .json(&json!({
+                "status": "cancelled",
+                "status_reason": reason,
+                "completed_at": Utc::now(),
+                "failure_reason": FailureReason::Cancelled,
+            }))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await?;
+            anyhow::bail!("Failed to cancel dependent jobs: {error_text}");
+        }
+
+        let cancelled: Vec<serde_json::Value> = response.json().await?;
+        Ok(cancelled
+            .iter()
+            .filter_map(|row| row["id"].as_str()?.parse().ok())
+            .collect())
+    }
+
+    /// Point the jobs waiting on a retried job at its retry
+    async fn repoint_needs(&self, from: Uuid, to: Uuid) -> Result<()> {
+        // `cs` = the `needs` array contains the retried job's ID
+        let response = self
+            .client
+            .get(format!(
+                "{}/jobs?status=in.(pending,uploading)&needs=cs.%5B%22{}%22%5D&select=id,needs",
+                self.rest_url(),
+                from
+            ))
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await?;
+            anyhow::bail!("Failed to find dependent jobs: {error_text}");
+        }
+
+        let dependents: Vec<serde_json::Value> = response.json().await?;
+        for dependent in dependents {
+            let Some(id) = dependent["id"].as_str() else {
+                continue;
+            };
+            let needs: Vec<Uuid> = serde_json::from_value(dependent["needs"].clone())?;
+            let needs: Vec<Uuid> = needs
+                .into_iter()
+                .map(|need| if need == from { to } else { need })
+                .collect();
+
+            let response = self
+                .client
+                .patch(format!("{}/jobs?id=eq.{}", self.rest_url(), id))
+                .header("apikey", &self.api_key)
+                .header("Authorization", format!("Bearer {}", self.api_key))
+                .header("Content-Type", "application/json")
+                .header("Prefer", "return=minimal")
+                .json(&json!({ "needs": needs }))
+                .send()
+                .await?;
+
+            if !response.status().is_success() {
+                let error_text = response.text().await?;
+                anyhow::bail!("Failed to update dependent job: {error_text}");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the attempt that retried a job
+    async fn get_retry(&self, job_id: Uuid) -> Result<Option<Job>> {
+        let response = self
+            .client
+            .get(format!(
+                "{}/jobs?parent_job_id=eq.{}&order=created_at.asc&limit=1",
+                self.rest_url(),
+                job_id
+            ))
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await?;
+            anyhow::bail!("Failed to get job retry: {error_text}");
+        }
+
+        let jobs: Vec<Job> = response.json().await?;
+        Ok(jobs.into_iter().next())
+    }
+
     /// Claim a pending job for a worker
-    pub async fn claim_pending_job(&self, worker_id: Uuid) -> Result<Option<Job>> {
-        // Find a pending job
+    async fn claim_pending_job(
+        &self,
+        worker_id: Uuid,
+        labels: &[String],
+        images: &[String],
+        lease_expires_at: DateTime<Utc>,
+    ) -> Result<Option<Job>> {
+        // Find the oldest pending jobs whose dependencies have completed,
+        // whose labels and image the worker has and that aren't avoiding it
+        let image_filter = if images.is_empty() {
+            "image.is.null".to_string()
+        } else {
+            format!("or(image.is.null,image.in.{})", filter_list(images))
+        };
+        let avoid_filter = format!(
+            "or(avoid_worker_id.is.null,avoid_worker_id.neq.{worker_id},avoid_worker_until.lt.{})",
+            filter_timestamp(Utc::now())
+        );
         let response = self
             .client
             .get(format!(
-                "{}/jobs?status=eq.pending&order=created_at.asc&limit=1",
+                "{}/claimable_jobs?order=created_at.asc&limit={CLAIM_CANDIDATES}",
                 self.rest_url()
             ))
+            .query(&[
+                ("runs_on", format!("cd.{}", serde_json::to_string(labels)?)),
+                ("and", format!("({image_filter},{avoid_filter})")),
+            ])
             .header("apikey", &self.api_key)
             .header("Authorization", format!("Bearer {}", self.api_key))
             .send()
@@ -129,13 +513,18 @@ impl SupabaseClient {
             anyhow::bail!("Failed to find pending job: {error_text}");
         }
 
-        let jobs: Vec<Job> = response.json().await?;
+        let candidates: Vec<Job> = response.json().await?;
 
-        if let Some(job) = jobs.into_iter().next() {
-            // Update to running status
+        for candidate in candidates {
+            // Conditional update: only succeeds if the job is still pending, so two
+            // workers racing for the same job can't both win
             let update_response = self
                 .client
-                .patch(format!("{}/jobs?id=eq.{}", self.rest_url(), job.id))
+                .patch(format!(
+                    "{}/jobs?id=eq.{}&status=eq.pending",
+                    self.rest_url(),
+                    candidate.id
+                ))
                 .header("apikey", &self.api_key)
                 .header("Authorization", format!("Bearer {}", self.api_key))
                 .header("Content-Type", "application/json")
@@ -144,132 +533,251 @@ impl SupabaseClient {
                     "status": "running",
                     "worker_id": worker_id,
                     "started_at": Utc::now(),
+                    "lease_expires_at": lease_expires_at,
+                    "status_reason": null,
                 }))
                 .send()
                 .await?;
 
-            if update_response.status().is_success() {
-                let updated_jobs: Vec<Job> = update_response.json().await?;
-                let mut job = updated_jobs.into_iter().next();
-
-                if let Some(ref mut j) = job {
-                    // If source is upload, we need to generate a signed URL
-                    if j.source_type == SourceType::Upload {
-                        if let Some(path) = &j.source_url {
-                            // Extract bucket and path (assumes stored format is "bucket/path")
-                            let parts: Vec<&str> = path.splitn(2, '/').collect();
-                            if parts.len() == 2 {
-                                let bucket = parts[0];
-                                let file_path = parts[1];
-
-                                // Generate signed URL (valid for 1 hour)
-                                match self.create_signed_url(bucket, file_path, 3600).await {
-                                    Ok(url) => {
-                                        j.source_url = Some(url);
-                                    },
-                                    Err(e) => {
-                                        tracing::error!("Failed to sign URL: {}", e);
-                                    },
-                                }
-                            }
-                        }
-                    }
-                }
-                return Ok(job);
+            if !update_response.status().is_success() {
+                let error_text = update_response.text().await?;
+                anyhow::bail!("Failed to claim job: {error_text}");
+            }
+
+            let updated_jobs: Vec<Job> = update_response.json().await?;
+            if let Some(job) = updated_jobs.into_iter().next() {
+                return Ok(Some(job));
             }
+
+            // Another worker claimed it first, try the next one
         }
 
         Ok(None)
     }
 
     /// Complete a job
-    pub async fn complete_job(
+    async fn complete_job(
         &self,
         job_id: Uuid,
+        worker_id: Uuid,
         status: JobStatus,
+        failure_reason: Option<FailureReason>,
         exit_code: i32,
         build_minutes: f64,
-    ) -> Result<()> {
+    ) -> Result<bool> {
         let response = self
             .client
-            .patch(format!("{}/jobs?id=eq.{}", self.rest_url(), job_id))
+            .patch(format!(
+                "{}/jobs?id=eq.{}&worker_id=eq.{}&status=eq.{}",
+                self.rest_url(),
+                job_id,
+                worker_id,
+                completable_status(status)
+            ))
             .header("apikey", &self.api_key)
             .header("Authorization", format!("Bearer {}", self.api_key))
             .header("Content-Type", "application/json")
+            .header("Prefer", "return=representation")
             .json(&json!({
                 "status": status.to_string(),
+                "failure_reason": failure_reason,
                 "exit_code": exit_code,
                 "build_minutes": build_minutes,
                 "completed_at": Utc::now(),
+                "lease_expires_at": null,
+            }))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await?;
+            anyhow::bail!("Failed to complete job: {error_text}");
+        }
+
+        let updated: Vec<Job> = response.json().await?;
+        Ok(!updated.is_empty())
+    }
+
+    /// Requeue a job after an infrastructure failure
+    async fn requeue_job(
+        &self,
+        job_id: Uuid,
+        worker_id: Uuid,
+        max_retries: u32,
+        avoid_until: DateTime<Utc>,
+        reason: &str,
+    ) -> Result<bool> {
+        let Some(job) = self.get_job(job_id).await? else {
+            return Ok(false);
+        };
+        if job.infra_retries >= max_retries {
+            return Ok(false);
+        }
+
+        // Conditional on the retry count too, so a concurrent update can't be lost
+        let response = self
+            .client
+            .patch(format!(
+                "{}/jobs?id=eq.{}&worker_id=eq.{}&status=eq.running&infra_retries=eq.{}",
+                self.rest_url(),
+                job_id,
+                worker_id,
+                job.infra_retries
+            ))
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .header("Content-Type", "application/json")
+            .header("Prefer", "return=representation")
+            .json(&json!({
+                "status": "pending",
+                "worker_id": null,
+                "started_at": null,
+                "lease_expires_at": null,
+                "status_reason": reason,
+                "infra_retries": job.infra_retries + 1,
+                "avoid_worker_id": worker_id,
+                "avoid_worker_until": avoid_until,
             }))
             .send()
             .await?;
 
         if !response.status().is_success() {
             let error_text = response.text().await?;
-            anyhow::bail!("Failed to complete job: {error_text}");
+            anyhow::bail!("Failed to requeue job: {error_text}");
+        }
+
+        let updated: Vec<Job> = response.json().await?;
+        Ok(!updated.is_empty())
+    }
+
+    /// Put a job a worker never started back in the queue
+    async fn release_job(&self, job_id: Uuid, worker_id: Uuid, reason: &str) -> Result<bool> {
+        let response = self
+            .client
+            .patch(format!(
+                "{}/jobs?id=eq.{}&worker_id=eq.{}&status=eq.running",
+                self.rest_url(),
+                job_id,
+                worker_id
+            ))
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .header("Content-Type", "application/json")
+            .header("Prefer", "return=representation")
+            .json(&json!({
+                "status": "pending",
+                "worker_id": null,
+                "started_at": null,
+                "lease_expires_at": null,
+                "status_reason": reason,
+            }))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await?;
+            anyhow::bail!("Failed to release job: {error_text}");
+        }
+
+        let updated: Vec<Job> = response.json().await?;
+        Ok(!updated.is_empty())
+    }
+
+    /// Record the commit a worker checked out
+    async fn set_resolved_sha(&self, job_id: Uuid, sha: &str) -> Result<()> {
+        let response = self
+            .client
+            .patch(format!("{}/jobs?id=eq.{}", self.rest_url(), job_id))
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .header("Content-Type", "application/json")
+            .header("Prefer", "return=minimal")
+            .json(&json!({ "resolved_sha": sha }))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await?;
+            anyhow::bail!("Failed to record resolved SHA: {error_text}");
         }
 
         Ok(())
     }
 
-    /// Update job status (e.g., for cancellation)
-    pub async fn update_job_status(&self, job_id: Uuid, status: JobStatus) -> Result<()> {
+    /// Record the outcome of each step of a pipeline job
+    async fn set_step_results(&self, job_id: Uuid, results: &[StepResult]) -> Result<()> {
         let response = self
             .client
             .patch(format!("{}/jobs?id=eq.{}", self.rest_url(), job_id))
             .header("apikey", &self.api_key)
             .header("Authorization", format!("Bearer {}", self.api_key))
             .header("Content-Type", "application/json")
-            .json(&json!({
-                "status": status.to_string(),
-            }))
+            .header("Prefer", "return=minimal")
+            .json(&json!({ "step_results": results }))
             .send()
             .await?;
 
         if !response.status().is_success() {
             let error_text = response.text().await?;
-            anyhow::bail!("Failed to update job status: {error_text}");
+            anyhow::bail!("Failed to record step results: {error_text}");
         }
 
         Ok(())
     }
 
-    /// Create a job log entry
-    #[allow(dead_code)]
-    pub async fn create_job_log(&self, job_id: Uuid, content: String) -> Result<()> {
+    /// Create a run, then its jobs in one insert; the run is removed again if
+    /// they can't be created
+    async fn create_run(&self, run: &Run, jobs: &[Job]) -> Result<()> {
         let response = self
             .client
-            .post(format!("{}/job_logs", self.rest_url()))
+            .post(format!("{}/runs", self.rest_url()))
             .header("apikey", &self.api_key)
             .header("Authorization", format!("Bearer {}", self.api_key))
             .header("Content-Type", "application/json")
             .header("Prefer", "return=minimal")
-            .json(&json!({
-                "job_id": job_id,
-                "content": content,
-            }))
+            .json(run)
             .send()
             .await?;
 
         if !response.status().is_success() {
             let error_text = response.text().await?;
-            anyhow::bail!("Failed to create job log: {error_text}");
+            anyhow::bail!("Failed to create run: {error_text}");
         }
 
-        Ok(())
+        let rows: Vec<serde_json::Value> = jobs.iter().map(job_row).collect();
+        let created = self
+            .client
+            .post(format!("{}/jobs", self.rest_url()))
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .header("Content-Type", "application/json")
+            .header("Prefer", "return=minimal")
+            .json(&rows)
+            .send()
+            .await;
+        let error_text = match created {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            Ok(response) => response.text().await?,
+            Err(e) => e.to_string(),
+        };
+
+        let removed = self
+            .client
+            .delete(format!("{}/runs?id=eq.{}", self.rest_url(), run.id))
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .send()
+            .await;
+        if !removed.is_ok_and(|response| response.status().is_success()) {
+            tracing::error!(run_id = %run.id, "Failed to remove run whose jobs weren't created");
+        }
+        anyhow::bail!("Failed to create run jobs: {error_text}");
     }
 
-    /// Get logs for a job
-    #[allow(dead_code)]
-    pub async fn get_job_logs(&self, job_id: Uuid) -> Result<Vec<shared::JobLog>> {
+    async fn get_run(&self, run_id: Uuid) -> Result<Option<Run>> {
         let response = self
             .client
-            .get(format!(
-                "{}/job_logs?job_id=eq.{}&order=created_at.asc",
-                self.rest_url(),
-                job_id
-            ))
+            .get(format!("{}/runs?id=eq.{}", self.rest_url(), run_id))
             .header("apikey", &self.api_key)
             .header("Authorization", format!("Bearer {}", self.api_key))
             .send()
@@ -277,45 +785,57 @@ impl SupabaseClient {
 
         if !response.status().is_success() {
             let error_text = response.text().await?;
-            anyhow::bail!("Failed to get job logs: {error_text}");
+            anyhow::bail!("Failed to get run: {error_text}");
         }
 
-        let logs: Vec<shared::JobLog> = response.json().await?;
-        Ok(logs)
+        let runs: Vec<Run> = response.json().await?;
+        Ok(runs.into_iter().next())
     }
 
-    /// Upload log file to Supabase Storage
-    pub async fn upload_log_file(&self, job_id: Uuid, data: Vec<u8>) -> Result<()> {
-        let path = format!("logs/{job_id}.log");
+    async fn renew_job_leases(
+        &self,
+        worker_id: Uuid,
+        job_ids: &[Uuid],
+        lease_expires_at: DateTime<Utc>,
+    ) -> Result<()> {
+        if job_ids.is_empty() {
+            return Ok(());
+        }
 
+        let ids = job_ids
+            .iter()
+            .map(Uuid::to_string)
+            .collect::<Vec<_>>()
+            .join(",");
         let response = self
             .client
-            .post(format!("{}/object/{}", self.storage_url(), path))
+            .patch(format!(
+                "{}/jobs?id=in.({ids})&worker_id=eq.{worker_id}&status=eq.running",
+                self.rest_url()
+            ))
             .header("apikey", &self.api_key)
             .header("Authorization", format!("Bearer {}", self.api_key))
-            .header("Content-Type", "text/plain")
-            .body(data)
+            .header("Content-Type", "application/json")
+            .json(&json!({ "lease_expires_at": lease_expires_at }))
             .send()
             .await?;
 
         if !response.status().is_success() {
             let error_text = response.text().await?;
-            anyhow::bail!("Failed to upload log file: {error_text}");
+            anyhow::bail!("Failed to renew job leases: {error_text}");
         }
 
         Ok(())
     }
 
-    /// Download log file from Supabase Storage
-    pub async fn download_log_file(&self, job_id: Uuid) -> Result<String> {
-        let path = format!("logs/{job_id}.log");
-
+    async fn list_expired_jobs(&self, now: DateTime<Utc>) -> Result<Vec<Job>> {
+        // Jobs claimed before leases existed have no lease and count as expired
         let response = self
             .client
             .get(format!(
-                "{}/object/authenticated/{}",
-                self.storage_url(),
-                path
+                "{}/jobs?status=eq.running&or=(lease_expires_at.is.null,lease_expires_at.lt.{})",
+                self.rest_url(),
+                filter_timestamp(now)
             ))
             .header("apikey", &self.api_key)
             .header("Authorization", format!("Bearer {}", self.api_key))
@@ -323,61 +843,69 @@ impl SupabaseClient {
             .await?;
 
         if !response.status().is_success() {
-            anyhow::bail!("Log file not found");
+            let error_text = response.text().await?;
+            anyhow::bail!("Failed to list expired jobs: {error_text}");
         }
 
-        Ok(response.text().await?)
+        Ok(response.json().await?)
     }
 
-    /// Create a signed URL for a file
-    pub async fn create_signed_url(
-        &self,
-        bucket: &str,
-        path: &str,
-        expiry_seconds: i32,
-    ) -> Result<String> {
-        #[derive(serde::Deserialize)]
-        struct SignedUrlResponse {
-            #[serde(rename = "signedURL")]
-            signed_url: String,
-        }
+    async fn reclaim_job(&self, job_id: Uuid, status: JobStatus, reason: &str) -> Result<bool> {
+        let now = Utc::now();
+        let mut filter = String::new();
+        let body = if status == JobStatus::Pending {
+            let Some(job) = self.get_job(job_id).await? else {
+                return Ok(false);
+            };
+            // Conditional on the retry count too, so a concurrent update can't be lost
+            filter = format!("&infra_retries=eq.{}", job.infra_retries);
+            json!({
+                "status": status.to_string(),
+                "worker_id": null,
+                "started_at": null,
+                "lease_expires_at": null,
+                "status_reason": reason,
+                "infra_retries": job.infra_retries + 1,
+            })
+        } else {
+            json!({
+                "status": status.to_string(),
+                "completed_at": now,
+                "lease_expires_at": null,
+                "status_reason": reason,
+                "failure_reason": FailureReason::Infra,
+            })
+        };
 
+        // Conditional on the lease still being expired, so a late renewal wins
         let response = self
             .client
-            .post(format!(
-                "{}/object/sign/{}/{}",
-                self.storage_url(),
-                bucket,
-                path
+            .patch(format!(
+                "{}/jobs?id=eq.{}&status=eq.running&or=(lease_expires_at.is.null,lease_expires_at.lt.{}){}",
+                self.rest_url(),
+                job_id,
+                filter_timestamp(now),
+                filter
             ))
             .header("apikey", &self.api_key)
             .header("Authorization", format!("Bearer {}", self.api_key))
             .header("Content-Type", "application/json")
-            .json(&json!({
-                "expiresIn": expiry_seconds,
-            }))
+            .header("Prefer", "return=representation")
+            .json(&body)
             .send()
             .await?;
 
         if !response.status().is_success() {
             let error_text = response.text().await?;
-            anyhow::bail!("Failed to create signed URL: {error_text}");
+            anyhow::bail!("Failed to reclaim job: {error_text}");
         }
 
-        let data: SignedUrlResponse = response.json().await?;
-
-        // Prepend base_url if returned URL is relative
-        let signed_url = if data.signed_url.starts_with('/') {
-            format!("{}{}", self.base_url, data.signed_url)
-        } else {
-            data.signed_url
-        };
-
-        Ok(signed_url)
+        let updated: Vec<Job> = response.json().await?;
+        Ok(!updated.is_empty())
     }
 
     /// Register a worker
-    pub async fn register_worker(&self, worker: &WorkerInfo) -> Result<()> {
+    async fn register_worker(&self, worker: &WorkerInfo) -> Result<()> {
         let response = self
             .client
             .post(format!("{}/workers", self.rest_url()))
@@ -392,6 +920,9 @@ impl SupabaseClient {
                 "current_jobs": worker.current_jobs,
                 "last_heartbeat": worker.last_heartbeat,
                 "status": format!("{:?}", worker.status).to_lowercase(),
+                "labels": worker.labels,
+                "capabilities": worker.capabilities,
+                "images": worker.images,
             }))
             .send()
             .await?;
@@ -404,8 +935,30 @@ impl SupabaseClient {
         Ok(())
     }
 
+    /// Refresh a worker's `last_heartbeat` timestamp
+    async fn update_worker_heartbeat(&self, worker_id: Uuid) -> Result<()> {
+        let response = self
+            .client
+            .patch(format!("{}/workers?id=eq.{}", self.rest_url(), worker_id))
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .header("Content-Type", "application/json")
+            .json(&json!({
+                "last_heartbeat": Utc::now(),
+            }))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await?;
+            anyhow::bail!("Failed to update worker heartbeat: {error_text}");
+        }
+
+        Ok(())
+    }
+
     /// Get a worker by ID
-    pub async fn get_worker(&self, worker_id: Uuid) -> Result<Option<WorkerInfo>> {
+    async fn get_worker(&self, worker_id: Uuid) -> Result<Option<WorkerInfo>> {
         let response = self
             .client
             .get(format!("{}/workers?id=eq.{}", self.rest_url(), worker_id))
@@ -424,7 +977,7 @@ impl SupabaseClient {
     }
 
     /// Update worker status (for deregistration)
-    pub async fn update_worker_status(
+    async fn update_worker_status(
         &self,
         worker_id: Uuid,
         status: shared::WorkerStatus,
@@ -450,7 +1003,7 @@ impl SupabaseClient {
     }
 
     /// Get artifacts for a job
-    pub async fn get_job_artifacts(&self, job_id: Uuid) -> Result<Vec<Artifact>> {
+    async fn get_job_artifacts(&self, job_id: Uuid) -> Result<Vec<Artifact>> {
         let response = self
             .client
             .get(format!(
@@ -473,7 +1026,7 @@ impl SupabaseClient {
     }
 
     /// Store an artifact record
-    pub async fn store_artifact(&self, job_id: Uuid, artifact: &Artifact) -> Result<()> {
+    async fn store_artifact(&self, job_id: Uuid, artifact: &Artifact) -> Result<()> {
         let response = self
             .client
             .post(format!("{}/artifacts", self.rest_url()))
@@ -499,36 +1052,8 @@ impl SupabaseClient {
         Ok(())
     }
 
-    /// Upload artifact file to Supabase Storage
-    pub async fn upload_artifact_file(
-        &self,
-        job_id: Uuid,
-        file_name: &str,
-        body: reqwest::Body,
-    ) -> Result<String> {
-        let path = format!("artifacts/{job_id}/{file_name}");
-
-        let response = self
-            .client
-            .post(format!("{}/object/{}", self.storage_url(), path))
-            .header("apikey", &self.api_key)
-            .header("Authorization", format!("Bearer {}", self.api_key))
-            .header("Content-Type", "application/octet-stream")
-            .body(body)
-            .send()
-            .await?;
-
-        if !response.status().is_success() {
-            let error_text = response.text().await?;
-            anyhow::bail!("Failed to upload artifact: {error_text}");
-        }
-
-        // Return public URL
-        Ok(format!("{}/object/public/{}", self.storage_url(), path))
-    }
-
     /// Verify an API key by its hash
-    pub async fn verify_api_key(&self, key_hash: &str) -> Result<Option<ApiKeyRecord>> {
+    async fn verify_api_key(&self, key_hash: &str) -> Result<Option<ApiKeyRecord>> {
         let response = self
             .client
             .get(format!(
@@ -551,7 +1076,7 @@ impl SupabaseClient {
     }
 
     /// Update API key `last_used_at` timestamp
-    pub async fn update_api_key_usage(&self, key_id: Uuid) -> Result<()> {
+    async fn update_api_key_usage(&self, key_id: Uuid) -> Result<()> {
         let response = self
             .client
             .patch(format!("{}/api_keys?id=eq.{}", self.rest_url(), key_id))
@@ -572,7 +1097,7 @@ impl SupabaseClient {
     }
 
     /// Create a new API key for a user
-    pub async fn create_api_key(&self, user_id: Uuid, name: &str, key_hash: &str) -> Result<Uuid> {
+    async fn create_api_key(&self, user_id: Uuid, name: &str, key_hash: &str) -> Result<Uuid> {
         let key_id = Uuid::new_v4();
 
         let response = self
@@ -600,7 +1125,7 @@ impl SupabaseClient {
     }
 
     /// List API keys for a user (without the hash)
-    pub async fn list_api_keys(&self, user_id: Uuid) -> Result<Vec<ApiKeyInfo>> {
+    async fn list_api_keys(&self, user_id: Uuid) -> Result<Vec<ApiKeyInfo>> {
         let response = self
             .client
             .get(format!(
@@ -623,7 +1148,7 @@ impl SupabaseClient {
     }
 
     /// Delete an API key
-    pub async fn delete_api_key(&self, user_id: Uuid, key_id: Uuid) -> Result<bool> {
+    async fn delete_api_key(&self, user_id: Uuid, key_id: Uuid) -> Result<bool> {
         let response = self
             .client
             .delete(format!(
@@ -640,8 +1165,170 @@ impl SupabaseClient {
         Ok(response.status().is_success())
     }
 
+    async fn create_git_credential(&self, credential: &GitCredentialRecord) -> Result<()> {
+        let response = self
+            .client
+            .post(format!("{}/git_credentials", self.rest_url()))
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .header("Content-Type", "application/json")
+            .header("Prefer", "return=minimal")
+            .json(credential)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await?;
+            anyhow::bail!("Failed to create git credential: {error_text}");
+        }
+
+        Ok(())
+    }
+
+    async fn get_git_credential(&self, credential_id: Uuid) -> Result<Option<GitCredentialRecord>> {
+        let response = self
+            .client
+            .get(format!(
+                "{}/git_credentials?id=eq.{}",
+                self.rest_url(),
+                credential_id
+            ))
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await?;
+            anyhow::bail!("Failed to get git credential: {error_text}");
+        }
+
+        let credentials: Vec<GitCredentialRecord> = response.json().await?;
+        Ok(credentials.into_iter().next())
+    }
+
+    async fn list_git_credentials(&self, user_id: Uuid) -> Result<Vec<GitCredentialRecord>> {
+        let response = self
+            .client
+            .get(format!(
+                "{}/git_credentials?user_id=eq.{}&order=created_at.asc",
+                self.rest_url(),
+                user_id
+            ))
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await?;
+            anyhow::bail!("Failed to list git credentials: {error_text}");
+        }
+
+        Ok(response.json().await?)
+    }
+
+    async fn delete_git_credential(&self, user_id: Uuid, credential_id: Uuid) -> Result<bool> {
+        let response = self
+            .client
+            .delete(format!(
+                "{}/git_credentials?id=eq.{}&user_id=eq.{}",
+                self.rest_url(),
+                credential_id,
+                user_id
+            ))
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .header("Prefer", "return=representation")
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await?;
+            anyhow::bail!("Failed to delete git credential: {error_text}");
+        }
+
+        let deleted: Vec<GitCredentialRecord> = response.json().await?;
+        Ok(!deleted.is_empty())
+    }
+
+    async fn set_secret(&self, secret: &SecretRecord) -> Result<()> {
+        // Merge on the primary key; created_at is left out so a replaced
+        // secret keeps its original one
+        let response = self
+            .client
+            .post(format!(
+                "{}/secrets?on_conflict=user_id,name",
+                self.rest_url()
+            ))
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .header("Content-Type", "application/json")
+            .header("Prefer", "resolution=merge-duplicates,return=minimal")
+            .json(&json!({
+                "user_id": secret.user_id,
+                "name": secret.name,
+                "encrypted_value": secret.encrypted_value,
+                "updated_at": secret.updated_at,
+            }))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await?;
+            anyhow::bail!("Failed to set secret: {error_text}");
+        }
+
+        Ok(())
+    }
+
+    async fn list_secrets(&self, user_id: Uuid) -> Result<Vec<SecretRecord>> {
+        let response = self
+            .client
+            .get(format!(
+                "{}/secrets?user_id=eq.{}&order=name.asc",
+                self.rest_url(),
+                user_id
+            ))
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await?;
+            anyhow::bail!("Failed to list secrets: {error_text}");
+        }
+
+        Ok(response.json().await?)
+    }
+
+    async fn delete_secret(&self, user_id: Uuid, name: &str) -> Result<bool> {
+        let response = self
+            .client
+            .delete(format!(
+                "{}/secrets?user_id=eq.{}&name=eq.{}",
+                self.rest_url(),
+                user_id,
+                name
+            ))
+            .header("apikey", &self.api_key)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .header("Prefer", "return=representation")
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await?;
+            anyhow::bail!("Failed to delete secret: {error_text}");
+        }
+
+        let deleted: Vec<SecretRecord> = response.json().await?;
+        Ok(!deleted.is_empty())
+    }
+
     /// Verify user credentials (for Supabase, use their Auth API)
-    pub async fn verify_user(&self, email: &str, password: &str) -> Result<Option<Uuid>> {
+    async fn verify_user(&self, email: &str, password: &str) -> Result<Option<Uuid>> {
         #[derive(serde::Deserialize)]
         struct AuthUser {
             id: Uuid,
@@ -676,7 +1363,7 @@ impl SupabaseClient {
     }
 
     /// Create a new user (for Supabase, use their Auth API)
-    pub async fn create_user(&self, email: &str, password: &str) -> Result<Uuid> {
+    async fn create_user(&self, email: &str, password: &str) -> Result<Uuid> {
         #[derive(serde::Deserialize)]
         struct AuthUser {
             id: Uuid,
@@ -708,23 +1395,19 @@ impl SupabaseClient {
     }
 }
 
-/// API key record from database (with hash for verification)
-#[derive(Debug, serde::Deserialize)]
-#[allow(dead_code)]
-pub struct ApiKeyRecord {
-    pub id: Uuid,
-    pub user_id: Uuid,
-    pub name: String,
-    pub key_hash: String,
-    pub created_at: chrono::DateTime<chrono::Utc>,
-    pub last_used_at: Option<chrono::DateTime<chrono::Utc>>,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-/// API key info (without sensitive hash)
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
-pub struct ApiKeyInfo {
-    pub id: Uuid,
-    pub name: String,
-    pub created_at: chrono::DateTime<chrono::Utc>,
-    pub last_used_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[test]
+    fn test_filter_list() {
+        assert_eq!(
+            filter_list(&["macos-15".to_string(), "xcode-16.2".to_string()]),
+            r#"("macos-15","xcode-16.2")"#
+        );
+        assert_eq!(
+            filter_list(&[r#"a,b).c"d\e"#.to_string()]),
+            r#"("a,b).c\"d\\e")"#
+        );
+    }
 }
@@ -2,37 +2,63 @@
 
 use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::sync::{mpsc, watch, RwLock};
 use uuid::Uuid;
 
 use crate::config::Config;
-use crate::services::SupabaseClient;
-use shared::WorkerInfo;
+use crate::db::Database;
+use crate::storage::Storage;
+use shared::{LogMask, WorkerCommand, WorkerInfo};
+
+/// Where a job's shipped logs are up to
+#[derive(Debug, Clone)]
+pub struct LogCursor {
+    /// Worker running the job; entries from any other are stale
+    pub worker_id: Uuid,
+    /// Sequence number of the next entry expected
+    pub next_seq: u64,
+    /// Masks the job's secret values in its entries
+    pub mask: Arc<LogMask>,
+}
 
 /// Shared application state
 #[derive(Clone)]
 pub struct AppState {
     pub config: Config,
-    pub supabase: SupabaseClient,
+    /// Database backend selected by `DatabaseBackend::from_env`
+    pub db: Arc<dyn Database>,
+    /// Object storage selected by `StorageBackend::from_env`
+    pub storage: Arc<dyn Storage>,
     /// HTTP client for external API calls (like Supabase Auth)
     pub client: reqwest::Client,
     /// In-memory cache of active workers (complement to DB)
     pub workers: Arc<RwLock<HashMap<Uuid, WorkerInfo>>>,
     /// Active log streams (`job_id` -> broadcast sender)
     pub log_streams: Arc<RwLock<HashMap<Uuid, tokio::sync::broadcast::Sender<String>>>>,
+    /// How far running jobs' shipped logs have come (`job_id` -> cursor)
+    pub log_cursors: Arc<RwLock<HashMap<Uuid, LogCursor>>>,
+    /// Push channels of connected workers (`worker_id` -> command sender)
+    pub worker_channels: Arc<RwLock<HashMap<Uuid, mpsc::UnboundedSender<WorkerCommand>>>>,
+    /// Bumped when jobs may have become claimable, waking idle worker channels
+    pub job_queue: Arc<watch::Sender<()>>,
 }
 
 impl AppState {
-    pub fn new(config: Config) -> Self {
-        let supabase = SupabaseClient::new(&config.supabase_url, &config.supabase_key);
+    pub async fn new(config: Config) -> anyhow::Result<Self> {
+        let db = config.database.connect().await?;
+        let storage = config.storage.connect().await?;
 
-        Self {
+        Ok(Self {
             config,
-            supabase,
+            db,
+            storage,
             client: reqwest::Client::new(),
             workers: Arc::new(RwLock::new(HashMap::new())),
             log_streams: Arc::new(RwLock::new(HashMap::new())),
-        }
+            log_cursors: Arc::new(RwLock::new(HashMap::new())),
+            worker_channels: Arc::new(RwLock::new(HashMap::new())),
+            job_queue: Arc::new(watch::channel(()).0),
+        })
     }
 
     /// Create a new log stream for a job
@@ -53,5 +79,33 @@ impl AppState {
     /// Remove a log stream when job completes
     pub async fn remove_log_stream(&self, job_id: Uuid) {
         self.log_streams.write().await.remove(&job_id);
+        self.log_cursors.write().await.remove(&job_id);
+    }
+
+    /// Expect a job's logs from the start, from the worker that just claimed it
+    pub async fn reset_log_cursor(&self, job_id: Uuid, worker_id: Uuid, mask: LogMask) {
+        self.log_cursors.write().await.insert(
+            job_id,
+            LogCursor {
+                worker_id,
+                next_seq: 0,
+                mask: Arc::new(mask),
+            },
+        );
+    }
+
+    /// Wake the workers waiting on their channel for a job, after jobs were
+    /// queued or unblocked
+    pub fn wake_workers(&self) {
+        self.job_queue.send_replace(());
+    }
+
+    /// Push a command down a worker's channel; false if it has none open
+    pub async fn send_to_worker(&self, worker_id: Uuid, command: WorkerCommand) -> bool {
+        self.worker_channels
+            .read()
+            .await
+            .get(&worker_id)
+            .is_some_and(|tx| tx.send(command).is_ok())
     }
 }
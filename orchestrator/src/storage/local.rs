@@ -0,0 +1,226 @@
+//! Local filesystem storage backend for self-hosted deployments
+//!
+//! Objects live under a configurable directory. Downloads are served by the
+//! orchestrator itself (`GET /api/v1/storage/*key`) through HMAC-signed URLs
+//! that expire, so no external object store is needed.
+
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::Utc;
+use futures_util::StreamExt;
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+use super::{ByteStream, Storage};
+use crate::crypto;
+
+/// Local filesystem storage implementation
+#[derive(Clone)]
+pub struct LocalStorage {
+    root: PathBuf,
+    base_url: String,
+    signing_key: Vec<u8>,
+}
+
+impl LocalStorage {
+    /// Create the storage root if needed
+    pub async fn new(root: &str, base_url: &str, signing_key: Vec<u8>) -> Result<Self> {
+        tokio::fs::create_dir_all(root).await?;
+
+        Ok(Self {
+            root: PathBuf::from(root),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            signing_key,
+        })
+    }
+
+    /// Resolve an object key to a path below the storage root
+    fn object_path(&self, key: &str) -> Result<PathBuf> {
+        let relative = Path::new(key);
+        let is_safe = !key.is_empty()
+            && !key.contains('\\')
+            && relative
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)));
+
+        if !is_safe {
+            anyhow::bail!("Invalid storage key: {key}");
+        }
+
+        Ok(self.root.join(relative))
+    }
+}
+
+/// Message covered by a download signature
+fn signature_message(key: &str, expires: i64) -> String {
+    format!("{key}:{expires}")
+}
+
+/// Sign a download of `key` that is valid until the `expires` unix timestamp
+pub fn sign(signing_key: &[u8], key: &str, expires: i64) -> String {
+    crypto::hmac_sha256_hex(signing_key, &signature_message(key, expires))
+}
+
+/// Check a download signature and its expiry
+pub fn verify(signing_key: &[u8], key: &str, expires: i64, signature: &str) -> bool {
+    expires >= Utc::now().timestamp()
+        && crypto::verify_hmac_sha256_hex(signing_key, &signature_message(key, expires), signature)
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, mut body: ByteStream, _content_type: &str) -> Result<()> {
+        let path = self.object_path(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        // Write to a temporary file first so readers never see a partial object
+        let temp_path = path.with_file_name(format!(".upload-{}", Uuid::new_v4()));
+        let mut file = tokio::fs::File::create(&temp_path).await?;
+
+        let written: Result<()> = async {
+            while let Some(chunk) = body.next().await {
+                file.write_all(&chunk?).await?;
+            }
+            file.flush().await?;
+            Ok(())
+        }
+        .await;
+
+        if let Err(e) = written {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            return Err(e);
+        }
+
+        tokio::fs::rename(&temp_path, &path).await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<ByteStream>> {
+        let path = self.object_path(key)?;
+
+        match tokio::fs::File::open(&path).await {
+            Ok(file) => Ok(Some(tokio_util::io::ReaderStream::new(file).boxed())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        Ok(tokio::fs::try_exists(self.object_path(key)?).await?)
+    }
+
+    async fn signed_url(&self, key: &str, expires_in: Duration) -> Result<String> {
+        // Reject bad keys up front rather than handing out a URL that can never work
+        self.object_path(key)?;
+
+        let expires = Utc::now().timestamp() + i64::try_from(expires_in.as_secs())?;
+        let signature = sign(&self.signing_key, key, expires);
+
+        Ok(format!(
+            "{}/api/v1/storage/{key}?expires={expires}&signature={signature}",
+            self.base_url
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::bytes_stream;
+
+    async fn test_storage() -> LocalStorage {
+        let root = std::env::temp_dir().join(format!("alloy-storage-{}", Uuid::new_v4()));
+        LocalStorage::new(
+            root.to_str().unwrap(),
+            "http://localhost:3000/",
+            b"secret".to_vec(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_put_get_round_trip() {
+        let storage = test_storage().await;
+        let key = "logs/job.log";
+
+        assert!(!storage.exists(key).await.unwrap());
+        assert!(storage.get_bytes(key).await.unwrap().is_none());
+
+        storage
+            .put(key, bytes_stream("hello\n"), "text/plain")
+            .await
+            .unwrap();
+        assert!(storage.exists(key).await.unwrap());
+        assert_eq!(storage.get_bytes(key).await.unwrap().unwrap(), b"hello\n");
+
+        // Overwrites replace the object
+        storage
+            .put(key, bytes_stream("bye\n"), "text/plain")
+            .await
+            .unwrap();
+        assert_eq!(storage.get_bytes(key).await.unwrap().unwrap(), b"bye\n");
+    }
+
+    #[tokio::test]
+    async fn test_rejects_traversal_keys() {
+        let storage = test_storage().await;
+
+        for key in ["", "../secret", "logs/../../secret", "/etc/passwd", "a\\b"] {
+            assert!(
+                storage
+                    .put(key, bytes_stream("x"), "text/plain")
+                    .await
+                    .is_err(),
+                "{key} should be rejected"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_signed_url() {
+        let storage = test_storage().await;
+        let url = storage
+            .signed_url("artifacts/job/app.ipa", Duration::from_mins(1))
+            .await
+            .unwrap();
+        assert!(url.starts_with("http://localhost:3000/api/v1/storage/artifacts/job/app.ipa?"));
+
+        let query = url.split_once('?').unwrap().1;
+        let params: std::collections::HashMap<_, _> = query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .collect();
+        let expires: i64 = params["expires"].parse().unwrap();
+        let signature = params["signature"];
+
+        assert!(verify(
+            b"secret",
+            "artifacts/job/app.ipa",
+            expires,
+            signature
+        ));
+        assert!(!verify(
+            b"secret",
+            "artifacts/job/other.ipa",
+            expires,
+            signature
+        ));
+        assert!(!verify(
+            b"other",
+            "artifacts/job/app.ipa",
+            expires,
+            signature
+        ));
+
+        // Expired signatures are rejected even when the HMAC matches
+        let past = Utc::now().timestamp() - 1;
+        let stale = sign(b"secret", "artifacts/job/app.ipa", past);
+        assert!(!verify(b"secret", "artifacts/job/app.ipa", past, &stale));
+    }
+}
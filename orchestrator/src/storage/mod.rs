@@ -0,0 +1,189 @@
+//! Object storage abstraction for source archives, artifacts and logs
+//!
+//! Supports:
+//! - Supabase Storage (managed cloud)
+//! - S3-compatible object stores (AWS S3, `MinIO`, R2)
+//! - Local filesystem (self-hosted, air-gapped installs)
+//!
+//! Objects are addressed by keys of the form `bucket/path`, e.g.
+//! `sources/<sha>.zip`, `artifacts/<job_id>/<file>` or `logs/<job_id>.log`.
+
+pub mod local;
+pub mod s3;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use axum::body::Bytes;
+use futures_util::stream::BoxStream;
+use futures_util::StreamExt;
+use uuid::Uuid;
+
+use crate::services::SupabaseClient;
+pub use local::LocalStorage;
+pub use s3::S3Storage;
+
+/// A stream of object bytes
+pub type ByteStream = BoxStream<'static, std::io::Result<Bytes>>;
+
+/// How long signed download URLs stay valid
+pub const SIGNED_URL_TTL: Duration = Duration::from_hours(1);
+
+/// Abstract object storage operations
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Store an object, replacing any existing object with the same key
+    async fn put(&self, key: &str, body: ByteStream, content_type: &str) -> Result<()>;
+
+    /// Open an object for reading, `None` if it does not exist
+    async fn get(&self, key: &str) -> Result<Option<ByteStream>>;
+
+    /// Check whether an object exists
+    async fn exists(&self, key: &str) -> Result<bool>;
+
+    /// Create a URL that allows downloading the object without credentials
+    async fn signed_url(&self, key: &str, expires_in: Duration) -> Result<String>;
+
+    /// Create a URL that allows uploading the object directly, `None` if uploads
+    /// must be proxied through the orchestrator
+    async fn signed_upload_url(&self, _key: &str, _expires_in: Duration) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    /// Read a whole object into memory, `None` if it does not exist
+    async fn get_bytes(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let Some(mut stream) = self.get(key).await? else {
+            return Ok(None);
+        };
+
+        let mut data = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            data.extend_from_slice(&chunk?);
+        }
+        Ok(Some(data))
+    }
+}
+
+/// Wrap an in-memory buffer as a `ByteStream`
+pub fn bytes_stream(data: impl Into<Bytes>) -> ByteStream {
+    futures_util::stream::once(futures_util::future::ready(Ok(data.into()))).boxed()
+}
+
+/// Storage key for an uploaded source archive
+pub fn source_key(name: &str) -> String {
+    format!("sources/{name}.zip")
+}
+
+/// Storage key for a build artifact
+pub fn artifact_key(job_id: Uuid, filename: &str) -> String {
+    format!("artifacts/{job_id}/{filename}")
+}
+
+/// Storage key for a job's complete log file
+pub fn log_key(job_id: Uuid) -> String {
+    format!("logs/{job_id}.log")
+}
+
+/// Storage backend type
+#[derive(Debug, Clone)]
+pub enum StorageBackend {
+    /// Supabase Storage (cloud)
+    Supabase { url: String, key: String },
+    /// S3-compatible object store (self-hosted or cloud)
+    S3 {
+        endpoint: String,
+        bucket: String,
+        region: String,
+        access_key: String,
+        secret_key: String,
+    },
+    /// Local filesystem served through signed orchestrator URLs (self-hosted)
+    Local {
+        path: String,
+        base_url: String,
+        signing_key: Vec<u8>,
+    },
+}
+
+impl StorageBackend {
+    /// Create from environment configuration
+    pub fn from_env(base_url: &str) -> Result<Self> {
+        // An explicit STORAGE_PATH always selects local storage
+        if let Ok(path) = std::env::var("STORAGE_PATH") {
+            return Ok(Self::local(path, base_url));
+        }
+
+        if let Ok(bucket) = std::env::var("S3_BUCKET") {
+            let region = std::env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".to_string());
+            return Ok(Self::S3 {
+                endpoint: std::env::var("S3_ENDPOINT")
+                    .unwrap_or_else(|_| format!("https://s3.{region}.amazonaws.com")),
+                bucket,
+                region,
+                access_key: std::env::var("S3_ACCESS_KEY")
+                    .context("S3_ACCESS_KEY is required when S3_BUCKET is set")?,
+                secret_key: std::env::var("S3_SECRET_KEY")
+                    .context("S3_SECRET_KEY is required when S3_BUCKET is set")?,
+            });
+        }
+
+        if let (Ok(url), Ok(key)) = (std::env::var("SUPABASE_URL"), std::env::var("SUPABASE_KEY")) {
+            return Ok(Self::Supabase { url, key });
+        }
+
+        Ok(Self::local("data/storage".to_string(), base_url))
+    }
+
+    fn local(path: String, base_url: &str) -> Self {
+        let signing_key = std::env::var("STORAGE_SIGNING_KEY").map_or_else(
+            |_| {
+                tracing::warn!(
+                    "STORAGE_SIGNING_KEY not set, signed URLs will not survive a restart"
+                );
+                rand::random::<[u8; 32]>().to_vec()
+            },
+            String::into_bytes,
+        );
+
+        Self::Local {
+            path,
+            base_url: base_url.to_string(),
+            signing_key,
+        }
+    }
+
+    /// Connect to the configured backend
+    pub async fn connect(&self) -> Result<Arc<dyn Storage>> {
+        match self {
+            Self::Supabase { url, key } => Ok(Arc::new(SupabaseClient::new(url, key))),
+            Self::S3 {
+                endpoint,
+                bucket,
+                region,
+                access_key,
+                secret_key,
+            } => Ok(Arc::new(S3Storage::new(
+                endpoint, bucket, region, access_key, secret_key,
+            )?)),
+            Self::Local {
+                path,
+                base_url,
+                signing_key,
+            } => Ok(Arc::new(
+                LocalStorage::new(path, base_url, signing_key.clone()).await?,
+            )),
+        }
+    }
+
+    /// Short name of the backend for logging
+    #[must_use]
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Supabase { .. } => "supabase",
+            Self::S3 { .. } => "s3",
+            Self::Local { .. } => "local",
+        }
+    }
+}
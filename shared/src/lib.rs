@@ -4,7 +4,9 @@
 //! worker, and CLI components.
 
 pub mod error;
+pub mod log_mask;
 pub mod models;
 
 pub use error::*;
+pub use log_mask::*;
 pub use models::*;
@@ -0,0 +1,66 @@
+//! Masking of secret values in build output
+
+/// Shown in place of a secret value
+const MASK: &str = "***";
+
+/// Replaces a job's secret values in its log lines
+#[derive(Debug, Clone, Default)]
+pub struct LogMask {
+    /// Longest first, so a value containing another is masked whole
+    values: Vec<String>,
+}
+
+impl LogMask {
+    /// Mask each of `secrets`, and each line of multi-line ones since output
+    /// is logged line by line
+    pub fn new<'a>(secrets: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut values: Vec<String> = secrets
+            .into_iter()
+            .flat_map(|secret| std::iter::once(secret).chain(secret.lines()))
+            .map(|value| value.trim_end_matches('\r'))
+            .filter(|value| !value.trim().is_empty())
+            .map(str::to_string)
+            .collect();
+        values.sort_by_key(|value| std::cmp::Reverse(value.len()));
+        values.dedup();
+
+        Self { values }
+    }
+
+    /// Whether there is nothing to mask
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// `text` with every secret value replaced
+    #[must_use]
+    pub fn apply(&self, text: &str) -> String {
+        self.values
+            .iter()
+            .fold(text.to_string(), |text, value| text.replace(value, MASK))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_mask() {
+        let mask = LogMask::new([
+            "hunter2",
+            "hunter2-extended",
+            "-----BEGIN KEY-----\r\nAAAA\n",
+        ]);
+
+        assert_eq!(mask.apply("password is hunter2!"), "password is ***!");
+        assert_eq!(mask.apply("token=hunter2-extended"), "token=***");
+        assert_eq!(mask.apply("key line: AAAA"), "key line: ***");
+        assert_eq!(mask.apply("-----BEGIN KEY-----"), "***");
+        assert_eq!(mask.apply("nothing secret"), "nothing secret");
+
+        // Blank values would mask everything
+        assert_eq!(LogMask::new(["", " "]).apply("a b"), "a b");
+    }
+}
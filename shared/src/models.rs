@@ -2,6 +2,7 @@
 
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use uuid::Uuid;
 
 /// How the source code is provided to the job
@@ -44,23 +45,101 @@ pub struct Job {
     pub completed_at: Option<DateTime<Utc>>,
     pub exit_code: Option<i32>,
     pub build_minutes: Option<f64>,
+    /// When the claiming worker's lease runs out unless renewed by a heartbeat
+    #[serde(default)]
+    pub lease_expires_at: Option<DateTime<Utc>>,
+    /// Why the job was last moved by the orchestrator (e.g. requeued after a lost lease)
+    #[serde(default)]
+    pub status_reason: Option<String>,
+    /// Branch, tag or other ref to check out (git jobs; default branch if unset)
+    #[serde(default)]
+    pub git_ref: Option<String>,
+    /// Exact commit to check out (git jobs); with `git_ref` it must be reachable from it
+    #[serde(default)]
+    pub commit_sha: Option<String>,
+    /// Also check out submodules, recursively
+    #[serde(default)]
+    pub submodules: bool,
+    /// Also fetch Git LFS objects
+    #[serde(default)]
+    pub lfs: bool,
+    /// Full SHA of the commit the worker checked out
+    #[serde(default)]
+    pub resolved_sha: Option<String>,
+    /// Stored git credential used to fetch the source (git jobs)
+    #[serde(default)]
+    pub git_credential_id: Option<Uuid>,
+    /// Environment variables exported to the build
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Names of the customer's stored secrets exported to the build (values
+    /// are only ever sent to the worker that claims the job)
+    #[serde(default)]
+    pub secrets: Vec<String>,
+    /// Steps run in order instead of a single command or script
+    #[serde(default)]
+    pub steps: Vec<Step>,
+    /// Outcome of each step, once the job has finished
+    #[serde(default)]
+    pub step_results: Vec<StepResult>,
+    /// Jobs that must complete before this one runs; their artifacts are
+    /// copied into this job's workspace
+    #[serde(default)]
+    pub needs: Vec<Uuid>,
+    /// Run this job is one matrix cell of
+    #[serde(default)]
+    pub run_id: Option<Uuid>,
+    /// This cell's value for each matrix axis, exported to the build
+    #[serde(default)]
+    pub matrix: BTreeMap<String, String>,
+    /// Labels a worker must have to claim the job (see `WorkerInfo::labels`)
+    #[serde(default)]
+    pub runs_on: Vec<String>,
+    /// Named base image the job's VM is cloned from (the worker's default if unset)
+    #[serde(default)]
+    pub image: Option<String>,
+    /// Minutes the job may run before it is stopped; set by the orchestrator
+    /// to its `DEFAULT_JOB_TIMEOUT_MINUTES` if the job doesn't say (the
+    /// worker's `JOB_TIMEOUT_MINUTES` applies to jobs queued without one)
+    #[serde(default)]
+    pub timeout_minutes: Option<u32>,
+    /// Why a finished job did not succeed
+    #[serde(default)]
+    pub failure_reason: Option<FailureReason>,
+    /// Times the job was requeued after an infrastructure failure
+    #[serde(default)]
+    pub infra_retries: u32,
+    /// Which attempt at the job this is, counting from 1
+    #[serde(default = "first_attempt")]
+    pub attempt: u32,
+    /// The attempt this one retries
+    #[serde(default)]
+    pub parent_job_id: Option<Uuid>,
+    /// When a failed attempt is retried automatically
+    #[serde(default)]
+    pub retry: Option<RetryPolicy>,
+    /// Not handed to a worker before this time (a retry waiting out its backoff)
+    #[serde(default)]
+    pub not_before: Option<DateTime<Utc>>,
+    /// Every attempt at the job, first to last (only filled in when a single
+    /// job is fetched)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attempts: Vec<JobAttempt>,
+}
+
+const fn first_attempt() -> u32 {
+    1
 }
 
 impl Job {
-    /// Create a new job with a command
-    #[must_use]
-    pub fn with_command(
-        customer_id: Uuid,
-        command: String,
-        source_type: SourceType,
-        source_url: Option<String>,
-    ) -> Self {
+    /// A pending job with nothing to run yet
+    fn new(customer_id: Uuid, source_type: SourceType, source_url: Option<String>) -> Self {
         Self {
             id: Uuid::new_v4(),
             customer_id,
             source_type,
             source_url,
-            command: Some(command),
+            command: None,
             script: None,
             status: JobStatus::Pending,
             worker_id: None,
@@ -69,6 +148,45 @@ impl Job {
             completed_at: None,
             exit_code: None,
             build_minutes: None,
+            lease_expires_at: None,
+            status_reason: None,
+            git_ref: None,
+            commit_sha: None,
+            submodules: false,
+            lfs: false,
+            resolved_sha: None,
+            git_credential_id: None,
+            env: BTreeMap::new(),
+            secrets: Vec::new(),
+            steps: Vec::new(),
+            step_results: Vec::new(),
+            needs: Vec::new(),
+            run_id: None,
+            matrix: BTreeMap::new(),
+            runs_on: Vec::new(),
+            image: None,
+            timeout_minutes: None,
+            failure_reason: None,
+            infra_retries: 0,
+            attempt: 1,
+            parent_job_id: None,
+            retry: None,
+            not_before: None,
+            attempts: Vec::new(),
+        }
+    }
+
+    /// Create a new job with a command
+    #[must_use]
+    pub fn with_command(
+        customer_id: Uuid,
+        command: String,
+        source_type: SourceType,
+        source_url: Option<String>,
+    ) -> Self {
+        Self {
+            command: Some(command),
+            ..Self::new(customer_id, source_type, source_url)
         }
     }
 
@@ -81,19 +199,22 @@ impl Job {
         source_url: Option<String>,
     ) -> Self {
         Self {
-            id: Uuid::new_v4(),
-            customer_id,
-            source_type,
-            source_url,
-            command: None,
             script: Some(script),
-            status: JobStatus::Pending,
-            worker_id: None,
-            created_at: Utc::now(),
-            started_at: None,
-            completed_at: None,
-            exit_code: None,
-            build_minutes: None,
+            ..Self::new(customer_id, source_type, source_url)
+        }
+    }
+
+    /// Create a new job running pipeline steps
+    #[must_use]
+    pub fn with_steps(
+        customer_id: Uuid,
+        steps: Vec<Step>,
+        source_type: SourceType,
+        source_url: Option<String>,
+    ) -> Self {
+        Self {
+            steps,
+            ..Self::new(customer_id, source_type, source_url)
         }
     }
 
@@ -104,6 +225,155 @@ impl Job {
     }
 }
 
+/// Whether `sha` is a full or abbreviated commit hash
+#[must_use]
+pub fn is_valid_commit_sha(sha: &str) -> bool {
+    (7..=64).contains(&sha.len()) && sha.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Whether `git_ref` is a ref name git accepts (see `git check-ref-format`)
+/// that can't be mistaken for a command-line option
+#[must_use]
+pub fn is_valid_git_ref(git_ref: &str) -> bool {
+    !git_ref.is_empty()
+        && !git_ref.starts_with(['-', '/', '.'])
+        && !git_ref.ends_with(['/', '.'])
+        && git_ref.strip_suffix(".lock").is_none()
+        && !git_ref.contains("..")
+        && !git_ref.contains("//")
+        && !git_ref.contains("/.")
+        && !git_ref.contains("@{")
+        && !git_ref.chars().any(|c| {
+            c.is_ascii_control() || matches!(c, ' ' | '~' | '^' | ':' | '?' | '*' | '[' | '\\')
+        })
+}
+
+/// A pipeline as checked in to a repository's `alloy.yml`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Pipeline {
+    /// Axes the pipeline is run across, one job per combination of values
+    #[serde(default)]
+    pub matrix: Matrix,
+    /// Labels a worker must have to run the pipeline
+    #[serde(default)]
+    pub runs_on: Vec<String>,
+    /// Named base image to run the pipeline in
+    #[serde(default)]
+    pub image: Option<String>,
+    pub steps: Vec<Step>,
+}
+
+/// A build matrix: each axis and the values it takes (e.g. `XCODE: [15.4, 16.0]`)
+pub type Matrix = BTreeMap<String, Vec<String>>;
+
+/// A job submitted with a matrix, grouping one job per matrix cell
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Run {
+    pub id: Uuid,
+    pub customer_id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub matrix: Matrix,
+}
+
+impl Run {
+    /// Status of the run as a whole, from the status of its jobs
+    #[must_use]
+    pub fn status(jobs: &[Job]) -> JobStatus {
+        if jobs
+            .iter()
+            .all(|job| matches!(job.status, JobStatus::Pending | JobStatus::Uploading))
+        {
+            JobStatus::Pending
+        } else if jobs.iter().any(|job| !job.status.is_finished()) {
+            JobStatus::Running
+        } else if jobs.iter().any(|job| job.status == JobStatus::Failed) {
+            JobStatus::Failed
+        } else if jobs.iter().any(|job| job.status == JobStatus::TimedOut) {
+            JobStatus::TimedOut
+        } else if jobs.iter().any(|job| job.status == JobStatus::Cancelled) {
+            JobStatus::Cancelled
+        } else {
+            JobStatus::Completed
+        }
+    }
+}
+
+/// A run with its aggregate status and jobs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunDetails {
+    #[serde(flatten)]
+    pub run: Run,
+    pub status: JobStatus,
+    pub jobs: Vec<Job>,
+}
+
+/// One named step of a pipeline
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Step {
+    pub name: String,
+    /// Command or script, run by the VM user's shell from the workspace
+    pub run: String,
+    /// Minutes the step may take before it is stopped and fails
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_minutes: Option<u32>,
+    /// Environment variables for this step, on top of the job's
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    /// Keep going (and don't fail the job) if this step fails
+    #[serde(default)]
+    pub continue_on_error: bool,
+    /// Files to keep as artifacts, as globs relative to the workspace
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub artifacts: Vec<String>,
+}
+
+/// How a pipeline step ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StepStatus {
+    Succeeded,
+    Failed,
+    /// Stopped after its `timeout_minutes`
+    TimedOut,
+    /// Stopped because the job was cancelled
+    Cancelled,
+    /// Not run because an earlier step failed or the job was cancelled
+    Skipped,
+}
+
+impl std::fmt::Display for StepStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Succeeded => write!(f, "succeeded"),
+            Self::Failed => write!(f, "failed"),
+            Self::TimedOut => write!(f, "timed_out"),
+            Self::Cancelled => write!(f, "cancelled"),
+            Self::Skipped => write!(f, "skipped"),
+        }
+    }
+}
+
+/// Outcome of one pipeline step
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StepResult {
+    pub name: String,
+    pub status: StepStatus,
+    /// Exit code of the step's command, if it ran to completion
+    pub exit_code: Option<i32>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub duration_secs: f64,
+}
+
+/// Whether `name` can be exported as an environment variable: letters, digits
+/// and underscores, not starting with a digit
+#[must_use]
+pub fn is_valid_env_name(name: &str) -> bool {
+    name.chars().next().is_some_and(|c| !c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
 /// Status of a job in the pipeline
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
@@ -114,6 +384,38 @@ pub enum JobStatus {
     Completed,
     Failed,
     Cancelled,
+    /// Stopped after running past its `timeout_minutes`
+    TimedOut,
+}
+
+impl JobStatus {
+    /// Whether the job has stopped for good
+    #[must_use]
+    pub const fn is_finished(self) -> bool {
+        matches!(
+            self,
+            Self::Completed | Self::Failed | Self::Cancelled | Self::TimedOut
+        )
+    }
+
+    /// Whether the job stopped without succeeding, so jobs that need it
+    /// can never run
+    #[must_use]
+    pub const fn stopped_short(self) -> bool {
+        matches!(self, Self::Failed | Self::Cancelled | Self::TimedOut)
+    }
+
+    /// The failure reason implied by a job ending in this status, for
+    /// results that don't carry one
+    #[must_use]
+    pub const fn failure_reason(self) -> Option<FailureReason> {
+        match self {
+            Self::Failed => Some(FailureReason::UserExit),
+            Self::Cancelled => Some(FailureReason::Cancelled),
+            Self::TimedOut => Some(FailureReason::Timeout),
+            _ => None,
+        }
+    }
 }
 
 impl std::fmt::Display for JobStatus {
@@ -125,6 +427,152 @@ impl std::fmt::Display for JobStatus {
             Self::Completed => write!(f, "completed"),
             Self::Failed => write!(f, "failed"),
             Self::Cancelled => write!(f, "cancelled"),
+            Self::TimedOut => write!(f, "timed_out"),
+        }
+    }
+}
+
+impl std::str::FromStr for JobStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(Self::Pending),
+            "uploading" => Ok(Self::Uploading),
+            "running" => Ok(Self::Running),
+            "completed" => Ok(Self::Completed),
+            "failed" => Ok(Self::Failed),
+            "cancelled" => Ok(Self::Cancelled),
+            "timed_out" => Ok(Self::TimedOut),
+            _ => Err(format!("Unknown job status: {s}")),
+        }
+    }
+}
+
+/// Why a job did not succeed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureReason {
+    /// The worker or its VM failed (e.g. a VM could not be started or
+    /// reached); such jobs are requeued automatically
+    Infra,
+    /// The job's source could not be fetched
+    SourceFetch,
+    /// The job ran past its timeout
+    Timeout,
+    /// The build exited with a non-zero code
+    UserExit,
+    /// The job was cancelled
+    Cancelled,
+}
+
+impl std::fmt::Display for FailureReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Infra => write!(f, "infra"),
+            Self::SourceFetch => write!(f, "source_fetch"),
+            Self::Timeout => write!(f, "timeout"),
+            Self::UserExit => write!(f, "user_exit"),
+            Self::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl std::str::FromStr for FailureReason {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "infra" => Ok(Self::Infra),
+            "source_fetch" => Ok(Self::SourceFetch),
+            "timeout" => Ok(Self::Timeout),
+            "user_exit" => Ok(Self::UserExit),
+            "cancelled" => Ok(Self::Cancelled),
+            _ => Err(format!("Unknown failure reason: {s}")),
+        }
+    }
+}
+
+/// When a failed attempt at a job is retried as a new attempt
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    /// Attempts in all, the first included
+    pub max_attempts: u32,
+    /// Failures that are retried
+    #[serde(default = "default_retry_on")]
+    pub retry_on: Vec<RetryOn>,
+    /// Exit codes retried under `exit_codes` (any non-zero code if empty)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exit_codes: Vec<i32>,
+    /// Seconds to wait before the first retry, doubled for each one after
+    #[serde(default)]
+    pub backoff_seconds: u32,
+}
+
+fn default_retry_on() -> Vec<RetryOn> {
+    vec![RetryOn::Infra]
+}
+
+/// A kind of failure a `RetryPolicy` retries
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryOn {
+    /// The worker failed under the job (once its requeues are used up)
+    Infra,
+    /// The job ran past its timeout
+    Timeout,
+    /// The build exited with one of the policy's `exit_codes`
+    ExitCodes,
+}
+
+impl std::fmt::Display for RetryOn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Infra => write!(f, "infra"),
+            Self::Timeout => write!(f, "timeout"),
+            Self::ExitCodes => write!(f, "exit_codes"),
+        }
+    }
+}
+
+impl std::str::FromStr for RetryOn {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "infra" => Ok(Self::Infra),
+            "timeout" => Ok(Self::Timeout),
+            "exit_codes" => Ok(Self::ExitCodes),
+            _ => Err(format!("Unknown retry condition: {s}")),
+        }
+    }
+}
+
+/// One attempt at a job, as listed in the job's attempt history
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobAttempt {
+    pub job_id: Uuid,
+    pub attempt: u32,
+    pub status: JobStatus,
+    #[serde(default)]
+    pub failure_reason: Option<FailureReason>,
+    #[serde(default)]
+    pub exit_code: Option<i32>,
+    pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub completed_at: Option<DateTime<Utc>>,
+}
+
+impl From<&Job> for JobAttempt {
+    fn from(job: &Job) -> Self {
+        Self {
+            job_id: job.id,
+            attempt: job.attempt,
+            status: job.status,
+            failure_reason: job.failure_reason,
+            exit_code: job.exit_code,
+            created_at: job.created_at,
+            completed_at: job.completed_at,
         }
     }
 }
@@ -141,6 +589,49 @@ pub struct CreateJobRequest {
     pub command: Option<String>,
     /// Script content to execute (use this OR command, not both)
     pub script: Option<String>,
+    /// Branch, tag or other ref to check out (git jobs; default branch if unset)
+    #[serde(default)]
+    pub git_ref: Option<String>,
+    /// Exact commit to check out (git jobs)
+    #[serde(default)]
+    pub commit_sha: Option<String>,
+    /// Also check out submodules, recursively
+    #[serde(default)]
+    pub submodules: bool,
+    /// Also fetch Git LFS objects
+    #[serde(default)]
+    pub lfs: bool,
+    /// Stored git credential to fetch a private repository with
+    #[serde(default)]
+    pub git_credential_id: Option<Uuid>,
+    /// Environment variables exported to the build
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Stored secrets exported to the build, each under its own name
+    #[serde(default)]
+    pub secrets: Vec<String>,
+    /// Contents of an `alloy.yml` whose steps are run instead of a command or script
+    #[serde(default)]
+    pub pipeline: Option<String>,
+    /// Jobs that must complete before this one runs
+    #[serde(default)]
+    pub needs: Vec<Uuid>,
+    /// Axes to run the job across, one job per combination of values
+    #[serde(default)]
+    pub matrix: Matrix,
+    /// Labels a worker must have to run the job
+    #[serde(default)]
+    pub runs_on: Vec<String>,
+    /// Named base image to run the job in (the worker's default if unset)
+    #[serde(default)]
+    pub image: Option<String>,
+    /// Minutes the job may run before it is stopped, up to the server's
+    /// maximum (the server's `DEFAULT_JOB_TIMEOUT_MINUTES` if unset)
+    #[serde(default)]
+    pub timeout_minutes: Option<u32>,
+    /// When a failed attempt is retried automatically
+    #[serde(default)]
+    pub retry: Option<RetryPolicy>,
 }
 
 /// Response with upload URL for local file uploads
@@ -165,6 +656,12 @@ pub struct CreateJobResponse {
     pub job_id: Uuid,
     pub status: JobStatus,
     pub stream_url: String,
+    /// Run grouping the jobs, when submitted with a matrix
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<Uuid>,
+    /// Every job of the run, one per matrix cell (`job_id` is the first)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub job_ids: Vec<Uuid>,
 }
 
 /// A single log entry from a running job
@@ -176,8 +673,34 @@ pub struct LogEntry {
     pub content: String,
 }
 
-/// Which output stream a log came from
+/// Consecutive log entries of a job, shipped by its worker together
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogBatch {
+    pub job_id: Uuid,
+    /// Sequence number of the first entry; the others follow on from it
+    pub first_seq: u64,
+    pub entries: Vec<LogEntry>,
+}
+
+impl LogBatch {
+    /// Sequence number of the entry after the batch's last
+    #[must_use]
+    pub const fn end_seq(&self) -> u64 {
+        self.first_seq + self.entries.len() as u64
+    }
+}
+
+/// How far the orchestrator has received a job's log entries
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct LogAck {
+    pub job_id: Uuid,
+    /// Sequence number of the next entry it expects; every entry before it
+    /// was received and needn't be sent again
+    pub next_seq: u64,
+}
+
+/// Which output stream a log came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum LogStream {
     Stdout,
@@ -193,6 +716,120 @@ pub struct WorkerInfo {
     pub current_jobs: u32,
     pub last_heartbeat: DateTime<Utc>,
     pub status: WorkerStatus,
+    /// Labels given to the worker by its operator
+    #[serde(default)]
+    pub labels: Vec<String>,
+    /// What the worker found on its host when it registered
+    #[serde(default)]
+    pub capabilities: WorkerCapabilities,
+    /// Names of the base images the worker can run jobs in
+    #[serde(default)]
+    pub images: Vec<String>,
+}
+
+impl WorkerInfo {
+    /// Every label the worker can satisfy: its own plus those derived from
+    /// its capabilities
+    #[must_use]
+    pub fn all_labels(&self) -> Vec<String> {
+        let mut labels = self.labels.clone();
+        for label in self.capabilities.labels() {
+            if !labels.contains(&label) {
+                labels.push(label);
+            }
+        }
+        labels
+    }
+
+    /// Labels from `runs_on` the worker lacks
+    #[must_use]
+    pub fn missing_labels(&self, runs_on: &[String]) -> Vec<String> {
+        let labels = self.all_labels();
+        runs_on
+            .iter()
+            .filter(|label| !labels.contains(label))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Hardware and software a worker discovered on its host
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WorkerCapabilities {
+    /// macOS version of the build VMs, e.g. `15.1`
+    #[serde(default)]
+    pub macos_version: Option<String>,
+    /// Installed Xcode versions, e.g. `16.0`
+    #[serde(default)]
+    pub xcode_versions: Vec<String>,
+    /// CPU brand, e.g. `Apple M2 Pro`
+    #[serde(default)]
+    pub cpu: Option<String>,
+    #[serde(default)]
+    pub cpu_cores: Option<u32>,
+    #[serde(default)]
+    pub memory_gb: Option<u32>,
+    /// Machine architecture, e.g. `arm64`
+    #[serde(default)]
+    pub arch: Option<String>,
+}
+
+impl WorkerCapabilities {
+    /// Labels derived from the capabilities, e.g. `macos-15`, `macos-15.1`,
+    /// `xcode-16`, `xcode-16.0`, `arm64` and `m2`
+    #[must_use]
+    pub fn labels(&self) -> Vec<String> {
+        let mut labels = Vec::new();
+        let mut push = |label: String| {
+            let label = label.to_lowercase();
+            if is_valid_label(&label) && !labels.contains(&label) {
+                labels.push(label);
+            }
+        };
+
+        let versioned = |prefix: &str, version: &str| {
+            let major = version.split('.').next().unwrap_or(version);
+            [format!("{prefix}-{major}"), format!("{prefix}-{version}")]
+        };
+        if let Some(version) = &self.macos_version {
+            versioned("macos", version).into_iter().for_each(&mut push);
+        }
+        for version in &self.xcode_versions {
+            versioned("xcode", version).into_iter().for_each(&mut push);
+        }
+        if let Some(arch) = &self.arch {
+            push(arch.clone());
+        }
+        // Apple silicon generation, e.g. "m2" from "Apple M2 Pro"
+        if let Some(chip) = self.cpu.as_deref().and_then(|cpu| {
+            cpu.split_whitespace().find(|word| {
+                word.len() > 1
+                    && word.starts_with(['M', 'm'])
+                    && word[1..].chars().all(|c| c.is_ascii_digit())
+            })
+        }) {
+            push(chip.to_string());
+        }
+
+        labels
+    }
+}
+
+/// Most labels a job can require or a worker can be given
+pub const MAX_LABELS: usize = 16;
+
+/// Whether `label` can be used to route jobs, as a label or an image name:
+/// lowercase letters, digits, `.`, `_` and `-`, starting with a letter or digit
+#[must_use]
+pub fn is_valid_label(label: &str) -> bool {
+    label.len() <= 64
+        && label
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && label
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '.' | '_' | '-'))
 }
 
 /// Status of a worker
@@ -205,12 +842,161 @@ pub enum WorkerStatus {
     Draining,
 }
 
+impl std::str::FromStr for WorkerStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "online" => Ok(Self::Online),
+            "busy" => Ok(Self::Busy),
+            "offline" => Ok(Self::Offline),
+            "draining" => Ok(Self::Draining),
+            _ => Err(format!("Unknown worker status: {s}")),
+        }
+    }
+}
+
 /// Request for worker to claim a job
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClaimJobRequest {
     pub worker_id: Uuid,
 }
 
+/// A job handed to a worker, with the decrypted values it needs
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ClaimedJob {
+    #[serde(flatten)]
+    pub job: Job,
+    /// Decrypted credential for a private repository
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git_credential: Option<GitCredential>,
+    /// Values of the job's `secrets`, by name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub secret_values: BTreeMap<String, String>,
+    /// Artifacts of the jobs this one needs, with signed download URLs
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub upstream_artifacts: Vec<Artifact>,
+}
+
+impl std::fmt::Debug for ClaimedJob {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClaimedJob")
+            .field("job", &self.job)
+            .field("git_credential", &self.git_credential)
+            .field("secret_values", &self.secret_values.keys())
+            .field("upstream_artifacts", &self.upstream_artifacts)
+            .finish()
+    }
+}
+
+/// Kind of a stored git credential
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GitCredentialKind {
+    /// SSH private key (e.g. a deploy key) for `ssh://` and `git@` URLs
+    SshKey,
+    /// Personal access or other HTTPS token
+    HttpsToken,
+    /// Short-lived GitHub App installation token, used over HTTPS
+    InstallationToken,
+}
+
+impl GitCredentialKind {
+    /// HTTPS username used when none is stored (SSH keys take theirs from the URL)
+    #[must_use]
+    pub const fn default_username(self) -> &'static str {
+        match self {
+            Self::SshKey => "git",
+            Self::HttpsToken => "oauth2",
+            Self::InstallationToken => "x-access-token",
+        }
+    }
+}
+
+impl std::fmt::Display for GitCredentialKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::SshKey => write!(f, "ssh_key"),
+            Self::HttpsToken => write!(f, "https_token"),
+            Self::InstallationToken => write!(f, "installation_token"),
+        }
+    }
+}
+
+impl std::str::FromStr for GitCredentialKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ssh_key" => Ok(Self::SshKey),
+            "https_token" => Ok(Self::HttpsToken),
+            "installation_token" => Ok(Self::InstallationToken),
+            _ => Err(format!("Unknown git credential kind: {s}")),
+        }
+    }
+}
+
+/// A decrypted git credential on its way to a worker
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GitCredential {
+    pub kind: GitCredentialKind,
+    /// HTTPS username (token kinds)
+    pub username: Option<String>,
+    /// Private key or token
+    pub secret: String,
+}
+
+impl std::fmt::Debug for GitCredential {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GitCredential")
+            .field("kind", &self.kind)
+            .field("username", &self.username)
+            .field("secret", &"<redacted>")
+            .finish()
+    }
+}
+
+/// Request to store a git credential
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CreateGitCredentialRequest {
+    pub name: String,
+    pub kind: GitCredentialKind,
+    /// HTTPS username (defaults depend on the kind)
+    #[serde(default)]
+    pub username: Option<String>,
+    /// Private key or token; never returned by the API
+    pub secret: String,
+    /// When the credential stops working (installation tokens)
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// A stored git credential, without its secret
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GitCredentialInfo {
+    pub id: Uuid,
+    pub name: String,
+    pub kind: GitCredentialKind,
+    pub username: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Request to create or replace a secret
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SetSecretRequest {
+    /// Never returned by the API
+    pub value: String,
+}
+
+/// A stored secret, without its value
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SecretInfo {
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
 /// Result of a completed job
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct JobResult {
@@ -218,6 +1004,27 @@ pub struct JobResult {
     pub exit_code: i32,
     pub artifacts: Vec<Artifact>,
     pub build_minutes: f64,
+    /// Set when the worker stopped the build because the job was cancelled
+    #[serde(default)]
+    pub cancelled: bool,
+    /// Set when the worker stopped the build at the job's timeout
+    #[serde(default)]
+    pub timed_out: bool,
+    /// Why the job did not succeed, if it didn't
+    #[serde(default)]
+    pub failure_reason: Option<FailureReason>,
+    /// Full SHA of the commit that was built (git jobs)
+    #[serde(default)]
+    pub resolved_sha: Option<String>,
+    /// Outcome of each step of a pipeline job
+    #[serde(default)]
+    pub steps: Vec<StepResult>,
+}
+
+/// Request for worker to hand back a job it claimed but won't run
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReleaseJobRequest {
+    pub job_id: Uuid,
 }
 
 /// An artifact produced by a build
@@ -235,6 +1042,49 @@ pub struct WorkerHeartbeat {
     pub worker_id: Uuid,
     pub current_jobs: u32,
     pub capacity: u32,
+    /// Jobs currently running on this worker, whose leases the heartbeat renews
+    #[serde(default)]
+    pub job_ids: Vec<Uuid>,
+}
+
+/// Orchestrator reply to a worker heartbeat
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WorkerHeartbeatResponse {
+    /// Jobs from the heartbeat that were cancelled and should be stopped
+    #[serde(default)]
+    pub cancelled_job_ids: Vec<Uuid>,
+    /// The worker is draining: it should take no more jobs, finish the ones
+    /// it runs and shut down
+    #[serde(default)]
+    pub drain: bool,
+}
+
+/// A message from a worker on its push channel to the orchestrator
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WorkerMessage {
+    /// Offer the worker a job as soon as there is one it can run
+    Claim,
+    /// Withdraw a `Claim` that wasn't answered yet
+    Unclaim,
+    /// The same heartbeat the worker otherwise posts
+    Heartbeat(WorkerHeartbeat),
+    /// Log entries of one of the worker's jobs, answered with a `LogAck`
+    Logs(LogBatch),
+}
+
+/// A message from the orchestrator on a worker's push channel
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WorkerCommand {
+    /// A job claimed for the worker, in answer to its `Claim`
+    Offer { job: Box<ClaimedJob> },
+    /// Jobs of the worker's that were cancelled and should be stopped
+    Cancel { job_ids: Vec<Uuid> },
+    /// Take no more jobs, finish the running ones and shut down
+    Drain,
+    /// Log entries of a job were received up to `next_seq`
+    LogAck(LogAck),
 }
 
 /// Worker registration request
@@ -243,6 +1093,15 @@ pub struct RegisterWorkerRequest {
     pub hostname: String,
     pub capacity: u32,
     pub worker_id: Option<Uuid>,
+    /// Labels given to the worker by its operator
+    #[serde(default)]
+    pub labels: Vec<String>,
+    /// What the worker found on its host
+    #[serde(default)]
+    pub capabilities: WorkerCapabilities,
+    /// Names of the base images the worker can run jobs in
+    #[serde(default)]
+    pub images: Vec<String>,
 }
 
 /// Worker registration response
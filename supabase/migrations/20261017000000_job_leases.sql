@@ -0,0 +1,6 @@
+-- Job leases: workers renew them through heartbeats, and the orchestrator's
+-- reaper requeues or fails running jobs whose lease has expired
+ALTER TABLE "public"."jobs" ADD COLUMN IF NOT EXISTS "lease_expires_at" TIMESTAMPTZ;
+ALTER TABLE "public"."jobs" ADD COLUMN IF NOT EXISTS "status_reason" TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_jobs_lease_expires_at ON jobs(lease_expires_at) WHERE status = 'running';
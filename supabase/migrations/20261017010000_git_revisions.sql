@@ -0,0 +1,7 @@
+-- Git revisions: jobs can pin a ref and/or commit, opt into submodules and
+-- LFS, and record the commit the worker actually built
+ALTER TABLE "public"."jobs" ADD COLUMN IF NOT EXISTS "git_ref" TEXT;
+ALTER TABLE "public"."jobs" ADD COLUMN IF NOT EXISTS "commit_sha" TEXT;
+ALTER TABLE "public"."jobs" ADD COLUMN IF NOT EXISTS "submodules" BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE "public"."jobs" ADD COLUMN IF NOT EXISTS "lfs" BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE "public"."jobs" ADD COLUMN IF NOT EXISTS "resolved_sha" TEXT;
@@ -0,0 +1,19 @@
+-- Stored git credentials for private repositories. Secrets are encrypted by
+-- the orchestrator (CREDENTIALS_KEY) before they reach the database, and only
+-- the service role can read the table.
+CREATE TABLE IF NOT EXISTS git_credentials (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id UUID NOT NULL,  -- References auth.users
+    name TEXT NOT NULL,
+    kind TEXT NOT NULL CHECK (kind IN ('ssh_key', 'https_token', 'installation_token')),
+    username TEXT,
+    encrypted_secret TEXT NOT NULL,
+    expires_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_git_credentials_user ON git_credentials(user_id);
+
+ALTER TABLE git_credentials ENABLE ROW LEVEL SECURITY;
+
+ALTER TABLE "public"."jobs" ADD COLUMN IF NOT EXISTS "git_credential_id" UUID;
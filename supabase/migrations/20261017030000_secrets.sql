@@ -0,0 +1,17 @@
+-- Per-user build secrets. Values are encrypted by the orchestrator
+-- (CREDENTIALS_KEY) before they reach the database, and only the service role
+-- can read the table.
+CREATE TABLE IF NOT EXISTS secrets (
+    user_id UUID NOT NULL,  -- References auth.users
+    name TEXT NOT NULL,
+    encrypted_value TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_id, name)
+);
+
+ALTER TABLE secrets ENABLE ROW LEVEL SECURITY;
+
+-- Environment variables and secret names (never values) exported to a job
+ALTER TABLE "public"."jobs" ADD COLUMN IF NOT EXISTS "env" JSONB NOT NULL DEFAULT '{}';
+ALTER TABLE "public"."jobs" ADD COLUMN IF NOT EXISTS "secrets" JSONB NOT NULL DEFAULT '[]';
@@ -0,0 +1,3 @@
+-- Steps of jobs created from an alloy.yml pipeline, and how each one ended
+ALTER TABLE "public"."jobs" ADD COLUMN IF NOT EXISTS "steps" JSONB NOT NULL DEFAULT '[]';
+ALTER TABLE "public"."jobs" ADD COLUMN IF NOT EXISTS "step_results" JSONB NOT NULL DEFAULT '[]';
@@ -0,0 +1,12 @@
+-- Jobs that must complete before a job runs (job IDs)
+ALTER TABLE "public"."jobs" ADD COLUMN IF NOT EXISTS "needs" JSONB NOT NULL DEFAULT '[]';
+
+-- Pending jobs whose dependencies have all completed, for workers to claim
+CREATE OR REPLACE VIEW claimable_jobs WITH (security_invoker = true) AS
+SELECT * FROM jobs
+WHERE status = 'pending'
+  AND NOT EXISTS (
+      SELECT 1 FROM jsonb_array_elements_text(jobs.needs) AS need
+      LEFT JOIN jobs AS upstream ON upstream.id = need::uuid
+      WHERE upstream.status IS DISTINCT FROM 'completed'
+  );
@@ -0,0 +1,18 @@
+-- Runs: jobs submitted with a build matrix, one job per matrix cell
+CREATE TABLE IF NOT EXISTS runs (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    customer_id UUID NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    matrix JSONB NOT NULL DEFAULT '{}'  -- Axis name -> values
+);
+
+ALTER TABLE runs ENABLE ROW LEVEL SECURITY;
+
+CREATE POLICY "Allow authenticated access to runs" ON runs
+    FOR ALL USING (true);
+
+-- The run a job belongs to, and the job's value for each matrix axis
+ALTER TABLE "public"."jobs" ADD COLUMN IF NOT EXISTS "run_id" UUID;
+ALTER TABLE "public"."jobs" ADD COLUMN IF NOT EXISTS "matrix" JSONB NOT NULL DEFAULT '{}';
+
+CREATE INDEX IF NOT EXISTS idx_jobs_run_id ON jobs(run_id);
@@ -0,0 +1,16 @@
+-- Labels a worker must have to claim a job
+ALTER TABLE "public"."jobs" ADD COLUMN IF NOT EXISTS "runs_on" JSONB NOT NULL DEFAULT '[]';
+
+-- Labels given to a worker, and what it discovered on its host
+ALTER TABLE "public"."workers" ADD COLUMN IF NOT EXISTS "labels" JSONB NOT NULL DEFAULT '[]';
+ALTER TABLE "public"."workers" ADD COLUMN IF NOT EXISTS "capabilities" JSONB NOT NULL DEFAULT '{}';
+
+-- Re-create the view so it picks up the columns added since (workers filter on runs_on)
+CREATE OR REPLACE VIEW claimable_jobs WITH (security_invoker = true) AS
+SELECT * FROM jobs
+WHERE status = 'pending'
+  AND NOT EXISTS (
+      SELECT 1 FROM jsonb_array_elements_text(jobs.needs) AS need
+      LEFT JOIN jobs AS upstream ON upstream.id = need::uuid
+      WHERE upstream.status IS DISTINCT FROM 'completed'
+  );
@@ -0,0 +1,15 @@
+-- Named base image a job's VM is cloned from (the worker's default if NULL)
+ALTER TABLE "public"."jobs" ADD COLUMN IF NOT EXISTS "image" TEXT;
+
+-- Names of the base images a worker can run jobs in
+ALTER TABLE "public"."workers" ADD COLUMN IF NOT EXISTS "images" JSONB NOT NULL DEFAULT '[]';
+
+-- Re-create the view so it picks up the image column (workers filter on it)
+CREATE OR REPLACE VIEW claimable_jobs WITH (security_invoker = true) AS
+SELECT * FROM jobs
+WHERE status = 'pending'
+  AND NOT EXISTS (
+      SELECT 1 FROM jsonb_array_elements_text(jobs.needs) AS need
+      LEFT JOIN jobs AS upstream ON upstream.id = need::uuid
+      WHERE upstream.status IS DISTINCT FROM 'completed'
+  );
@@ -0,0 +1,18 @@
+-- Minutes a job may run before it is stopped (the worker's default if NULL)
+ALTER TABLE "public"."jobs" ADD COLUMN IF NOT EXISTS "timeout_minutes" INTEGER;
+
+-- Jobs stopped at their timeout get their own status
+ALTER TABLE "public"."jobs" DROP CONSTRAINT "jobs_status_check";
+
+ALTER TABLE "public"."jobs" ADD CONSTRAINT "jobs_status_check"
+CHECK (status = ANY (ARRAY['pending'::text, 'uploading'::text, 'running'::text, 'completed'::text, 'failed'::text, 'cancelled'::text, 'timed_out'::text]));
+
+-- Re-create the view so it picks up the timeout_minutes column
+CREATE OR REPLACE VIEW claimable_jobs WITH (security_invoker = true) AS
+SELECT * FROM jobs
+WHERE status = 'pending'
+  AND NOT EXISTS (
+      SELECT 1 FROM jsonb_array_elements_text(jobs.needs) AS need
+      LEFT JOIN jobs AS upstream ON upstream.id = need::uuid
+      WHERE upstream.status IS DISTINCT FROM 'completed'
+  );
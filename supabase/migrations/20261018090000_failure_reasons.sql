@@ -0,0 +1,19 @@
+-- Why a finished job did not succeed
+ALTER TABLE "public"."jobs" ADD COLUMN IF NOT EXISTS "failure_reason" TEXT
+CHECK (failure_reason = ANY (ARRAY['infra'::text, 'source_fetch'::text, 'timeout'::text, 'user_exit'::text, 'cancelled'::text]));
+
+-- Jobs that failed for infrastructure reasons are requeued, preferably on
+-- another worker than the one they failed on
+ALTER TABLE "public"."jobs" ADD COLUMN IF NOT EXISTS "infra_retries" INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE "public"."jobs" ADD COLUMN IF NOT EXISTS "avoid_worker_id" UUID;
+ALTER TABLE "public"."jobs" ADD COLUMN IF NOT EXISTS "avoid_worker_until" TIMESTAMPTZ;
+
+-- Re-create the view so it picks up the new columns
+CREATE OR REPLACE VIEW claimable_jobs WITH (security_invoker = true) AS
+SELECT * FROM jobs
+WHERE status = 'pending'
+  AND NOT EXISTS (
+      SELECT 1 FROM jsonb_array_elements_text(jobs.needs) AS need
+      LEFT JOIN jobs AS upstream ON upstream.id = need::uuid
+      WHERE upstream.status IS DISTINCT FROM 'completed'
+  );
@@ -0,0 +1,18 @@
+-- Retries are new attempts at a job, linked to the attempt they retry
+ALTER TABLE "public"."jobs" ADD COLUMN IF NOT EXISTS "attempt" INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE "public"."jobs" ADD COLUMN IF NOT EXISTS "parent_job_id" UUID;
+ALTER TABLE "public"."jobs" ADD COLUMN IF NOT EXISTS "retry" JSONB;
+ALTER TABLE "public"."jobs" ADD COLUMN IF NOT EXISTS "not_before" TIMESTAMPTZ;
+
+CREATE INDEX IF NOT EXISTS idx_jobs_parent_job_id ON jobs(parent_job_id);
+
+-- Retries waiting out their backoff can't be claimed yet
+CREATE OR REPLACE VIEW claimable_jobs WITH (security_invoker = true) AS
+SELECT * FROM jobs
+WHERE status = 'pending'
+  AND (not_before IS NULL OR not_before < now())
+  AND NOT EXISTS (
+      SELECT 1 FROM jsonb_array_elements_text(jobs.needs) AS need
+      LEFT JOIN jobs AS upstream ON upstream.id = need::uuid
+      WHERE upstream.status IS DISTINCT FROM 'completed'
+  );
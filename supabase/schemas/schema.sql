@@ -12,13 +12,47 @@ CREATE TABLE IF NOT EXISTS jobs (
     source_url TEXT,
     command TEXT,
     script TEXT,
-    status TEXT NOT NULL DEFAULT 'pending' CHECK (status IN ('pending', 'running', 'completed', 'failed', 'cancelled')),
+    status TEXT NOT NULL DEFAULT 'pending' CHECK (status IN ('pending', 'uploading', 'running', 'completed', 'failed', 'cancelled', 'timed_out')),
     worker_id UUID,
     created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
     started_at TIMESTAMPTZ,
     completed_at TIMESTAMPTZ,
     exit_code INTEGER,
-    build_minutes DOUBLE PRECISION
+    build_minutes DOUBLE PRECISION,
+    lease_expires_at TIMESTAMPTZ,
+    status_reason TEXT,
+    git_ref TEXT,
+    commit_sha TEXT,
+    submodules BOOLEAN NOT NULL DEFAULT FALSE,
+    lfs BOOLEAN NOT NULL DEFAULT FALSE,
+    resolved_sha TEXT,
+    git_credential_id UUID,
+    env JSONB NOT NULL DEFAULT '{}',
+    secrets JSONB NOT NULL DEFAULT '[]',
+    steps JSONB NOT NULL DEFAULT '[]',
+    step_results JSONB NOT NULL DEFAULT '[]',
+    needs JSONB NOT NULL DEFAULT '[]',
+    run_id UUID,
+    matrix JSONB NOT NULL DEFAULT '{}',
+    runs_on JSONB NOT NULL DEFAULT '[]',
+    image TEXT,
+    timeout_minutes INTEGER,
+    failure_reason TEXT CHECK (failure_reason IN ('infra', 'source_fetch', 'timeout', 'user_exit', 'cancelled')),
+    infra_retries INTEGER NOT NULL DEFAULT 0,
+    avoid_worker_id UUID,
+    avoid_worker_until TIMESTAMPTZ,
+    attempt INTEGER NOT NULL DEFAULT 1,
+    parent_job_id UUID,
+    retry JSONB,
+    not_before TIMESTAMPTZ
+);
+
+-- Runs: jobs submitted with a build matrix, one job per matrix cell
+CREATE TABLE IF NOT EXISTS runs (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    customer_id UUID NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    matrix JSONB NOT NULL DEFAULT '{}'
 );
 
 -- Workers table
@@ -28,7 +62,10 @@ CREATE TABLE IF NOT EXISTS workers (
     capacity INTEGER NOT NULL DEFAULT 2,
     current_jobs INTEGER NOT NULL DEFAULT 0,
     last_heartbeat TIMESTAMPTZ NOT NULL DEFAULT NOW(),
-    status TEXT NOT NULL DEFAULT 'online' CHECK (status IN ('online', 'busy', 'offline', 'draining'))
+    status TEXT NOT NULL DEFAULT 'online' CHECK (status IN ('online', 'busy', 'offline', 'draining')),
+    labels JSONB NOT NULL DEFAULT '[]',
+    capabilities JSONB NOT NULL DEFAULT '{}',
+    images JSONB NOT NULL DEFAULT '[]'
 );
 
 -- Artifacts table
@@ -46,14 +83,29 @@ CREATE TABLE IF NOT EXISTS artifacts (
 CREATE INDEX IF NOT EXISTS idx_jobs_status ON jobs(status);
 CREATE INDEX IF NOT EXISTS idx_jobs_customer_id ON jobs(customer_id);
 CREATE INDEX IF NOT EXISTS idx_jobs_created_at ON jobs(created_at);
+CREATE INDEX IF NOT EXISTS idx_jobs_lease_expires_at ON jobs(lease_expires_at) WHERE status = 'running';
+CREATE INDEX IF NOT EXISTS idx_jobs_run_id ON jobs(run_id);
+CREATE INDEX IF NOT EXISTS idx_jobs_parent_job_id ON jobs(parent_job_id);
 CREATE INDEX IF NOT EXISTS idx_artifacts_job_id ON artifacts(job_id);
 CREATE INDEX IF NOT EXISTS idx_workers_status ON workers(status);
 
+-- Pending jobs whose dependencies have all completed, for workers to claim
+CREATE OR REPLACE VIEW claimable_jobs WITH (security_invoker = true) AS
+SELECT * FROM jobs
+WHERE status = 'pending'
+  AND (not_before IS NULL OR not_before < now())
+  AND NOT EXISTS (
+      SELECT 1 FROM jsonb_array_elements_text(jobs.needs) AS need
+      LEFT JOIN jobs AS upstream ON upstream.id = need::uuid
+      WHERE upstream.status IS DISTINCT FROM 'completed'
+  );
+
 -- Row Level Security (RLS) policies
 -- Enable RLS on all tables
 ALTER TABLE jobs ENABLE ROW LEVEL SECURITY;
 ALTER TABLE workers ENABLE ROW LEVEL SECURITY;
 ALTER TABLE artifacts ENABLE ROW LEVEL SECURITY;
+ALTER TABLE runs ENABLE ROW LEVEL SECURITY;
 
 -- For now, allow authenticated access to all tables
 -- You should customize these policies based on your auth setup
@@ -66,6 +118,9 @@ CREATE POLICY "Allow authenticated access to workers" ON workers
 CREATE POLICY "Allow authenticated access to artifacts" ON artifacts
     FOR ALL USING (true);
 
+CREATE POLICY "Allow authenticated access to runs" ON runs
+    FOR ALL USING (true);
+
 -- Create storage bucket for artifacts
 INSERT INTO storage.buckets (id, name, public)
 VALUES ('artifacts', 'artifacts', true)
@@ -105,6 +160,36 @@ CREATE POLICY "Users can delete own API keys"
     ON api_keys FOR DELETE
     USING (auth.uid() = user_id);
 
+-- Git credentials for private repositories (secrets encrypted by the orchestrator)
+CREATE TABLE IF NOT EXISTS git_credentials (
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id UUID NOT NULL,  -- References auth.users
+    name TEXT NOT NULL,
+    kind TEXT NOT NULL CHECK (kind IN ('ssh_key', 'https_token', 'installation_token')),
+    username TEXT,
+    encrypted_secret TEXT NOT NULL,
+    expires_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_git_credentials_user ON git_credentials(user_id);
+
+-- RLS without policies: only the orchestrator's service role can read secrets
+ALTER TABLE git_credentials ENABLE ROW LEVEL SECURITY;
+
+-- Build secrets (values encrypted by the orchestrator)
+CREATE TABLE IF NOT EXISTS secrets (
+    user_id UUID NOT NULL,  -- References auth.users
+    name TEXT NOT NULL,
+    encrypted_value TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_id, name)
+);
+
+-- RLS without policies: only the orchestrator's service role can read secrets
+ALTER TABLE secrets ENABLE ROW LEVEL SECURITY;
+
 -- Storage policy for artifacts bucket
 CREATE POLICY "Allow public read access to artifacts"
 ON storage.objects FOR SELECT
@@ -0,0 +1,252 @@
+//! Local backend - "VMs" are directories on the host and commands run as
+//! local processes with that directory as `HOME`.
+//!
+//! This keeps jobs out of each other's way but is not a security boundary:
+//! use it for tests and development, never for untrusted builds.
+
+use anyhow::Result;
+use async_trait::async_trait;
+use shared::LogStream;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::Command;
+
+use super::{ExecOutput, LineSender, VmBackend, VmSession};
+use crate::vm_pool::PooledVm;
+
+/// Address reported for every local VM
+const LOCAL_IP: &str = "127.0.0.1";
+
+pub struct LocalBackend {
+    root: PathBuf,
+}
+
+impl LocalBackend {
+    pub const fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    /// Home directory of a VM
+    fn home(&self, name: &str) -> PathBuf {
+        self.root.join(name)
+    }
+}
+
+#[async_trait]
+impl VmBackend for LocalBackend {
+    async fn clone_vm(&self, image: &str, name: &str) -> Result<()> {
+        let home = self.home(name);
+        if tokio::fs::try_exists(&home).await? {
+            tracing::info!(vm_name = %name, "VM already exists, reusing");
+            return Ok(());
+        }
+        tokio::fs::create_dir_all(&home).await?;
+
+        // A directory image seeds the VM's home; anything else starts it empty
+        if Path::new(image).is_dir() {
+            let output = Command::new("cp")
+                .arg("-R")
+                .arg(Path::new(image).join("."))
+                .arg(&home)
+                .output()
+                .await?;
+            if !output.status.success() {
+                anyhow::bail!(
+                    "Failed to copy image {image}: {}",
+                    String::from_utf8_lossy(&output.stderr)
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn run(&self, _name: &str) -> Result<()> {
+        Ok(())
+    }
+
+    async fn ip(&self, name: &str) -> Result<Option<String>> {
+        Ok(tokio::fs::try_exists(self.home(name))
+            .await?
+            .then(|| LOCAL_IP.to_string()))
+    }
+
+    async fn connect(&self, vm: &PooledVm) -> Result<Box<dyn VmSession>> {
+        let home = self.home(&vm.name);
+        if !tokio::fs::try_exists(&home).await? {
+            anyhow::bail!("VM {} does not exist", vm.name);
+        }
+        Ok(Box::new(LocalSession { home }))
+    }
+
+    async fn delete(&self, name: &str) -> Result<()> {
+        let home = self.home(name);
+        if tokio::fs::try_exists(&home).await? {
+            tokio::fs::remove_dir_all(&home).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Commands for a local VM, run with its directory as `HOME`
+struct LocalSession {
+    home: PathBuf,
+}
+
+impl LocalSession {
+    /// A process started in the VM's home directory
+    fn command(&self, program: &str) -> Command {
+        let mut cmd = Command::new(program);
+        cmd.current_dir(&self.home)
+            .env_clear()
+            .env("HOME", &self.home)
+            .env("PATH", std::env::var_os("PATH").unwrap_or_default())
+            .kill_on_drop(true);
+
+        // Lead a process group of its own, as an SSH session would, so the
+        // build's process tree can be signalled without touching the worker
+        #[cfg(unix)]
+        cmd.process_group(0);
+
+        cmd
+    }
+
+    /// A command run through the shell
+    fn shell(&self, command: &str) -> Command {
+        let mut cmd = self.command("sh");
+        cmd.args(["-c", command]);
+        cmd
+    }
+
+    /// Resolve a VM path (`~/...`, relative or absolute) on the host
+    fn resolve(&self, remote: &str) -> PathBuf {
+        match remote.strip_prefix("~/") {
+            Some(rest) => self.home.join(rest),
+            None if Path::new(remote).is_absolute() => PathBuf::from(remote),
+            None => self.home.join(remote),
+        }
+    }
+}
+
+#[async_trait]
+impl VmSession for LocalSession {
+    async fn exec(&self, command: &str) -> Result<ExecOutput> {
+        run(self.shell(command)).await
+    }
+
+    async fn exec_args(&self, args: &[&str]) -> Result<ExecOutput> {
+        let (program, args) = args
+            .split_first()
+            .ok_or_else(|| anyhow::anyhow!("No program to run"))?;
+        let mut cmd = self.command(program);
+        cmd.args(args);
+        run(cmd).await
+    }
+
+    async fn exec_streaming(&self, command: &str, _tty: bool, lines: LineSender) -> Result<i32> {
+        let mut child = self
+            .shell(command)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let stderr = child.stderr.take().expect("stderr is piped");
+
+        let ((), (), status) = tokio::join!(
+            forward_lines(stdout, LogStream::Stdout, &lines),
+            forward_lines(stderr, LogStream::Stderr, &lines),
+            child.wait()
+        );
+        Ok(status?.code().unwrap_or(-1))
+    }
+
+    async fn upload(&self, local: &Path, remote: &str) -> Result<()> {
+        tokio::fs::copy(local, self.resolve(remote)).await?;
+        Ok(())
+    }
+
+    async fn download(&self, remote: &str, local: &Path) -> Result<()> {
+        tokio::fs::copy(self.resolve(remote), local).await?;
+        Ok(())
+    }
+}
+
+/// Run a command to completion
+async fn run(mut cmd: Command) -> Result<ExecOutput> {
+    let output = cmd.output().await?;
+    Ok(ExecOutput {
+        exit_code: output.status.code().unwrap_or(-1),
+        stdout: output.stdout,
+        stderr: output.stderr,
+    })
+}
+
+/// Send each line read from `reader` to `lines`
+async fn forward_lines(reader: impl AsyncRead + Unpin, stream: LogStream, lines: &LineSender) {
+    let mut reader = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = reader.next_line().await {
+        let _ = lines.send((stream, line));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm_pool::VmState;
+
+    #[tokio::test]
+    async fn test_local_vm_lifecycle() {
+        let root = std::env::temp_dir().join(format!("alloy-local-vms-{}", uuid::Uuid::new_v4()));
+        let backend = LocalBackend::new(root.clone());
+
+        backend.clone_vm("no-such-image", "vm-0").await.unwrap();
+        let vm = PooledVm {
+            name: "vm-0".to_string(),
+            image: "default".to_string(),
+            ip: backend.ip("vm-0").await.unwrap().unwrap(),
+            state: VmState::Ready,
+        };
+
+        let session = backend.connect(&vm).await.unwrap();
+
+        // Commands run with the VM directory as their home
+        let output = session.exec("echo built > ~/out.txt && pwd").await.unwrap();
+        assert!(output.success());
+        assert_eq!(
+            output.stdout_lossy().trim(),
+            root.join("vm-0").to_string_lossy()
+        );
+
+        let copied = root.join("copied.txt");
+        session.download("~/out.txt", &copied).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&copied).unwrap(), "built\n");
+
+        // Streamed output arrives line by line, tagged with its stream
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let code = session
+            .exec_streaming("echo one; echo two >&2; exit 4", false, tx)
+            .await
+            .unwrap();
+        assert_eq!(code, 4);
+        let mut lines = Vec::new();
+        while let Some(line) = rx.recv().await {
+            lines.push(line);
+        }
+        lines.sort_by_key(|(_, content)| content.clone());
+        assert_eq!(
+            lines,
+            vec![
+                (LogStream::Stdout, "one".to_string()),
+                (LogStream::Stderr, "two".to_string())
+            ]
+        );
+
+        drop(session);
+        backend.delete("vm-0").await.unwrap();
+        assert_eq!(backend.ip("vm-0").await.unwrap(), None);
+        backend.delete("vm-0").await.unwrap();
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
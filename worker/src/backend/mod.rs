@@ -0,0 +1,221 @@
+//! VM backends - how the worker creates, reaches and destroys build VMs
+//!
+//! `tart` runs real macOS VMs and reaches them over SSH. `local` runs each
+//! "VM" as a directory on the host with commands executed as local processes,
+//! so the worker's job flow can be exercised on Linux and in CI.
+
+pub mod local;
+pub mod tart;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use shared::LogStream;
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+use crate::vm_pool::PooledVm;
+
+/// Receives output lines from a streamed command
+pub type LineSender = mpsc::UnboundedSender<(LogStream, String)>;
+
+/// Operations the pool and executor need from a VM provider
+#[async_trait]
+pub trait VmBackend: Send + Sync {
+    /// Make sure `base_image` can be used by this worker, returning the image
+    /// pool VMs should actually be cloned from
+    async fn prepare_image(&self, base_image: &str) -> Result<String> {
+        Ok(base_image.to_string())
+    }
+
+    /// Clone `image` into a new VM called `name`; an existing VM with that name is kept
+    async fn clone_vm(&self, image: &str, name: &str) -> Result<()>;
+
+    /// Boot a VM in the background
+    async fn run(&self, name: &str) -> Result<()>;
+
+    /// Address of a running VM, or `None` while it is still booting
+    async fn ip(&self, name: &str) -> Result<Option<String>>;
+
+    /// Wait for a freshly booted VM to accept connections
+    async fn wait_ready(&self, _vm: &PooledVm) -> Result<()> {
+        Ok(())
+    }
+
+    /// Open a session to a ready VM
+    async fn connect(&self, vm: &PooledVm) -> Result<Box<dyn VmSession>>;
+
+    /// Stop and delete a VM; succeeds if it does not exist
+    async fn delete(&self, name: &str) -> Result<()>;
+}
+
+/// An open connection to one VM. Every command and transfer made through a
+/// session shares it, so a job only connects once.
+#[async_trait]
+pub trait VmSession: Send + Sync {
+    /// Run `command` through a shell inside the VM and collect its output.
+    /// Only for commands the worker writes itself: job input goes through
+    /// [`VmSession::exec_args`] or [`VmSession::upload`].
+    async fn exec(&self, command: &str) -> Result<ExecOutput>;
+
+    /// Run a program with `args` (program first) from the home directory,
+    /// passing each argument through unchanged, and collect its output
+    async fn exec_args(&self, args: &[&str]) -> Result<ExecOutput> {
+        self.exec(&shell_join(args)).await
+    }
+
+    /// Run `command` through a shell inside the VM, sending each line of output
+    /// to `lines`, and return its exit code. With `tty` a pseudo-terminal is
+    /// allocated for tools that need one.
+    async fn exec_streaming(&self, command: &str, tty: bool, lines: LineSender) -> Result<i32>;
+
+    /// Copy a local file into the VM
+    async fn upload(&self, local: &Path, remote: &str) -> Result<()>;
+
+    /// Write `contents` to a file in the VM that only the VM user can read,
+    /// without it ever being readable by others on the worker either
+    async fn upload_private(&self, contents: &str, remote: &str) -> Result<()> {
+        let local = std::env::temp_dir().join(format!("alloy-{}", uuid::Uuid::new_v4()));
+        let copied = async {
+            write_private(&local, contents).await?;
+            self.upload(&local, remote).await
+        }
+        .await;
+        let _ = tokio::fs::remove_file(&local).await;
+        copied?;
+
+        let output = self.exec_args(&["chmod", "600", remote]).await?;
+        if !output.success() {
+            anyhow::bail!("Failed to protect {remote}: {}", output.stderr_lossy());
+        }
+        Ok(())
+    }
+
+    /// Copy a file out of the VM
+    async fn download(&self, remote: &str, local: &Path) -> Result<()>;
+}
+
+/// Output of a command run to completion
+#[derive(Debug)]
+pub struct ExecOutput {
+    /// Exit code, or -1 if the command was killed by a signal
+    pub exit_code: i32,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+impl ExecOutput {
+    pub const fn success(&self) -> bool {
+        self.exit_code == 0
+    }
+
+    pub fn stdout_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.stdout)
+    }
+
+    pub fn stderr_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.stderr)
+    }
+}
+
+/// Write a file only the worker's user can read
+async fn write_private(path: &Path, contents: &str) -> Result<()> {
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut file = options.open(path).await?;
+    tokio::io::AsyncWriteExt::write_all(&mut file, contents.as_bytes()).await?;
+    Ok(())
+}
+
+/// Quote each argument so a POSIX shell (or zsh) reads it back verbatim
+pub fn shell_join(args: &[&str]) -> String {
+    args.iter()
+        .map(|arg| format!("'{}'", arg.replace('\'', r"'\''")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Which VM backend the worker uses
+#[derive(Debug, Clone)]
+pub enum VmBackendKind {
+    /// Tart macOS VMs reached over SSH with the worker's key at `key_path`
+    Tart { key_path: PathBuf },
+    /// Local directories and processes under `root`
+    Local { root: PathBuf },
+}
+
+impl VmBackendKind {
+    /// Select the backend from `VM_BACKEND` (`tart` or `local`)
+    pub fn from_env(data_dir: &str) -> Result<Self> {
+        match std::env::var("VM_BACKEND")
+            .unwrap_or_else(|_| "tart".to_string())
+            .to_lowercase()
+            .as_str()
+        {
+            "tart" => Ok(Self::Tart {
+                key_path: std::env::var("VM_SSH_KEY")
+                    .map_or_else(|_| Path::new(data_dir).join("vm_ssh_key"), PathBuf::from),
+            }),
+            "local" => Ok(Self::Local {
+                root: std::env::var("LOCAL_VM_DIR")
+                    .map_or_else(|_| Path::new(data_dir).join("vms"), PathBuf::from),
+            }),
+            other => anyhow::bail!("Unknown VM_BACKEND: {other} (expected tart or local)"),
+        }
+    }
+
+    /// Human-readable name for logs
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Tart { .. } => "tart",
+            Self::Local { .. } => "local",
+        }
+    }
+
+    /// Instantiate the backend
+    pub fn build(&self) -> Result<Arc<dyn VmBackend>> {
+        Ok(match self {
+            Self::Tart { key_path } => Arc::new(tart::TartBackend::new(key_path)?),
+            Self::Local { root } => Arc::new(local::LocalBackend::new(root.clone())),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shell_join_survives_injection() {
+        let args = [
+            "printf",
+            "%s\\n",
+            "it's",
+            "'; touch pwned; '",
+            "$(touch pwned)",
+            "`touch pwned`",
+            "a;b && c | d",
+            "line\nbreak",
+            "$HOME *",
+        ];
+        let dir = std::env::temp_dir().join(format!("alloy-quote-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let output = std::process::Command::new("sh")
+            .args(["-c", &shell_join(&args)])
+            .current_dir(&dir)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+
+        let expected = args[2..].join("\n") + "\n";
+        assert_eq!(String::from_utf8_lossy(&output.stdout), expected);
+        assert!(!dir.join("pwned").exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
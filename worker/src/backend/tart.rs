@@ -0,0 +1,523 @@
+//! Tart backend - macOS VMs managed by the `tart` CLI, reached over SSH
+//!
+//! The worker logs in with its own keypair, generated on first start. Base
+//! images only allow the default password, so each one is prepared once: a
+//! staging clone is booted, the worker's public key is added to its
+//! `authorized_keys`, and the result is saved as a local image that pool VMs
+//! are cloned from. A pool VM's host key is pinned when it boots and every
+//! later connection must present the same key.
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use russh::client;
+use russh::keys::ssh_key::rand_core::OsRng;
+use russh::keys::ssh_key::LineEnding;
+use russh::keys::{Algorithm, HashAlg, PrivateKey, PrivateKeyWithHashAlg, PublicKey};
+use russh::ChannelMsg;
+use russh_sftp::client::SftpSession;
+use shared::LogStream;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::OnceCell;
+
+use super::{ExecOutput, LineSender, VmBackend, VmSession};
+use crate::vm_pool::{wait_for_ip, PooledVm};
+
+/// Account used inside the VM (admin/admin for Cirrus Tart images)
+const VM_USER: &str = "admin";
+
+/// Password of the base image, only used to install the worker's key
+const BOOTSTRAP_PASSWORD: &str = "admin";
+
+const SSH_PORT: u16 = 22;
+
+/// How long a booted VM gets to start accepting SSH connections
+const SSH_READY_TIMEOUT: Duration = Duration::from_mins(2);
+
+/// Terminal size reported for jobs that need a PTY
+const PTY_COLUMNS: u32 = 200;
+const PTY_ROWS: u32 = 50;
+
+/// How the worker logs into a VM
+#[derive(Debug, Clone, Copy)]
+enum Login {
+    /// The worker's key, for VMs cloned from a prepared image
+    Key,
+    /// The base image's password, only while preparing an image
+    Password,
+}
+
+pub struct TartBackend {
+    /// The worker's private key, authorized in every prepared image
+    key: Arc<PrivateKey>,
+    /// Host keys pinned at boot, by VM name
+    host_keys: Mutex<HashMap<String, PublicKey>>,
+    ssh_config: Arc<client::Config>,
+}
+
+impl TartBackend {
+    /// Create the backend, generating the worker's key at `key_path` if needed
+    pub fn new(key_path: &Path) -> Result<Self> {
+        let key = if key_path.exists() {
+            russh::keys::load_secret_key(key_path, None)
+                .with_context(|| format!("Failed to load VM SSH key {}", key_path.display()))?
+        } else {
+            tracing::info!(path = %key_path.display(), "Generating VM SSH key...");
+            let mut key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519)?;
+            key.set_comment("alloy-worker");
+            if let Some(parent) = key_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            key.write_openssh_file(key_path, LineEnding::LF)
+                .with_context(|| format!("Failed to write VM SSH key {}", key_path.display()))?;
+            key
+        };
+
+        Ok(Self {
+            key: Arc::new(key),
+            host_keys: Mutex::new(HashMap::new()),
+            ssh_config: Arc::new(client::Config {
+                keepalive_interval: Some(Duration::from_secs(30)),
+                ..Default::default()
+            }),
+        })
+    }
+
+    /// Name of the local image holding `base_image` with this worker's key installed
+    fn prepared_image_name(&self, base_image: &str) -> String {
+        let fingerprint = self.key.public_key().fingerprint(HashAlg::Sha256);
+        let key_id = fingerprint.as_bytes()[..6]
+            .iter()
+            .fold(String::new(), |mut id, byte| {
+                let _ = write!(id, "{byte:02x}");
+                id
+            });
+        let base: String = base_image
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .collect();
+        format!("alloy-{base}-{key_id}")
+    }
+
+    /// Boot a staging clone, install the worker's key and save it as `image`
+    async fn install_key(&self, staging: &str, image: &str) -> Result<()> {
+        self.run(staging).await?;
+        let ip = wait_for_ip(self, staging)
+            .await
+            .context("Staging VM did not get an IP address")?;
+        let (session, _) = self.first_connect(&ip, Login::Password).await?;
+
+        let public_key = self.key.public_key().to_openssh()?;
+        let output = session
+            .exec(&format!(
+                "mkdir -p ~/.ssh && chmod 700 ~/.ssh && \
+                 echo '{public_key}' >> ~/.ssh/authorized_keys && \
+                 chmod 600 ~/.ssh/authorized_keys && sync"
+            ))
+            .await?;
+        if !output.success() {
+            anyhow::bail!("Failed to install SSH key: {}", output.stderr_lossy());
+        }
+        drop(session);
+
+        stop(staging).await;
+        self.clone_vm(staging, image).await
+    }
+
+    /// Connect and log in. With `pinned` the VM must present that host key.
+    /// Returns the session along with the host key the VM presented.
+    async fn open(
+        &self,
+        ip: &str,
+        pinned: Option<PublicKey>,
+        login: Login,
+    ) -> Result<(SshSession, PublicKey)> {
+        let presented = Arc::new(Mutex::new(None));
+        let check = HostKeyCheck {
+            pinned,
+            presented: Arc::clone(&presented),
+        };
+        let mut handle =
+            client::connect(Arc::clone(&self.ssh_config), (ip, SSH_PORT), check).await?;
+
+        let auth = match login {
+            Login::Key => {
+                handle
+                    .authenticate_publickey(
+                        VM_USER,
+                        PrivateKeyWithHashAlg::new(Arc::clone(&self.key), None),
+                    )
+                    .await?
+            },
+            Login::Password => {
+                handle
+                    .authenticate_password(VM_USER, BOOTSTRAP_PASSWORD)
+                    .await?
+            },
+        };
+        if !auth.success() {
+            anyhow::bail!("SSH login as {VM_USER} was rejected ({login:?})");
+        }
+
+        let host_key = presented
+            .lock()
+            .expect("host key lock poisoned")
+            .take()
+            .context("VM presented no host key")?;
+        Ok((
+            SshSession {
+                handle,
+                sftp: OnceCell::new(),
+            },
+            host_key,
+        ))
+    }
+
+    /// Connect to a freshly booted VM, retrying until its SSH server is up.
+    /// Any host key is accepted: the VM was just cloned and booted by us.
+    async fn first_connect(&self, ip: &str, login: Login) -> Result<(SshSession, PublicKey)> {
+        let start = Instant::now();
+        loop {
+            match self.open(ip, None, login).await {
+                Ok(connected) => return Ok(connected),
+                Err(e) if start.elapsed() < SSH_READY_TIMEOUT => {
+                    tracing::debug!(ip = %ip, "SSH not ready yet: {}", e);
+                    tokio::time::sleep(Duration::from_secs(2)).await;
+                },
+                Err(e) => return Err(e.context(format!("VM at {ip} never accepted SSH"))),
+            }
+        }
+    }
+
+    fn forget_host_key(&self, name: &str) {
+        self.host_keys
+            .lock()
+            .expect("host key lock poisoned")
+            .remove(name);
+    }
+}
+
+#[async_trait]
+impl VmBackend for TartBackend {
+    async fn prepare_image(&self, base_image: &str) -> Result<String> {
+        let image = self.prepared_image_name(base_image);
+        if image_exists(&image).await? {
+            tracing::info!(image = %image, "Using prepared image");
+            return Ok(image);
+        }
+
+        tracing::info!(base_image = %base_image, image = %image, "Installing worker SSH key into base image...");
+        let staging = format!("{image}-staging");
+        self.delete(&staging).await?;
+        self.clone_vm(base_image, &staging).await?;
+        let result = self.install_key(&staging, &image).await;
+        if let Err(e) = self.delete(&staging).await {
+            tracing::warn!("Failed to delete VM {}: {}", staging, e);
+        }
+        result.with_context(|| format!("Failed to prepare image {image} from {base_image}"))?;
+
+        tracing::info!(image = %image, "Prepared image ready");
+        Ok(image)
+    }
+
+    async fn clone_vm(&self, image: &str, name: &str) -> Result<()> {
+        let output = Command::new("tart")
+            .args(["clone", image, name])
+            .output()
+            .await?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            // If VM already exists, that's ok - just use it
+            if !stderr.contains("already exists") {
+                anyhow::bail!("Failed to clone VM {name}: {stderr}");
+            }
+            tracing::info!(vm_name = %name, "VM already exists, reusing");
+        }
+
+        Ok(())
+    }
+
+    async fn run(&self, name: &str) -> Result<()> {
+        // A fresh boot gets its host key pinned again once it is up
+        self.forget_host_key(name);
+        let _child = Command::new("tart")
+            .args(["run", name, "--no-graphics"])
+            .spawn()?;
+        Ok(())
+    }
+
+    async fn ip(&self, name: &str) -> Result<Option<String>> {
+        let output = Command::new("tart").args(["ip", name]).output().await?;
+        if !output.status.success() {
+            return Ok(None);
+        }
+
+        let ip = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        Ok((!ip.is_empty()).then_some(ip))
+    }
+
+    async fn wait_ready(&self, vm: &PooledVm) -> Result<()> {
+        let (_session, host_key) = self.first_connect(&vm.ip, Login::Key).await?;
+        tracing::info!(
+            vm_name = %vm.name,
+            fingerprint = %host_key.fingerprint(HashAlg::Sha256),
+            "Pinned VM host key"
+        );
+        self.host_keys
+            .lock()
+            .expect("host key lock poisoned")
+            .insert(vm.name.clone(), host_key);
+        Ok(())
+    }
+
+    async fn connect(&self, vm: &PooledVm) -> Result<Box<dyn VmSession>> {
+        let pinned = self
+            .host_keys
+            .lock()
+            .expect("host key lock poisoned")
+            .get(&vm.name)
+            .cloned()
+            .with_context(|| format!("No host key pinned for VM {}", vm.name))?;
+
+        let (session, _) = self
+            .open(&vm.ip, Some(pinned), Login::Key)
+            .await
+            .with_context(|| format!("Failed to connect to VM {}", vm.name))?;
+        Ok(Box::new(session))
+    }
+
+    async fn delete(&self, name: &str) -> Result<()> {
+        self.forget_host_key(name);
+
+        // First stop the VM if running
+        stop(name).await;
+
+        // Then delete it
+        let output = Command::new("tart").args(["delete", name]).output().await?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            // Ignore "does not exist" errors - VM may have never been created
+            if !stderr.contains("does not exist") {
+                anyhow::bail!("Failed to delete VM {name}: {stderr}");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Stop a VM, ignoring failures (it may not be running)
+async fn stop(name: &str) {
+    let _ = Command::new("tart").args(["stop", name]).output().await;
+}
+
+/// Whether a local image or VM called `name` exists
+async fn image_exists(name: &str) -> Result<bool> {
+    let output = Command::new("tart")
+        .args(["list", "--source", "local", "--format", "json"])
+        .output()
+        .await?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "Failed to list VMs: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    let vms: Vec<serde_json::Value> = serde_json::from_slice(&output.stdout)?;
+    Ok(vms.iter().any(|vm| vm["Name"] == name))
+}
+
+/// Accepts the pinned host key (or any key when none is pinned yet) and
+/// records the key the VM presented
+struct HostKeyCheck {
+    pinned: Option<PublicKey>,
+    presented: Arc<Mutex<Option<PublicKey>>>,
+}
+
+impl client::Handler for HostKeyCheck {
+    type Error = russh::Error;
+
+    async fn check_server_key(&mut self, key: &PublicKey) -> Result<bool, Self::Error> {
+        *self.presented.lock().expect("host key lock poisoned") = Some(key.clone());
+        Ok(self
+            .pinned
+            .as_ref()
+            .is_none_or(|pinned| pinned.key_data() == key.key_data()))
+    }
+}
+
+/// One SSH connection to a VM; commands and file transfers each get a
+/// channel on it
+struct SshSession {
+    handle: client::Handle<HostKeyCheck>,
+    /// SFTP channel, opened on the first transfer
+    sftp: OnceCell<SftpSession>,
+}
+
+impl SshSession {
+    /// Run `command` on a new channel, passing output to `output` as it
+    /// arrives, and return its exit code
+    async fn run_command(
+        &self,
+        command: &str,
+        tty: bool,
+        mut output: impl FnMut(LogStream, &[u8]) + Send,
+    ) -> Result<i32> {
+        let mut channel = self.handle.channel_open_session().await?;
+        if tty {
+            // Force PTY allocation for tools like fastlane
+            channel
+                .request_pty(false, "xterm", PTY_COLUMNS, PTY_ROWS, 0, 0, &[])
+                .await?;
+        }
+        channel.exec(true, command).await?;
+
+        let mut exit_code = -1;
+        while let Some(msg) = channel.wait().await {
+            match msg {
+                ChannelMsg::Data { data } => output(LogStream::Stdout, &data),
+                ChannelMsg::ExtendedData { data, ext: 1 } => output(LogStream::Stderr, &data),
+                ChannelMsg::ExitStatus { exit_status } => {
+                    exit_code = i32::try_from(exit_status).unwrap_or(-1);
+                },
+                ChannelMsg::Failure => anyhow::bail!("VM refused to run the command"),
+                ChannelMsg::Close => break,
+                _ => {},
+            }
+        }
+
+        Ok(exit_code)
+    }
+
+    async fn sftp(&self) -> Result<&SftpSession> {
+        self.sftp
+            .get_or_try_init(|| async {
+                let channel = self.handle.channel_open_session().await?;
+                channel.request_subsystem(true, "sftp").await?;
+                Ok(SftpSession::new(channel.into_stream()).await?)
+            })
+            .await
+    }
+}
+
+/// SFTP paths are relative to the home directory and don't expand `~`
+fn sftp_path(remote: &str) -> &str {
+    remote.strip_prefix("~/").unwrap_or(remote)
+}
+
+#[async_trait]
+impl VmSession for SshSession {
+    async fn exec(&self, command: &str) -> Result<ExecOutput> {
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let exit_code = self
+            .run_command(command, false, |stream, data| match stream {
+                LogStream::Stdout => stdout.extend_from_slice(data),
+                LogStream::Stderr => stderr.extend_from_slice(data),
+            })
+            .await?;
+
+        Ok(ExecOutput {
+            exit_code,
+            stdout,
+            stderr,
+        })
+    }
+
+    async fn exec_streaming(&self, command: &str, tty: bool, lines: LineSender) -> Result<i32> {
+        let mut stdout = LineBuffer::default();
+        let mut stderr = LineBuffer::default();
+        let exit_code = self
+            .run_command(command, tty, |stream, data| {
+                let buffer = match stream {
+                    LogStream::Stdout => &mut stdout,
+                    LogStream::Stderr => &mut stderr,
+                };
+                for line in buffer.push(data) {
+                    let _ = lines.send((stream, line));
+                }
+            })
+            .await?;
+
+        for (stream, buffer) in [(LogStream::Stdout, stdout), (LogStream::Stderr, stderr)] {
+            if let Some(line) = buffer.finish() {
+                let _ = lines.send((stream, line));
+            }
+        }
+
+        Ok(exit_code)
+    }
+
+    async fn upload(&self, local: &Path, remote: &str) -> Result<()> {
+        let mut source = tokio::fs::File::open(local).await?;
+        let mut file = self.sftp().await?.create(sftp_path(remote)).await?;
+        tokio::io::copy(&mut source, &mut file).await?;
+        file.shutdown().await?;
+        Ok(())
+    }
+
+    async fn download(&self, remote: &str, local: &Path) -> Result<()> {
+        let mut file = self.sftp().await?.open(sftp_path(remote)).await?;
+        let mut dest = tokio::fs::File::create(local).await?;
+        tokio::io::copy(&mut file, &mut dest).await?;
+        dest.flush().await?;
+        Ok(())
+    }
+}
+
+/// Reassembles lines from the chunks of one output stream
+#[derive(Default)]
+struct LineBuffer(Vec<u8>);
+
+impl LineBuffer {
+    /// Append `data`, returning every line it completes
+    fn push(&mut self, data: &[u8]) -> Vec<String> {
+        self.0.extend_from_slice(data);
+        let mut lines = Vec::new();
+        while let Some(end) = self.0.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.0.drain(..=end).collect();
+            lines.push(to_line(&line));
+        }
+        lines
+    }
+
+    /// The final line, if the stream did not end with a newline
+    fn finish(self) -> Option<String> {
+        (!self.0.is_empty()).then(|| to_line(&self.0))
+    }
+}
+
+/// Decode a line, dropping the line ending (PTY output ends lines with `\r\n`)
+fn to_line(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes)
+        .trim_end_matches(['\r', '\n'])
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_buffer() {
+        let mut buffer = LineBuffer::default();
+        assert!(buffer.push(b"comp").is_empty());
+        assert_eq!(
+            buffer.push(b"iling\r\nlinking\nsig"),
+            vec!["compiling", "linking"]
+        );
+        assert_eq!(buffer.push(b"ning\n"), vec!["signing"]);
+        assert_eq!(buffer.push(b"done").len(), 0);
+        assert_eq!(buffer.finish().as_deref(), Some("done"));
+
+        assert_eq!(LineBuffer::default().finish(), None);
+    }
+}
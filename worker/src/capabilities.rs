@@ -0,0 +1,161 @@
+//! Discovering what this worker can build with
+//!
+//! The macOS and Xcode versions jobs see are those of the build VMs, so they
+//! are read inside a VM of the default image (jobs naming another image get
+//! what it has); the CPU and memory are the host's. The orchestrator derives
+//! routing labels from the result (e.g. `xcode-16`).
+
+use anyhow::Result;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::backend::VmBackend;
+use crate::vm_pool::{PooledVm, VmPool};
+use shared::WorkerCapabilities;
+
+/// Prints one `key=value` line per fact, on macOS or (for the local backend
+/// in development) Linux
+const DISCOVER_SCRIPT: &str = r#"
+if command -v sw_vers >/dev/null 2>&1; then
+    echo "macos=$(sw_vers -productVersion)"
+fi
+for app in /Applications/Xcode*.app; do
+    if [ -d "$app" ]; then
+        echo "xcode=$(defaults read "$app/Contents/Info" CFBundleShortVersionString 2>/dev/null)"
+    fi
+done
+echo "arch=$(uname -m)"
+if sysctl -n machdep.cpu.brand_string >/dev/null 2>&1; then
+    echo "cpu=$(sysctl -n machdep.cpu.brand_string)"
+    echo "cores=$(sysctl -n hw.ncpu)"
+    echo "memory=$(sysctl -n hw.memsize)"
+else
+    echo "cpu=$(grep -m1 'model name' /proc/cpuinfo 2>/dev/null | cut -d: -f2)"
+    echo "cores=$(nproc 2>/dev/null)"
+    echo "memory=$(awk '/MemTotal/ { printf "%.0f\n", $2 * 1024 }' /proc/meminfo 2>/dev/null)"
+fi
+"#;
+
+/// Discover the worker's capabilities, leaving out whatever can't be found
+pub async fn discover(backend: &Arc<dyn VmBackend>, pool: &VmPool) -> WorkerCapabilities {
+    let host = match discover_on_host().await {
+        Ok(output) => parse(&output),
+        Err(e) => {
+            tracing::warn!("Failed to discover host capabilities: {}", e);
+            WorkerCapabilities::default()
+        },
+    };
+
+    let guest = match pool.acquire(None).await {
+        Ok(vm) => {
+            let guest = discover_in_vm(backend, &vm).await;
+            pool.put_back(vm).await;
+            guest
+        },
+        Err(e) => Err(e),
+    };
+    let guest = match guest {
+        Ok(output) => parse(&output),
+        Err(e) => {
+            tracing::warn!("Failed to discover VM capabilities: {}", e);
+            WorkerCapabilities::default()
+        },
+    };
+
+    WorkerCapabilities {
+        macos_version: guest.macos_version.or(host.macos_version),
+        xcode_versions: if guest.xcode_versions.is_empty() {
+            host.xcode_versions
+        } else {
+            guest.xcode_versions
+        },
+        arch: guest.arch.or(host.arch),
+        ..host
+    }
+}
+
+async fn discover_on_host() -> Result<String> {
+    let output = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(DISCOVER_SCRIPT)
+        .output()
+        .await?;
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+async fn discover_in_vm(backend: &Arc<dyn VmBackend>, vm: &Arc<Mutex<PooledVm>>) -> Result<String> {
+    let vm = vm.lock().await.clone();
+    let session = backend.connect(&vm).await?;
+    let output = session.exec(DISCOVER_SCRIPT).await?;
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Parse the output of the discovery script
+fn parse(output: &str) -> WorkerCapabilities {
+    let mut capabilities = WorkerCapabilities::default();
+
+    for line in output.lines() {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let value = value.trim();
+        if value.is_empty() {
+            continue;
+        }
+
+        match key {
+            "macos" => capabilities.macos_version = Some(value.to_string()),
+            "xcode" if !capabilities.xcode_versions.iter().any(|v| v == value) => {
+                capabilities.xcode_versions.push(value.to_string());
+            },
+            "arch" => capabilities.arch = Some(value.to_string()),
+            "cpu" => capabilities.cpu = Some(value.to_string()),
+            "cores" => capabilities.cpu_cores = value.parse().ok(),
+            "memory" => {
+                capabilities.memory_gb = value
+                    .parse::<u64>()
+                    .ok()
+                    .and_then(|bytes| u32::try_from(bytes.div_ceil(1 << 30)).ok());
+            },
+            _ => {},
+        }
+    }
+
+    capabilities
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let capabilities = parse(
+            "macos=15.1\nxcode=16.0\nxcode=15.4\nxcode=\narch=arm64\n\
+             cpu=Apple M2 Pro\ncores=12\nmemory=34359738368\nnoise\n",
+        );
+        assert_eq!(capabilities.macos_version.as_deref(), Some("15.1"));
+        assert_eq!(capabilities.xcode_versions, ["16.0", "15.4"]);
+        assert_eq!(capabilities.cpu_cores, Some(12));
+        assert_eq!(capabilities.memory_gb, Some(32));
+        assert_eq!(
+            capabilities.labels(),
+            [
+                "macos-15",
+                "macos-15.1",
+                "xcode-16",
+                "xcode-16.0",
+                "xcode-15",
+                "xcode-15.4",
+                "arm64",
+                "m2"
+            ]
+        );
+
+        // Linux hosts (local backend) report little
+        let capabilities = parse("arch=x86_64\ncpu= Intel(R) Xeon(R)\ncores=4\nmemory=\n");
+        assert_eq!(capabilities.cpu.as_deref(), Some("Intel(R) Xeon(R)"));
+        assert_eq!(capabilities.memory_gb, None);
+        assert_eq!(capabilities.labels(), ["x86_64"]);
+    }
+}
@@ -0,0 +1,253 @@
+//! Push channel to the orchestrator
+//!
+//! A WebSocket the worker sends its claims, heartbeats and log batches up, and
+//! is pushed job offers, cancellations, drain commands and log
+//! acknowledgements down, so a queued job starts without waiting for the next
+//! poll. It is reopened whenever it drops; meanwhile the worker polls for
+//! jobs and posts heartbeats and logs over HTTP.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::sync::{mpsc, watch};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::heartbeat::{self, ActiveJobs};
+use crate::orchestrator_client::{ChannelSocket, OrchestratorClient};
+use shared::{ClaimedJob, LogAck, LogBatch, WorkerCommand, WorkerMessage};
+
+/// How long to wait before reopening a channel that dropped or didn't open
+const REOPEN_DELAY: Duration = Duration::from_secs(5);
+
+/// How often the worker pings the orchestrator over an open channel
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How long a channel may go without hearing from the orchestrator before
+/// it is taken for dead
+const SILENCE_LIMIT: Duration = Duration::from_secs(45);
+
+/// Handle to the worker's push channel, open or not
+#[derive(Clone)]
+pub struct Channel {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    /// Messages for the open connection, if there is one
+    outgoing: Mutex<Option<mpsc::UnboundedSender<WorkerMessage>>>,
+    /// Jobs the orchestrator offered in answer to claims
+    offers: tokio::sync::Mutex<mpsc::UnboundedReceiver<ClaimedJob>>,
+    connected: watch::Sender<bool>,
+    /// How far the orchestrator acknowledged the logs of jobs shipping them
+    log_acks: Mutex<HashMap<Uuid, watch::Sender<u64>>>,
+}
+
+impl Channel {
+    /// Keep the channel of worker `worker_id` open in the background,
+    /// stopping the jobs the orchestrator cancels and cancelling `shutdown`
+    /// when it asks the worker to drain
+    pub fn open(
+        client: OrchestratorClient,
+        worker_id: Uuid,
+        active_jobs: ActiveJobs,
+        shutdown: CancellationToken,
+    ) -> Self {
+        let (offers_tx, offers_rx) = mpsc::unbounded_channel();
+        let channel = Self {
+            inner: Arc::new(Inner {
+                outgoing: Mutex::new(None),
+                offers: tokio::sync::Mutex::new(offers_rx),
+                connected: watch::channel(false).0,
+                log_acks: Mutex::new(HashMap::new()),
+            }),
+        };
+
+        let handle = channel.clone();
+        tokio::spawn(async move {
+            loop {
+                match client.open_channel(worker_id).await {
+                    Ok(socket) => {
+                        tracing::info!("Push channel open");
+                        handle
+                            .serve(socket, &active_jobs, &offers_tx, &shutdown)
+                            .await;
+                        tracing::warn!("Push channel closed, polling until it reopens");
+                    },
+                    Err(e) => tracing::debug!("Failed to open push channel: {}", e),
+                }
+                tokio::time::sleep(REOPEN_DELAY).await;
+            }
+        });
+
+        channel
+    }
+
+    /// Wait until the channel is open
+    pub async fn connected(&self) {
+        let _ = self
+            .inner
+            .connected
+            .subscribe()
+            .wait_for(|connected| *connected)
+            .await;
+    }
+
+    /// Send a message on the channel; false if it isn't open
+    pub fn send(&self, message: WorkerMessage) -> bool {
+        self.inner
+            .outgoing
+            .lock()
+            .expect("channel lock poisoned")
+            .as_ref()
+            .is_some_and(|tx| tx.send(message).is_ok())
+    }
+
+    /// Ask for a job and wait until the orchestrator offers one, or `None` if
+    /// the channel isn't open or drops first
+    pub async fn claim(&self) -> Option<ClaimedJob> {
+        let mut offers = self.inner.offers.lock().await;
+
+        // Offered in answer to a claim that was given up on
+        if let Ok(claimed) = offers.try_recv() {
+            return Some(claimed);
+        }
+
+        let mut connected = self.inner.connected.subscribe();
+        if !self.send(WorkerMessage::Claim) {
+            return None;
+        }
+        let claimed = tokio::select! {
+            claimed = offers.recv() => claimed,
+            _ = connected.wait_for(|connected| !*connected) => None,
+        };
+        drop(offers);
+        claimed
+    }
+
+    /// Withdraw a claim given up on, returning the jobs already offered in
+    /// answer to it; an offer still on its way is requeued when its lease
+    /// runs out
+    pub async fn withdraw_claim(&self) -> Vec<ClaimedJob> {
+        self.send(WorkerMessage::Unclaim);
+        let mut offers = self.inner.offers.lock().await;
+        let mut offered = Vec::new();
+        while let Ok(claimed) = offers.try_recv() {
+            offered.push(claimed);
+        }
+        drop(offers);
+        offered
+    }
+
+    /// Ship a batch of log entries, returning a receiver of how far the
+    /// orchestrator acknowledged the job's logs, or `None` if the channel
+    /// isn't open
+    pub fn ship_logs(&self, batch: LogBatch) -> Option<watch::Receiver<u64>> {
+        let acks = self
+            .inner
+            .log_acks
+            .lock()
+            .expect("channel lock poisoned")
+            .entry(batch.job_id)
+            .or_insert_with(|| watch::channel(0).0)
+            .subscribe();
+        self.send(WorkerMessage::Logs(batch)).then_some(acks)
+    }
+
+    /// Stop tracking acknowledgements of a job's logs, once it shipped them
+    pub fn forget_logs(&self, job_id: Uuid) {
+        self.inner
+            .log_acks
+            .lock()
+            .expect("channel lock poisoned")
+            .remove(&job_id);
+    }
+
+    /// Relay messages over an open connection until it closes
+    async fn serve(
+        &self,
+        socket: ChannelSocket,
+        active_jobs: &ActiveJobs,
+        offers: &mpsc::UnboundedSender<ClaimedJob>,
+        shutdown: &CancellationToken,
+    ) {
+        let (mut sink, mut stream) = socket.split();
+        let (tx, mut outgoing) = mpsc::unbounded_channel();
+        *self.inner.outgoing.lock().expect("channel lock poisoned") = Some(tx);
+        self.inner.connected.send_replace(true);
+
+        let mut ping = tokio::time::interval(PING_INTERVAL);
+        let mut last_heard = Instant::now();
+        loop {
+            tokio::select! {
+                Some(message) = outgoing.recv() => {
+                    let text = serde_json::to_string(&message).expect("worker messages serialize");
+                    if sink.send(Message::Text(text)).await.is_err() {
+                        break;
+                    }
+                },
+                message = stream.next() => {
+                    last_heard = Instant::now();
+                    match message {
+                        Some(Ok(Message::Text(text))) => match serde_json::from_str(&text) {
+                            Ok(command) => self.handle(command, active_jobs, offers, shutdown),
+                            Err(e) => tracing::warn!("Ignoring malformed channel command: {}", e),
+                        },
+                        Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                        Some(Ok(_)) => {},
+                    }
+                },
+                _ = ping.tick() => {
+                    // A connection that died quietly never errors on its own
+                    if last_heard.elapsed() > SILENCE_LIMIT
+                        || sink.send(Message::Ping(Vec::new())).await.is_err()
+                    {
+                        break;
+                    }
+                },
+            }
+        }
+
+        self.inner.connected.send_replace(false);
+        *self.inner.outgoing.lock().expect("channel lock poisoned") = None;
+    }
+
+    /// Act on a command pushed by the orchestrator
+    fn handle(
+        &self,
+        command: WorkerCommand,
+        active_jobs: &ActiveJobs,
+        offers: &mpsc::UnboundedSender<ClaimedJob>,
+        shutdown: &CancellationToken,
+    ) {
+        match command {
+            WorkerCommand::Offer { job } => {
+                let _ = offers.send(*job);
+            },
+            WorkerCommand::Cancel { job_ids } => heartbeat::cancel_jobs(active_jobs, &job_ids),
+            WorkerCommand::Drain => {
+                if !shutdown.is_cancelled() {
+                    tracing::info!("Orchestrator asked us to drain, finishing running jobs...");
+                    shutdown.cancel();
+                }
+            },
+            WorkerCommand::LogAck(ack) => self.acknowledge_logs(ack),
+        }
+    }
+
+    fn acknowledge_logs(&self, ack: LogAck) {
+        if let Some(acks) = self
+            .inner
+            .log_acks
+            .lock()
+            .expect("channel lock poisoned")
+            .get(&ack.job_id)
+        {
+            // Not necessarily ahead: the orchestrator may ask for entries again
+            acks.send_replace(ack.next_seq);
+        }
+    }
+}
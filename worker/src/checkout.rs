@@ -0,0 +1,247 @@
+//! Git checkout of a job's source inside the VM
+//!
+//! Every piece of job input reaches git as a single argument. A private
+//! repository's credential is written into the VM only for the duration of the
+//! checkout and handed to git through `-c` options, so it never ends up in
+//! the workspace's git config, on a command line, or in the build's logs.
+
+use anyhow::Result;
+
+use crate::backend::{ExecOutput, VmSession};
+use crate::executor::SourceFetchError;
+use shared::{is_valid_commit_sha, is_valid_git_ref, GitCredential, GitCredentialKind, Job};
+
+/// Where an SSH key is written inside the VM during a checkout (relative to home)
+const SSH_KEY_FILE: &str = ".alloy-git-key";
+
+/// Where HTTPS credentials are written inside the VM during a checkout (relative to home)
+const CREDENTIALS_FILE: &str = ".alloy-git-credentials";
+
+/// Check out the job's revision of a git repository into `~/workspace` and
+/// return the full SHA of the commit checked out.
+///
+/// Fails with a `SourceFetchError` when git itself fails or the job asks for
+/// something git can't give it, and with any other error when the VM can't be
+/// reached.
+pub async fn checkout(
+    job: &Job,
+    source_url: &str,
+    credential: Option<&GitCredential>,
+    session: &dyn VmSession,
+) -> Result<String> {
+    // Re-check what the orchestrator validated: both end up as git arguments
+    if let Some(git_ref) = job.git_ref.as_deref() {
+        if !is_valid_git_ref(git_ref) {
+            return Err(SourceFetchError::new(format!("Invalid git ref: {git_ref:?}")).into());
+        }
+    }
+    if let Some(sha) = job.commit_sha.as_deref() {
+        if !is_valid_commit_sha(sha) {
+            return Err(SourceFetchError::new(format!("Invalid commit SHA: {sha:?}")).into());
+        }
+    }
+
+    let Some(credential) = credential else {
+        let git = Git {
+            session,
+            config: Vec::new(),
+        };
+        return fetch_revision(job, source_url, &git).await;
+    };
+
+    let result = async {
+        let config = install_credential(source_url, credential, session).await?;
+        let git = Git { session, config };
+        fetch_revision(job, source_url, &git).await
+    }
+    .await;
+
+    // The build itself never gets to see the credential
+    match session
+        .exec_args(&["rm", "-f", SSH_KEY_FILE, CREDENTIALS_FILE])
+        .await
+    {
+        Ok(output) if output.success() => {},
+        Ok(output) => anyhow::bail!(
+            "Failed to remove git credential from VM: {}",
+            output.stderr_lossy()
+        ),
+        Err(e) => anyhow::bail!("Failed to remove git credential from VM: {e}"),
+    }
+
+    // Git doesn't print credentials, but keep them out of errors (and so
+    // the job's logs) regardless
+    result.map_err(|e| {
+        let message = e.to_string().replace(credential.secret.trim(), "***");
+        if e.is::<SourceFetchError>() {
+            SourceFetchError::new(message).into()
+        } else {
+            anyhow::anyhow!(message)
+        }
+    })
+}
+
+/// Fetch and check out the job's revision.
+///
+/// A full `commit_sha` is fetched on its own where the server allows it;
+/// otherwise the ref (or every branch and tag) is fetched with history so an
+/// abbreviated SHA can be found. Without a SHA only the tip of the ref, or of
+/// the default branch, is fetched.
+async fn fetch_revision(job: &Job, source_url: &str, git: &Git<'_>) -> Result<String> {
+    let git_ref = job.git_ref.as_deref();
+    let commit_sha = job.commit_sha.as_deref().map(str::to_ascii_lowercase);
+
+    let output = git
+        .session
+        .exec_args(&["git", "init", "-q", "workspace"])
+        .await?;
+    if !output.success() {
+        return Err(SourceFetchError::new(format!(
+            "Failed to fetch source: {}",
+            output.stderr_lossy()
+        ))
+        .into());
+    }
+    git.run(&["remote", "add", "origin", "--", source_url])
+        .await?;
+
+    let fetched_sha = match commit_sha.as_deref() {
+        Some(sha) if sha.len() >= 40 => git
+            .output(&["fetch", "-q", "--depth", "1", "origin", "--", sha])
+            .await?
+            .success(),
+        _ => false,
+    };
+
+    let target = match (commit_sha.as_deref(), git_ref) {
+        (Some(sha), _) if fetched_sha => sha,
+        (Some(sha), Some(git_ref)) => {
+            git.run(&["fetch", "-q", "origin", "--", git_ref]).await?;
+            sha
+        },
+        (Some(sha), None) => {
+            git.run(&["fetch", "-q", "--tags", "origin"]).await?;
+            sha
+        },
+        (None, git_ref) => {
+            let git_ref = git_ref.unwrap_or("HEAD");
+            git.run(&["fetch", "-q", "--depth", "1", "origin", "--", git_ref])
+                .await?;
+            "FETCH_HEAD"
+        },
+    };
+    git.run(&["checkout", "-q", "--detach", target]).await?;
+
+    if job.submodules {
+        git.run(&[
+            "submodule",
+            "update",
+            "-q",
+            "--init",
+            "--recursive",
+            "--depth",
+            "1",
+        ])
+        .await?;
+    }
+    if job.lfs {
+        git.run(&["lfs", "install", "--local"]).await?;
+        git.run(&["lfs", "pull"]).await?;
+    }
+
+    let output = git.run(&["rev-parse", "HEAD"]).await?;
+    let resolved = output.stdout_lossy().trim().to_string();
+    if let Some(sha) = commit_sha {
+        if !resolved.starts_with(&sha) {
+            return Err(SourceFetchError::new(format!(
+                "Checked out {resolved} instead of commit {sha}"
+            ))
+            .into());
+        }
+    }
+
+    Ok(resolved)
+}
+
+/// Copy a credential into the VM, returning the git config that uses it
+async fn install_credential(
+    source_url: &str,
+    credential: &GitCredential,
+    session: &dyn VmSession,
+) -> Result<Vec<String>> {
+    let secret = credential.secret.trim();
+
+    let (remote_path, contents, config) = match credential.kind {
+        GitCredentialKind::SshKey => (
+            SSH_KEY_FILE,
+            format!("{secret}\n"),
+            vec![format!(
+                "core.sshCommand=ssh -i ~/{SSH_KEY_FILE} -o IdentitiesOnly=yes \
+                 -o StrictHostKeyChecking=accept-new"
+            )],
+        ),
+        GitCredentialKind::HttpsToken | GitCredentialKind::InstallationToken => {
+            let url = reqwest::Url::parse(source_url)
+                .ok()
+                .filter(|url| url.scheme() == "https" && url.host_str().is_some())
+                .ok_or_else(|| {
+                    SourceFetchError::new("Token credentials need an https:// source URL")
+                })?;
+            let origin = url.origin().ascii_serialization();
+            let username = credential
+                .username
+                .as_deref()
+                .unwrap_or_else(|| credential.kind.default_username());
+
+            // Only answer for the repository's host, and ignore helpers the
+            // image may have configured (e.g. a keychain) so the token is
+            // never stored anywhere
+            (
+                CREDENTIALS_FILE,
+                format!("username={username}\npassword={secret}\n"),
+                vec![
+                    "credential.helper=".to_string(),
+                    format!(
+                        "credential.{origin}.helper=!f() {{ test \"$1\" = get && cat ~/{CREDENTIALS_FILE}; }}; f"
+                    ),
+                ],
+            )
+        },
+    };
+
+    session.upload_private(&contents, remote_path).await?;
+
+    Ok(config)
+}
+
+/// Git on the workspace repository, with extra `-c` configuration
+struct Git<'a> {
+    session: &'a dyn VmSession,
+    config: Vec<String>,
+}
+
+impl Git<'_> {
+    /// Run git and collect its output
+    async fn output(&self, args: &[&str]) -> Result<ExecOutput> {
+        let mut command = vec!["git", "-C", "workspace"];
+        for config in &self.config {
+            command.extend(["-c", config.as_str()]);
+        }
+        command.extend_from_slice(args);
+        self.session.exec_args(&command).await
+    }
+
+    /// Run git, failing with a `SourceFetchError` unless it succeeds
+    async fn run(&self, args: &[&str]) -> Result<ExecOutput> {
+        let output = self.output(args).await?;
+        if !output.success() {
+            return Err(SourceFetchError::new(format!(
+                "Failed to fetch source: git {} failed: {}",
+                args[0],
+                output.stderr_lossy().trim()
+            ))
+            .into());
+        }
+        Ok(output)
+    }
+}
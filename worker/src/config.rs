@@ -2,6 +2,22 @@
 
 use anyhow::{Context, Result};
 
+use crate::backend::VmBackendKind;
+
+/// Image VMs are cloned from when `TART_BASE_IMAGE` is not set
+const DEFAULT_BASE_IMAGE: &str = "ghcr.io/cirruslabs/macos-tahoe-xcode:latest";
+
+/// A base image jobs can run in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkerImage {
+    /// Name jobs ask for the image by (e.g. `sequoia-xcode-16`)
+    pub name: String,
+    /// Tart image (or, for the local backend, directory) VMs are cloned from
+    pub image: String,
+    /// VMs of the image to keep booted and waiting for a job
+    pub warm: u32,
+}
+
 #[derive(Debug, Clone)]
 pub struct Config {
     /// URL of the orchestrator API
@@ -10,30 +26,48 @@ pub struct Config {
     /// This worker's hostname
     pub hostname: String,
 
-    /// Maximum concurrent jobs
+    /// Maximum concurrent jobs, and VMs that may exist at once
     pub capacity: u32,
 
-    /// Base Tart VM image to clone
-    pub tart_base_image: String,
+    /// Labels jobs can require of this worker, beyond those derived from
+    /// its capabilities (e.g. `signing`)
+    pub labels: Vec<String>,
+
+    /// Which VM backend runs jobs (tart or local)
+    pub vm_backend: VmBackendKind,
+
+    /// Base images jobs can run in; the first is used for jobs that don't
+    /// name one
+    pub images: Vec<WorkerImage>,
 
     /// Job timeout in minutes (default: 60)
     pub job_timeout_minutes: u64,
 
-    /// Number of VMs to pre-create in the pool (default: 2)
-    pub vm_pool_size: u32,
-
     /// Optional script to run when VM is initialized (e.g., install fastlane)
     pub vm_setup_script: Option<String>,
 
+    /// Reuse VMs between jobs, only wiping the workspace, instead of re-cloning
+    pub vm_reuse: bool,
+
     /// Secret key for authenticating with orchestrator (optional)
     pub worker_secret_key: Option<String>,
 
     /// Directory to store persistent data (like worker ID)
     pub data_dir: String,
+
+    /// Seconds between heartbeats, which also renew job leases (default: 15)
+    pub heartbeat_interval_secs: u64,
 }
 
 impl Config {
     pub fn from_env() -> Result<Self> {
+        let data_dir = std::env::var("WORKER_DATA_DIR").unwrap_or_else(|_| {
+            dirs::home_dir().map_or_else(
+                || "/tmp/alloy-worker".to_string(),
+                |h| h.join(".alloy").to_string_lossy().to_string(),
+            )
+        });
+
         Ok(Self {
             orchestrator_url: std::env::var("ORCHESTRATOR_URL")
                 .context("ORCHESTRATOR_URL environment variable required")?,
@@ -47,24 +81,158 @@ impl Config {
                 .unwrap_or_else(|_| "2".to_string())
                 .parse()
                 .context("Invalid WORKER_CAPACITY value")?,
-            tart_base_image: std::env::var("TART_BASE_IMAGE")
-                .unwrap_or_else(|_| "ghcr.io/cirruslabs/macos-tahoe-xcode:latest".to_string()),
+            labels: labels_from_env()?,
+            vm_backend: VmBackendKind::from_env(&data_dir)?,
+            images: images_from_env()?,
             job_timeout_minutes: std::env::var("JOB_TIMEOUT_MINUTES")
                 .unwrap_or_else(|_| "60".to_string())
                 .parse()
                 .context("Invalid JOB_TIMEOUT_MINUTES value")?,
-            vm_pool_size: std::env::var("VM_POOL_SIZE")
-                .unwrap_or_else(|_| "1".to_string())
-                .parse()
-                .context("Invalid VM_POOL_SIZE value")?,
             vm_setup_script: std::env::var("VM_SETUP_SCRIPT").ok(),
+            vm_reuse: std::env::var("VM_REUSE")
+                .is_ok_and(|v| v.to_lowercase() == "true" || v == "1"),
             worker_secret_key: std::env::var("WORKER_SECRET_KEY").ok(),
-            data_dir: std::env::var("WORKER_DATA_DIR").unwrap_or_else(|_| {
-                dirs::home_dir().map_or_else(
-                    || "/tmp/alloy-worker".to_string(),
-                    |h| h.join(".alloy").to_string_lossy().to_string(),
-                )
-            }),
+            data_dir,
+            heartbeat_interval_secs: std::env::var("HEARTBEAT_INTERVAL_SECS")
+                .unwrap_or_else(|_| "15".to_string())
+                .parse()
+                .context("Invalid HEARTBEAT_INTERVAL_SECS value")?,
         })
     }
 }
+
+/// Comma-separated `WORKER_LABELS`, lowercased
+fn labels_from_env() -> Result<Vec<String>> {
+    let Ok(labels) = std::env::var("WORKER_LABELS") else {
+        return Ok(Vec::new());
+    };
+
+    let labels: Vec<String> = labels
+        .split(',')
+        .map(|label| label.trim().to_lowercase())
+        .filter(|label| !label.is_empty())
+        .collect();
+    if let Some(label) = labels.iter().find(|label| !shared::is_valid_label(label)) {
+        anyhow::bail!("Invalid WORKER_LABELS label: {label:?}");
+    }
+    if labels.len() > shared::MAX_LABELS {
+        anyhow::bail!(
+            "WORKER_LABELS can list at most {} labels",
+            shared::MAX_LABELS
+        );
+    }
+    Ok(labels)
+}
+
+/// Base images from `WORKER_IMAGES` (`name=image,...`), or else a single
+/// `default` image from `TART_BASE_IMAGE`, each kept `VM_POOL_SIZE` warm VMs
+/// unless `VM_POOL_SIZES` (`name=count,...`) says otherwise
+fn images_from_env() -> Result<Vec<WorkerImage>> {
+    let warm = std::env::var("VM_POOL_SIZE")
+        .unwrap_or_else(|_| "1".to_string())
+        .parse()
+        .context("Invalid VM_POOL_SIZE value")?;
+    let base_image =
+        std::env::var("TART_BASE_IMAGE").unwrap_or_else(|_| DEFAULT_BASE_IMAGE.to_string());
+
+    parse_images(
+        std::env::var("WORKER_IMAGES").ok().as_deref(),
+        &base_image,
+        warm,
+        std::env::var("VM_POOL_SIZES").ok().as_deref(),
+    )
+}
+
+fn parse_images(
+    images: Option<&str>,
+    base_image: &str,
+    warm: u32,
+    warm_counts: Option<&str>,
+) -> Result<Vec<WorkerImage>> {
+    let pairs = |list: &str| -> Vec<(String, String)> {
+        list.split(',')
+            .filter(|pair| !pair.trim().is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (name.trim().to_lowercase(), value.trim().to_string())
+            })
+            .collect()
+    };
+
+    let mut parsed: Vec<WorkerImage> = Vec::new();
+    for (name, image) in images.map_or_else(
+        || vec![("default".to_string(), base_image.to_string())],
+        pairs,
+    ) {
+        if !shared::is_valid_label(&name) || image.is_empty() {
+            anyhow::bail!("Invalid WORKER_IMAGES entry {name:?}: expected name=image");
+        }
+        if parsed.iter().any(|other| other.name == name) {
+            anyhow::bail!("WORKER_IMAGES lists {name} more than once");
+        }
+        parsed.push(WorkerImage { name, image, warm });
+    }
+    if parsed.is_empty() {
+        anyhow::bail!("WORKER_IMAGES must list at least one image");
+    }
+    if parsed.len() > shared::MAX_LABELS {
+        anyhow::bail!(
+            "WORKER_IMAGES can list at most {} images",
+            shared::MAX_LABELS
+        );
+    }
+
+    for (name, count) in warm_counts.map(pairs).unwrap_or_default() {
+        let image = parsed
+            .iter_mut()
+            .find(|image| image.name == name)
+            .with_context(|| format!("VM_POOL_SIZES names unknown image {name:?}"))?;
+        image.warm = count
+            .parse()
+            .with_context(|| format!("Invalid VM_POOL_SIZES count for {name}"))?;
+    }
+
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_images() {
+        let images = parse_images(None, "alloy-base", 2, None).unwrap();
+        assert_eq!(
+            images,
+            [WorkerImage {
+                name: "default".to_string(),
+                image: "alloy-base".to_string(),
+                warm: 2,
+            }]
+        );
+
+        let images = parse_images(
+            Some(
+                "Sonoma-Xcode-15=ghcr.io/cirruslabs/macos-sonoma-xcode:15.4, sequoia=alloy-sequoia",
+            ),
+            "ignored",
+            1,
+            Some("sequoia=0"),
+        )
+        .unwrap();
+        assert_eq!(images[0].name, "sonoma-xcode-15");
+        assert_eq!(
+            images[0].image,
+            "ghcr.io/cirruslabs/macos-sonoma-xcode:15.4"
+        );
+        assert_eq!(images[0].warm, 1);
+        assert_eq!(images[1].name, "sequoia");
+        assert_eq!(images[1].warm, 0);
+
+        assert!(parse_images(Some("sonoma"), "", 1, None).is_err());
+        assert!(parse_images(Some("a=x,a=y"), "", 1, None).is_err());
+        assert!(parse_images(Some(""), "", 1, None).is_err());
+        assert!(parse_images(None, "alloy-base", 1, Some("sonoma=1")).is_err());
+        assert!(parse_images(None, "alloy-base", 1, Some("default=many")).is_err());
+    }
+}
@@ -2,59 +2,174 @@
 
 use anyhow::Result;
 use chrono::Utc;
-use std::process::Stdio;
+use std::fmt::Write;
 use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::process::Command;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
+use crate::backend::{shell_join, LineSender, VmBackend, VmSession};
+use crate::channel::Channel;
+use crate::checkout;
 use crate::config::Config;
+use crate::log_shipper::{self, EntrySender, LogShipper};
 use crate::orchestrator_client::OrchestratorClient;
 use crate::vm_pool::{PooledVm, VmPool};
-use shared::{Artifact, Job, JobResult, LogEntry, LogStream, SourceType};
+use shared::{
+    Artifact, ClaimedJob, FailureReason, GitCredential, Job, JobResult, LogEntry, LogMask,
+    LogStream, SourceType, StepResult, StepStatus,
+};
 
-/// Default VM credentials (admin/admin for Cirrus Tart images)
-const VM_USER: &str = "admin";
-const VM_PASSWORD: &str = "admin";
+/// File inside the VM holding the PID of the build's process group leader
+const JOB_PID_FILE: &str = "~/.alloy-job.pid";
+
+/// Where a job's command or script is written inside the VM
+const BUILD_SCRIPT: &str = "~/.alloy-build.sh";
+
+/// Where a job's environment variables are written inside the VM (relative
+/// to home); the build's shell reads and deletes it before running the build
+const BUILD_ENV: &str = ".alloy-env";
+
+/// Where an uploaded source archive is copied inside the VM (relative to home)
+const SOURCE_ARCHIVE: &str = "source.zip";
+
+/// Where the artifacts of the jobs a job needs are copied inside the VM
+/// (relative to home)
+const UPSTREAM_ARTIFACTS: &str = "workspace/.alloy/artifacts";
+
+/// How long a cancelled build gets to exit after SIGTERM before it is killed
+const CANCEL_GRACE_SECS: u32 = 5;
+
+/// How long past its deadline a job gets to stop its build and upload its
+/// logs before the worker gives up on it
+const DEADLINE_GRACE: Duration = Duration::from_mins(2);
+
+/// Lists the files in the workspace matching the glob in `$1`, one per line
+const LIST_GLOB: &str = "cd ~/workspace || exit 1; IFS=; \
+    for f in $1; do [ -f \"$f\" ] && printf '%s\\n' \"$f\"; done; true";
+
+/// A job's source could not be fetched: the job's inputs are at fault rather
+/// than the worker, so the job is not retried. Only failures of git or unzip
+/// themselves are; trouble reaching the VM or the orchestrator is the
+/// worker's, and is retried elsewhere.
+#[derive(Debug)]
+pub struct SourceFetchError(anyhow::Error);
+
+impl SourceFetchError {
+    pub fn new(message: impl std::fmt::Display) -> Self {
+        Self(anyhow::anyhow!("{message}"))
+    }
+}
+
+impl std::fmt::Display for SourceFetchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for SourceFetchError {}
 
 pub struct JobExecutor {
     worker_id: Uuid,
     client: OrchestratorClient,
+    channel: Channel,
     config: Config,
+    backend: Arc<dyn VmBackend>,
     vm_pool: Arc<VmPool>,
+    leftovers: log_shipper::Leftovers,
 }
 
 impl JobExecutor {
     pub const fn new(
         worker_id: Uuid,
         client: OrchestratorClient,
+        channel: Channel,
         config: Config,
+        backend: Arc<dyn VmBackend>,
         vm_pool: Arc<VmPool>,
+        leftovers: log_shipper::Leftovers,
     ) -> Self {
         Self {
             worker_id,
             client,
+            channel,
             config,
+            backend,
             vm_pool,
+            leftovers,
         }
     }
 
-    /// Execute a job in a Tart VM with timeout
-    pub async fn execute(&self, job: &Job) -> Result<JobResult> {
-        let timeout_duration = std::time::Duration::from_secs(self.config.job_timeout_minutes * 60);
+    /// Execute a job in a pooled VM of its image, stopping its build at the
+    /// job's timeout or early if `cancel` fires. Output is logged through `mask`,
+    /// and shipped to the orchestrator before this returns unless it is
+    /// unreachable, in which case shipping goes on in the background.
+    pub async fn execute(
+        &self,
+        claimed: &ClaimedJob,
+        mask: &LogMask,
+        cancel: &CancellationToken,
+    ) -> Result<JobResult> {
+        let job = &claimed.job;
+        self.leftovers.stop(job.id).await;
+        let (logs, shipping) = LogShipper::start(
+            job.id,
+            self.worker_id,
+            self.client.clone(),
+            self.channel.clone(),
+            &log_shipper::spool_dir(&self.config.data_dir),
+        )
+        .await?
+        .spawn();
+
+        let result = self
+            .execute_with_deadline(claimed, mask, &logs, cancel)
+            .await;
+        if let Err(e) = &result {
+            let _ = logs.send(LogEntry {
+                job_id: job.id,
+                timestamp: Utc::now(),
+                stream: LogStream::Stderr,
+                content: mask.apply(&format!("Job execution failed on worker: {e}")),
+            });
+        }
+
+        // The build's own senders are gone with it, so this waits for
+        // everything logged to be shipped, or left to ship in the background
+        drop(logs);
+        let shipped = shipping.await?;
+        result.and_then(|result| shipped.map(|()| result))
+    }
 
-        // Acquire a VM from the pool
-        let vm = self
-            .vm_pool
-            .acquire()
-            .await
-            .ok_or_else(|| anyhow::anyhow!("No VMs available in pool"))?;
+    /// Execute a job in a pooled VM, giving up on it a while after its
+    /// timeout
+    async fn execute_with_deadline(
+        &self,
+        claimed: &ClaimedJob,
+        mask: &LogMask,
+        logs: &EntrySender,
+        cancel: &CancellationToken,
+    ) -> Result<JobResult> {
+        let job = &claimed.job;
+
+        // Waiting for (or cloning) a VM doesn't count against the timeout
+        let vm = self.vm_pool.acquire(job.image.as_deref()).await?;
+        let timeout_minutes = self.timeout_minutes(job);
+        let deadline = Instant::now() + Duration::from_secs(timeout_minutes * 60);
 
         let vm_for_release = Arc::clone(&vm);
         let pool_for_release = Arc::clone(&self.vm_pool);
 
-        let result = tokio::time::timeout(timeout_duration, self.execute_with_vm(job, &vm)).await;
+        // The build itself is stopped at the deadline; this only catches a job
+        // stuck elsewhere (e.g. fetching its source)
+        let result = tokio::time::timeout_at(
+            deadline + DEADLINE_GRACE,
+            self.execute_with_vm(claimed, mask, logs, &vm, deadline, cancel),
+        )
+        .await;
 
         // Always release VM back to pool
         if let Err(e) = pool_for_release.release(vm_for_release).await {
@@ -62,36 +177,89 @@ impl JobExecutor {
         }
 
         if let Ok(inner_result) = result {
-            inner_result
-        } else {
-            tracing::error!(job_id = %job.id, timeout_minutes = self.config.job_timeout_minutes, "Job timed out");
-            anyhow::bail!(
-                "Job timed out after {} minutes",
-                self.config.job_timeout_minutes
-            )
+            return inner_result;
         }
+
+        tracing::error!(job_id = %job.id, timeout_minutes = timeout_minutes, "Job timed out");
+        let _ = logs.send(LogEntry {
+            job_id: job.id,
+            timestamp: Utc::now(),
+            stream: LogStream::Stderr,
+            content: format!("Job hit its {timeout_minutes} minute timeout"),
+        });
+
+        #[allow(clippy::cast_precision_loss)]
+        let build_minutes = timeout_minutes as f64;
+        Ok(JobResult {
+            job_id: job.id,
+            exit_code: -1,
+            artifacts: vec![],
+            build_minutes,
+            cancelled: false,
+            timed_out: true,
+            failure_reason: Some(FailureReason::Timeout),
+            resolved_sha: None,
+            steps: vec![],
+        })
     }
 
-    /// Execute job with a specific pooled VM
-    async fn execute_with_vm(&self, job: &Job, vm: &Arc<Mutex<PooledVm>>) -> Result<JobResult> {
+    /// Minutes a job may run: its own timeout, or else the worker's
+    fn timeout_minutes(&self, job: &Job) -> u64 {
+        job.timeout_minutes
+            .map_or(self.config.job_timeout_minutes, u64::from)
+    }
+
+    /// Execute job with a specific pooled VM, stopping its build at `deadline`
+    async fn execute_with_vm(
+        &self,
+        claimed: &ClaimedJob,
+        mask: &LogMask,
+        logs: &EntrySender,
+        vm: &Arc<Mutex<PooledVm>>,
+        deadline: Instant,
+        cancel: &CancellationToken,
+    ) -> Result<JobResult> {
+        let job = &claimed.job;
         let start_time = Utc::now();
 
-        let vm_ip = {
+        let vm = {
             let guard = vm.lock().await;
             tracing::info!(job_id = %job.id, vm_name = %guard.name, vm_ip = %guard.ip, "Using pooled VM");
-            guard.ip.clone()
+            guard.clone()
         };
 
+        // One connection serves every step of the job
+        let session = self.backend.connect(&vm).await?;
+
         // Define log path
         let log_path = std::env::temp_dir().join(format!("job-{}.log", job.id));
 
         // Step 1: Fetch source code into VM
         tracing::info!(job_id = %job.id, source_type = ?job.source_type, "Fetching source...");
-        self.fetch_source(job, &vm_ip).await?;
+        let resolved_sha = self
+            .fetch_source(job, claimed.git_credential.as_ref(), session.as_ref())
+            .await?;
 
-        // Step 2: Execute the command (capturing logs to file)
-        tracing::info!(job_id = %job.id, "Executing command...");
-        let exit_code = self.execute_in_vm(job, &vm_ip, &log_path).await?;
+        // Copy in what the jobs it needs produced
+        self.fetch_upstream_artifacts(claimed, session.as_ref())
+            .await?;
+
+        // Step 2: Execute the command or steps (capturing logs to file)
+        let outcome = if cancel.is_cancelled() {
+            BuildOutcome::stopped()
+        } else {
+            tracing::info!(job_id = %job.id, "Executing command...");
+            self.execute_in_vm(
+                claimed,
+                mask,
+                logs,
+                session.as_ref(),
+                &log_path,
+                deadline,
+                cancel,
+            )
+            .await?
+        };
 
         // Step 3: Upload logs to storage
         tracing::info!(job_id = %job.id, "Uploading logs...");
@@ -104,223 +272,312 @@ impl JobExecutor {
         // Cleanup log file
         let _ = tokio::fs::remove_file(&log_path).await;
 
-        // Step 4: Collect artifacts
-        let artifacts = self.collect_artifacts(job, &vm_ip).await?;
+        // Step 4: Collect artifacts (a cancelled or timed out build has none
+        // worth keeping)
+        let cancelled = cancel.is_cancelled();
+        let artifacts = if cancelled || outcome.timed_out {
+            Vec::new()
+        } else {
+            let mut artifacts = self.collect_artifacts(job, session.as_ref()).await?;
+            artifacts.extend(
+                self.collect_step_artifacts(job, &outcome.steps, session.as_ref())
+                    .await?,
+            );
+            artifacts
+        };
 
         let end_time = Utc::now();
         #[allow(clippy::cast_precision_loss)]
         let build_minutes = (end_time - start_time).num_seconds() as f64 / 60.0;
 
+        let timed_out = outcome.timed_out && !cancelled;
+        let failure_reason = if cancelled {
+            Some(FailureReason::Cancelled)
+        } else if timed_out {
+            Some(FailureReason::Timeout)
+        } else if outcome.exit_code != 0 {
+            Some(FailureReason::UserExit)
+        } else {
+            None
+        };
+
         Ok(JobResult {
             job_id: job.id,
-            exit_code,
+            exit_code: outcome.exit_code,
             artifacts,
             build_minutes,
+            cancelled,
+            timed_out,
+            failure_reason,
+            resolved_sha,
+            steps: outcome.steps,
         })
     }
 
-    /// Fetch source code into the VM based on source type
-    async fn fetch_source(&self, job: &Job, vm_ip: &str) -> Result<()> {
+    /// Fetch source code into the VM based on source type, returning the
+    /// commit that was checked out for git sources
+    async fn fetch_source(
+        &self,
+        job: &Job,
+        git_credential: Option<&GitCredential>,
+        session: &dyn VmSession,
+    ) -> Result<Option<String>> {
         let source_url = job
             .source_url
             .as_ref()
-            .ok_or_else(|| anyhow::anyhow!("No source URL provided"))?;
+            .ok_or_else(|| SourceFetchError::new("No source URL provided"))?;
 
-        let fetch_cmd = match job.source_type {
+        // Job input only ever reaches the VM as a file or a single argument
+        let output = match job.source_type {
             SourceType::Git => {
-                // Clone git repository
-                format!("cd ~ && git clone --depth 1 '{source_url}' workspace && cd workspace")
+                return checkout::checkout(job, source_url, git_credential, session)
+                    .await
+                    .map(Some)
             },
             SourceType::Upload => {
-                // Download and extract archive
-                format!(
-                    "cd ~ && curl -sL '{source_url}' -o source.zip && unzip -q source.zip -d workspace && cd workspace"
-                )
+                // Download the archive here and copy it in
+                let archive = std::env::temp_dir().join(format!("{}-source.zip", job.id));
+                let copied = async {
+                    self.client.download_file(source_url, &archive).await?;
+                    session.upload(&archive, SOURCE_ARCHIVE).await
+                }
+                .await;
+                let _ = tokio::fs::remove_file(&archive).await;
+                copied?;
+
+                session
+                    .exec_args(&["unzip", "-q", SOURCE_ARCHIVE, "-d", "workspace"])
+                    .await?
             },
         };
 
-        // Use sshpass for non-interactive password authentication
-        let output = Command::new("sshpass")
-            .args([
-                "-p",
-                VM_PASSWORD,
-                "ssh",
-                "-o",
-                "StrictHostKeyChecking=no",
-                "-o",
-                "UserKnownHostsFile=/dev/null",
-                "-o",
-                "PubkeyAuthentication=no",
-                &format!("{VM_USER}@{vm_ip}"),
-                &fetch_cmd,
-            ])
-            .output()
-            .await?;
-
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            anyhow::bail!("Failed to fetch source: {stderr}");
+        if !output.success() {
+            return Err(SourceFetchError::new(format!(
+                "Failed to fetch source: {}",
+                output.stderr_lossy()
+            ))
+            .into());
         }
 
-        Ok(())
+        Ok(None)
     }
 
-    /// Clone the base VM image
-    #[allow(dead_code)]
-    async fn tart_clone(&self, vm_name: &str) -> Result<()> {
-        let output = Command::new("tart")
-            .args(["clone", &self.config.tart_base_image, vm_name])
-            .output()
-            .await?;
+    /// Copy the artifacts of the jobs this one needs into the workspace
+    async fn fetch_upstream_artifacts(
+        &self,
+        claimed: &ClaimedJob,
+        session: &dyn VmSession,
+    ) -> Result<()> {
+        if claimed.upstream_artifacts.is_empty() {
+            return Ok(());
+        }
 
-        if !output.status.success() {
+        let output = session
+            .exec_args(&["mkdir", "-p", UPSTREAM_ARTIFACTS])
+            .await?;
+        if !output.success() {
             anyhow::bail!(
-                "Failed to clone VM: {}",
-                String::from_utf8_lossy(&output.stderr)
+                "Failed to create artifacts directory: {}",
+                output.stderr_lossy()
             );
         }
 
-        Ok(())
-    }
-
-    /// Start the VM and get its IP address
-    #[allow(dead_code)]
-    async fn tart_run(&self, vm_name: &str) -> Result<String> {
-        // Start VM in background
-        let _child = Command::new("tart")
-            .args(["run", vm_name, "--no-graphics"])
-            .spawn()?;
-
-        // Wait for VM to boot and get IP
-        tokio::time::sleep(std::time::Duration::from_secs(30)).await;
-
-        let output = Command::new("tart").args(["ip", vm_name]).output().await?;
-
-        if !output.status.success() {
-            anyhow::bail!("Failed to get VM IP");
+        for artifact in &claimed.upstream_artifacts {
+            let name = artifact.name.as_str();
+            // Names were checked on upload, but here they become part of a path
+            anyhow::ensure!(
+                is_valid_artifact_name(name),
+                "Invalid artifact name: {name:?}"
+            );
+            let url = artifact
+                .download_url
+                .as_deref()
+                .ok_or_else(|| anyhow::anyhow!("No download URL for needed artifact {name}"))?;
+
+            let local = std::env::temp_dir().join(format!("{}-needed-{name}", claimed.job.id));
+            let copied = async {
+                self.client.download_file(url, &local).await?;
+                session
+                    .upload(&local, &format!("{UPSTREAM_ARTIFACTS}/{name}"))
+                    .await
+            }
+            .await;
+            let _ = tokio::fs::remove_file(&local).await;
+            copied.map_err(|e| anyhow::anyhow!("Failed to fetch needed artifact {name}: {e}"))?;
         }
 
-        let ip = String::from_utf8_lossy(&output.stdout).trim().to_string();
-        Ok(ip)
+        Ok(())
     }
 
-    /// Execute the job command inside the VM via SSH
+    /// Execute the job's command, script or steps inside the VM, stopping
+    /// them at `deadline`
+    #[allow(clippy::too_many_arguments)]
     async fn execute_in_vm(
         &self,
-        job: &Job,
-        vm_ip: &str,
+        claimed: &ClaimedJob,
+        mask: &LogMask,
+        logs: &EntrySender,
+        session: &dyn VmSession,
         log_path: &std::path::Path,
-    ) -> Result<i32> {
-        // Get the executable (command or script)
-        let executable = job
-            .executable()
-            .ok_or_else(|| anyhow::anyhow!("Job has no command or script"))?;
+        deadline: Instant,
+        cancel: &CancellationToken,
+    ) -> Result<BuildOutcome> {
+        let job = &claimed.job;
 
-        // Setup log writer
+        // Write each output line to the log file and ship it to the orchestrator
         let log_file = tokio::fs::File::create(log_path).await?;
-        let log_writer = Arc::new(Mutex::new(tokio::io::BufWriter::new(log_file)));
-
-        // If it's a script, write it to VM and execute
-        let run_cmd = if job.script.is_some() {
-            // Write script to file and execute
-            format!(
-                "cat > /tmp/build_script.sh << 'SCRIPT_EOF'\n{executable}\nSCRIPT_EOF\nchmod +x /tmp/build_script.sh && cd ~/workspace && /tmp/build_script.sh"
+        let (lines, mut received) = mpsc::unbounded_channel::<(LogStream, String)>();
+        let job_id = job.id;
+        let mask = mask.clone();
+        let logs = logs.clone();
+        let log_writer = tokio::spawn(async move {
+            let mut writer = tokio::io::BufWriter::new(log_file);
+            while let Some((stream, line)) = received.recv().await {
+                // Secrets are masked before they are streamed or stored
+                let line = mask.apply(&line);
+                let tag = match stream {
+                    LogStream::Stdout => "stdout",
+                    LogStream::Stderr => "stderr",
+                };
+                let _ = writer
+                    .write_all(format!("[{tag}] {line}\n").as_bytes())
+                    .await;
+
+                let _ = logs.send(LogEntry {
+                    job_id,
+                    timestamp: Utc::now(),
+                    stream,
+                    content: line,
+                });
+            }
+            writer.flush().await
+        });
+
+        let outcome = if job.steps.is_empty() {
+            let executable = job
+                .executable()
+                .ok_or_else(|| anyhow::anyhow!("Job has no command or script"))?;
+            let exports = env_exports(
+                job.matrix
+                    .iter()
+                    .chain(&job.env)
+                    .chain(&claimed.secret_values),
+            );
+            let exit = run_build(
+                job.id,
+                session,
+                executable,
+                &exports,
+                deadline,
+                lines.clone(),
+                cancel,
             )
+            .await?;
+            match exit {
+                BuildExit::Exited(exit_code) => BuildOutcome {
+                    exit_code,
+                    steps: Vec::new(),
+                    timed_out: false,
+                },
+                BuildExit::TimedOut => BuildOutcome {
+                    timed_out: true,
+                    ..BuildOutcome::stopped()
+                },
+                BuildExit::Cancelled => BuildOutcome::stopped(),
+            }
         } else {
-            // Single command, run in workspace
-            format!("cd ~/workspace && {executable}")
+            run_steps(claimed, session, &lines, deadline, cancel).await?
         };
 
-        let mut cmd = Command::new("sshpass");
-        cmd.args([
-            "-p",
-            VM_PASSWORD,
-            "ssh",
-            "-tt", // Force PTY allocation for tools like fastlane
-            "-o",
-            "StrictHostKeyChecking=no",
-            "-o",
-            "UserKnownHostsFile=/dev/null",
-            "-o",
-            "PubkeyAuthentication=no",
-            &format!("{VM_USER}@{vm_ip}"),
-            &run_cmd,
-        ])
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped());
-
-        let mut child = cmd.spawn()?;
-
-        // Stream stdout
-        if let Some(stdout) = child.stdout.take() {
-            let client = self.client.clone();
-            let worker_id = self.worker_id;
-            let job_id = job.id;
-            let writer = Arc::clone(&log_writer);
-
-            tokio::spawn(async move {
-                let reader = BufReader::new(stdout);
-                let mut lines = reader.lines();
-
-                while let Ok(Some(line)) = lines.next_line().await {
-                    // Write to file
-                    {
-                        let file_entry = format!("[stdout] {line}\n");
-                        let _ = writer.lock().await.write_all(file_entry.as_bytes()).await;
-                    }
-
-                    // Send to orchestrator (real-time stream)
-                    let entry = LogEntry {
-                        job_id,
-                        timestamp: Utc::now(),
-                        stream: LogStream::Stdout,
-                        content: line,
-                    };
-                    let _ = client.push_log(worker_id, &entry).await;
-                }
-            });
+        if outcome.timed_out {
+            let minutes = self.timeout_minutes(job);
+            let _ = lines.send((
+                LogStream::Stderr,
+                format!("Job hit its {minutes} minute timeout"),
+            ));
         }
 
-        // Stream stderr
-        if let Some(stderr) = child.stderr.take() {
-            let client = self.client.clone();
-            let worker_id = self.worker_id;
-            let job_id = job.id;
-            let writer = Arc::clone(&log_writer);
+        // Every sender is gone with the build, so this drains the remaining
+        // lines into the log file before it is uploaded
+        drop(lines);
+        log_writer.await??;
+
+        Ok(outcome)
+    }
+
+    /// Collect the files matching the artifact globs of each step that ran
+    async fn collect_step_artifacts(
+        &self,
+        job: &Job,
+        results: &[StepResult],
+        session: &dyn VmSession,
+    ) -> Result<Vec<Artifact>> {
+        let mut artifacts: Vec<Artifact> = Vec::new();
+
+        for (step, result) in job.steps.iter().zip(results) {
+            if matches!(result.status, StepStatus::Skipped | StepStatus::Cancelled) {
+                continue;
+            }
 
-            tokio::spawn(async move {
-                let reader = BufReader::new(stderr);
-                let mut lines = reader.lines();
+            for glob in &step.artifacts {
+                // The glob reaches the VM's shell as a single argument, so it is
+                // only ever expanded, never run
+                let output = session
+                    .exec_args(&["sh", "-c", LIST_GLOB, "sh", glob])
+                    .await?;
+                if !output.success() {
+                    tracing::warn!(job_id = %job.id, glob = %glob, "Failed to list step artifacts: {}", output.stderr_lossy());
+                    continue;
+                }
+
+                for path in output.stdout_lossy().lines() {
+                    let name = path.rsplit('/').next().unwrap_or(path);
+                    if !is_valid_artifact_name(name) {
+                        tracing::warn!(job_id = %job.id, path = %path, "Skipping artifact with unsupported name");
+                        continue;
+                    }
+                    if artifacts.iter().any(|artifact| artifact.name == name) {
+                        tracing::warn!(job_id = %job.id, path = %path, "Skipping artifact with duplicate name");
+                        continue;
+                    }
 
-                while let Ok(Some(line)) = lines.next_line().await {
-                    // Write to file
+                    let temp_path = std::env::temp_dir().join(format!("{}-{name}", job.id));
+                    if let Err(e) = session
+                        .download(&format!("workspace/{path}"), &temp_path)
+                        .await
                     {
-                        let file_entry = format!("[stderr] {line}\n");
-                        let _ = writer.lock().await.write_all(file_entry.as_bytes()).await;
+                        tracing::error!("Failed to download artifact {} from VM: {}", path, e);
+                        continue;
                     }
 
-                    // Send to orchestrator (real-time stream)
-                    let entry = LogEntry {
-                        job_id,
-                        timestamp: Utc::now(),
-                        stream: LogStream::Stderr,
-                        content: line,
-                    };
-                    let _ = client.push_log(worker_id, &entry).await;
+                    let size_bytes = tokio::fs::metadata(&temp_path)
+                        .await
+                        .map_or(0, |metadata| metadata.len());
+                    let download_url =
+                        match self.client.upload_artifact(job.id, name, &temp_path).await {
+                            Ok(url) => Some(url),
+                            Err(e) => {
+                                tracing::error!("Failed to upload artifact {}: {}", name, e);
+                                None
+                            },
+                        };
+                    let _ = tokio::fs::remove_file(&temp_path).await;
+
+                    artifacts.push(Artifact {
+                        name: name.to_string(),
+                        path: path.to_string(),
+                        size_bytes,
+                        download_url,
+                    });
                 }
-            });
+            }
         }
 
-        let status = child.wait().await?;
-
-        // Flush writer
-        log_writer.lock().await.flush().await?;
-
-        Ok(status.code().unwrap_or(-1))
+        Ok(artifacts)
     }
 
     /// Collect build artifacts from the VM
-    async fn collect_artifacts(&self, job: &Job, vm_ip: &str) -> Result<Vec<Artifact>> {
+    async fn collect_artifacts(&self, job: &Job, session: &dyn VmSession) -> Result<Vec<Artifact>> {
         let mut artifacts = Vec::new();
 
         // Look for common artifact patterns
@@ -331,27 +588,17 @@ impl JobExecutor {
         ];
 
         for pattern in patterns {
-            let output = Command::new("sshpass")
-                .args([
-                    "-p",
-                    VM_PASSWORD,
-                    "ssh",
-                    "-o",
-                    "StrictHostKeyChecking=no",
-                    "-o",
-                    "UserKnownHostsFile=/dev/null",
-                    &format!("{VM_USER}@{vm_ip}"),
-                    &format!("ls -la {pattern} 2>/dev/null || true"),
-                ])
-                .output()
+            let output = session
+                .exec(&format!("ls -la {pattern} 2>/dev/null || true"))
                 .await?;
 
-            if output.status.success() {
-                let files = String::from_utf8_lossy(&output.stdout);
+            if output.success() {
+                let files = output.stdout_lossy();
                 for line in files.lines() {
                     if let Some(mut artifact) = parse_ls_line(line, pattern) {
                         // Download the file from VM
-                        let file_path = self.download_from_vm(vm_ip, &artifact.name).await;
+                        let file_path =
+                            self.download_from_vm(job.id, session, &artifact.name).await;
                         match file_path {
                             Ok(path) => {
                                 // Upload to orchestrator
@@ -393,8 +640,13 @@ impl JobExecutor {
         Ok(artifacts)
     }
 
-    /// Download a file from the VM via SCP
-    async fn download_from_vm(&self, vm_ip: &str, filename: &str) -> Result<std::path::PathBuf> {
+    /// Download a file from the VM
+    async fn download_from_vm(
+        &self,
+        job_id: Uuid,
+        session: &dyn VmSession,
+        filename: &str,
+    ) -> Result<std::path::PathBuf> {
         // Validate filename to prevent shell injection
         if filename
             .chars()
@@ -404,77 +656,228 @@ impl JobExecutor {
         }
 
         // Find where the file is using `find` inside the VM
-        let find_cmd = format!("find ~ -name '{filename}' -type f | head -n 1");
-        let output = Command::new("sshpass")
-            .args([
-                "-p",
-                VM_PASSWORD,
-                "ssh",
-                "-o",
-                "StrictHostKeyChecking=no",
-                "-o",
-                "UserKnownHostsFile=/dev/null",
-                &format!("{VM_USER}@{vm_ip}"),
-                &find_cmd,
-            ])
-            .output()
+        let output = session
+            .exec_args(&["find", ".", "-name", filename, "-type", "f"])
             .await?;
 
-        let full_path = String::from_utf8_lossy(&output.stdout).trim().to_string();
-        if full_path.is_empty() {
+        let stdout = output.stdout_lossy();
+        let Some(full_path) = stdout.lines().next() else {
             anyhow::bail!("File not found in VM");
-        }
+        };
 
-        // Now scp it
-        let temp_path = std::env::temp_dir().join(filename);
-        let scp_output = Command::new("sshpass")
-            .args([
-                "-p",
-                VM_PASSWORD,
-                "scp",
-                "-o",
-                "StrictHostKeyChecking=no",
-                "-o",
-                "UserKnownHostsFile=/dev/null",
-                &format!("{VM_USER}@{vm_ip}:{full_path}"),
-                temp_path.to_str().unwrap(),
-            ])
-            .output()
-            .await?;
-
-        if !scp_output.status.success() {
-            anyhow::bail!(
-                "SCP failed: {}",
-                String::from_utf8_lossy(&scp_output.stderr)
-            );
-        }
+        // Now copy it out
+        // Prefix with the job ID so concurrent jobs never share a temp file
+        let temp_path = std::env::temp_dir().join(format!("{job_id}-{filename}"));
+        session.download(full_path, &temp_path).await?;
 
         // Return path for streaming upload
         Ok(temp_path)
     }
+}
 
-    /// Delete the VM
-    #[allow(dead_code)]
-    async fn tart_delete(&self, vm_name: &str) -> Result<()> {
-        // First stop the VM if running
-        let _ = Command::new("tart").args(["stop", vm_name]).output().await;
+/// Terminate the build's whole process group inside the VM
+async fn kill_build(session: &dyn VmSession) -> Result<()> {
+    let kill_cmd = format!(
+        "pid=$(cat {JOB_PID_FILE}) || exit 0; \\
+         pkill -TERM -g \"$pid\"; sleep {CANCEL_GRACE_SECS}; pkill -KILL -g \"$pid\"; \\
+         rm -f {JOB_PID_FILE}; true"
+    );
 
-        // Then delete it
-        let output = Command::new("tart")
-            .args(["delete", vm_name])
-            .output()
-            .await?;
+    let output = session.exec(&kill_cmd).await?;
 
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            // Ignore "does not exist" errors - VM may have never been created
-            if !stderr.contains("does not exist") {
-                tracing::warn!("Failed to delete VM {}: {}", vm_name, stderr);
-            }
+    if !output.success() {
+        anyhow::bail!("Failed to kill build: {}", output.stderr_lossy());
+    }
+
+    Ok(())
+}
+
+/// How a job's command or steps ended
+struct BuildOutcome {
+    exit_code: i32,
+    /// Outcome of each step of a pipeline job
+    steps: Vec<StepResult>,
+    /// Stopped at the job's deadline
+    timed_out: bool,
+}
+
+impl BuildOutcome {
+    /// A build stopped before it could exit
+    const fn stopped() -> Self {
+        Self {
+            exit_code: -1,
+            steps: Vec::new(),
+            timed_out: false,
         }
+    }
+}
 
-        Ok(())
+/// How a build command ended
+enum BuildExit {
+    Exited(i32),
+    TimedOut,
+    Cancelled,
+}
+
+/// Run a command or script from the workspace with `exports` loaded,
+/// stopping it at `deadline` or once `cancel` fires
+async fn run_build(
+    job_id: Uuid,
+    session: &dyn VmSession,
+    executable: &str,
+    exports: &str,
+    deadline: Instant,
+    lines: LineSender,
+    cancel: &CancellationToken,
+) -> Result<BuildExit> {
+    // Deliver the command or script as a file so the shell never parses it
+    // as part of our own command line
+    let script_path = std::env::temp_dir().join(format!("{job_id}-build.sh"));
+    tokio::fs::write(&script_path, executable).await?;
+    let uploaded = session.upload(&script_path, BUILD_SCRIPT).await;
+    let _ = tokio::fs::remove_file(&script_path).await;
+    uploaded?;
+
+    // Environment variables and secret values reach the VM in a file
+    // only its user can read, never on a command line
+    let load_env = if exports.is_empty() {
+        String::new()
+    } else {
+        session.upload_private(exports, BUILD_ENV).await?;
+        format!(". ~/{BUILD_ENV}; rm -f ~/{BUILD_ENV}\n")
+    };
+
+    // Record the process group leader's PID so a cancelled build can be
+    // killed as a whole, then run the build from the workspace. Files
+    // without a shebang are run by the VM user's shell.
+    let run_cmd = format!(
+        "echo $$ > {JOB_PID_FILE}\n\
+         {load_env}\
+         chmod +x {BUILD_SCRIPT} && cd ~/workspace && {BUILD_SCRIPT}"
+    );
+
+    // Allocate a PTY for tools like fastlane
+    let run = session.exec_streaming(&run_cmd, true, lines);
+    let exit = tokio::select! {
+        code = run => return Ok(BuildExit::Exited(code?)),
+        () = tokio::time::sleep_until(deadline) => BuildExit::TimedOut,
+        () = cancel.cancelled() => BuildExit::Cancelled,
+    };
+
+    tracing::info!(job_id = %job_id, "Killing stopped build");
+    if let Err(e) = kill_build(session).await {
+        tracing::warn!(job_id = %job_id, "Failed to kill build in VM: {}", e);
     }
+
+    Ok(exit)
+}
+
+/// Run a job's steps in order, each stopped after its own `timeout_minutes`
+/// or at the job's `deadline`. Once a step fails (without `continue_on_error`),
+/// the job times out or it is cancelled, the remaining steps are skipped.
+async fn run_steps(
+    claimed: &ClaimedJob,
+    session: &dyn VmSession,
+    lines: &LineSender,
+    deadline: Instant,
+    cancel: &CancellationToken,
+) -> Result<BuildOutcome> {
+    let job = &claimed.job;
+    let total = job.steps.len();
+    let mut exit_code = 0;
+    let mut timed_out = false;
+    let mut results = Vec::with_capacity(total);
+
+    for (i, step) in job.steps.iter().enumerate() {
+        if exit_code != 0 || timed_out || cancel.is_cancelled() {
+            results.push(StepResult {
+                name: step.name.clone(),
+                status: StepStatus::Skipped,
+                exit_code: None,
+                started_at: None,
+                duration_secs: 0.0,
+            });
+            continue;
+        }
+
+        let _ = lines.send((
+            LogStream::Stdout,
+            format!("==> Step {}/{total}: {}", i + 1, step.name),
+        ));
+        let started_at = Utc::now();
+
+        // Matrix axes and secrets never share a name with other variables
+        let exports = env_exports(
+            job.matrix
+                .iter()
+                .chain(&job.env)
+                .chain(&step.env)
+                .chain(&claimed.secret_values),
+        );
+        let step_deadline = step.timeout_minutes.map_or(deadline, |minutes| {
+            deadline.min(Instant::now() + Duration::from_secs(u64::from(minutes) * 60))
+        });
+        let exit = run_build(
+            job.id,
+            session,
+            &step.run,
+            &exports,
+            step_deadline,
+            lines.clone(),
+            cancel,
+        )
+        .await?;
+        timed_out = matches!(exit, BuildExit::TimedOut) && Instant::now() >= deadline;
+
+        let (status, step_exit_code) = match exit {
+            BuildExit::Exited(0) => (StepStatus::Succeeded, Some(0)),
+            BuildExit::Exited(code) => (StepStatus::Failed, Some(code)),
+            BuildExit::TimedOut => (StepStatus::TimedOut, None),
+            BuildExit::Cancelled => (StepStatus::Cancelled, None),
+        };
+        #[allow(clippy::cast_precision_loss)]
+        let duration_secs = (Utc::now() - started_at).num_milliseconds() as f64 / 1000.0;
+        let _ = lines.send((
+            LogStream::Stdout,
+            format!("==> {}: {status} after {duration_secs:.1}s", step.name),
+        ));
+
+        if timed_out || (status != StepStatus::Succeeded && !step.continue_on_error) {
+            exit_code = step_exit_code.unwrap_or(-1);
+        }
+        results.push(StepResult {
+            name: step.name.clone(),
+            status,
+            exit_code: step_exit_code,
+            started_at: Some(started_at),
+            duration_secs,
+        });
+    }
+
+    Ok(BuildOutcome {
+        exit_code,
+        steps: results,
+        timed_out,
+    })
+}
+
+/// Shell `export` lines for environment variables, later ones winning
+fn env_exports<'a>(vars: impl Iterator<Item = (&'a String, &'a String)>) -> String {
+    let mut exports = String::new();
+    for (name, value) in vars {
+        let _ = writeln!(exports, "export {name}={}", shell_join(&[value]));
+    }
+    exports
+}
+
+/// Whether a file can be uploaded as an artifact under `name`
+fn is_valid_artifact_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_')
 }
 
 /// Helper to parse ls -la output
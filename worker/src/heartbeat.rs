@@ -0,0 +1,106 @@
+//! Background heartbeat loop
+//!
+//! Heartbeats run independently of job execution so that leases on running
+//! jobs keep being renewed while a long build is in progress. They go over
+//! the push channel while it is open, and are posted otherwise.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::channel::Channel;
+use crate::config::Config;
+use crate::orchestrator_client::OrchestratorClient;
+use shared::{RegisterWorkerRequest, WorkerHeartbeat, WorkerMessage};
+
+/// Jobs this worker is currently executing, with the token that cancels each
+pub type ActiveJobs = Arc<Mutex<HashMap<Uuid, CancellationToken>>>;
+
+/// Stop the builds of the given jobs, where they run on this worker
+pub fn cancel_jobs(active_jobs: &ActiveJobs, job_ids: &[Uuid]) {
+    let active = active_jobs.lock().expect("active jobs lock poisoned");
+    for job_id in job_ids {
+        if let Some(token) = active.get(job_id) {
+            tracing::info!(job_id = %job_id, "Job cancelled, stopping build");
+            token.cancel();
+        }
+    }
+}
+
+/// Send heartbeats until the task is aborted, re-registering with
+/// `registration` if the orchestrator forgets the worker and cancelling
+/// `shutdown` if it asks the worker to drain
+pub async fn run(
+    client: OrchestratorClient,
+    config: Config,
+    registration: RegisterWorkerRequest,
+    active_jobs: ActiveJobs,
+    channel: Channel,
+    shutdown: CancellationToken,
+) {
+    let worker_id = registration
+        .worker_id
+        .expect("heartbeats are only sent once registered");
+    let interval = Duration::from_secs(config.heartbeat_interval_secs);
+
+    loop {
+        let job_ids: Vec<Uuid> = active_jobs
+            .lock()
+            .expect("active jobs lock poisoned")
+            .keys()
+            .copied()
+            .collect();
+
+        // Answered on the channel with cancellations and drain commands
+        let heartbeat = WorkerHeartbeat {
+            worker_id,
+            current_jobs: u32::try_from(job_ids.len()).unwrap_or(u32::MAX),
+            capacity: config.capacity,
+            job_ids,
+        };
+        if channel.send(WorkerMessage::Heartbeat(heartbeat.clone())) {
+            tokio::time::sleep(interval).await;
+            continue;
+        }
+
+        match client
+            .heartbeat(worker_id, &heartbeat.job_ids, config.capacity)
+            .await
+        {
+            Ok(response) => {
+                cancel_jobs(&active_jobs, &response.cancelled_job_ids);
+                if response.drain && !shutdown.is_cancelled() {
+                    tracing::info!("Orchestrator asked us to drain, finishing running jobs...");
+                    shutdown.cancel();
+                }
+            },
+            Err(e) => {
+                tracing::warn!("Failed to send heartbeat: {}", e);
+
+                // Check if failure is due to worker not found (orchestrator restarted)
+                // The error message from client includes the response text which contains "worker_not_found"
+                if e.to_string().contains("worker_not_found") {
+                    tracing::warn!("Orchestrator lost our session, re-registering...");
+
+                    // Add a small delay to avoid hammering if it's flapping
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+
+                    match client.register(&registration).await {
+                        Ok(_) => {
+                            tracing::info!("Re-registered successfully");
+                            // Renew leases right away rather than waiting a full interval
+                            continue;
+                        },
+                        Err(reg_err) => {
+                            tracing::error!("Failed to re-register: {}", reg_err);
+                        },
+                    }
+                }
+            },
+        }
+
+        tokio::time::sleep(interval).await;
+    }
+}
@@ -0,0 +1,408 @@
+//! Log shipping
+//!
+//! A job's log entries are numbered and appended to a spool file on disk as
+//! the build prints them, and shipped to the orchestrator from there in
+//! batches, cut by count, size and time, over the push channel or by HTTP
+//! while it is down. One batch is in flight at a time and is resent until it
+//! is acknowledged, so entries arrive in order and at least once; the
+//! orchestrator drops the ones it already has by sequence number, and asks
+//! again for any it missed. The build never waits on the orchestrator: while
+//! it is slow or unreachable the spool just grows.
+//!
+//! A spool is removed once all of it is acknowledged, which the orchestrator
+//! also does for a job it no longer takes logs for. Until then it is shipped
+//! in the background past the end of its job, and after a restart of the
+//! worker.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+use crate::channel::Channel;
+use crate::orchestrator_client::OrchestratorClient;
+use shared::{LogBatch, LogEntry};
+
+/// Most entries shipped in one batch
+const MAX_BATCH_ENTRIES: usize = 500;
+
+/// Size of spooled entries past which a batch is cut
+const MAX_BATCH_BYTES: usize = 256 * 1024;
+
+/// How long a batch waits for more entries to join it before it is shipped
+const BATCH_WINDOW: Duration = Duration::from_millis(200);
+
+/// How long a batch waits for its acknowledgement before it is resent
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Delay before the first resend of an unacknowledged batch, doubling up to
+/// `MAX_RETRY_DELAY`
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// How long a finished job waits for its remaining logs to be shipped before
+/// it leaves them to ship in the background
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Feeds log entries to a shipper running in the background
+pub type EntrySender = mpsc::UnboundedSender<LogEntry>;
+
+/// Where log entries are spooled under the worker's data directory
+pub fn spool_dir(data_dir: &str) -> PathBuf {
+    Path::new(data_dir).join("log-spool")
+}
+
+/// Spools left behind by an earlier run of the worker, shipped in the
+/// background
+#[derive(Clone)]
+pub struct Leftovers {
+    tasks: Arc<Mutex<HashMap<Uuid, JoinHandle<()>>>>,
+    channel: Channel,
+}
+
+impl Leftovers {
+    /// Resume shipping the spools in `dir` that a worker stopped before they
+    /// were acknowledged
+    pub async fn resume(
+        dir: &Path,
+        worker_id: Uuid,
+        client: &OrchestratorClient,
+        channel: &Channel,
+    ) -> Self {
+        let leftovers = Self {
+            tasks: Arc::default(),
+            channel: channel.clone(),
+        };
+        let mut spools = match tokio::fs::read_dir(dir).await {
+            Ok(spools) => spools,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return leftovers,
+            Err(e) => {
+                tracing::warn!("Failed to read log spool {}: {}", dir.display(), e);
+                return leftovers;
+            },
+        };
+
+        while let Ok(Some(spool)) = spools.next_entry().await {
+            let path = spool.path();
+            if path
+                .extension()
+                .is_none_or(|extension| extension != "jsonl")
+            {
+                continue;
+            }
+            let Some(job_id) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str()?.parse::<Uuid>().ok())
+            else {
+                continue;
+            };
+
+            let sender = Sender {
+                job_id,
+                worker_id,
+                client: client.clone(),
+                channel: channel.clone(),
+            };
+            match sender.resume(path).await {
+                Ok(task) => {
+                    tracing::info!(job_id = %job_id, "Resumed shipping leftover logs");
+                    leftovers
+                        .tasks
+                        .lock()
+                        .expect("leftovers lock poisoned")
+                        .insert(job_id, task);
+                },
+                Err(e) => {
+                    tracing::warn!(job_id = %job_id, "Failed to resume shipping logs: {}", e);
+                },
+            }
+        }
+        leftovers
+    }
+
+    /// Stop shipping the leftover spool of `job_id`, which is about to run
+    /// again; its new run's logs take over from the start
+    pub async fn stop(&self, job_id: Uuid) {
+        let task = self
+            .tasks
+            .lock()
+            .expect("leftovers lock poisoned")
+            .remove(&job_id);
+        if let Some(task) = task {
+            task.abort();
+            let _ = task.await;
+            self.channel.forget_logs(job_id);
+        }
+    }
+}
+
+/// Spools a job's log entries and ships them in the background
+pub struct LogShipper {
+    job_id: Uuid,
+    spool: BufWriter<File>,
+    /// Entries pushed so far
+    pushed: u64,
+    /// Entries flushed to the spool, and so ready to ship
+    flushed: watch::Sender<u64>,
+    task: JoinHandle<()>,
+}
+
+impl LogShipper {
+    /// Start shipping the logs of job `job_id`, spooling them in `dir`
+    pub async fn start(
+        job_id: Uuid,
+        worker_id: Uuid,
+        client: OrchestratorClient,
+        channel: Channel,
+        dir: &Path,
+    ) -> Result<Self> {
+        tokio::fs::create_dir_all(dir).await?;
+        let spool_path = dir.join(format!("{job_id}.jsonl"));
+        let spool = File::create(&spool_path).await?;
+        let reader = File::open(&spool_path).await?;
+
+        let (flushed, ready) = watch::channel(0);
+        let sender = Sender {
+            job_id,
+            worker_id,
+            client,
+            channel,
+        };
+        let task = tokio::spawn(sender.run(BufReader::new(reader), spool_path, ready));
+
+        Ok(Self {
+            job_id,
+            spool: BufWriter::new(spool),
+            pushed: 0,
+            flushed,
+            task,
+        })
+    }
+
+    /// Spool an entry; it is shipped once flushed
+    pub async fn push(&mut self, entry: &LogEntry) -> Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        self.spool.write_all(&line).await?;
+        self.pushed += 1;
+        Ok(())
+    }
+
+    /// Flush the spool, making every entry pushed so far ready to ship
+    pub async fn flush(&mut self) -> Result<()> {
+        self.spool.flush().await?;
+        self.flushed.send_replace(self.pushed);
+        Ok(())
+    }
+
+    /// Spool entries sent on the returned channel from a background task,
+    /// flushing whenever it runs dry; once every sender is dropped the task
+    /// finishes the shipper
+    pub fn spawn(mut self) -> (EntrySender, JoinHandle<Result<()>>) {
+        let (entries, mut received) = mpsc::unbounded_channel();
+        let task = tokio::spawn(async move {
+            while let Some(entry) = received.recv().await {
+                self.push(&entry).await?;
+                // Ship what the build printed once it pauses for breath
+                if received.is_empty() {
+                    self.flush().await?;
+                }
+            }
+            self.finish().await
+        });
+        (entries, task)
+    }
+
+    /// Flush the spool and wait for the rest of the entries to be shipped;
+    /// after `DRAIN_TIMEOUT` they are left to ship in the background, so an
+    /// unreachable orchestrator doesn't hold up the job
+    pub async fn finish(mut self) -> Result<()> {
+        self.flush().await?;
+        let Self {
+            job_id,
+            spool,
+            flushed,
+            mut task,
+            ..
+        } = self;
+        drop(spool);
+
+        // With no more entries coming, the task ends once it shipped them all
+        drop(flushed);
+        if tokio::time::timeout(DRAIN_TIMEOUT, &mut task)
+            .await
+            .is_err()
+        {
+            tracing::warn!(job_id = %job_id, "Orchestrator is unreachable, shipping the rest of the logs in the background");
+        }
+        Ok(())
+    }
+}
+
+/// Ships a job's spooled entries in order
+struct Sender {
+    job_id: Uuid,
+    worker_id: Uuid,
+    client: OrchestratorClient,
+    channel: Channel,
+}
+
+impl Sender {
+    /// Ship the whole entries of a spool left behind by an earlier run of the
+    /// worker; an entry cut short as the worker stopped is left out
+    async fn resume(self, spool_path: PathBuf) -> Result<JoinHandle<()>> {
+        let mut spool = BufReader::new(File::open(&spool_path).await?);
+        let mut line = Vec::new();
+        let mut entries = 0;
+        while spool.read_until(b'\n', &mut line).await? > 0 {
+            if line.ends_with(b"\n") {
+                entries += 1;
+            }
+            line.clear();
+        }
+
+        let spool = BufReader::new(File::open(&spool_path).await?);
+        let (_, ready) = watch::channel(entries);
+        Ok(tokio::spawn(self.run(spool, spool_path, ready)))
+    }
+
+    /// Ship entries from `spool` as they become ready, until the shipper
+    /// finishes and every entry is shipped, then remove the spool
+    async fn run(self, spool: BufReader<File>, spool_path: PathBuf, ready: watch::Receiver<u64>) {
+        if let Err(e) = self.ship(spool, &spool_path, ready).await {
+            tracing::error!(job_id = %self.job_id, "Dropping unreadable log spool: {}", e);
+        }
+        self.channel.forget_logs(self.job_id);
+        let _ = tokio::fs::remove_file(&spool_path).await;
+    }
+
+    /// Ship entries from `spool` as they become ready, until the shipper
+    /// finishes and every entry is shipped
+    async fn ship(
+        &self,
+        mut spool: BufReader<File>,
+        spool_path: &Path,
+        mut ready: watch::Receiver<u64>,
+    ) -> Result<()> {
+        let mut next_seq = 0;
+        loop {
+            let Ok(available) = ready.wait_for(|&ready| ready > next_seq).await.map(|r| *r) else {
+                return Ok(());
+            };
+
+            // Let a batch that isn't full fill up a little before shipping it
+            let available = if available - next_seq < MAX_BATCH_ENTRIES as u64 {
+                tokio::time::sleep(BATCH_WINDOW).await;
+                *ready.borrow()
+            } else {
+                available
+            };
+
+            let batch = read_batch(&mut spool, self.job_id, next_seq, available).await?;
+            let acked = self.deliver(&batch).await;
+            if acked >= batch.first_seq {
+                next_seq = batch.end_seq();
+                continue;
+            }
+
+            // The orchestrator is missing entries from before the batch
+            tracing::debug!(job_id = %self.job_id, "Logs asked for again from {}", acked);
+            spool = open_spool_at(spool_path, acked).await?;
+            next_seq = acked;
+        }
+    }
+
+    /// Send `batch` until the orchestrator acknowledges it, or asks for
+    /// earlier entries, returning how far it has the job's logs
+    async fn deliver(&self, batch: &LogBatch) -> u64 {
+        let mut delay = RETRY_DELAY;
+        loop {
+            match self.send(batch).await {
+                Ok(next_seq) if next_seq >= batch.end_seq() || next_seq < batch.first_seq => {
+                    return next_seq;
+                },
+                Ok(next_seq) => tracing::debug!(
+                    job_id = %self.job_id,
+                    "Logs acknowledged only up to {}, resending", next_seq
+                ),
+                Err(e) => {
+                    tracing::debug!(job_id = %self.job_id, "Failed to ship logs, retrying: {}", e);
+                },
+            }
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        }
+    }
+
+    /// Send `batch` once, over the push channel if it is open, returning how
+    /// far the orchestrator has the job's logs
+    async fn send(&self, batch: &LogBatch) -> Result<u64> {
+        let Some(mut acks) = self.channel.ship_logs(batch.clone()) else {
+            // An orchestrator that stopped answering would hold the post forever
+            return tokio::time::timeout(ACK_TIMEOUT, self.client.push_logs(self.worker_id, batch))
+                .await
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("Timed out pushing logs")));
+        };
+
+        let answered = async {
+            loop {
+                acks.changed().await.ok()?;
+                let acked = *acks.borrow_and_update();
+                if acked >= batch.end_seq() || acked < batch.first_seq {
+                    return Some(acked);
+                }
+            }
+        };
+        let acked = tokio::time::timeout(ACK_TIMEOUT, answered)
+            .await
+            .ok()
+            .flatten();
+        acked.ok_or_else(|| anyhow::anyhow!("No acknowledgement on the push channel"))
+    }
+}
+
+/// Open the spool at `path` for reading from entry `seq` on
+async fn open_spool_at(path: &Path, seq: u64) -> Result<BufReader<File>> {
+    let mut spool = BufReader::new(File::open(path).await?);
+    let mut line = String::new();
+    for _ in 0..seq {
+        line.clear();
+        spool.read_line(&mut line).await?;
+    }
+    Ok(spool)
+}
+
+/// Read the entries from `first_seq` on, up to the `available` ones or a
+/// full batch
+async fn read_batch(
+    spool: &mut BufReader<File>,
+    job_id: Uuid,
+    first_seq: u64,
+    available: u64,
+) -> Result<LogBatch> {
+    let mut entries = Vec::new();
+    let mut bytes = 0;
+    let mut line = String::new();
+    for _ in first_seq..available {
+        line.clear();
+        // Flushed entries are whole lines, so this never stops short
+        bytes += spool.read_line(&mut line).await?;
+        entries.push(serde_json::from_str(&line)?);
+        if entries.len() >= MAX_BATCH_ENTRIES || bytes >= MAX_BATCH_BYTES {
+            break;
+        }
+    }
+
+    Ok(LogBatch {
+        job_id,
+        first_seq,
+        entries,
+    })
+}
@@ -2,22 +2,34 @@
 //!
 //! Runs on Mac Minis to execute build jobs in Tart VMs.
 
+mod backend;
+mod capabilities;
+mod channel;
+mod checkout;
 mod config;
 mod executor;
+mod heartbeat;
+mod log_shipper;
 mod orchestrator_client;
 mod vm_pool;
 
-use chrono::Utc;
-use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use uuid::Uuid;
 
+use crate::channel::Channel;
 use crate::config::Config;
-use crate::executor::JobExecutor;
+use crate::executor::{JobExecutor, SourceFetchError};
 use crate::orchestrator_client::OrchestratorClient;
 use crate::vm_pool::VmPool;
-use shared::{JobResult, LogEntry, LogStream};
+use shared::{ClaimedJob, FailureReason, Job, JobResult, LogMask, RegisterWorkerRequest};
+
+/// How long to wait before polling for a job again when there was none
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
 
 #[tokio::main]
 #[allow(clippy::too_many_lines)]
@@ -35,28 +47,42 @@ async fn main() -> anyhow::Result<()> {
         .init();
 
     let config = Config::from_env()?;
+    if config.capacity == 0 {
+        anyhow::bail!("WORKER_CAPACITY must be at least 1");
+    }
 
     tracing::info!("Starting Alloy Worker");
     tracing::info!("Orchestrator URL: {}", config.orchestrator_url);
-    tracing::info!("VM Pool Size: {}", config.vm_pool_size);
+    for image in &config.images {
+        tracing::info!(
+            "Image {}: {} ({} warm)",
+            image.name,
+            image.image,
+            image.warm
+        );
+    }
 
     // Initialize VM pool
     if let Some(ref script) = config.vm_setup_script {
         tracing::info!("VM Setup Script: {}", script);
     }
+    tracing::info!("VM backend: {}", config.vm_backend.name());
+    let backend = config.vm_backend.build()?;
     let vm_pool = Arc::new(
         VmPool::new(
-            config.vm_pool_size,
-            &config.tart_base_image,
+            Arc::clone(&backend),
+            &config.images,
+            config.capacity,
             config.vm_setup_script.as_deref(),
+            config.vm_reuse,
         )
         .await?,
     );
     let pool_for_shutdown = Arc::clone(&vm_pool);
 
-    // Shutdown flag for graceful shutdown
-    let shutdown_requested = Arc::new(AtomicBool::new(false));
-    let shutdown_flag = shutdown_requested.clone();
+    // Cancelled to request a graceful shutdown
+    let shutdown = CancellationToken::new();
+    let shutdown_signal = shutdown.clone();
 
     // Set up signal handlers
     tokio::spawn(async move {
@@ -86,7 +112,7 @@ async fn main() -> anyhow::Result<()> {
             }
         }
 
-        shutdown_flag.store(true, Ordering::SeqCst);
+        shutdown_signal.cancel();
     });
 
     let client =
@@ -127,11 +153,25 @@ async fn main() -> anyhow::Result<()> {
         None
     };
 
-    // Register with orchestrator
-    let mut registration = client
-        .register(&config.hostname, config.capacity, stored_worker_id)
-        .await?;
+    // Register with orchestrator, advertising what jobs can ask of us (labels
+    // derived from the VM's macOS and Xcode describe the default image)
+    let capabilities = capabilities::discover(&backend, &vm_pool).await;
+    tracing::info!(
+        labels = %config.labels.join(","),
+        derived_labels = %capabilities.labels().join(","),
+        "Discovered worker capabilities"
+    );
+    let mut register_request = RegisterWorkerRequest {
+        hostname: config.hostname.clone(),
+        capacity: config.capacity,
+        worker_id: stored_worker_id,
+        labels: config.labels.clone(),
+        capabilities,
+        images: vm_pool.image_names(),
+    };
+    let registration = client.register(&register_request).await?;
     tracing::info!("Registered as worker {}", registration.worker_id);
+    register_request.worker_id = Some(registration.worker_id);
 
     // Save worker ID if it's new or different
     if Some(registration.worker_id) != stored_worker_id {
@@ -143,122 +183,117 @@ async fn main() -> anyhow::Result<()> {
         }
     }
 
-    let executor = JobExecutor::new(
+    // Jobs are pushed to us over the channel while it is open
+    let active_jobs = heartbeat::ActiveJobs::default();
+    let channel = Channel::open(
+        client.clone(),
+        registration.worker_id,
+        Arc::clone(&active_jobs),
+        shutdown.clone(),
+    );
+
+    // Logs a previous run didn't get to ship go out alongside new jobs
+    let leftovers = log_shipper::Leftovers::resume(
+        &log_shipper::spool_dir(&config.data_dir),
+        registration.worker_id,
+        &client,
+        &channel,
+    )
+    .await;
+    let executor = Arc::new(JobExecutor::new(
         registration.worker_id,
         client.clone(),
+        channel.clone(),
         config.clone(),
+        backend,
         Arc::clone(&vm_pool),
-    );
+        leftovers,
+    ));
+
+    // Heartbeats run in the background so leases stay fresh during long builds
+    let heartbeat_task = tokio::spawn(heartbeat::run(
+        client.clone(),
+        config.clone(),
+        register_request,
+        Arc::clone(&active_jobs),
+        channel.clone(),
+        shutdown.clone(),
+    ));
+
+    // One slot per job we can run at once, each backed by a pooled VM
+    let slots = Arc::new(Semaphore::new(config.capacity as usize));
+    let mut jobs = JoinSet::new();
 
     // Main worker loop
-    while !shutdown_requested.load(Ordering::SeqCst) {
-        // Send heartbeat
-        if let Err(e) = client
-            .heartbeat(registration.worker_id, 0, config.capacity)
-            .await
-        {
-            tracing::warn!("Failed to send heartbeat: {}", e);
-
-            // Check if failure is due to worker not found (orchestrator restarted)
-            // The error message from client includes the response text which contains "worker_not_found"
-            let error_msg = e.to_string();
-            if error_msg.contains("worker_not_found") {
-                tracing::warn!("Orchestrator lost our session, re-registering...");
-
-                // Add a small delay to avoid hammering if it's flapping
-                tokio::time::sleep(Duration::from_secs(1)).await;
-
-                match client
-                    .register(
-                        &config.hostname,
-                        config.capacity,
-                        Some(registration.worker_id),
-                    )
-                    .await
-                {
-                    Ok(new_reg) => {
-                        tracing::info!("Re-registered successfully");
-                        registration = new_reg;
-                        // Continue loop to send heartbeat immediately with new session?
-                        // Or just let next iteration handle it.
-                    },
-                    Err(reg_err) => {
-                        tracing::error!("Failed to re-register: {}", reg_err);
-                        // Backoff before retrying loop
-                        tokio::time::sleep(Duration::from_secs(5)).await;
-                    },
-                }
-            }
-        }
+    while !shutdown.is_cancelled() {
+        // Reap jobs that have finished
+        while jobs.try_join_next().is_some() {}
+
+        // Wait for a free slot before claiming more work
+        let permit = tokio::select! {
+            permit = Arc::clone(&slots).acquire_owned() => permit?,
+            () = shutdown.cancelled() => break,
+        };
 
-        // Try to claim a job
-        match client.claim_job(registration.worker_id).await {
-            Ok(Some(job)) => {
-                tracing::info!(job_id = %job.id, "Claimed job, executing...");
-
-                // Execute the job
-                let start_time = Instant::now();
-                match executor.execute(&job).await {
-                    Ok(result) => {
-                        tracing::info!(
-                            job_id = %job.id,
-                            exit_code = result.exit_code,
-                            "Job completed"
-                        );
-
-                        // Report completion
-                        if let Err(e) = client.complete_job(registration.worker_id, result).await {
-                            tracing::error!("Failed to report job completion: {}", e);
-                        }
-                    },
-                    Err(e) => {
-                        tracing::error!(job_id = %job.id, "Job execution failed: {}", e);
-
-                        // Report failure to orchestrator so job isn't stuck
-                        let error_msg = format!("Job execution failed on worker: {e}");
-
-                        // 1. Push error log
-                        let log_entry = LogEntry {
-                            job_id: job.id,
-                            timestamp: Utc::now(),
-                            stream: LogStream::Stderr,
-                            content: error_msg,
-                        };
-                        if let Err(log_err) =
-                            client.push_log(registration.worker_id, &log_entry).await
-                        {
-                            tracing::warn!("Failed to push failure log: {}", log_err);
-                        }
-
-                        // 2. Report completion with failure
-                        let duration = start_time.elapsed().as_secs_f64() / 60.0;
-                        let failure_result = JobResult {
-                            job_id: job.id,
-                            exit_code: -1, // Internal error
-                            artifacts: vec![],
-                            build_minutes: duration,
-                        };
-
-                        if let Err(report_err) = client
-                            .complete_job(registration.worker_id, failure_result)
-                            .await
-                        {
-                            tracing::error!("Failed to report job failure: {}", report_err);
-                        }
-                    },
-                }
+        // Take the next job offered on the channel, or else poll for one
+        let claim = async {
+            if let Some(claimed) = channel.claim().await {
+                return Ok(Some(claimed));
+            }
+            client.claim_job(registration.worker_id).await
+        };
+        let claimed = tokio::select! {
+            claimed = claim => claimed,
+            () = shutdown.cancelled() => break,
+        };
+        match claimed {
+            Ok(Some(claimed)) => {
+                tracing::info!(job_id = %claimed.job.id, "Claimed job, executing...");
+                let cancel = CancellationToken::new();
+                active_jobs
+                    .lock()
+                    .expect("active jobs lock poisoned")
+                    .insert(claimed.job.id, cancel.clone());
+
+                jobs.spawn(run_job(
+                    Arc::clone(&executor),
+                    client.clone(),
+                    registration.worker_id,
+                    claimed,
+                    cancel,
+                    Arc::clone(&active_jobs),
+                    permit,
+                ));
             },
             Ok(None) => {
-                // No jobs available, wait before polling again
-                tokio::time::sleep(Duration::from_secs(5)).await;
+                // No jobs available, wait before polling again unless the
+                // channel opens meanwhile
+                drop(permit);
+                tokio::select! {
+                    () = tokio::time::sleep(POLL_INTERVAL) => {},
+                    () = channel.connected() => {},
+                }
             },
             Err(e) => {
                 tracing::warn!("Failed to claim job: {}", e);
+                drop(permit);
                 tokio::time::sleep(Duration::from_secs(10)).await;
             },
         }
     }
 
+    // Hand back the jobs offered for a claim we gave up on, so they run elsewhere
+    for claimed in channel.withdraw_claim().await {
+        hand_back(&client, registration.worker_id, &claimed.job).await;
+    }
+
+    // Let in-flight jobs finish; heartbeats keep their leases alive meanwhile
+    if !jobs.is_empty() {
+        tracing::info!("Waiting for {} in-flight job(s) to finish...", jobs.len());
+    }
+    while jobs.join_next().await.is_some() {}
+    heartbeat_task.abort();
+
     // Deregister from orchestrator before shutting down
     tracing::info!("Deregistering from orchestrator...");
     if let Err(e) = client.deregister(registration.worker_id).await {
@@ -271,3 +306,75 @@ async fn main() -> anyhow::Result<()> {
     tracing::info!("Graceful shutdown complete");
     Ok(())
 }
+
+/// Give back a job we claimed but won't run, for the orchestrator to offer
+/// to another worker
+async fn hand_back(client: &OrchestratorClient, worker_id: Uuid, job: &Job) {
+    tracing::info!(job_id = %job.id, "Handing back job offered while shutting down");
+    if let Err(e) = client.release_job(worker_id, job.id).await {
+        tracing::warn!(job_id = %job.id, "Failed to hand back job: {}", e);
+    }
+}
+
+/// Execute a claimed job and report its result, freeing its slot when done
+async fn run_job(
+    executor: Arc<JobExecutor>,
+    client: OrchestratorClient,
+    worker_id: Uuid,
+    claimed: ClaimedJob,
+    cancel: CancellationToken,
+    active_jobs: heartbeat::ActiveJobs,
+    _permit: OwnedSemaphorePermit,
+) {
+    let start_time = Instant::now();
+    let job = &claimed.job;
+    let mask = LogMask::new(claimed.secret_values.values().map(String::as_str));
+    match executor.execute(&claimed, &mask, &cancel).await {
+        Ok(result) => {
+            tracing::info!(
+                job_id = %job.id,
+                exit_code = result.exit_code,
+                "Job completed"
+            );
+
+            // Report completion
+            if let Err(e) = client.complete_job(worker_id, result).await {
+                tracing::error!("Failed to report job completion: {}", e);
+            }
+        },
+        Err(e) => {
+            tracing::error!(job_id = %job.id, "Job execution failed: {}", e);
+
+            // Report failure to orchestrator so job isn't stuck (the error
+            // was logged for the job by the executor)
+            let duration = start_time.elapsed().as_secs_f64() / 60.0;
+            // Anything but a bad source is the worker's fault, and worth
+            // retrying elsewhere
+            let failure_reason = if e.is::<SourceFetchError>() {
+                FailureReason::SourceFetch
+            } else {
+                FailureReason::Infra
+            };
+            let failure_result = JobResult {
+                job_id: job.id,
+                exit_code: -1, // Internal error
+                artifacts: vec![],
+                build_minutes: duration,
+                cancelled: false,
+                timed_out: false,
+                failure_reason: Some(failure_reason),
+                resolved_sha: None,
+                steps: vec![],
+            };
+
+            if let Err(report_err) = client.complete_job(worker_id, failure_result).await {
+                tracing::error!("Failed to report job failure: {}", report_err);
+            }
+        },
+    }
+
+    active_jobs
+        .lock()
+        .expect("active jobs lock poisoned")
+        .remove(&job.id);
+}
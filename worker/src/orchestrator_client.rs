@@ -3,13 +3,23 @@
 use anyhow::Result;
 use reqwest::{Client, RequestBuilder};
 use serde_json::json;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 use uuid::Uuid;
 
-use shared::{Job, JobResult, RegisterWorkerResponse};
+use shared::{
+    ClaimedJob, JobResult, RegisterWorkerRequest, RegisterWorkerResponse, ReleaseJobRequest,
+    WorkerHeartbeatResponse,
+};
 
 /// Header name for worker authentication
 const WORKER_SECRET_HEADER: &str = "X-Worker-Secret";
 
+/// A worker's open push channel to the orchestrator
+pub type ChannelSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
 #[derive(Clone)]
 pub struct OrchestratorClient {
     client: Client,
@@ -38,18 +48,12 @@ impl OrchestratorClient {
     /// Register this worker with the orchestrator
     pub async fn register(
         &self,
-        hostname: &str,
-        capacity: u32,
-        worker_id: Option<Uuid>,
+        registration: &RegisterWorkerRequest,
     ) -> Result<RegisterWorkerResponse> {
         let request = self
             .client
             .post(format!("{}/api/v1/workers/register", self.base_url))
-            .json(&json!({
-                "hostname": hostname,
-                "capacity": capacity,
-                "worker_id": worker_id,
-            }));
+            .json(registration);
 
         let response = self.with_auth(request).send().await?;
 
@@ -62,14 +66,20 @@ impl OrchestratorClient {
     }
 
     /// Send heartbeat to orchestrator
-    pub async fn heartbeat(&self, worker_id: Uuid, current_jobs: u32, capacity: u32) -> Result<()> {
+    pub async fn heartbeat(
+        &self,
+        worker_id: Uuid,
+        job_ids: &[Uuid],
+        capacity: u32,
+    ) -> Result<WorkerHeartbeatResponse> {
         let request = self
             .client
             .post(format!("{}/api/v1/workers/heartbeat", self.base_url))
             .json(&json!({
                 "worker_id": worker_id,
-                "current_jobs": current_jobs,
+                "current_jobs": job_ids.len(),
                 "capacity": capacity,
+                "job_ids": job_ids,
             }));
 
         let response = self.with_auth(request).send().await?;
@@ -79,7 +89,7 @@ impl OrchestratorClient {
             anyhow::bail!("Heartbeat failed: {error}");
         }
 
-        Ok(())
+        Ok(response.json().await?)
     }
 
     /// Deregister this worker (mark as offline)
@@ -99,8 +109,26 @@ impl OrchestratorClient {
         Ok(())
     }
 
+    /// Open this worker's push channel
+    pub async fn open_channel(&self, worker_id: Uuid) -> Result<ChannelSocket> {
+        let ws_base = self
+            .base_url
+            .replace("http://", "ws://")
+            .replace("https://", "wss://");
+        let mut request =
+            format!("{ws_base}/api/v1/workers/{worker_id}/channel").into_client_request()?;
+        if let Some(secret) = &self.worker_secret_key {
+            request
+                .headers_mut()
+                .insert(WORKER_SECRET_HEADER, secret.parse()?);
+        }
+
+        let (socket, _) = tokio_tungstenite::connect_async(request).await?;
+        Ok(socket)
+    }
+
     /// Try to claim a pending job
-    pub async fn claim_job(&self, worker_id: Uuid) -> Result<Option<Job>> {
+    pub async fn claim_job(&self, worker_id: Uuid) -> Result<Option<ClaimedJob>> {
         let request = self
             .client
             .post(format!("{}/api/v1/workers/claim", self.base_url))
@@ -138,26 +166,48 @@ impl OrchestratorClient {
         Ok(())
     }
 
-    /// Push a log entry to the orchestrator
-    pub async fn push_log(&self, worker_id: Uuid, entry: &shared::LogEntry) -> Result<()> {
+    /// Hand back a job this worker claimed but won't run
+    pub async fn release_job(&self, worker_id: Uuid, job_id: Uuid) -> Result<()> {
         let request = self
             .client
             .post(format!(
-                "{}/api/v1/workers/{}/log",
+                "{}/api/v1/workers/{}/release",
                 self.base_url, worker_id
             ))
-            .json(entry);
+            .json(&ReleaseJobRequest { job_id });
 
         let response = self.with_auth(request).send().await?;
 
         if !response.status().is_success() {
-            // Don't fail the whole job for log issues
-            tracing::warn!("Failed to push log: {}", response.status());
+            let error = response.text().await?;
+            anyhow::bail!("Failed to release job: {error}");
         }
 
         Ok(())
     }
 
+    /// Push a batch of log entries, returning how far the orchestrator has
+    /// the job's logs
+    pub async fn push_logs(&self, worker_id: Uuid, batch: &shared::LogBatch) -> Result<u64> {
+        let request = self
+            .client
+            .post(format!(
+                "{}/api/v1/workers/{}/logs",
+                self.base_url, worker_id
+            ))
+            .json(batch);
+
+        let response = self.with_auth(request).send().await?;
+
+        if !response.status().is_success() {
+            let error = response.text().await?;
+            anyhow::bail!("Failed to push logs: {error}");
+        }
+
+        let ack: shared::LogAck = response.json().await?;
+        Ok(ack.next_seq)
+    }
+
     /// Upload log file to orchestrator
     pub async fn upload_log_file(&self, job_id: Uuid, log_path: &std::path::Path) -> Result<()> {
         let file = tokio::fs::File::open(log_path).await?;
@@ -182,6 +232,25 @@ impl OrchestratorClient {
         Ok(())
     }
 
+    /// Download an uploaded source archive or an artifact to `path` from a
+    /// signed URL. The URL may point outside the orchestrator, so no worker
+    /// credentials are sent.
+    pub async fn download_file(&self, url: &str, path: &std::path::Path) -> Result<()> {
+        let mut response = self.client.get(url).send().await?;
+
+        if !response.status().is_success() {
+            anyhow::bail!("Failed to download file: {}", response.status());
+        }
+
+        let mut file = tokio::fs::File::create(path).await?;
+        while let Some(chunk) = response.chunk().await? {
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+
+        Ok(())
+    }
+
     /// Upload artifact file to orchestrator (streaming)
     pub async fn upload_artifact(
         &self,
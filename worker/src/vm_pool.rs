@@ -1,16 +1,34 @@
-//! VM Pool - manages a pool of pre-warmed VMs for faster job startup
+//! VM Pool - manages pools of pre-warmed VMs for faster job startup
+//!
+//! A worker can offer several base images (e.g. Sonoma with Xcode 15 and
+//! Sequoia with Xcode 16), keeping a configured number of VMs of each booted
+//! and waiting. A job asking for an image with no warm VM gets a cold clone,
+//! made room for by retiring an idle VM of another image if the pool is full.
+//!
+//! By default every VM is used for a single job: on release it is deleted and
+//! a fresh clone is provisioned in the background for whichever image is
+//! furthest below its warm count, so nothing a job leaves behind (keychains,
+//! simulators, caches) reaches the next one. With `VM_REUSE=true` VMs are
+//! instead kept and only their workspace is wiped.
 
 use anyhow::Result;
 use std::sync::Arc;
-use tokio::process::Command;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::{Mutex, Notify};
+use tokio::task::JoinSet;
+
+use crate::backend::VmBackend;
+use crate::config::WorkerImage;
+
+/// Delay before retrying a VM that failed to provision
+const REFILL_RETRY_DELAY: Duration = Duration::from_secs(30);
 
 /// State of a VM in the pool
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum VmState {
     /// VM is ready to accept a job
     Ready,
-    /// VM is currently running a job
+    /// VM is currently running a job (or being cloned for one)
     InUse,
     /// VM is being reset after a job
     Resetting,
@@ -21,251 +39,304 @@ pub enum VmState {
 pub struct PooledVm {
     /// VM name (used by tart)
     pub name: String,
+    /// Name of the base image the VM was cloned from
+    pub image: String,
     /// IP address of the running VM
     pub ip: String,
     /// Current state
     pub state: VmState,
 }
 
+/// A base image the pool keeps VMs of
+struct PoolImage {
+    /// Name jobs request the image by
+    name: String,
+    /// Prepared image VMs are cloned from
+    image: String,
+    /// VMs to keep ready for jobs
+    warm: usize,
+}
+
 /// Pool of pre-warmed VMs
 pub struct VmPool {
-    vms: Vec<Arc<Mutex<PooledVm>>>,
-    #[allow(dead_code)]
-    base_image: String,
+    backend: Arc<dyn VmBackend>,
+    /// Images VMs can be cloned from; the first is the default
+    images: Vec<PoolImage>,
+    /// Every VM that exists, whatever its state. Never locked while holding
+    /// the lock of one of its VMs.
+    vms: Mutex<Vec<Arc<Mutex<PooledVm>>>>,
+    /// Most VMs that may exist at once
+    max_vms: usize,
+    setup_script: Option<String>,
+    /// Keep VMs between jobs instead of re-cloning them
+    reuse: bool,
+    /// Signalled whenever a VM becomes ready or room is made for one
+    ready: Arc<Notify>,
+    /// Background tasks re-provisioning released VMs
+    refills: Mutex<JoinSet<()>>,
 }
 
 impl VmPool {
-    /// Create a new VM pool and initialize VMs
-    #[allow(clippy::too_many_lines)]
-    pub async fn new(pool_size: u32, base_image: &str, setup_script: Option<&str>) -> Result<Self> {
-        tracing::info!(pool_size = pool_size, "Initializing VM pool...");
-
-        let mut vms = Vec::with_capacity(pool_size as usize);
-
-        for i in 0..pool_size {
-            let vm_name = format!("pool-vm-{i}");
+    /// Create a new VM pool, preparing each image and booting its warm VMs
+    /// (as many as fit in `max_vms`)
+    pub async fn new(
+        backend: Arc<dyn VmBackend>,
+        images: &[WorkerImage],
+        max_vms: u32,
+        setup_script: Option<&str>,
+        reuse: bool,
+    ) -> Result<Self> {
+        tracing::info!(max_vms = max_vms, reuse = reuse, "Initializing VM pool...");
+
+        let mut prepared = Vec::with_capacity(images.len());
+        for image in images {
+            prepared.push(PoolImage {
+                name: image.name.clone(),
+                image: backend.prepare_image(&image.image).await?,
+                warm: image.warm as usize,
+            });
+        }
 
-            // Clone the VM
-            tracing::info!(vm_name = %vm_name, "Cloning VM for pool...");
-            let output = Command::new("tart")
-                .args(["clone", base_image, &vm_name])
-                .output()
-                .await?;
+        let max_vms = max_vms as usize;
+        let wanted: usize = prepared.iter().map(|image| image.warm).sum();
+        if wanted > max_vms {
+            tracing::warn!(
+                max_vms = max_vms,
+                "Warm VM counts add up to {}, only keeping {} warm",
+                wanted,
+                max_vms
+            );
+        }
 
-            if !output.status.success() {
-                let stderr = String::from_utf8_lossy(&output.stderr);
-                // If VM already exists, that's ok - just use it
-                if !stderr.contains("already exists") {
-                    anyhow::bail!("Failed to clone VM {vm_name}: {stderr}");
+        let mut vms = Vec::with_capacity(max_vms);
+        for image in &prepared {
+            for i in 0..image.warm {
+                if vms.len() == max_vms {
+                    break;
                 }
-                tracing::info!(vm_name = %vm_name, "VM already exists, reusing");
-            }
-
-            // Start the VM
-            tracing::info!(vm_name = %vm_name, "Starting VM...");
-            let _child = Command::new("tart")
-                .args(["run", &vm_name, "--no-graphics"])
-                .spawn()?;
-
-            // Wait for VM to boot and get IP
-            let ip = Self::wait_for_ip(&vm_name).await.unwrap_or_else(|| {
-                tracing::warn!(vm_name = %vm_name, "Failed to get VM IP, will retry later");
-                String::new()
-            });
+                let vm_name = vm_name(&image.name, i);
+                let ip = provision(
+                    backend.as_ref(),
+                    &vm_name,
+                    &image.image,
+                    setup_script,
+                    reuse,
+                )
+                .await?;
 
-            // Run setup script if provided
-            if let Some(script) = setup_script {
-                if !ip.is_empty() {
-                    // Check if script is a file path
-                    let script_path = std::path::Path::new(script);
-                    if script_path.exists() && script_path.is_file() {
-                        tracing::info!(vm_name = %vm_name, script = %script, "Copying setup script to VM...");
-
-                        // Copy script to VM
-                        let scp_output = Command::new("sshpass")
-                            .args([
-                                "-p",
-                                "admin",
-                                "scp",
-                                "-o",
-                                "StrictHostKeyChecking=no",
-                                "-o",
-                                "UserKnownHostsFile=/dev/null",
-                                "-o",
-                                "PubkeyAuthentication=no",
-                                script,
-                                &format!("admin@{ip}:~/setup.sh"),
-                            ])
-                            .output()
-                            .await;
-
-                        if let Ok(output) = scp_output {
-                            if output.status.success() {
-                                tracing::info!(vm_name = %vm_name, "Running VM setup script...");
-                                let setup_output = Command::new("sshpass")
-                                    .args([
-                                        "-p",
-                                        "admin",
-                                        "ssh",
-                                        "-o",
-                                        "StrictHostKeyChecking=no",
-                                        "-o",
-                                        "UserKnownHostsFile=/dev/null",
-                                        "-o",
-                                        "PubkeyAuthentication=no",
-                                        &format!("admin@{ip}"),
-                                        "chmod +x ~/setup.sh && ~/setup.sh",
-                                    ])
-                                    .output()
-                                    .await;
-
-                                match setup_output {
-                                    Ok(output) if output.status.success() => {
-                                        tracing::info!(vm_name = %vm_name, "VM setup script completed");
-                                    },
-                                    Ok(output) => {
-                                        let stderr = String::from_utf8_lossy(&output.stderr);
-                                        let stdout = String::from_utf8_lossy(&output.stdout);
-                                        tracing::warn!(vm_name = %vm_name, "VM setup script output: {}{}", stdout, stderr);
-                                    },
-                                    Err(e) => {
-                                        tracing::warn!(vm_name = %vm_name, "VM setup script error: {}", e);
-                                    },
-                                }
-                            } else {
-                                let stderr = String::from_utf8_lossy(&output.stderr);
-                                tracing::warn!(vm_name = %vm_name, "Failed to copy setup script: {}", stderr);
-                            }
-                        }
-                    } else {
-                        // Run as inline command
-                        tracing::info!(vm_name = %vm_name, "Running VM setup command...");
-                        let setup_output = Command::new("sshpass")
-                            .args([
-                                "-p",
-                                "admin",
-                                "ssh",
-                                "-o",
-                                "StrictHostKeyChecking=no",
-                                "-o",
-                                "UserKnownHostsFile=/dev/null",
-                                "-o",
-                                "PubkeyAuthentication=no",
-                                &format!("admin@{ip}"),
-                                script,
-                            ])
-                            .output()
-                            .await;
-
-                        match setup_output {
-                            Ok(output) if output.status.success() => {
-                                tracing::info!(vm_name = %vm_name, "VM setup command completed");
-                            },
-                            Ok(output) => {
-                                let stderr = String::from_utf8_lossy(&output.stderr);
-                                tracing::warn!(vm_name = %vm_name, "VM setup command failed: {}", stderr);
-                            },
-                            Err(e) => {
-                                tracing::warn!(vm_name = %vm_name, "VM setup command error: {}", e);
-                            },
-                        }
-                    }
-                }
+                vms.push(Arc::new(Mutex::new(PooledVm {
+                    name: vm_name,
+                    image: image.name.clone(),
+                    ip,
+                    state: VmState::Ready,
+                })));
             }
-
-            tracing::info!(vm_name = %vm_name, ip = %ip, "VM ready in pool");
-
-            vms.push(Arc::new(Mutex::new(PooledVm {
-                name: vm_name,
-                ip,
-                state: VmState::Ready,
-            })));
         }
 
-        tracing::info!(pool_size = pool_size, "VM pool initialized");
+        tracing::info!(warm_vms = vms.len(), "VM pool initialized");
 
         Ok(Self {
-            vms,
-            base_image: base_image.to_string(),
+            backend,
+            images: prepared,
+            vms: Mutex::new(vms),
+            max_vms,
+            setup_script: setup_script.map(str::to_string),
+            reuse,
+            ready: Arc::new(Notify::new()),
+            refills: Mutex::new(JoinSet::new()),
         })
     }
 
-    /// Wait for VM to get an IP address (with timeout)
-    async fn wait_for_ip(vm_name: &str) -> Option<String> {
-        let start = std::time::Instant::now();
-        let timeout = std::time::Duration::from_secs(60);
-
-        while start.elapsed() < timeout {
-            match Command::new("tart").args(["ip", vm_name]).output().await {
-                Ok(output) if output.status.success() => {
-                    let ip = String::from_utf8_lossy(&output.stdout).trim().to_string();
-                    if !ip.is_empty() {
-                        return Some(ip);
-                    }
-                },
-                _ => {},
+    /// Names of the images jobs can ask for
+    pub fn image_names(&self) -> Vec<String> {
+        self.images.iter().map(|image| image.name.clone()).collect()
+    }
+
+    /// The image called `name`, or the default one
+    fn image(&self, name: Option<&str>) -> Result<&PoolImage> {
+        name.map_or_else(
+            || Ok(&self.images[0]),
+            |name| {
+                self.images
+                    .iter()
+                    .find(|image| image.name == name)
+                    .ok_or_else(|| anyhow::anyhow!("This worker has no image called {name}"))
+            },
+        )
+    }
+
+    /// Acquire a ready VM of an image (the default if `None`), cloning one if
+    /// none is warm and waiting for a VM to be released if the pool is full
+    pub async fn acquire(&self, image: Option<&str>) -> Result<Arc<Mutex<PooledVm>>> {
+        let image = self.image(image)?;
+
+        loop {
+            // Register for wakeups before checking so a refill can't slip past us
+            let notified = self.ready.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            match self.try_acquire(image).await {
+                Acquired::Warm(vm) => return Ok(vm),
+                Acquired::Cold(vm, retired) => return self.clone_cold(image, vm, retired).await,
+                Acquired::Full => {},
             }
 
-            tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
+            tracing::debug!(image = %image.name, "No VM ready, waiting for the pool to refill...");
+            notified.await;
         }
-
-        None
     }
 
-    /// Acquire a ready VM from the pool
-    pub async fn acquire(&self) -> Option<Arc<Mutex<PooledVm>>> {
-        for vm in &self.vms {
+    /// Take a ready VM of the image, or else claim room for a cold clone:
+    /// either a free place in the pool or an idle VM of another image
+    async fn try_acquire(&self, image: &PoolImage) -> Acquired {
+        let mut vms = self.vms.lock().await;
+
+        let mut idle = None;
+        for vm in vms.iter() {
             let mut guard = vm.lock().await;
             if guard.state == VmState::Ready {
-                guard.state = VmState::InUse;
-
-                // Refresh IP if empty
-                if guard.ip.is_empty() {
-                    if let Ok(output) = Command::new("tart")
-                        .args(["ip", &guard.name])
-                        .output()
-                        .await
-                    {
-                        if output.status.success() {
-                            guard.ip = String::from_utf8_lossy(&output.stdout).trim().to_string();
-                        }
-                    }
+                if guard.image == image.name {
+                    guard.state = VmState::InUse;
+                    tracing::info!(vm_name = %guard.name, image = %image.name, "Acquired VM from pool");
+                    return Acquired::Warm(Arc::clone(vm));
+                }
+                if idle.is_none() {
+                    idle = Some(Arc::clone(vm));
                 }
-
-                tracing::info!(vm_name = %guard.name, "Acquired VM from pool");
-                return Some(Arc::clone(vm));
             }
         }
-        None
+
+        if vms.len() < self.max_vms {
+            let vm = Arc::new(Mutex::new(PooledVm {
+                name: next_vm_name(&vms, &image.name).await,
+                image: image.name.clone(),
+                ip: String::new(),
+                state: VmState::InUse,
+            }));
+            vms.push(Arc::clone(&vm));
+            return Acquired::Cold(vm, None);
+        }
+        drop(vms);
+
+        match idle {
+            Some(vm) => {
+                let retired = {
+                    let mut guard = vm.lock().await;
+                    guard.state = VmState::InUse;
+                    guard.name.clone()
+                };
+                Acquired::Cold(vm, Some(retired))
+            },
+            None => Acquired::Full,
+        }
+    }
+
+    /// Provision a VM of the image in a claimed place, first deleting the VM
+    /// of another image that held it if any
+    async fn clone_cold(
+        &self,
+        image: &PoolImage,
+        vm: Arc<Mutex<PooledVm>>,
+        retired: Option<String>,
+    ) -> Result<Arc<Mutex<PooledVm>>> {
+        if let Some(retired) = retired {
+            tracing::info!(vm_name = %retired, image = %image.name, "Retiring idle VM to make room");
+            delete_vm(self.backend.as_ref(), &retired).await;
+            self.rename(&vm, &image.name).await;
+        }
+
+        let vm_name = vm.lock().await.name.clone();
+        tracing::info!(vm_name = %vm_name, image = %image.name, "No warm VM of the image, cloning one...");
+        match provision(
+            self.backend.as_ref(),
+            &vm_name,
+            &image.image,
+            self.setup_script.as_deref(),
+            false,
+        )
+        .await
+        {
+            Ok(ip) => {
+                vm.lock().await.ip = ip;
+                Ok(vm)
+            },
+            Err(e) => {
+                delete_vm(self.backend.as_ref(), &vm_name).await;
+                self.remove(&vm).await;
+                Err(e)
+            },
+        }
+    }
+
+    /// Move a VM to another image, giving it a name of that image
+    async fn rename(&self, vm: &Arc<Mutex<PooledVm>>, image: &str) {
+        let vms = self.vms.lock().await;
+        let name = next_vm_name(&vms, image).await;
+        let mut guard = vm.lock().await;
+        guard.name = name;
+        guard.image = image.to_string();
+        drop(guard);
+        drop(vms);
+    }
+
+    /// Forget a deleted VM, making room for another
+    async fn remove(&self, vm: &Arc<Mutex<PooledVm>>) {
+        self.vms
+            .lock()
+            .await
+            .retain(|other| !Arc::ptr_eq(other, vm));
+        self.ready.notify_waiters();
     }
 
-    /// Release a VM back to the pool after resetting it
+    /// Return a VM that was acquired but never used for a job
+    pub async fn put_back(&self, vm: Arc<Mutex<PooledVm>>) {
+        vm.lock().await.state = VmState::Ready;
+        self.ready.notify_waiters();
+    }
+
+    /// Release a VM after a job: wipe it in reuse mode, otherwise replace it
+    /// in the background with a fresh clone of the image furthest below its
+    /// warm count, or delete it if every image has its warm VMs
     pub async fn release(&self, vm: Arc<Mutex<PooledVm>>) -> Result<()> {
-        let vm_name = {
+        let snapshot = {
             let mut guard = vm.lock().await;
             guard.state = VmState::Resetting;
-            guard.name.clone()
+            guard.clone()
         };
+        let vm_name = &snapshot.name;
+
+        if !self.reuse {
+            if let Some(image) = self.refill_image(&vm, &snapshot.image).await {
+                if image.name != snapshot.image {
+                    delete_vm(self.backend.as_ref(), vm_name).await;
+                    self.rename(&vm, &image.name).await;
+                }
+                tracing::info!(vm_name = %vm_name, image = %image.name, "Replacing VM with a fresh clone...");
+                self.refills.lock().await.spawn(refill(
+                    Arc::clone(&self.backend),
+                    vm,
+                    image.image.clone(),
+                    self.setup_script.clone(),
+                    Arc::clone(&self.ready),
+                ));
+            } else {
+                tracing::info!(vm_name = %vm_name, "Deleting VM, every image has its warm VMs");
+                delete_vm(self.backend.as_ref(), vm_name).await;
+                self.remove(&vm).await;
+            }
+            return Ok(());
+        }
 
         tracing::info!(vm_name = %vm_name, "Resetting VM...");
 
         // Reset VM by cleaning workspace
-        let _ = Command::new("sshpass")
-            .args([
-                "-p",
-                "admin",
-                "ssh",
-                "-o",
-                "StrictHostKeyChecking=no",
-                "-o",
-                "UserKnownHostsFile=/dev/null",
-                "-o",
-                "PubkeyAuthentication=no",
-                &format!("admin@{}", vm.lock().await.ip),
-                "rm -rf ~/workspace ~/source.zip",
-            ])
-            .output()
-            .await;
+        match self.backend.connect(&snapshot).await {
+            Ok(session) => {
+                let _ = session.exec("rm -rf ~/workspace ~/source.zip").await;
+            },
+            Err(e) => tracing::warn!(vm_name = %vm_name, "Failed to reset VM: {}", e),
+        }
 
         // Mark as ready
         {
@@ -273,26 +344,236 @@ impl VmPool {
             guard.state = VmState::Ready;
             tracing::info!(vm_name = %guard.name, "VM reset and ready");
         }
+        self.ready.notify_waiters();
 
         Ok(())
     }
 
+    /// The image furthest below its warm count, not counting `released`
+    /// (preferring its own image on ties), if any is below it
+    async fn refill_image(&self, released: &Arc<Mutex<PooledVm>>, own: &str) -> Option<&PoolImage> {
+        let mut warm = vec![0; self.images.len()];
+        for vm in self.vms.lock().await.iter() {
+            if Arc::ptr_eq(vm, released) {
+                continue;
+            }
+            let guard = vm.lock().await;
+            if guard.state != VmState::InUse {
+                if let Some(i) = self
+                    .images
+                    .iter()
+                    .position(|image| image.name == guard.image)
+                {
+                    warm[i] += 1;
+                }
+            }
+        }
+
+        self.images
+            .iter()
+            .zip(warm)
+            .filter(|(image, warm)| *warm < image.warm)
+            .max_by_key(|(image, warm)| (image.warm - warm, image.name == own))
+            .map(|(image, _)| image)
+    }
+
     /// Shutdown all VMs in the pool
     pub async fn shutdown(&self) {
         tracing::info!("Shutting down VM pool...");
 
-        for vm in &self.vms {
+        // Stop any in-progress refills so they don't recreate deleted VMs
+        self.refills.lock().await.shutdown().await;
+
+        let vms = self.vms.lock().await.clone();
+        for vm in vms {
             let vm_name = vm.lock().await.name.clone();
             tracing::info!(vm_name = %vm_name, "Stopping VM...");
+            delete_vm(self.backend.as_ref(), &vm_name).await;
+        }
 
-            let _ = Command::new("tart").args(["stop", &vm_name]).output().await;
+        tracing::info!("VM pool shutdown complete");
+    }
+}
+
+/// What `try_acquire` found
+enum Acquired {
+    /// A ready VM of the image
+    Warm(Arc<Mutex<PooledVm>>),
+    /// A place for a cold clone, and the idle VM it is taken from if any
+    Cold(Arc<Mutex<PooledVm>>, Option<String>),
+    /// No room until a VM is released
+    Full,
+}
+
+/// Name of the `i`th VM of an image
+fn vm_name(image: &str, i: usize) -> String {
+    format!("pool-vm-{image}-{i}")
+}
 
-            let _ = Command::new("tart")
-                .args(["delete", &vm_name])
-                .output()
-                .await;
+/// The first name of an image no VM has
+async fn next_vm_name(vms: &[Arc<Mutex<PooledVm>>], image: &str) -> String {
+    let mut taken = Vec::with_capacity(vms.len());
+    for vm in vms {
+        taken.push(vm.lock().await.name.clone());
+    }
+    (0..=vms.len())
+        .map(|i| vm_name(image, i))
+        .find(|name| !taken.contains(name))
+        .expect("fewer VMs than names")
+}
+
+/// Delete a used VM and provision a fresh one under the same name
+async fn refill(
+    backend: Arc<dyn VmBackend>,
+    vm: Arc<Mutex<PooledVm>>,
+    base_image: String,
+    setup_script: Option<String>,
+    ready: Arc<Notify>,
+) {
+    let vm_name = vm.lock().await.name.clone();
+
+    loop {
+        match provision(
+            backend.as_ref(),
+            &vm_name,
+            &base_image,
+            setup_script.as_deref(),
+            false,
+        )
+        .await
+        {
+            Ok(ip) => {
+                let mut guard = vm.lock().await;
+                guard.ip = ip;
+                guard.state = VmState::Ready;
+                drop(guard);
+                break;
+            },
+            Err(e) => {
+                tracing::error!(vm_name = %vm_name, "Failed to re-provision VM: {}", e);
+                tokio::time::sleep(REFILL_RETRY_DELAY).await;
+            },
         }
+    }
 
-        tracing::info!("VM pool shutdown complete");
+    tracing::info!(vm_name = %vm_name, "Fresh VM ready in pool");
+    ready.notify_waiters();
+}
+
+/// Clone, boot and set up a VM from the base image, returning its IP.
+///
+/// Unless `reuse_existing` is set, a leftover VM with the same name is deleted
+/// first so the new one starts from a clean base image.
+async fn provision(
+    backend: &dyn VmBackend,
+    vm_name: &str,
+    base_image: &str,
+    setup_script: Option<&str>,
+    reuse_existing: bool,
+) -> Result<String> {
+    if !reuse_existing {
+        delete_vm(backend, vm_name).await;
+    }
+
+    // Clone the VM
+    tracing::info!(vm_name = %vm_name, "Cloning VM for pool...");
+    backend.clone_vm(base_image, vm_name).await?;
+
+    // Start the VM
+    tracing::info!(vm_name = %vm_name, "Starting VM...");
+    backend.run(vm_name).await?;
+
+    // Wait for VM to boot and get IP
+    let ip = wait_for_ip(backend, vm_name)
+        .await
+        .ok_or_else(|| anyhow::anyhow!("VM {vm_name} did not get an IP address"))?;
+
+    // Wait for it to accept connections (pinning its host key)
+    let vm = PooledVm {
+        name: vm_name.to_string(),
+        image: String::new(),
+        ip: ip.clone(),
+        state: VmState::Resetting,
+    };
+    backend.wait_ready(&vm).await?;
+
+    // Run setup script if provided
+    if let Some(script) = setup_script {
+        run_setup_script(backend, &vm, script).await;
+    }
+
+    tracing::info!(vm_name = %vm_name, ip = %ip, "VM ready in pool");
+    Ok(ip)
+}
+
+/// Wait for VM to get an IP address (with timeout)
+#[allow(clippy::duration_suboptimal_units)]
+pub async fn wait_for_ip(backend: &dyn VmBackend, vm_name: &str) -> Option<String> {
+    let start = std::time::Instant::now();
+    let timeout = Duration::from_secs(60);
+
+    while start.elapsed() < timeout {
+        if let Ok(Some(ip)) = backend.ip(vm_name).await {
+            return Some(ip);
+        }
+
+        tokio::time::sleep(Duration::from_millis(1000)).await;
+    }
+
+    None
+}
+
+/// Run the configured setup script (a file path or an inline command) in the VM
+async fn run_setup_script(backend: &dyn VmBackend, vm: &PooledVm, script: &str) {
+    let vm_name = &vm.name;
+    let session = match backend.connect(vm).await {
+        Ok(session) => session,
+        Err(e) => {
+            tracing::warn!(vm_name = %vm_name, "VM setup error: {}", e);
+            return;
+        },
+    };
+
+    // Check if script is a file path
+    let script_path = std::path::Path::new(script);
+    let command = if script_path.exists() && script_path.is_file() {
+        tracing::info!(vm_name = %vm_name, script = %script, "Copying setup script to VM...");
+
+        // Copy script to VM
+        if let Err(e) = session.upload(script_path, "~/setup.sh").await {
+            tracing::warn!(vm_name = %vm_name, "Failed to copy setup script: {}", e);
+            return;
+        }
+
+        tracing::info!(vm_name = %vm_name, "Running VM setup script...");
+        "chmod +x ~/setup.sh && ~/setup.sh"
+    } else {
+        // Run as inline command
+        tracing::info!(vm_name = %vm_name, "Running VM setup command...");
+        script
+    };
+
+    match session.exec(command).await {
+        Ok(output) if output.success() => {
+            tracing::info!(vm_name = %vm_name, "VM setup completed");
+        },
+        Ok(output) => {
+            tracing::warn!(
+                vm_name = %vm_name,
+                "VM setup failed: {}{}",
+                output.stdout_lossy(),
+                output.stderr_lossy()
+            );
+        },
+        Err(e) => {
+            tracing::warn!(vm_name = %vm_name, "VM setup error: {}", e);
+        },
+    }
+}
+
+/// Stop and delete a VM, logging failures
+async fn delete_vm(backend: &dyn VmBackend, vm_name: &str) {
+    if let Err(e) = backend.delete(vm_name).await {
+        tracing::warn!("Failed to delete VM {}: {}", vm_name, e);
     }
 }